@@ -10,25 +10,28 @@ extern crate termcolor;
 use aho_corasick::{Automaton, AcAutomaton};
 use clap::{Arg, ArgMatches, App};
 use std::collections::{HashMap, HashSet};
+use std::env;
 use std::ffi::{OsStr, OsString};
-use std::fs::File;
-use std::io::Write;
+use std::fs::{self, File};
+use std::io::{Read, Write};
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::process;
 
 use tectonic::config::PersistentConfig;
-use tectonic::digest::DigestData;
+use tectonic::digest::{self, Digest, DigestData};
 use tectonic::engines::IoEventBackend;
 use tectonic::errors::{ErrorKind, Result, ResultExt};
-use tectonic::io::{FilesystemIo, FilesystemPrimaryInputIo, GenuineStdoutIo, InputOrigin,
-                   IoProvider, IoStack, MemoryIo, OpenResult};
+use tectonic::io::{AtomicFileWriter, FilesystemIo, FilesystemPrimaryInputIo, GenuineStdoutIo,
+                   InputOrigin, IoProvider, IoStack, IoStackBuilder, MemoryIo, OpenResult};
 use tectonic::io::itarbundle::{HttpITarIoFactory, ITarBundle};
-use tectonic::io::stdstreams::BufferedPrimaryIo;
+use tectonic::io::stdstreams::{BufferedFormatIo, BufferedPrimaryIo};
+use tectonic::io::texmfconfig::TexmfConfigIo;
 use tectonic::io::zipbundle::ZipBundle;
 use tectonic::status::{ChatterLevel, StatusBackend};
 use tectonic::status::termcolor::TermcolorStatusBackend;
-use tectonic::{BibtexEngine, TexEngine, TexResult, XdvipdfmxEngine};
+use tectonic::texlog::{self, WarningCategory};
+use tectonic::{BibtexEngine, EngineMode, TexEngine, TexResult, XdvipdfmxEngine};
 
 
 /// The CliIoSetup struct encapsulates, well, the input/output setup used by
@@ -51,44 +54,54 @@ struct CliIoSetup {
     filesystem: FilesystemIo,
     genuine_stdout: Option<GenuineStdoutIo>,
     format_primary: Option<BufferedPrimaryIo>,
+    pinned_format: Option<BufferedFormatIo>,
+    config: TexmfConfigIo,
+    prefer_bundle: bool,
 }
 
 impl CliIoSetup {
     fn as_stack<'a> (&'a mut self) -> IoStack<'a> {
-        let mut providers: Vec<&mut IoProvider> = Vec::new();
+        let mut builder = IoStackBuilder::new();
 
         if let Some(ref mut p) = self.genuine_stdout {
-            providers.push(p);
+            builder.stdout(p);
         }
 
-        providers.push(&mut *self.primary_input);
-        providers.push(&mut self.mem);
-        providers.push(&mut self.filesystem);
+        builder.primary(&mut *self.primary_input);
+
+        if let Some(ref mut f) = self.pinned_format {
+            builder.format(f);
+        }
+
+        builder.memory(&mut self.mem);
+        builder.filesystem(&mut self.filesystem);
 
         if let Some(ref mut b) = self.bundle {
-            providers.push(&mut **b);
+            builder.bundle(&mut **b);
         }
 
-        IoStack::new(providers)
+        builder.defaults(&mut self.config);
+        builder.prefer_bundle(self.prefer_bundle);
+        builder.create()
     }
 
     fn as_stack_for_format<'a> (&'a mut self, kickstart: &str) -> IoStack<'a> {
-        let mut providers: Vec<&mut IoProvider> = Vec::new();
+        let mut builder = IoStackBuilder::new();
 
         if let Some(ref mut p) = self.genuine_stdout {
-            providers.push(p);
+            builder.stdout(p);
         }
 
-
         self.format_primary = Some(BufferedPrimaryIo::from_text(kickstart));
-        providers.push(self.format_primary.as_mut().unwrap());
-        providers.push(&mut self.mem);
+        builder.primary(self.format_primary.as_mut().unwrap());
+        builder.memory(&mut self.mem);
 
         if let Some(ref mut b) = self.bundle {
-            providers.push(&mut **b);
+            builder.bundle(&mut **b);
         }
 
-        IoStack::new(providers)
+        builder.defaults(&mut self.config);
+        builder.create()
     }
 }
 
@@ -102,6 +115,8 @@ struct CliIoBuilder {
     bundle: Option<Box<IoProvider>>,
     use_genuine_stdout: bool,
     hidden_input_paths: HashSet<PathBuf>,
+    format_bytes: Option<Vec<u8>>,
+    prefer_bundle: bool,
 }
 
 impl Default for CliIoBuilder {
@@ -113,6 +128,8 @@ impl Default for CliIoBuilder {
             bundle: None,
             use_genuine_stdout: false,
             hidden_input_paths: HashSet::new(),
+            format_bytes: None,
+            prefer_bundle: false,
         }
     }
 }
@@ -136,6 +153,10 @@ impl CliIoBuilder {
         self
     }
 
+    /// Set the directory against which relative input paths are resolved.
+    /// This is not necessarily the current directory: like other TeX
+    /// engines, we resolve them against the directory containing the
+    /// primary input file.
     fn filesystem_root<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
         self.filesystem_root = path.as_ref().to_owned();
         self
@@ -161,6 +182,19 @@ impl CliIoBuilder {
         self
     }
 
+    /// Use these data as the format file, rather than locating (or
+    /// generating) one in the bundle.
+    fn format_bytes(&mut self, data: Vec<u8>) -> &mut Self {
+        self.format_bytes = Some(data);
+        self
+    }
+
+    /// Look for support files in the bundle before the filesystem.
+    fn prefer_bundle(&mut self, setting: bool) -> &mut Self {
+        self.prefer_bundle = setting;
+        self
+    }
+
     fn create(self) -> Result<CliIoSetup> {
         let pio: Box<IoProvider> = if self.use_stdin {
             Box::new(ctry!(BufferedPrimaryIo::from_stdin(); "error reading standard input"))
@@ -181,6 +215,9 @@ impl CliIoBuilder {
                 None
             },
             format_primary: None,
+            pinned_format: self.format_bytes.map(BufferedFormatIo::new),
+            config: TexmfConfigIo::new(),
+            prefer_bundle: self.prefer_bundle,
         })
     }
 }
@@ -240,15 +277,26 @@ impl FileSummary {
 /// The CliIoEvents type implements the IoEventBackend. The CLI uses it to
 /// figure out when to rerun the TeX engine; to figure out which files should
 /// be written to disk; and to emit Makefile rules.
-struct CliIoEvents(HashMap<OsString, FileSummary>);
+struct CliIoEvents {
+    files: HashMap<OsString, FileSummary>,
+
+    /// If set, this is called with the page number each time the TeX engine
+    /// ships out a page.
+    on_page: Option<Box<Fn(usize)>>,
+}
 
 impl CliIoEvents {
-    fn new() -> CliIoEvents { CliIoEvents(HashMap::new()) }
+    fn new() -> CliIoEvents {
+        CliIoEvents {
+            files: HashMap::new(),
+            on_page: None,
+        }
+    }
 }
 
 impl IoEventBackend for CliIoEvents {
     fn output_opened(&mut self, name: &OsStr) {
-        if let Some(summ) = self.0.get_mut(name) {
+        if let Some(summ) = self.files.get_mut(name) {
             summ.access_pattern = match summ.access_pattern {
                 AccessPattern::Read => AccessPattern::ReadThenWritten,
                 c => c, // identity mapping makes sense for remaining options
@@ -256,14 +304,14 @@ impl IoEventBackend for CliIoEvents {
             return;
         }
 
-        self.0.insert(name.to_os_string(), FileSummary::new(AccessPattern::Written, InputOrigin::NotInput));
+        self.files.insert(name.to_os_string(), FileSummary::new(AccessPattern::Written, InputOrigin::NotInput));
     }
 
     fn stdout_opened(&mut self) {
         // Life is easier if we track stdout in the same way that we do other
         // output files.
 
-        if let Some(summ) = self.0.get_mut(OsStr::new("")) {
+        if let Some(summ) = self.files.get_mut(OsStr::new("")) {
             summ.access_pattern = match summ.access_pattern {
                 AccessPattern::Read => AccessPattern::ReadThenWritten,
                 c => c, // identity mapping makes sense for remaining options
@@ -271,11 +319,11 @@ impl IoEventBackend for CliIoEvents {
             return;
         }
 
-        self.0.insert(OsString::from(""), FileSummary::new(AccessPattern::Written, InputOrigin::NotInput));
+        self.files.insert(OsString::from(""), FileSummary::new(AccessPattern::Written, InputOrigin::NotInput));
     }
 
     fn output_closed(&mut self, name: OsString, digest: DigestData) {
-        let mut summ = self.0.get_mut(&name).expect("closing file that wasn't opened?");
+        let mut summ = self.files.get_mut(&name).expect("closing file that wasn't opened?");
         summ.write_digest = Some(digest);
     }
 
@@ -285,7 +333,7 @@ impl IoEventBackend for CliIoEvents {
         // don't see how such a file could have previously been written, but
         // let's use the full update logic just in case.
 
-        if let Some(summ) = self.0.get_mut(name) {
+        if let Some(summ) = self.files.get_mut(name) {
             summ.access_pattern = match summ.access_pattern {
                 AccessPattern::Written => AccessPattern::WrittenThenRead,
                 c => c, // identity mapping makes sense for remaining options
@@ -299,11 +347,11 @@ impl IoEventBackend for CliIoEvents {
         // is the contents of the file the very first time it was read.
         let mut fs = FileSummary::new(AccessPattern::Read, InputOrigin::NotInput);
         fs.read_digest = Some(DigestData::of_nothing());
-        self.0.insert(name.to_os_string(), fs);
+        self.files.insert(name.to_os_string(), fs);
     }
 
     fn input_opened(&mut self, name: &OsStr, origin: InputOrigin) {
-        if let Some(summ) = self.0.get_mut(name) {
+        if let Some(summ) = self.files.get_mut(name) {
             summ.access_pattern = match summ.access_pattern {
                 AccessPattern::Written => AccessPattern::WrittenThenRead,
                 c => c, // identity mapping makes sense for remaining options
@@ -311,13 +359,19 @@ impl IoEventBackend for CliIoEvents {
             return;
         }
 
-        self.0.insert(name.to_os_string(), FileSummary::new(AccessPattern::Read, origin));
+        self.files.insert(name.to_os_string(), FileSummary::new(AccessPattern::Read, origin));
     }
 
     //fn primary_input_opened(&mut self, _origin: InputOrigin) {}
 
+    fn page_shipped(&mut self, page_number: usize) {
+        if let Some(ref f) = self.on_page {
+            f(page_number);
+        }
+    }
+
     fn input_closed(&mut self, name: OsString, digest: Option<DigestData>) {
-        let mut summ = self.0.get_mut(&name).expect("closing file that wasn't opened?");
+        let mut summ = self.files.get_mut(&name).expect("closing file that wasn't opened?");
 
         // It's what was in the file the *first* time that it was read that
         // matters, so don't replace the read digest if it's already got one.
@@ -370,6 +424,7 @@ struct ProcessingSession {
     /// TeX paths, even though I've since realized that it should really just
     /// use String.
     tex_aux_path: OsString,
+    tex_log_path: OsString,
     tex_xdv_path: OsString,
     tex_pdf_path: OsString,
 
@@ -377,6 +432,10 @@ struct ProcessingSession {
     /// engine doesn't know about this path at all.
     makefile_output_path: Option<PathBuf>,
 
+    /// If we're writing out a JSON manifest of the files that we save, this
+    /// is where it goes.
+    manifest_output_path: Option<PathBuf>,
+
     /// This is the path, the processed file will be saved at. It defaults 
     /// to the path of `primary_input_path` or `.` if STDIN is used.
     output_path: PathBuf,
@@ -388,6 +447,18 @@ struct ProcessingSession {
     keep_logs: bool,
     noted_tex_warnings: bool,
     synctex_enabled: bool,
+
+    /// If set, the engines use this as the current time (in seconds since
+    /// the Unix epoch), for reproducible output.
+    source_date_epoch: Option<i64>,
+
+    /// If nonempty, a successful run is turned into a failure if the TeX log
+    /// contains any warnings of these kinds.
+    fatal_warnings: Vec<WarningCategory>,
+
+    /// If set, copies of the bundle files that the engines read are saved
+    /// in this directory.
+    keep_bundle_files_dir: Option<PathBuf>,
 }
 
 
@@ -420,6 +491,28 @@ impl ProcessingSession {
         };
 
         let makefile_output_path = args.value_of_os("makefile_rules").map(|s| s.into());
+        let manifest_output_path = args.value_of_os("manifest").map(|s| s.into());
+
+        // This is the convention of https://reproducible-builds.org/.
+        let source_date_epoch = match env::var("SOURCE_DATE_EPOCH") {
+            Ok(s) => match s.parse::<i64>() {
+                Ok(t) if t >= 0 => Some(t),
+                _ => {
+                    tt_warning!(status, "ignoring invalid SOURCE_DATE_EPOCH value \"{}\"", s);
+                    None
+                },
+            },
+            Err(_) => None,
+        };
+
+        let fatal_warnings = if args.is_present("warnings_as_errors") {
+            match args.value_of("warning_kinds") {
+                Some(kinds) => kinds.split(',').map(|k| k.trim().parse()).collect::<Result<Vec<_>>>()?,
+                None => WarningCategory::all(),
+            }
+        } else {
+            Vec::new()
+        };
 
         // Input and path setup
 
@@ -448,6 +541,8 @@ impl ProcessingSession {
             };
 
             if let Some(par) = tex_path.parent() {
+                // So that `\input{fig}` in `/docs/paper.tex` finds
+                // `/docs/fig.tex`, regardless of the current directory.
                 output_path = par;
                 io_builder.filesystem_root(par);
             } else {
@@ -465,6 +560,8 @@ impl ProcessingSession {
 
         let mut aux_path = Path::new(tex_input_stem).to_owned();
         aux_path.set_extension("aux");
+        let mut log_path = aux_path.clone();
+        log_path.set_extension("log");
         let mut xdv_path = aux_path.clone();
         xdv_path.set_extension("xdv");
         let mut pdf_path = aux_path.clone();
@@ -473,6 +570,7 @@ impl ProcessingSession {
         // Set up the rest of I/O.
 
         io_builder.use_genuine_stdout(args.is_present("print_stdout"));
+        io_builder.prefer_bundle(args.is_present("prefer_bundle"));
 
         if let Some(items) = args.values_of_os("hide") {
             for v in items {
@@ -480,9 +578,35 @@ impl ProcessingSession {
             }
         }
 
+        if let Some(p) = args.value_of_os("format_file") {
+            let mut data = Vec::new();
+            let mut f = ctry!(File::open(p); "error opening format file \"{}\"", p.to_string_lossy());
+            ctry!(f.read_to_end(&mut data); "error reading format file \"{}\"", p.to_string_lossy());
+            io_builder.format_bytes(data);
+        }
+
+        let keep_bundle_files_dir = args.value_of_os("keep_bundle_files").map(PathBuf::from);
+
         if let Some(p) = args.value_of("bundle") {
-            let zb = ctry!(ZipBundle::<File>::open(Path::new(&p)); "error opening bundle");
-            io_builder.bundle(zb);
+            let p = Path::new(p);
+
+            if p.is_dir() {
+                // An unpacked bundle, handy when developing bundles. We can
+                // just read its files directly, but they're still bundle
+                // files as far as dependency tracking is concerned.
+                let mut dirbundle = FilesystemIo::new(p, false, false, HashSet::new());
+                dirbundle.serves_bundle(true);
+                io_builder.bundle(dirbundle);
+            } else {
+                let mut zb = ctry!(ZipBundle::<File>::open(p); "error opening bundle");
+
+                if let Some(ref d) = keep_bundle_files_dir {
+                    ctry!(fs::create_dir_all(d); "couldn't create directory {}", d.display());
+                    zb.keep_extracted_files(d);
+                }
+
+                io_builder.bundle(zb);
+            }
         } else if let Some(u) = args.value_of("web_bundle") {
             let tb = ITarBundle::<HttpITarIoFactory>::new(&u);
             io_builder.bundle(tb);
@@ -502,20 +626,34 @@ impl ProcessingSession {
             primary_input_tex_path: tex_input_stem.to_string_lossy().into_owned(),
             format_path: format_path.to_owned(),
             tex_aux_path: aux_path.into_os_string(),
+            tex_log_path: log_path.into_os_string(),
             tex_xdv_path: xdv_path.into_os_string(),
             tex_pdf_path: pdf_path.into_os_string(),
             output_format: output_format,
             makefile_output_path: makefile_output_path,
+            manifest_output_path: manifest_output_path,
             output_path: output_path.to_owned(),
             tex_rerun_specification: reruns,
             keep_intermediates: args.is_present("keep_intermediates"),
             keep_logs: args.is_present("keep_logs"),
             noted_tex_warnings: false,
             synctex_enabled: args.is_present("synctex"),
+            source_date_epoch: source_date_epoch,
+            fatal_warnings: fatal_warnings,
+            keep_bundle_files_dir: keep_bundle_files_dir,
         })
     }
 
 
+    /// Register a function to be called with the page number each time the
+    /// TeX engine ships out a page, e.g. to drive a progress display. Page
+    /// numbers restart from 1 at each TeX pass.
+    fn on_page<F: 'static + Fn(usize)>(&mut self, f: F) -> &mut Self {
+        self.events.on_page = Some(Box::new(f));
+        self
+    }
+
+
     /// Assess whether we need to rerun an engine. This is the case if there
     /// was a file that the engine read and then rewrote, and the rewritten
     /// version is different than the version that it read in.
@@ -524,7 +662,7 @@ impl ProcessingSession {
         // stuff could get finicky and we're going to want to be able to
         // figure out why rerun detection is breaking.
 
-        for (name, info) in &self.events.0 {
+        for (name, info) in &self.events.files {
             if info.access_pattern == AccessPattern::ReadThenWritten {
                 let file_changed = match (&info.read_digest, &info.write_digest) {
                     (&Some(ref d1), &Some(ref d2)) => d1 != d2,
@@ -548,7 +686,7 @@ impl ProcessingSession {
 
     #[allow(dead_code)]
     fn _dump_access_info(&self, status: &mut TermcolorStatusBackend) {
-        for (name, info) in &self.events.0 {
+        for (name, info) in &self.events.files {
             if info.access_pattern != AccessPattern::Read {
                 use std::string::ToString;
                 let r = match info.read_digest {
@@ -566,6 +704,29 @@ impl ProcessingSession {
         }
     }
 
+    /// If we've been asked to treat warnings as errors, look through the TeX
+    /// log for them, and fail if any are found.
+    fn check_fatal_warnings(&mut self, status: &mut TermcolorStatusBackend) -> Result<()> {
+        if self.fatal_warnings.is_empty() {
+            return Ok(());
+        }
+
+        let warnings = match self.io.mem.files.borrow().get(&self.tex_log_path) {
+            Some(data) => texlog::find_warnings(&String::from_utf8_lossy(data), &self.fatal_warnings),
+            None => Vec::new(),
+        };
+
+        if warnings.is_empty() {
+            return Ok(());
+        }
+
+        for w in &warnings {
+            tt_error!(status, "{}", w);
+        }
+
+        Err(ErrorKind::Msg(format!("{} TeX warning(s) were treated as errors", warnings.len())).into())
+    }
+
     fn run(&mut self, status: &mut TermcolorStatusBackend) -> Result<i32> {
         // Do we need to generate the format file?
 
@@ -599,6 +760,8 @@ impl ProcessingSession {
             PassSetting::BibtexFirst => self.default_pass(true, status),
         };
 
+        let result = result.and_then(|_| self.check_fatal_warnings(status));
+
         if let Err(e) = result {
             self.write_files(None, status, true)?;
             return Err(e);
@@ -627,10 +790,13 @@ impl ProcessingSession {
                 ctry!(mf_dest.write_all(pip.as_os_str().as_bytes()); "couldn't write to Makefile-rules file");
             }
 
-            for (name, info) in &self.events.0 {
-                if info.input_origin != InputOrigin::Filesystem {
-                    continue;
-                }
+            for (name, info) in &self.events.files {
+                // List the file where it was found, which may not be where
+                // the engine asked for it.
+                let path = match info.input_origin.path() {
+                    Some(p) => p,
+                    None => continue,
+                };
 
                 if info.got_written_to_disk {
                     // If the file originally came from the filesystem, and it
@@ -646,12 +812,22 @@ impl ProcessingSession {
                     continue;
                 }
 
-                ctry!(write!(mf_dest, " \\\n  {}", name.to_string_lossy()); "couldn't write to Makefile-rules file");
+                ctry!(write!(mf_dest, " \\\n  {}", path.display()); "couldn't write to Makefile-rules file");
             }
 
             ctry!(writeln!(mf_dest, ""); "couldn't write to Makefile-rules file");
         }
 
+        // Write the manifest, maybe.
+
+        if let Some(ref p) = self.manifest_output_path {
+            ctry!(self.write_manifest(p); "couldn't write manifest file {}", p.display());
+        }
+
+        if let Some(ref d) = self.keep_bundle_files_dir {
+            tt_note!(status, "copies of the bundle files that were used are in {}", d.display());
+        }
+
         // All done.
 
         Ok(0)
@@ -667,7 +843,7 @@ impl ProcessingSession {
             }
 
             let sname = name.to_string_lossy();
-            let mut summ = self.events.0.get_mut(name).unwrap();
+            let mut summ = self.events.files.get_mut(name).unwrap();
 
             if !only_logs && (self.output_format == OutputFormat::Aux) {
                 // In this mode we're only writing the .aux file. I initially
@@ -703,8 +879,9 @@ impl ProcessingSession {
 
             status.note_highlighted("Writing ", &real_path.to_string_lossy(), &format!(" ({} bytes)", contents.len()));
 
-            let mut f = File::create(&real_path)?;
+            let mut f = AtomicFileWriter::create(&real_path)?;
             f.write_all(contents)?;
+            f.finish()?;
             summ.got_written_to_disk = true;
 
             if let Some(ref mut mf_dest) = mf_dest_maybe {
@@ -721,6 +898,34 @@ impl ProcessingSession {
         Ok(n_skipped_intermediates)
     }
 
+    /// Write a JSON file listing the name, byte size, and SHA256 digest of
+    /// each file that we saved to disk.
+    fn write_manifest(&self, path: &Path) -> Result<()> {
+        let files = self.io.mem.files.borrow();
+        let mut entries = Vec::new();
+
+        for (name, summ) in &self.events.files {
+            if !summ.got_written_to_disk {
+                continue;
+            }
+
+            let contents = match files.get(name) {
+                Some(c) => &c[..],
+                None => continue,
+            };
+
+            if let Some(ref digest) = summ.write_digest {
+                entries.push((name.as_os_str(), contents, digest));
+            }
+        }
+
+        let text = render_manifest(&entries)?;
+        let mut f = AtomicFileWriter::create(path)?;
+        f.write_all(text.as_bytes())?;
+        f.finish()?;
+        Ok(())
+    }
+
     /// The "default" pass really runs a bunch of sub-passes. It is a "Do What
     /// I Mean" operation.
     fn default_pass(&mut self, bibtex_first: bool, status: &mut TermcolorStatusBackend) -> Result<i32> {
@@ -784,7 +989,7 @@ impl ProcessingSession {
             // can later know that it's OK to delete. I am not super confident
             // that the access_pattern data can just be left as-is when we do
             // this, but, uh, so far it seems to work.
-            for summ in self.events.0.values_mut() {
+            for summ in self.events.files.values_mut() {
                 summ.read_digest = None;
             }
 
@@ -828,8 +1033,8 @@ impl ProcessingSession {
             let mut stack = self.io.as_stack_for_format(&format!("\\input tectonic-format-{}.tex", stem));
             TexEngine::new()
                     .halt_on_error_mode(true)
-                    .initex_mode(true)
-                    .process(&mut stack, &mut self.events, status, "UNUSED.fmt.gz", "texput")
+                    .process_in_mode(&mut stack, &mut self.events, status,
+                                     &EngineMode::InitFormat { dump_name: stem.to_owned() }, "texput")
         };
 
         match result {
@@ -896,6 +1101,7 @@ impl ProcessingSession {
                     .halt_on_error_mode(true)
                     .initex_mode(self.output_format == OutputFormat::Format)
                     .synctex(self.synctex_enabled)
+                    .source_date_epoch(self.source_date_epoch)
                     .process(&mut stack, &mut self.events, status, &self.format_path, &self.primary_input_tex_path)
         };
 
@@ -972,6 +1178,7 @@ impl ProcessingSession {
         {
             let mut stack = self.io.as_stack();
             let mut engine = XdvipdfmxEngine::new ();
+            engine.source_date_epoch(self.source_date_epoch);
             status.note_highlighted("Running ", "xdvipdfmx", " ...");
             engine.process(&mut stack, &mut self.events, status,
                            &self.tex_xdv_path.to_str().unwrap(), &self.tex_pdf_path.to_str().unwrap())?;
@@ -983,8 +1190,58 @@ impl ProcessingSession {
 }
 
 
+/// Render the JSON manifest written by `--manifest`. Each entry gives the
+/// name of a saved file, the data saved, and the digest that was recorded
+/// when the engine wrote it. The manifest is only useful if build tools can
+/// trust it, so it's an error for the data and the digest to disagree.
+fn render_manifest(entries: &[(&OsStr, &[u8], &DigestData)]) -> Result<String> {
+    let mut lines = Vec::new();
+
+    for &(name, contents, recorded) in entries {
+        let mut dc = digest::create();
+        dc.input(contents);
+
+        if DigestData::from(dc) != *recorded {
+            return Err(ErrorKind::Msg(format!("the saved contents of \"{}\" don't match what the engine wrote",
+                                              name.to_string_lossy())).into());
+        }
+
+        lines.push(format!("    {{\"name\": \"{}\", \"size\": {}, \"sha256\": \"{}\"}}",
+                           json_escape(&name.to_string_lossy()), contents.len(), recorded.to_string()));
+    }
+
+    lines.sort();
+    Ok(format!("{{\n  \"outputs\": [\n{}\n  ]\n}}\n", lines.join(",\n")))
+}
+
+
+/// Escape a string for inclusion in a JSON document.
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+
 fn inner(matches: ArgMatches, config: PersistentConfig, status: &mut TermcolorStatusBackend) -> Result<i32> {
     let mut sess = ProcessingSession::new(&matches, &config, status)?;
+
+    if matches.is_present("page_progress") {
+        // Imitate classic TeX's "[1] [2] ..." page-number chatter.
+        sess.on_page(|n| {
+            let _ = write!(::std::io::stderr(), "[{}] ", n);
+        });
+    }
+
     sess.run(status)
 }
 
@@ -998,11 +1255,24 @@ fn main() {
              .value_name("PATH")
              .help("The name of the \"format\" file used to initialize the TeX engine.")
              .default_value("latex"))
+        .arg(Arg::with_name("format_file")
+             .long("format-file")
+             .value_name("PATH")
+             .help("Use this precompiled (gzipped) format file rather than locating or generating one.")
+             .takes_value(true))
         .arg(Arg::with_name("bundle")
              .long("bundle")
              .short("b")
              .value_name("PATH")
-             .help("Use this Zip-format bundle file to find resource files instead of the default.")
+             .help("Use this Zip-format bundle file, or directory of unpacked bundle files, to find resource \
+                    files instead of the default.")
+             .takes_value(true))
+        .arg(Arg::with_name("keep_bundle_files")
+             .long("keep-bundle-files")
+             .value_name("DIR")
+             .requires("bundle")
+             .help("Save copies of the files read from the Zip-format bundle in this directory, for \
+                    inspection after the run.")
              .takes_value(true))
         .arg(Arg::with_name("web_bundle")
              .long("web-bundle")
@@ -1010,6 +1280,10 @@ fn main() {
              .value_name("URL")
              .help("Use this URL find resource files instead of the default.")
              .takes_value(true))
+        .arg(Arg::with_name("prefer_bundle")
+             .long("prefer-bundle")
+             .help("Look for resource files in the bundle before the filesystem, so that local copies \
+                    can't shadow them."))
         .arg(Arg::with_name("outfmt")
              .long("outfmt")
              .value_name("FORMAT")
@@ -1020,6 +1294,11 @@ fn main() {
              .long("makefile-rules")
              .value_name("PATH")
              .help("Write Makefile-format rules expressing the dependencies of this run to <PATH>."))
+        .arg(Arg::with_name("manifest")
+             .long("manifest")
+             .value_name("PATH")
+             .help("Write a JSON listing of the files saved by this run, with their sizes and SHA256 \
+                    digests, to <PATH>."))
         .arg(Arg::with_name("pass")
              .long("pass")
              .value_name("PASS")
@@ -1038,6 +1317,15 @@ fn main() {
         .arg(Arg::with_name("keep_logs")
              .long("keep-logs")
              .help("Keep the log files generated during processing."))
+        .arg(Arg::with_name("warnings_as_errors")
+             .long("warnings-as-errors")
+             .help("Fail if the TeX log contains any warnings."))
+        .arg(Arg::with_name("warning_kinds")
+             .long("warning-kinds")
+             .value_name("LIST")
+             .requires("warnings_as_errors")
+             .help("The comma-separated kinds of warnings that --warnings-as-errors counts: latex, package, \
+                    overfull, underfull. [default: all]"))
         .arg(Arg::with_name("synctex")
              .long("synctex")
              .help("Generate SyncTeX data."))
@@ -1047,6 +1335,9 @@ fn main() {
              .multiple(true)
              .number_of_values(1)
              .help("Tell the engine that no file at <PATH> exists, if it tries to read it."))
+        .arg(Arg::with_name("page_progress")
+             .long("page-progress")
+             .help("Print the number of each page as the engine ships it out."))
         .arg(Arg::with_name("print_stdout")
              .long("print")
              .short("p")
@@ -1116,3 +1407,41 @@ fn main() {
         }
     })
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest_of(data: &[u8]) -> DigestData {
+        let mut dc = digest::create();
+        dc.input(data);
+        DigestData::from(dc)
+    }
+
+    #[test]
+    fn manifest_round_trip() {
+        let pdf = b"%PDF-1.5".to_vec();
+        let log = b"This is Tectonic".to_vec();
+        let (pdf_digest, log_digest) = (digest_of(&pdf), digest_of(&log));
+
+        let text = render_manifest(&[(OsStr::new("doc \"1\".pdf"), &pdf[..], &pdf_digest),
+                                     (OsStr::new("doc.log"), &log[..], &log_digest)]).unwrap();
+
+        // Read the digests back out of what we wrote; the entries are sorted.
+        let digests = text.lines()
+            .filter(|l| l.contains("\"sha256\""))
+            .map(|l| l.rsplitn(3, '"').nth(1).unwrap().parse::<DigestData>().unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(digests, vec![pdf_digest, log_digest]);
+        assert!(text.contains("\"name\": \"doc \\\"1\\\".pdf\", \"size\": 8,"));
+        assert!(text.contains("\"name\": \"doc.log\", \"size\": 16,"));
+    }
+
+    #[test]
+    fn manifest_rejects_mismatched_digest() {
+        let stale = digest_of(b"old contents");
+        assert!(render_manifest(&[(OsStr::new("doc.aux"), &b"new contents"[..], &stale)]).is_err());
+    }
+}
@@ -7,8 +7,9 @@
 
 pub use sha2::Sha256 as DigestComputer;
 pub use sha2::Digest;
-use std::fs;
-use std::path::{Path, PathBuf};
+#[cfg(not(feature = "no-filesystem"))] use std::fs;
+#[cfg(not(feature = "no-filesystem"))] use std::path::{Path, PathBuf};
+use std::io::Read;
 use std::str::FromStr;
 use std::string::ToString;
 
@@ -41,6 +42,44 @@ pub fn hex_to_bytes(text: &str, dest: &mut [u8]) -> Result<()> {
 }
 
 
+/// Check that the data read from `src` have the digest `expected`, given as a
+/// hex string, computed with the algorithm `D`. This is how to check a
+/// downloaded bundle against a published checksum. `expected` must have the
+/// length of `D`'s output, so a checksum computed with some other algorithm
+/// is rejected rather than compared.
+pub fn verify_digest_with<D: Digest + Default, R: Read>(src: &mut R, expected: &str) -> Result<()> {
+    let mut dc = D::default();
+    let mut buf = [0u8; 8192];
+
+    loop {
+        let n = src.read(&mut buf)?;
+
+        if n == 0 {
+            break;
+        }
+
+        dc.input(&buf[..n]);
+    }
+
+    let observed = dc.result();
+    let mut expected_bytes = vec![0u8; observed.len()];
+    hex_to_bytes(expected, &mut expected_bytes)?;
+
+    if &expected_bytes[..] != &observed[..] {
+        return Err(ErrorKind::Msg(format!("digest mismatch: expected {}, got {}",
+                                          expected, bytes_to_hex(&observed))).into());
+    }
+
+    Ok(())
+}
+
+
+/// Like `verify_digest_with()`, using our standard algorithm, SHA256.
+pub fn verify_digest<R: Read>(src: &mut R, expected: &str) -> Result<()> {
+    verify_digest_with::<DigestComputer, R>(src, expected)
+}
+
+
 // The specific implementation we're using: SHA256.
 
 const N_BYTES: usize = 32;
@@ -51,7 +90,7 @@ pub fn create() -> DigestComputer {
 }
 
 
-#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+#[derive(Copy,Clone,Debug,Eq,Hash,PartialEq)]
 pub struct DigestData([u8; N_BYTES]);
 
 impl DigestData {
@@ -69,6 +108,7 @@ impl DigestData {
     /// the digest, then a name consisting of the rest of the hex data. **The
     /// first-byte subdirectory and all parent directories are created when
     /// you call this function!**
+    #[cfg(not(feature = "no-filesystem"))]
     pub fn create_two_part_path(&self, base: &Path) -> Result<PathBuf> {
         let mut p = base.to_path_buf();
         p.push(format!("{:02x}", self.0[0]));
@@ -103,3 +143,25 @@ impl From<DigestComputer> for DigestData {
         result
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::Sha512;
+
+    const SHA256_ABC: &'static str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[test]
+    fn verification_uses_chosen_algorithm() {
+        verify_digest(&mut &b"abc"[..], SHA256_ABC).unwrap();
+        assert!(verify_digest(&mut &b"abd"[..], SHA256_ABC).is_err());
+
+        verify_digest_with::<Sha512, _>(&mut &b"abc"[..],
+            "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+             2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f").unwrap();
+
+        // A SHA256 checksum can't be used to check a SHA512 digest.
+        assert!(verify_digest_with::<Sha512, _>(&mut &b"abc"[..], SHA256_ABC).is_err());
+    }
+}
@@ -0,0 +1,1386 @@
+// src/driver.rs -- a high-level interface for processing whole documents
+// Copyright 2017 the Tectonic Project
+// Licensed under the MIT License.
+
+//! A simple interface for running a document all the way through to its
+//! outputs, for programs that embed Tectonic. All of the I/O happens in
+//! memory: the caller supplies the primary input and a bundle, and gets back
+//! the files that the engines created. The command-line program has its own,
+//! more elaborate driver that also deals with the filesystem, BibTeX, and
+//! so on.
+//!
+//! The engines keep their state in C global variables, so only one of them
+//! can run at a time in any given process. The functions here take a global
+//! lock to ensure this, which means that it is safe to compile on multiple
+//! threads, but the compiles won't actually proceed in parallel.
+
+use std::collections::HashMap;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::io::{Cursor, Read, Seek, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+use zip::{CompressionMethod, ZipWriter};
+
+use digest::{self, Digest, DigestComputer, DigestData};
+use engines::{AccessPolicy, EngineMode, FileFormat, FileListRecorder, IoEventBackend, NoopIoEventBackend,
+              ShellEscape};
+use errors::{Error, ErrorKind, Result};
+use io::{InputHandle, IoProvider, IoStack, IoStackBuilder, MemoryIo, OpenResult, OutputHandle};
+use io::stdstreams::{BufferedFormatIo, BufferedPrimaryIo, StreamingOutputIo};
+use io::texmfconfig::TexmfConfigIo;
+use status::{NoopStatusBackend, StatusBackend};
+use {TexEngine, TexResult, XdvipdfmxEngine};
+
+#[cfg(feature = "async")] use futures::Future;
+#[cfg(feature = "async")] use futures::sync::oneshot;
+#[cfg(not(feature = "no-filesystem"))] use std::fs::File;
+#[cfg(not(feature = "no-filesystem"))] use io::zipbundle::ZipBundle;
+
+
+/// The maximum number of times that we'll run the TeX engine, by default.
+pub const DEFAULT_MAX_TEX_PASSES: usize = 4;
+
+
+lazy_static! {
+    static ref ENGINE_LOCK: Mutex<()> = Mutex::new(());
+}
+
+
+/// A CompileRequest describes a document to process and how to process it.
+///
+/// The request owns everything that it needs, so that it can be moved to
+/// another thread; for the same reason, the bundle must be `Send`.
+pub struct CompileRequest {
+    primary_name: String,
+    primary_input: Vec<u8>,
+    bundle: Box<IoProvider + Send>,
+    mode: EngineMode,
+    max_tex_passes: usize,
+    make_pdf: bool,
+    shell_escape: ShellEscape,
+    aux_seed: Option<Vec<u8>>,
+    cancel_token: Option<Arc<AtomicBool>>,
+    page_limit: Option<usize>,
+    access_policy: Option<AccessPolicy>,
+    job_name: Option<String>,
+    file_list: bool,
+    source_date_epoch: Option<i64>,
+    pdf_writer: Option<Box<Write + Send>>,
+    on_pass_complete: Option<Box<FnMut(usize, &mut IoProvider) + Send>>,
+    cache: Option<Box<CompileCache>>,
+    lookups: Option<Arc<Mutex<Vec<CachedInput>>>>,
+}
+
+
+/// The results of a successful CompileRequest.
+#[derive(Clone,Debug)]
+pub struct CompileResult {
+    /// The outcome of the final TeX pass.
+    pub tex_result: TexResult,
+
+    /// How many times the TeX engine was run.
+    pub n_tex_passes: usize,
+
+    /// The number of pages in the document, as shipped out by the final TeX
+    /// pass.
+    pub page_count: usize,
+
+    /// All of the files that the engines created, by name, including
+    /// intermediate files such as the `.aux` and `.log`.
+    pub outputs: HashMap<OsString, Vec<u8>>,
+}
+
+
+impl CompileResult {
+    /// The text of the TeX engine's log file, decoding it as UTF-8 and
+    /// replacing anything invalid, or None if there isn't one. The engine
+    /// writes exactly one `.log` file, named after the job.
+    pub fn log_text(&self) -> Option<String> {
+        self.outputs.iter()
+            .find(|&(name, _)| Path::new(name).extension() == Some(OsStr::new("log")))
+            .map(|(_, data)| String::from_utf8_lossy(data).into_owned())
+    }
+}
+
+
+impl CompileRequest {
+    /// Create a request to process `primary_input`. The `primary_name` is the
+    /// name that TeX is told for it (e.g., "paper.tex"); the outputs are
+    /// named after it. Support files come from `bundle`.
+    pub fn new<S: Into<String>>(primary_name: S, primary_input: Vec<u8>,
+                                bundle: Box<IoProvider + Send>) -> CompileRequest {
+        CompileRequest {
+            primary_name: primary_name.into(),
+            primary_input: primary_input,
+            bundle: bundle,
+            mode: EngineMode::Normal { format: "latex".to_owned() },
+            max_tex_passes: DEFAULT_MAX_TEX_PASSES,
+            make_pdf: true,
+            shell_escape: ShellEscape::Disabled,
+            aux_seed: None,
+            cancel_token: None,
+            page_limit: None,
+            access_policy: None,
+            job_name: None,
+            file_list: false,
+            source_date_epoch: None,
+            pdf_writer: None,
+            on_pass_complete: None,
+            cache: None,
+            lookups: None,
+        }
+    }
+
+    /// Set the name of the format to use; the default is "latex". If the
+    /// bundle doesn't have the format file yet, it is generated. This is
+    /// shorthand for `mode(EngineMode::Normal { format: name })`.
+    pub fn format_name(&mut self, name: &str) -> &mut Self {
+        self.mode = EngineMode::Normal { format: name.to_owned() };
+        self
+    }
+
+    /// Set whether to process the document using a format, or to run TeX
+    /// on it once in "initex" mode to generate a format. In the latter
+    /// case, the format file appears among the outputs and is saved in the
+    /// bundle, if the bundle allows it; no PDF is made.
+    pub fn mode(&mut self, mode: EngineMode) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Set the maximum number of TeX passes. The TeX engine is rerun until
+    /// the `.aux` file stops changing or this many passes have happened.
+    pub fn max_tex_passes(&mut self, n: usize) -> &mut Self {
+        self.max_tex_passes = ::std::cmp::max(n, 1);
+        self
+    }
+
+    /// Set whether to turn the TeX engine's XDV output into a PDF; the
+    /// default is true.
+    pub fn make_pdf(&mut self, make_pdf: bool) -> &mut Self {
+        self.make_pdf = make_pdf;
+        self
+    }
+
+    /// Set whether and which external commands the document may run with
+    /// `\write18`; see `ShellEscape`. The default is not to run any.
+    pub fn shell_escape(&mut self, policy: ShellEscape) -> &mut Self {
+        self.shell_escape = policy;
+        self
+    }
+
+    /// Start the first TeX pass with `data` as the `.aux` file, as if it had
+    /// been left behind by an earlier pass. Passing the `.aux` from a
+    /// previous compile of the same document means that a single pass is
+    /// often enough, if the references haven't changed. The seed is
+    /// replaced as soon as TeX writes a new `.aux`.
+    pub fn seed_aux(&mut self, data: Vec<u8>) -> &mut Self {
+        self.aux_seed = Some(data);
+        self
+    }
+
+    /// Give up on the compile, with an `ErrorKind::Cancelled` error, once
+    /// `token` becomes true, e.g. because another thread decided that it has
+    /// taken too long. Cancellation is cooperative: the engines check the
+    /// token whenever they open a file or ship out a page, and we check it
+    /// between passes, so a compile stops within a page or so of work. The
+    /// engines can't be interrupted in the middle of a single step, though,
+    /// such as loading a large font.
+    pub fn cancel_token(&mut self, token: Arc<AtomicBool>) -> &mut Self {
+        self.cancel_token = Some(token);
+        self
+    }
+
+    /// Only typeset the first `n` pages of the document, e.g. for a quick
+    /// preview. TeX stops as soon as it has shipped out page `n`, and the
+    /// PDF contains only those pages. Everything after them is never
+    /// processed, so the `.aux` and other auxiliary files are incomplete.
+    pub fn stop_after_page(&mut self, n: usize) -> &mut Self {
+        self.page_limit = Some(n);
+        self
+    }
+
+    /// Ask `policy` for permission before the engines open each of their
+    /// inputs, with each name tried for a file and the kind of file wanted;
+    /// denied files look to the engines as if they didn't exist. The
+    /// primary input is always readable. See `AccessPolicy`.
+    pub fn access_policy<F: 'static + Fn(&Path, FileFormat) -> bool + Send + Sync>(&mut self, policy: F) -> &mut Self {
+        self.access_policy = Some(AccessPolicy::new(policy));
+        self
+    }
+
+    /// Set the TeX job name, which is what `\jobname` expands to, and what
+    /// the outputs are named after instead of `primary_name`: with a job
+    /// name of "paper", the PDF is "paper.pdf".
+    pub fn job_name<S: Into<String>>(&mut self, name: S) -> &mut Self {
+        self.job_name = Some(name.into());
+        self
+    }
+
+    /// Set whether to add a list of the files that the engines read and
+    /// wrote to the outputs, in the `.fls` format of TeX's `-recorder`
+    /// option, for build tools such as `latexmk`. The list is named after
+    /// the job, like the other outputs: "paper.fls", say. Its names are
+    /// relative to the current directory, which its `PWD` line gives, and
+    /// the `.xdv`, which is deleted once the PDF has been made, isn't
+    /// listed. The default is not to.
+    pub fn file_list(&mut self, file_list: bool) -> &mut Self {
+        self.file_list = file_list;
+        self
+    }
+
+    /// Use `epoch`, in seconds since the Unix epoch, as the current time:
+    /// it's what TeX reports to documents through `\year` and friends and
+    /// the creation date recorded in the PDF, and it seeds the font subset
+    /// tags, so that the same input always gives byte-identical output. By
+    /// default, TeX uses the actual time, and xdvipdfmx consults the
+    /// `SOURCE_DATE_EPOCH` environment variable.
+    pub fn source_date_epoch(&mut self, epoch: i64) -> &mut Self {
+        self.source_date_epoch = Some(epoch);
+        self
+    }
+
+    /// The name of the output file with the given extension.
+    fn job_output_name(&self, extension: &str) -> OsString {
+        match self.job_name {
+            Some(ref j) => OsString::from(format!("{}.{}", j, extension)),
+            None => output_name(&self.primary_name, extension),
+        }
+    }
+
+    fn check_cancelled(&self) -> Result<()> {
+        match self.cancel_token {
+            Some(ref t) if t.load(Ordering::SeqCst) => Err(ErrorKind::Cancelled.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Register a function to be called after each TeX pass, with the index
+    /// of the pass (starting at zero) and an I/O provider holding the files
+    /// created so far. The function may read intermediate files such as the
+    /// `.aux` through the provider's input methods, and create or replace
+    /// files with its output methods, before the next pass starts. Changes
+    /// that it makes to the `.aux` count when deciding whether to rerun TeX.
+    pub fn on_pass_complete<F: 'static + Send + FnMut(usize, &mut IoProvider)>(&mut self, f: F) -> &mut Self {
+        self.on_pass_complete = Some(Box::new(f));
+        self
+    }
+
+    /// Keep the results of compiles in `cache`, and reuse them when the same
+    /// request is made again with the same files in the bundle, without
+    /// running the engines at all. A result is found by a digest of the
+    /// request's settings and primary input, and of the contents of every
+    /// bundle file that the original compile looked up; files that it
+    /// looked for but didn't find count too, in case they appear later. Any
+    /// `on_pass_complete` function is assumed to behave the same every
+    /// time, and isn't called when a result comes from the cache. Requests
+    /// with an access policy or a PDF writer always run the engines, since
+    /// neither can be digested.
+    pub fn cache(&mut self, cache: Box<CompileCache>) -> &mut Self {
+        if self.lookups.is_none() {
+            let lookups = Arc::new(Mutex::new(Vec::new()));
+            let inner = mem::replace(&mut self.bundle, Box::new(NoBundle));
+
+            self.bundle = Box::new(LookupRecorder {
+                inner: inner,
+                lookups: lookups.clone(),
+            });
+            self.lookups = Some(lookups);
+        }
+
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Process the document.
+    pub fn compile(&mut self, status: &mut StatusBackend) -> Result<CompileResult> {
+        if self.access_policy.is_some() || self.pdf_writer.is_some() {
+            return self.prepare(status)?.run(status);
+        }
+
+        let mut cache = match self.cache.take() {
+            Some(c) => c,
+            None => return self.prepare(status)?.run(status),
+        };
+
+        let result = self.compile_with_cache(&mut *cache, status);
+        self.cache = Some(cache);
+        result
+    }
+
+    fn compile_with_cache(&mut self, cache: &mut CompileCache, status: &mut StatusBackend) -> Result<CompileResult> {
+        let request_key = self.request_digest();
+
+        if let Some(inputs) = cache.get_inputs(&request_key) {
+            let key = self.inputs_digest(&request_key, &inputs, status)?;
+
+            if let Some(result) = cache.get_result(&key) {
+                return Ok(result);
+            }
+        }
+
+        self.take_lookups();
+        let result = self.prepare(status)?.run(status)?;
+
+        let mut inputs = self.take_lookups();
+        inputs.sort();
+        inputs.dedup();
+
+        let key = self.inputs_digest(&request_key, &inputs, status)?;
+        cache.put_inputs(&request_key, inputs);
+        cache.put_result(&key, &result);
+        Ok(result)
+    }
+
+    /// A digest of everything about the request except the bundle.
+    fn request_digest(&self) -> DigestData {
+        let mut dc = digest::create();
+        let options = format!("{:?} {} {} {:?} {:?} {:?} {} {} {:?}", self.mode, self.max_tex_passes,
+                              self.make_pdf, self.shell_escape, self.page_limit, self.job_name,
+                              self.aux_seed.is_some(), self.file_list, self.source_date_epoch);
+
+        hash_part(&mut dc, options.as_bytes());
+        hash_part(&mut dc, self.primary_name.as_bytes());
+        hash_part(&mut dc, &self.primary_input);
+
+        if let Some(ref seed) = self.aux_seed {
+            hash_part(&mut dc, seed);
+        }
+
+        DigestData::from(dc)
+    }
+
+    /// A digest of the request and of the current state of the bundle files
+    /// `inputs`.
+    fn inputs_digest(&mut self, request_key: &DigestData, inputs: &[CachedInput],
+                     status: &mut StatusBackend) -> Result<DigestData> {
+        let mut dc = digest::create();
+        hash_part(&mut dc, request_key.to_string().as_bytes());
+
+        for input in inputs {
+            hash_part(&mut dc, format!("{:?}", input).as_bytes());
+
+            let r = match *input {
+                CachedInput::File(ref name) => self.bundle.input_open_name(name, status),
+                CachedInput::Format(ref name) => self.bundle.input_open_format(name, status),
+            };
+
+            match r {
+                OpenResult::Ok(mut ih) => {
+                    let mut data = Vec::new();
+                    ih.read_to_end(&mut data)?;
+                    hash_part(&mut dc, b"present");
+                    hash_part(&mut dc, &data);
+                },
+                OpenResult::NotAvailable => hash_part(&mut dc, b"absent"),
+                OpenResult::Err(e) => return Err(e),
+            }
+        }
+
+        Ok(DigestData::from(dc))
+    }
+
+    /// Get and forget the bundle lookups recorded so far.
+    fn take_lookups(&self) -> Vec<CachedInput> {
+        match self.lookups {
+            Some(ref l) => mem::replace(&mut *l.lock().unwrap_or_else(|e| e.into_inner()), Vec::new()),
+            None => Vec::new(),
+        }
+    }
+
+    /// Do all of the setup that a compile needs before the engines can run:
+    /// locate (or generate) the format file and load it into memory, and
+    /// buffer the primary input. The returned PreparedCompile then does
+    /// only the engine work, which is useful for benchmarking.
+    pub fn prepare(&mut self, status: &mut StatusBackend) -> Result<PreparedCompile> {
+        let format = match self.mode.clone() {
+            EngineMode::InitFormat { .. } => BufferedFormatIo::new(Vec::new()),
+            EngineMode::Normal { ref format } => {
+                let _guard = lock_engines();
+                self.ensure_format(format, status)?
+            },
+        };
+
+        let primary = BufferedPrimaryIo::from_shared(Rc::new(self.primary_input.clone()));
+        let mut config = TexmfConfigIo::new();
+        config.shell_escape(self.shell_escape.texmf_cnf_setting());
+
+        Ok(PreparedCompile {
+            request: self,
+            format: format,
+            primary: primary,
+            config: config,
+        })
+    }
+
+    /// Load the format file into memory, generating it if need be. We try
+    /// to save a generated format in the bundle for next time, but not all
+    /// bundles can do that, so this compile uses the in-memory copy
+    /// regardless.
+    fn ensure_format(&mut self, format_name: &str, status: &mut StatusBackend) -> Result<BufferedFormatIo> {
+        {
+            let mut stack = IoStack::new(vec![&mut *self.bundle as &mut IoProvider]);
+
+            match stack.input_open_format(OsStr::new(format_name), status) {
+                OpenResult::Ok(mut ih) => {
+                    let mut data = Vec::new();
+                    ih.read_to_end(&mut data)?;
+                    return Ok(BufferedFormatIo::new(data));
+                },
+                OpenResult::NotAvailable => {},
+                OpenResult::Err(e) => return Err(e),
+            }
+        }
+
+        let stem = format_name.splitn(2, '.').next().unwrap().to_owned();
+        let mut kickstart = BufferedPrimaryIo::from_text(format!("\\input tectonic-format-{}.tex", stem));
+        let mut mem = MemoryIo::new(true);
+        let mut config = TexmfConfigIo::new();
+
+        {
+            let mut builder = IoStackBuilder::new();
+            builder.primary(&mut kickstart);
+            builder.memory(&mut mem);
+            builder.bundle(&mut *self.bundle);
+            builder.defaults(&mut config);
+            let mut stack = builder.create();
+            TexEngine::new()
+                .halt_on_error_mode(true)
+                .cancel_token(self.cancel_token.clone())
+                .process_in_mode(&mut stack, &mut NoopIoEventBackend::new(), status,
+                                 &EngineMode::InitFormat { dump_name: stem.clone() }, "texput")?;
+        }
+
+        let data = match generated_format(&mem) {
+            Some(d) => d,
+            None => return Err(ErrorKind::Msg(format!("generating format \"{}\" produced no format file",
+                                                      format_name)).into()),
+        };
+
+        if let Err(e) = self.bundle.write_format(&stem, &data, status) {
+            tt_note!(status, "the generated format file could not be saved for later use"; e);
+        }
+
+        Ok(BufferedFormatIo::new(data))
+    }
+}
+
+
+/// A compile whose setup has been done by `CompileRequest::prepare()`.
+pub struct PreparedCompile<'a> {
+    request: &'a mut CompileRequest,
+    format: BufferedFormatIo,
+    primary: BufferedPrimaryIo,
+    config: TexmfConfigIo,
+}
+
+impl<'a> PreparedCompile<'a> {
+    /// Run the engines.
+    pub fn run(&mut self, status: &mut StatusBackend) -> Result<CompileResult> {
+        self.run_in(&mut MemoryIo::new(true), status)
+    }
+
+    /// Run the engines, with `mem` holding their outputs. If the compile
+    /// fails, `mem` holds whatever files were created before it did.
+    fn run_in(&mut self, mem: &mut MemoryIo, status: &mut StatusBackend) -> Result<CompileResult> {
+        let _guard = lock_engines();
+
+        let req = &mut *self.request;
+
+        let aux_name = req.job_output_name("aux");
+        let mut n_tex_passes = 0;
+
+        // Seeding the memory layer has the same effect as an AuxSeedIo, and
+        // means that the seed is what the first pass is compared against.
+        if let Some(ref seed) = req.aux_seed {
+            mem.create_entry(&aux_name, seed.clone());
+        }
+
+        let mut events = FileListRecorder::new(PageCounter::default());
+        events.primary_name(&req.primary_name);
+        let making_format = match req.mode {
+            EngineMode::InitFormat { .. } => true,
+            EngineMode::Normal { .. } => false,
+        };
+
+        let tex_result = loop {
+            req.check_cancelled()?;
+            let aux_before = mem.files.borrow().get(&aux_name).cloned();
+            events.inner_mut().pages = 0;
+
+            let result = {
+                let mut stack = make_stack(&mut self.primary, &mut self.format, &mut *mem, &mut *req.bundle,
+                                           &mut self.config);
+                TexEngine::new()
+                    .halt_on_error_mode(true)
+                    .shell_escape(req.shell_escape.clone())
+                    .cancel_token(req.cancel_token.clone())
+                    .stop_after_page(req.page_limit)
+                    .access_policy(req.access_policy.clone())
+                    .job_name(req.job_name.clone())
+                    .source_date_epoch(req.source_date_epoch)
+                    .process_in_mode(&mut stack, &mut events, status, &req.mode, &req.primary_name)?
+            };
+
+            if let Some(ref mut f) = req.on_pass_complete {
+                f(n_tex_passes, &mut *mem);
+            }
+
+            n_tex_passes += 1;
+
+            // A format run makes no cross-references to resolve.
+            if making_format || n_tex_passes >= req.max_tex_passes ||
+                mem.files.borrow().get(&aux_name) == aux_before.as_ref() {
+                break result;
+            }
+        };
+
+        if let EngineMode::InitFormat { ref dump_name } = req.mode {
+            let data = match generated_format(mem) {
+                Some(d) => d,
+                None => return Err(ErrorKind::Msg("the format run produced no format file".to_owned()).into()),
+            };
+
+            if let Err(e) = req.bundle.write_format(dump_name, &data, status) {
+                tt_note!(status, "the generated format file could not be saved for later use"; e);
+            }
+        }
+
+        if req.make_pdf && !making_format {
+            let xdv_name = req.job_output_name("xdv");
+            let pdf_name = req.job_output_name("pdf");
+
+            req.check_cancelled()?;
+
+            {
+                let pdf_writer = req.pdf_writer.take();
+                let mut stack = make_stack(&mut self.primary, &mut self.format, &mut *mem, &mut *req.bundle,
+                                           &mut self.config);
+                let mut engine = XdvipdfmxEngine::new();
+                engine.cancel_token(req.cancel_token.clone())
+                    .access_policy(req.access_policy.clone())
+                    .source_date_epoch(req.source_date_epoch);
+
+                match pdf_writer {
+                    Some(w) => {
+                        let mut streaming = StreamingOutputIo::new(&pdf_name, w);
+                        let mut outer = IoStack::new(vec![&mut streaming as &mut IoProvider,
+                                                          &mut stack as &mut IoProvider]);
+                        engine.process(&mut outer, &mut events, status,
+                                       &xdv_name.to_string_lossy(), &pdf_name.to_string_lossy())?;
+                    },
+                    None => {
+                        engine.process(&mut stack, &mut events, status,
+                                       &xdv_name.to_string_lossy(), &pdf_name.to_string_lossy())?;
+                    },
+                }
+            }
+
+            mem.files.borrow_mut().remove(&xdv_name);
+            events.forget(&xdv_name);
+        }
+
+        if req.file_list {
+            // Build tools expect a PWD line. The outputs don't live anywhere
+            // yet, so say that they're relative to the current directory, as
+            // they would be on the command line.
+            let pwd = env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+            let mut fls = Vec::new();
+            events.write_fls(&mut fls, Some(&pwd))?;
+            mem.create_entry(&req.job_output_name("fls"), fls);
+        }
+
+        let outputs = take_outputs(mem);
+
+        Ok(CompileResult {
+            tex_result: tex_result,
+            n_tex_passes: n_tex_passes,
+            page_count: events.inner().pages,
+            outputs: outputs,
+        })
+    }
+}
+
+
+/// The outcome of `compile_with_timeout()`.
+#[derive(Clone,Debug)]
+pub enum TimedCompile {
+    /// The compile finished in time.
+    Finished(CompileResult),
+
+    /// The compile ran out of time and was cancelled. These are the files
+    /// that the engines had created by then, such as the `.log` and an
+    /// incomplete `.xdv`; any of them may be truncated.
+    TimedOut {
+        outputs: HashMap<OsString, Vec<u8>>,
+    },
+}
+
+
+/// Process a document on a separate thread, giving up if it takes longer
+/// than `timeout`. Engine messages are discarded.
+///
+/// Giving up relies on cancellation (see `CompileRequest::cancel_token()`,
+/// which this function overrides), which is cooperative: the engines only
+/// notice it every so often, so the compile can overrun the timeout
+/// somewhat, and we wait for it to stop before returning. Time spent waiting
+/// for other compiles to finish counts. The request's cache, if any, isn't
+/// used.
+pub fn compile_with_timeout(mut request: CompileRequest, timeout: Duration) -> Result<TimedCompile> {
+    let token = Arc::new(AtomicBool::new(false));
+    request.cancel_token(token.clone());
+
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut status = NoopStatusBackend::new();
+        let mut mem = MemoryIo::new(true);
+        let result = request.prepare(&mut status).and_then(|mut p| p.run_in(&mut mem, &mut status));
+        let _ = tx.send((result, take_outputs(&mem)));
+    });
+
+    let (result, partial) = match rx.recv_timeout(timeout) {
+        Ok(r) => r,
+        Err(RecvTimeoutError::Timeout) => {
+            token.store(true, Ordering::SeqCst);
+
+            match rx.recv() {
+                Ok(r) => r,
+                Err(_) => return Err(ErrorKind::Msg("the compilation thread panicked".to_owned()).into()),
+            }
+        },
+        Err(RecvTimeoutError::Disconnected) => {
+            return Err(ErrorKind::Msg("the compilation thread panicked".to_owned()).into());
+        },
+    };
+
+    match result {
+        Ok(r) => Ok(TimedCompile::Finished(r)),
+        Err(ref e) if token.load(Ordering::SeqCst) && is_cancellation(e) => {
+            Ok(TimedCompile::TimedOut { outputs: partial })
+        },
+        Err(e) => Err(e),
+    }
+}
+
+
+fn is_cancellation(e: &Error) -> bool {
+    match *e.kind() {
+        ErrorKind::Cancelled => true,
+        _ => false,
+    }
+}
+
+
+/// Compile the LaTeX document `source` into a PDF, using the Zip bundle at
+/// `bundle` for support files, and return the PDF data. TeX is rerun as
+/// needed to resolve cross-references. Engine messages are discarded; use
+/// a CompileRequest for more control.
+#[cfg(not(feature = "no-filesystem"))]
+pub fn latex_to_pdf(source: &str, bundle: &Path) -> Result<Vec<u8>> {
+    let bundle = ZipBundle::<File>::open(bundle)?;
+    let mut result = CompileRequest::new("texput.tex", source.as_bytes().to_owned(), Box::new(bundle))
+        .compile(&mut NoopStatusBackend::new())?;
+
+    match result.outputs.remove(OsStr::new("texput.pdf")) {
+        Some(pdf) => Ok(pdf),
+        None => Err(ErrorKind::Msg("the engines did not produce a PDF file".to_owned()).into()),
+    }
+}
+
+
+/// Settings for `compile_to_memory()`. The defaults are the same as those
+/// of a new CompileRequest; use one of those directly for anything fancier.
+#[derive(Clone,Debug)]
+pub struct CompileOptions {
+    /// The name of the format to use.
+    pub format_name: String,
+
+    /// The most times that the TeX engine will be run.
+    pub max_tex_passes: usize,
+
+    /// Whether to turn the engine's XDV output into a PDF.
+    pub make_pdf: bool,
+
+    /// The job name, which sets the names of the outputs; by default it
+    /// comes from the name of the primary input, `texput.tex`.
+    pub job_name: Option<String>,
+
+    /// The time to use as "now", in seconds since the Unix epoch; see
+    /// `CompileRequest::source_date_epoch()`.
+    pub source_date_epoch: Option<i64>,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            format_name: "latex".to_owned(),
+            max_tex_passes: DEFAULT_MAX_TEX_PASSES,
+            make_pdf: true,
+            job_name: None,
+            source_date_epoch: None,
+        }
+    }
+}
+
+
+/// Compile the document `primary` all the way through, using `bundle` for
+/// support files, and return every file that the engines created, keyed by
+/// name: the PDF, of course, but also the `.log`, `.aux`, and so on. Names
+/// that aren't valid Unicode are converted lossily. Engine messages are
+/// discarded.
+pub fn compile_to_memory(primary: &str, bundle: Box<IoProvider + Send>,
+                         options: &CompileOptions) -> Result<HashMap<String, Vec<u8>>> {
+    compile_outputs(&mut options_request(primary, bundle, options))
+}
+
+
+/// Like `compile_to_memory()`, but write the PDF to `out` as xdvipdfmx
+/// produces it, rather than collecting it in memory, e.g. to send it
+/// straight over a network connection. The other outputs are returned as
+/// usual. Nothing is written to `out` unless `options.make_pdf` is set, and
+/// if the compile fails partway through, `out` may have received part of a
+/// PDF. The writer has to be `'static` since the engines' output handles
+/// hold on to it; pass a `File` or a `TcpStream`, say.
+pub fn compile_to_writer<W: 'static + Write + Send>(primary: &str, bundle: Box<IoProvider + Send>, out: W,
+                                                   options: &CompileOptions) -> Result<HashMap<String, Vec<u8>>> {
+    let mut request = options_request(primary, bundle, options);
+    request.pdf_writer = Some(Box::new(out));
+    compile_outputs(&mut request)
+}
+
+
+fn options_request(primary: &str, bundle: Box<IoProvider + Send>, options: &CompileOptions) -> CompileRequest {
+    let mut request = CompileRequest::new("texput.tex", primary.as_bytes().to_owned(), bundle);
+    request.format_name(&options.format_name)
+        .max_tex_passes(options.max_tex_passes)
+        .make_pdf(options.make_pdf);
+
+    if let Some(ref name) = options.job_name {
+        request.job_name(name.clone());
+    }
+
+    if let Some(epoch) = options.source_date_epoch {
+        request.source_date_epoch(epoch);
+    }
+
+    request
+}
+
+
+fn compile_outputs(request: &mut CompileRequest) -> Result<HashMap<String, Vec<u8>>> {
+    let result = request.compile(&mut NoopStatusBackend::new())?;
+
+    Ok(result.outputs.into_iter()
+       .map(|(name, data)| (name.to_string_lossy().into_owned(), data))
+       .collect())
+}
+
+
+/// Compile a document once, and write a Zip bundle to `dest` that contains
+/// only the files of `full_bundle` that it used. The new bundle is enough to
+/// compile the document again, usually at a small fraction of the size of
+/// the full one. Any cached format file in `full_bundle` is ignored, so that
+/// the files needed to generate the format are included too.
+pub fn minimal_bundle<S, W>(primary_name: S, primary_input: Vec<u8>, full_bundle: Box<IoProvider + Send>,
+                            dest: W, status: &mut StatusBackend) -> Result<W>
+    where S: Into<String>, W: Write + Seek
+{
+    let used = Arc::new(Mutex::new(HashMap::new()));
+
+    let recorder = RecordingBundle {
+        inner: full_bundle,
+        used: used.clone(),
+    };
+
+    CompileRequest::new(primary_name, primary_input, Box::new(recorder)).compile(status)?;
+
+    let used = used.lock().unwrap_or_else(|e| e.into_inner());
+    let mut names = used.keys().collect::<Vec<_>>();
+    names.sort();
+
+    let mut zip = ZipWriter::new(dest);
+
+    for name in names {
+        zip.start_file(name.to_string_lossy(), CompressionMethod::Deflated)?;
+        zip.write_all(&used[name])?;
+    }
+
+    Ok(zip.finish()?)
+}
+
+
+/// A place to keep the results of earlier compiles, so that a repeated
+/// request can be answered without running the engines; see
+/// `CompileRequest::cache()`. The driver computes all of the keys, so a
+/// store only has to remember what it's given. It is always safe for a
+/// store to forget entries.
+pub trait CompileCache: Send {
+    /// Get the bundle files looked up by an earlier compile of a request
+    /// whose settings and primary input have the digest `key`.
+    fn get_inputs(&mut self, key: &DigestData) -> Option<Vec<CachedInput>>;
+
+    fn put_inputs(&mut self, key: &DigestData, inputs: Vec<CachedInput>);
+
+    /// Get the result of an earlier compile whose request and inputs have
+    /// the digest `key`.
+    fn get_result(&mut self, key: &DigestData) -> Option<CompileResult>;
+
+    fn put_result(&mut self, key: &DigestData, result: &CompileResult);
+}
+
+
+/// A file that a compile looked up in the bundle, by name.
+#[derive(Clone,Debug,Eq,Hash,Ord,PartialEq,PartialOrd)]
+pub enum CachedInput {
+    /// A file opened with `IoProvider::input_open_name()`.
+    File(OsString),
+
+    /// A format file opened with `IoProvider::input_open_format()`.
+    Format(OsString),
+}
+
+
+/// A CompileCache that keeps everything in memory, for as long as it lives.
+#[derive(Default)]
+pub struct MemoryCompileCache {
+    inputs: HashMap<DigestData, Vec<CachedInput>>,
+    results: HashMap<DigestData, CompileResult>,
+}
+
+impl MemoryCompileCache {
+    pub fn new() -> MemoryCompileCache {
+        MemoryCompileCache::default()
+    }
+}
+
+impl CompileCache for MemoryCompileCache {
+    fn get_inputs(&mut self, key: &DigestData) -> Option<Vec<CachedInput>> {
+        self.inputs.get(key).cloned()
+    }
+
+    fn put_inputs(&mut self, key: &DigestData, inputs: Vec<CachedInput>) {
+        self.inputs.insert(*key, inputs);
+    }
+
+    fn get_result(&mut self, key: &DigestData) -> Option<CompileResult> {
+        self.results.get(key).cloned()
+    }
+
+    fn put_result(&mut self, key: &DigestData, result: &CompileResult) {
+        self.results.insert(*key, result.clone());
+    }
+}
+
+
+/// Feed `data` to a digest in a way that can't run into the next part.
+fn hash_part(dc: &mut DigestComputer, data: &[u8]) {
+    dc.input(format!("{}:", data.len()).as_bytes());
+    dc.input(data);
+}
+
+
+/// Take all of the files that the engines created out of `mem`.
+fn take_outputs(mem: &MemoryIo) -> HashMap<OsString, Vec<u8>> {
+    let mut outputs = mem.files.borrow_mut().drain().collect::<HashMap<_, _>>();
+    outputs.remove(mem.stdout_key());
+    outputs
+}
+
+
+/// Find the format file that an "initex" run left in `mem`. The engine
+/// names it after the job, so we go by the extension.
+fn generated_format(mem: &MemoryIo) -> Option<Vec<u8>> {
+    mem.files.borrow().iter()
+        .find(|&(name, _)| name.to_string_lossy().ends_with(".fmt.gz"))
+        .map(|(_, data)| data.clone())
+}
+
+
+/// Keeps track of the number of the last page that TeX shipped out, which
+/// is its count of the pages so far.
+#[derive(Default)]
+struct PageCounter {
+    pages: usize,
+}
+
+impl IoEventBackend for PageCounter {
+    fn page_shipped(&mut self, page_number: usize) {
+        self.pages = page_number;
+    }
+}
+
+
+/// A wrapper around a bundle that keeps a copy of every file read from it.
+struct RecordingBundle {
+    inner: Box<IoProvider + Send>,
+    used: Arc<Mutex<HashMap<OsString, Vec<u8>>>>,
+}
+
+impl IoProvider for RecordingBundle {
+    fn input_open_name(&mut self, name: &OsStr, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        let mut ih = match self.inner.input_open_name(name, status) {
+            OpenResult::Ok(ih) => ih,
+            r => return r,
+        };
+
+        let mut data = Vec::new();
+
+        if let Err(e) = ih.read_to_end(&mut data) {
+            return OpenResult::Err(e.into());
+        }
+
+        self.used.lock().unwrap_or_else(|e| e.into_inner()).insert(name.to_os_string(), data.clone());
+        OpenResult::Ok(InputHandle::new(name, Cursor::new(data), ih.origin()))
+    }
+
+    fn input_open_format(&mut self, _name: &OsStr, _status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        OpenResult::NotAvailable
+    }
+}
+
+
+/// A wrapper around a bundle that notes the name of every file looked up in
+/// it, whether or not the file was found.
+struct LookupRecorder {
+    inner: Box<IoProvider + Send>,
+    lookups: Arc<Mutex<Vec<CachedInput>>>,
+}
+
+impl LookupRecorder {
+    fn note(&self, input: CachedInput) {
+        self.lookups.lock().unwrap_or_else(|e| e.into_inner()).push(input);
+    }
+}
+
+impl IoProvider for LookupRecorder {
+    fn output_open_name(&mut self, name: &OsStr) -> OpenResult<OutputHandle> {
+        self.inner.output_open_name(name)
+    }
+
+    fn output_open_stdout(&mut self) -> OpenResult<OutputHandle> {
+        self.inner.output_open_stdout()
+    }
+
+    fn input_open_name(&mut self, name: &OsStr, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        self.note(CachedInput::File(name.to_os_string()));
+        self.inner.input_open_name(name, status)
+    }
+
+    fn input_open_primary(&mut self, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        self.inner.input_open_primary(status)
+    }
+
+    fn input_open_format(&mut self, name: &OsStr, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        self.note(CachedInput::Format(name.to_os_string()));
+        self.inner.input_open_format(name, status)
+    }
+
+    fn write_format(&mut self, name: &str, data: &[u8], status: &mut StatusBackend) -> Result<()> {
+        self.inner.write_format(name, data, status)
+    }
+}
+
+
+/// A placeholder for a bundle that has been moved elsewhere.
+struct NoBundle;
+
+impl IoProvider for NoBundle {}
+
+
+/// Only one engine can run at a time; see the module documentation. A panic
+/// while holding the lock doesn't leave anything in a state that we care
+/// about, so we don't let poisoning stop us.
+fn lock_engines() -> MutexGuard<'static, ()> {
+    ENGINE_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+
+fn make_stack<'a>(primary: &'a mut IoProvider, format: &'a mut IoProvider,
+                  mem: &'a mut MemoryIo, bundle: &'a mut IoProvider,
+                  config: &'a mut TexmfConfigIo) -> IoStack<'a> {
+    let mut builder = IoStackBuilder::new();
+    builder.primary(primary);
+    builder.format(format);
+    builder.memory(mem);
+    builder.bundle(bundle);
+    builder.defaults(config);
+    builder.create()
+}
+
+
+/// The name of an output file: `primary_name` with its extension replaced.
+fn output_name(primary_name: &str, extension: &str) -> OsString {
+    Path::new(primary_name).with_extension(extension).into_os_string()
+}
+
+
+/// Process a document on a separate thread, so as not to block an
+/// asynchronous runtime such as Tokio; the engines are synchronous and
+/// CPU-bound. The returned future resolves when the compile is done.
+/// Engine messages are discarded.
+#[cfg(feature = "async")]
+pub fn compile_blocking(mut request: CompileRequest) -> Box<Future<Item = CompileResult, Error = Error> + Send> {
+    let (tx, rx) = oneshot::channel();
+
+    thread::spawn(move || {
+        let mut status = NoopStatusBackend::new();
+        let _ = tx.send(request.compile(&mut status));
+    });
+
+    Box::new(rx.then(|r| match r {
+        Ok(result) => result,
+        Err(_) => Err(ErrorKind::Msg("the compilation thread panicked".to_owned()).into()),
+    }))
+}
+
+
+#[cfg(all(test, not(feature = "no-filesystem")))]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+    use io::{FilesystemIo, InputOrigin};
+
+    const TOP: &'static str = env!("CARGO_MANIFEST_DIR");
+
+    /// A bundle with just enough in it to generate and use the plain TeX
+    /// format, made from the files that the integration tests use. The
+    /// "latex" format is plain TeX too, for code that doesn't let us choose.
+    /// It keeps the formats generated with it, so that later compiles can
+    /// reuse them.
+    struct PlainBundle {
+        files: FilesystemIo,
+        format: Option<Vec<u8>>,
+
+        /// More files to serve, which tests can change between compiles.
+        extra: Arc<Mutex<HashMap<OsString, Vec<u8>>>>,
+    }
+
+    impl PlainBundle {
+        fn new() -> PlainBundle {
+            let mut dir = PathBuf::from(TOP);
+            dir.push("tests");
+            dir.push("formats");
+            dir.push("plain");
+
+            let mut files = FilesystemIo::new(&dir, false, false, HashSet::new());
+            files.serves_bundle(true);
+
+            PlainBundle {
+                files: files,
+                format: None,
+                extra: Arc::new(Mutex::new(HashMap::new())),
+            }
+        }
+    }
+
+    impl IoProvider for PlainBundle {
+        fn input_open_name(&mut self, name: &OsStr, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+            if name == "tectonic-format-plain.tex" || name == "tectonic-format-latex.tex" {
+                return OpenResult::Ok(InputHandle::new(name, Cursor::new(b"\\input plain".to_vec()),
+                                                       InputOrigin::Bundle(name.to_os_string())));
+            }
+
+            if let Some(data) = self.extra.lock().unwrap().get(name) {
+                return OpenResult::Ok(InputHandle::new(name, Cursor::new(data.clone()),
+                                                       InputOrigin::Bundle(name.to_os_string())));
+            }
+
+            self.files.input_open_name(name, status)
+        }
+
+        fn input_open_format(&mut self, name: &OsStr, _status: &mut StatusBackend) -> OpenResult<InputHandle> {
+            match self.format {
+                Some(ref data) => OpenResult::Ok(InputHandle::new(name, Cursor::new(data.clone()), InputOrigin::Other)),
+                None => OpenResult::NotAvailable,
+            }
+        }
+
+        fn write_format(&mut self, _name: &str, data: &[u8], _status: &mut StatusBackend) -> Result<()> {
+            self.format = Some(data.to_owned());
+            Ok(())
+        }
+    }
+
+    /// A request to process `source` with the plain format, stopping at the
+    /// XDV so that no fonts are needed beyond the metrics.
+    fn plain_request(source: &str) -> CompileRequest {
+        let mut req = CompileRequest::new("texput.tex", source.as_bytes().to_owned(), Box::new(PlainBundle::new()));
+        req.format_name("plain").make_pdf(false);
+        req
+    }
+
+    fn compile(req: &mut CompileRequest) -> CompileResult {
+        req.compile(&mut NoopStatusBackend::new()).unwrap()
+    }
+
+    /// Options for `compile_to_memory()` and friends to match
+    /// `plain_request()`.
+    fn plain_options() -> CompileOptions {
+        CompileOptions {
+            format_name: "plain".to_owned(),
+            make_pdf: false,
+            .. CompileOptions::default()
+        }
+    }
+
+    #[test]
+    fn pages_and_passes_are_counted() {
+        let result = compile(&mut plain_request("\\message{hello from the log}First\\vfill\\eject Second\\end"));
+        assert_eq!(result.tex_result, TexResult::Spotless);
+        assert_eq!(result.n_tex_passes, 1);
+        assert_eq!(result.page_count, 2);
+        assert!(result.outputs.contains_key(OsStr::new("texput.xdv")));
+        assert!(result.log_text().unwrap().contains("hello from the log"));
+    }
+
+    #[test]
+    fn aux_changes_cause_reruns() {
+        let source = "\\immediate\\openout1=\\jobname.aux \\immediate\\write1{\\relax}\\immediate\\closeout1 \\end";
+
+        // The first pass creates the .aux, so a second one is needed to see
+        // that it has settled down.
+        assert_eq!(compile(&mut plain_request(source)).n_tex_passes, 2);
+        assert_eq!(compile(plain_request(source).max_tex_passes(1)).n_tex_passes, 1);
+
+        // A seed that matches what TeX writes saves the second pass.
+        assert_eq!(compile(plain_request(source).seed_aux(b"\\relax \n".to_vec())).n_tex_passes, 1);
+    }
+
+    #[test]
+    fn format_mode_dumps_a_format() {
+        let mut req = plain_request("\\input plain");
+        req.mode(EngineMode::InitFormat { dump_name: "custom".to_owned() });
+        let result = compile(&mut req);
+
+        assert_eq!(result.n_tex_passes, 1);
+        assert!(result.outputs.contains_key(OsStr::new("custom.fmt.gz")));
+        assert!(!result.outputs.contains_key(OsStr::new("texput.pdf")));
+    }
+
+    #[test]
+    fn compile_to_memory_names_outputs() {
+        let outputs = compile_to_memory("\\message{in memory}\\end", Box::new(PlainBundle::new()),
+                                        &plain_options()).unwrap();
+        assert!(String::from_utf8_lossy(&outputs["texput.log"]).contains("in memory"));
+
+        let options = CompileOptions { job_name: Some("renamed".to_owned()), .. plain_options() };
+        let outputs = compile_to_memory("\\end", Box::new(PlainBundle::new()), &options).unwrap();
+        assert!(outputs.contains_key("renamed.log"));
+        assert!(!outputs.contains_key("texput.log"));
+    }
+
+    #[test]
+    fn timeouts_cancel_runaway_compiles() {
+        match compile_with_timeout(plain_request("\\end"), Duration::from_secs(600)).unwrap() {
+            TimedCompile::Finished(r) => assert_eq!(r.n_tex_passes, 1),
+            TimedCompile::TimedOut { .. } => panic!("a trivial compile timed out"),
+        }
+
+        // Ship out empty pages forever.
+        match compile_with_timeout(plain_request("\\def\\x{\\shipout\\hbox{}\\x}\\x"),
+                                   Duration::from_millis(500)).unwrap() {
+            TimedCompile::Finished(_) => panic!("an endless compile finished"),
+            TimedCompile::TimedOut { .. } => {},
+        }
+    }
+
+    #[test]
+    fn pass_hooks_see_and_change_outputs() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen2 = seen.clone();
+
+        let mut req = plain_request("\\end");
+        req.on_pass_complete(move |pass, io| {
+            let mut status = NoopStatusBackend::new();
+            assert!(!io.input_open_name(OsStr::new("texput.log"), &mut status).is_not_available());
+            seen2.lock().unwrap().push(pass);
+
+            // Writing an .aux makes it look like the document needs another
+            // pass.
+            if pass == 0 {
+                io.output_open_name(OsStr::new("texput.aux")).unwrap().write_all(b"\\relax").unwrap();
+            }
+        });
+
+        assert_eq!(compile(&mut req).n_tex_passes, 2);
+        assert_eq!(*seen.lock().unwrap(), vec![0, 1]);
+    }
+
+    #[test]
+    fn prepared_compiles_can_be_rerun() {
+        let mut status = NoopStatusBackend::new();
+        let mut req = plain_request("\\message{prepared}Page\\end");
+        let mut prepared = req.prepare(&mut status).unwrap();
+
+        let first = prepared.run(&mut status).unwrap();
+        let second = prepared.run(&mut status).unwrap();
+        assert_eq!(first.page_count, 1);
+        assert_eq!(second.page_count, 1);
+        assert_eq!(first.outputs[OsStr::new("texput.xdv")], second.outputs[OsStr::new("texput.xdv")]);
+        assert!(second.log_text().unwrap().contains("prepared"));
+    }
+
+    #[test]
+    fn minimal_bundles_can_recompile() {
+        // No text, so that the PDF needs no font files.
+        let source = b"\\nopagenumbers \\hrule height 1in width 1in \\end";
+        let mut status = NoopStatusBackend::new();
+
+        let zipped = minimal_bundle("texput.tex", source.to_vec(), Box::new(PlainBundle::new()),
+                                    Cursor::new(Vec::new()), &mut status).unwrap().into_inner();
+
+        let mut bundle = ZipBundle::new(Cursor::new(zipped)).unwrap();
+        assert!(!bundle.input_open_name(OsStr::new("plain.tex"), &mut status).is_not_available());
+        assert!(bundle.input_open_name(OsStr::new("texput.tex"), &mut status).is_not_available());
+
+        let result = CompileRequest::new("texput.tex", source.to_vec(), Box::new(bundle)).compile(&mut status).unwrap();
+        assert_eq!(result.page_count, 1);
+        assert!(result.outputs.contains_key(OsStr::new("texput.pdf")));
+    }
+
+    #[test]
+    fn latex_to_pdf_uses_a_zip_file() {
+        use tempdir::TempDir;
+
+        let source = "\\nopagenumbers \\hrule height 1in width 1in \\end";
+        let tempdir = TempDir::new("tectonic_driver_test").unwrap();
+        let path = tempdir.path().join("bundle.zip");
+
+        minimal_bundle("texput.tex", source.as_bytes().to_owned(), Box::new(PlainBundle::new()),
+                       File::create(&path).unwrap(), &mut NoopStatusBackend::new()).unwrap();
+
+        let pdf = latex_to_pdf(source, &path).unwrap();
+        assert!(pdf.starts_with(b"%PDF-"));
+        assert!(latex_to_pdf(source, &tempdir.path().join("missing.zip")).is_err());
+    }
+
+    #[test]
+    fn cancelled_compiles_stop() {
+        let token = Arc::new(AtomicBool::new(false));
+        let setter = {
+            let token = token.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(500));
+                token.store(true, Ordering::SeqCst);
+            })
+        };
+
+        // Ship out empty pages until we're told to stop.
+        let mut req = plain_request("\\def\\x{\\shipout\\hbox{}\\x}\\x");
+        req.cancel_token(token);
+        let e = req.compile(&mut NoopStatusBackend::new()).unwrap_err();
+        setter.join().unwrap();
+
+        match *e.kind() {
+            ErrorKind::Cancelled => {},
+            ref k => panic!("expected a cancellation, got: {}", k),
+        }
+    }
+
+    #[test]
+    fn page_limits_stop_early() {
+        let source = "One\\vfill\\eject Two\\vfill\\eject Three\\vfill\\eject Four\\end";
+        assert_eq!(compile(&mut plain_request(source)).page_count, 4);
+
+        let result = compile(plain_request(source).stop_after_page(2));
+        assert_eq!(result.tex_result, TexResult::Spotless);
+        assert_eq!(result.page_count, 2);
+        assert!(result.outputs.contains_key(OsStr::new("texput.xdv")));
+
+        // The engine is left in a state in which it can run again.
+        assert_eq!(compile(plain_request(source).stop_after_page(3)).page_count, 3);
+    }
+
+    #[test]
+    fn cached_results_are_reused_until_inputs_change() {
+        use std::sync::atomic::AtomicUsize;
+
+        let bundle = PlainBundle::new();
+        let extra = bundle.extra.clone();
+        extra.lock().unwrap().insert(OsString::from("extra.tex"), b"\\message{first}".to_vec());
+
+        let runs = Arc::new(AtomicUsize::new(0));
+        let runs2 = runs.clone();
+
+        let mut req = CompileRequest::new("texput.tex", b"\\input extra \\end".to_vec(), Box::new(bundle));
+        req.format_name("plain").make_pdf(false).cache(Box::new(MemoryCompileCache::new()));
+        req.on_pass_complete(move |_, _| { runs2.fetch_add(1, Ordering::SeqCst); });
+
+        // A hit doesn't run the engines.
+        let first = compile(&mut req);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        let again = compile(&mut req);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(again.outputs, first.outputs);
+
+        // Different settings miss.
+        compile(req.max_tex_passes(3));
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+
+        // So does a change to a bundle file that the compile used.
+        extra.lock().unwrap().insert(OsString::from("extra.tex"), b"\\message{second}".to_vec());
+        let changed = compile(&mut req);
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert!(changed.log_text().unwrap().contains("second"));
+
+        // An access policy can't be digested, so it turns the cache off.
+        req.access_policy(|_, _| true);
+        compile(&mut req);
+        compile(&mut req);
+        assert_eq!(runs.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn file_lists_leave_out_deleted_files() {
+        let mut req = plain_request("\\nopagenumbers \\hrule height 1in width 1in \\end");
+        req.make_pdf(true).file_list(true);
+        let result = compile(&mut req);
+
+        let fls = String::from_utf8(result.outputs[OsStr::new("texput.fls")].clone()).unwrap();
+        let mut lines = fls.lines();
+        assert_eq!(lines.next(), Some(&format!("PWD {}", env::current_dir().unwrap().display())[..]));
+
+        let lines = lines.collect::<Vec<_>>();
+        assert!(lines.contains(&"OUTPUT texput.pdf"));
+        assert!(lines.contains(&"OUTPUT texput.log"));
+        assert!(!lines.iter().any(|l| l.ends_with("texput.xdv")));
+    }
+
+    #[test]
+    fn streamed_pdfs_match_collected_ones() {
+        use std::fs::File;
+        use tempdir::TempDir;
+
+        let source = "\\nopagenumbers \\hrule height 1in width 1in \\end";
+
+        // Pin the PDF's dates so that the two runs produce the same bytes.
+        let options = CompileOptions {
+            make_pdf: true,
+            source_date_epoch: Some(1456304492),
+            .. plain_options()
+        };
+
+        let collected = compile_to_memory(source, Box::new(PlainBundle::new()), &options).unwrap();
+        let expected = &collected["texput.pdf"];
+
+        let tempdir = TempDir::new("tectonic_driver_test").unwrap();
+        let path = tempdir.path().join("texput.pdf");
+        let outputs = compile_to_writer(source, Box::new(PlainBundle::new()),
+                                        File::create(&path).unwrap(), &options).unwrap();
+        assert!(!outputs.contains_key("texput.pdf"));
+
+        let mut streamed = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut streamed).unwrap();
+        assert!(streamed.starts_with(b"%PDF-"));
+        assert_eq!(&streamed, expected);
+    }
+}
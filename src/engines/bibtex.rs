@@ -28,7 +28,7 @@ impl BibtexEngine {
         let /*mut*/ state = ExecutionState::new(io, events, status);
         let bridge = TectonicBridgeApi::new(&state);
 
-        unsafe {
+        let result = unsafe {
             match super::bibtex_simple_main(&bridge, caux.as_ptr()) {
                 0 => Ok(TexResult::Spotless),
                 1 => Ok(TexResult::Warnings),
@@ -43,6 +43,11 @@ impl BibtexEngine {
                 },
                 x => Err(ErrorKind::Msg(format!("internal error: unexpected 'history' value {}", x)).into())
             }
+        };
+
+        match state.callback_error() {
+            Some(e) => Err(e),
+            None => result,
         }
     }
 }
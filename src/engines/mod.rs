@@ -12,17 +12,25 @@
 
 use flate2::{Compression, GzBuilder};
 use flate2::read::{GzDecoder};
+use flate2::write::GzEncoder;
 use md5::{Md5, Digest};
 use libc;
+use std::collections::HashSet;
 use std::ffi::{CStr, OsStr, OsString};
+use std::fmt;
 use std::io::{Read, SeekFrom, Write};
 use std::os::unix::ffi::OsStrExt;
-use std::path::Path;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::{io, ptr, slice};
 
 use digest::DigestData;
 use errors::{Error, ErrorKind, Result};
-use io::{InputOrigin, IoProvider, InputFeatures, InputHandle, OpenResult, OutputHandle};
+use io::{decompress_if_compressed, InputOrigin, IoProvider, InputFeatures, InputHandle, OpenResult,
+         OutputFeatures, OutputHandle, DEFAULT_MAX_DECOMPRESSED_SIZE};
 use status::StatusBackend;
 
 
@@ -32,13 +40,86 @@ pub mod tex;
 pub mod xdvipdfmx;
 pub mod bibtex;
 
-pub use self::tex::TexEngine;
+pub use self::tex::{EngineMode, TexEngine, TexOutcome};
 pub use self::xdvipdfmx::XdvipdfmxEngine;
 pub use self::bibtex::BibtexEngine;
 
 
 // Now, the public API.
 
+/// What the TeX engine may do when a document asks to run an external
+/// command with `\write18`. Letting documents run arbitrary commands is a
+/// serious security risk, so the default is not to.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub enum ShellEscape {
+    /// No commands are run.
+    Disabled,
+
+    /// Only commands whose program name is in the list are run. They are
+    /// run directly, not through a shell, with their arguments split on
+    /// whitespace.
+    Restricted(Vec<String>),
+
+    /// Every command is run through `sh -c`.
+    Full,
+}
+
+impl Default for ShellEscape {
+    fn default() -> Self {
+        ShellEscape::Disabled
+    }
+}
+
+impl ShellEscape {
+    /// The value that TeX's `\pdfshellescape` reports for this policy.
+    fn engine_mode(&self) -> libc::c_int {
+        match *self {
+            ShellEscape::Disabled => 0,
+            ShellEscape::Full => 1,
+            ShellEscape::Restricted(_) => 2,
+        }
+    }
+
+    /// The value of the `shell_escape` setting in `texmf.cnf` that goes with
+    /// this policy, in TeX Live's notation.
+    pub fn texmf_cnf_setting(&self) -> &'static str {
+        match *self {
+            ShellEscape::Disabled => "f",
+            ShellEscape::Full => "t",
+            ShellEscape::Restricted(_) => "p",
+        }
+    }
+}
+
+
+/// A function that decides whether the engine may read a file, for
+/// auditing or sandboxing. It is given each name that is tried for a file,
+/// with any suffixes added and any `./` parts dropped, before any I/O layer
+/// is searched for it, along with the kind of file wanted. If it returns
+/// false, that name is treated as if it didn't exist. This is separate from
+/// the I/O stack, which decides where files come from rather than whether they may
+/// be read at all, and it doesn't apply to the primary input.
+#[derive(Clone)]
+pub struct AccessPolicy(Arc<Fn(&Path, FileFormat) -> bool + Send + Sync>);
+
+impl AccessPolicy {
+    pub fn new<F: 'static + Fn(&Path, FileFormat) -> bool + Send + Sync>(f: F) -> AccessPolicy {
+        AccessPolicy(Arc::new(f))
+    }
+
+    /// Whether the engine may read `name`.
+    pub fn allows(&self, name: &Path, format: FileFormat) -> bool {
+        (self.0)(name, format)
+    }
+}
+
+impl fmt::Debug for AccessPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("AccessPolicy")
+    }
+}
+
+
 /// The IoEventBackend trait allows the program driving the TeX engines to
 /// track its input and output access patterns. The CLI program uses this
 /// information to intelligently decide when to rerun the TeX engine, to
@@ -64,6 +145,11 @@ pub trait IoEventBackend {
     /// This function is called when a file is opened for input.
     fn input_opened(&mut self, _name: &OsStr, _origin: InputOrigin) {}
 
+    /// This function is called along with `input_opened` when the engine
+    /// opens a file as a particular kind of input. The name is the same one
+    /// passed to `input_opened`.
+    fn input_format_opened(&mut self, _name: &OsStr, _format: FileFormat) {}
+
     /// This function is called when the "primary input" stream is opened.
     fn primary_input_opened(&mut self, _origin: InputOrigin) {}
 
@@ -71,12 +157,21 @@ pub trait IoEventBackend {
     /// the specified name but it was not available.
     fn input_not_available(&mut self, _name: &OsStr) {}
 
+    /// This function is called along with `input_not_available` with the
+    /// kind of input that the engine was looking for.
+    fn input_format_not_available(&mut self, _name: &OsStr, _format: FileFormat) {}
+
     /// This function is called when an input file is closed. The "digest"
     /// argument specifies the cryptographic digest of the data that were
     /// read, if available. This digest is not always available, if the engine
     /// used seeks while reading the file. Note that this function takes
     /// ownership of the name and digest.
     fn input_closed(&mut self, _name: OsString, _digest: Option<DigestData>) {}
+
+    /// This function is called each time the TeX engine ships out a page.
+    /// The argument counts the pages shipped out so far in this run, so it
+    /// starts at 1.
+    fn page_shipped(&mut self, _page_number: usize) {}
 }
 
 
@@ -90,8 +185,325 @@ impl NoopIoEventBackend {
 impl IoEventBackend for NoopIoEventBackend { }
 
 
+/// This struct implements the IoEventBackend trait by recording the name and
+/// format of every file that the engine opens as an input. This can, for
+/// instance, tell you which fonts a document uses.
+#[derive(Clone,Debug,Default)]
+pub struct InputRecorder {
+    inputs: Vec<(PathBuf, FileFormat)>,
+}
+
+impl InputRecorder {
+    pub fn new() -> InputRecorder {
+        InputRecorder::default()
+    }
+
+    /// All of the inputs that were opened, in order. A file that was opened
+    /// more than once appears more than once.
+    pub fn inputs(&self) -> &[(PathBuf, FileFormat)] {
+        &self.inputs
+    }
+
+    /// The inputs that were opened as fonts of some kind, without
+    /// duplicates.
+    pub fn font_inputs(&self) -> Vec<(PathBuf, FileFormat)> {
+        let mut fonts = Vec::new();
+
+        for &(ref path, format) in &self.inputs {
+            if format.is_font() && !fonts.iter().any(|&(ref p, _)| p == path) {
+                fonts.push((path.clone(), format));
+            }
+        }
+
+        fonts
+    }
+
+    /// The names of the LaTeX packages that were loaded, that is, the base
+    /// names of the `.sty` files that were opened, without duplicates. A
+    /// package may pull in others, so this includes indirect dependencies
+    /// as well as those mentioned in `\usepackage` commands.
+    pub fn packages_used(&self) -> Vec<String> {
+        let mut packages = Vec::new();
+
+        for &(ref path, format) in &self.inputs {
+            if format != FileFormat::Tex || path.extension() != Some(OsStr::new("sty")) {
+                continue;
+            }
+
+            if let Some(stem) = path.file_stem() {
+                let stem = stem.to_string_lossy().into_owned();
+
+                if !packages.contains(&stem) {
+                    packages.push(stem);
+                }
+            }
+        }
+
+        packages
+    }
+}
+
+impl IoEventBackend for InputRecorder {
+    fn input_format_opened(&mut self, name: &OsStr, format: FileFormat) {
+        self.inputs.push((PathBuf::from(name), format));
+    }
+}
+
+
+/// This struct records every input that the engine looks for but can't
+/// find, along with the kind of file that it wanted, and passes all events
+/// on to another IoEventBackend. After a run against an incomplete bundle,
+/// the list says which files the bundle would need for the document to
+/// work. Bear in mind that documents often probe for optional files, so not
+/// every missing file is a problem.
+pub struct MissingInputRecorder<E: IoEventBackend> {
+    inner: E,
+    missing: Vec<(PathBuf, FileFormat)>,
+}
+
+impl<E: IoEventBackend> MissingInputRecorder<E> {
+    pub fn new(inner: E) -> MissingInputRecorder<E> {
+        MissingInputRecorder {
+            inner: inner,
+            missing: Vec::new(),
+        }
+    }
+
+    /// The inputs that weren't available, in the order that they were first
+    /// looked for, without duplicates.
+    pub fn missing(&self) -> &[(PathBuf, FileFormat)] {
+        &self.missing
+    }
+
+    /// Write out the missing inputs as a manifest, with one line per file
+    /// giving its kind and name separated by a tab, sorted by name.
+    pub fn write_manifest<W: Write>(&self, dest: &mut W) -> Result<()> {
+        let mut missing = self.missing.iter().collect::<Vec<_>>();
+        missing.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for &&(ref path, format) in &missing {
+            writeln!(dest, "{:?}\t{}", format, path.display())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+}
+
+impl<E: IoEventBackend> IoEventBackend for MissingInputRecorder<E> {
+    fn output_opened(&mut self, name: &OsStr) {
+        self.inner.output_opened(name)
+    }
+
+    fn stdout_opened(&mut self) {
+        self.inner.stdout_opened()
+    }
+
+    fn output_closed(&mut self, name: OsString, digest: DigestData) {
+        self.inner.output_closed(name, digest)
+    }
+
+    fn input_opened(&mut self, name: &OsStr, origin: InputOrigin) {
+        self.inner.input_opened(name, origin)
+    }
+
+    fn input_format_opened(&mut self, name: &OsStr, format: FileFormat) {
+        self.inner.input_format_opened(name, format)
+    }
+
+    fn primary_input_opened(&mut self, origin: InputOrigin) {
+        self.inner.primary_input_opened(origin)
+    }
+
+    fn input_not_available(&mut self, name: &OsStr) {
+        self.inner.input_not_available(name)
+    }
+
+    fn input_format_not_available(&mut self, name: &OsStr, format: FileFormat) {
+        let entry = (PathBuf::from(name), format);
+
+        if !self.missing.contains(&entry) {
+            self.missing.push(entry);
+        }
+
+        self.inner.input_format_not_available(name, format)
+    }
+
+    fn input_closed(&mut self, name: OsString, digest: Option<DigestData>) {
+        self.inner.input_closed(name, digest)
+    }
+
+    fn page_shipped(&mut self, page_number: usize) {
+        self.inner.page_shipped(page_number)
+    }
+}
+
+
+/// This struct records the files that the engines read and write, in the
+/// order that they were first opened, and passes all events on to another
+/// IoEventBackend. The list can be written out in the `.fls` format of TeX's
+/// `-recorder` option, which build tools such as `latexmk` use to learn a
+/// document's dependencies. Each file is listed once as an input and once as
+/// an output at most, however many times it was opened; files that couldn't
+/// be found aren't listed. Inputs that came from the filesystem are listed
+/// under the paths where they were found, as TeX itself would list them.
+pub struct FileListRecorder<E: IoEventBackend> {
+    inner: E,
+    primary_name: Option<PathBuf>,
+    files: Vec<(bool, PathBuf)>,
+}
+
+impl<E: IoEventBackend> FileListRecorder<E> {
+    pub fn new(inner: E) -> FileListRecorder<E> {
+        FileListRecorder {
+            inner: inner,
+            primary_name: None,
+            files: Vec::new(),
+        }
+    }
+
+    /// Set the name to list the primary input under, which the engines
+    /// don't know. If this isn't set, the primary input isn't listed.
+    pub fn primary_name<P: AsRef<Path>>(&mut self, name: P) -> &mut Self {
+        self.primary_name = Some(name.as_ref().to_owned());
+        self
+    }
+
+    fn note(&mut self, is_output: bool, path: PathBuf) {
+        let entry = (is_output, path);
+
+        if !self.files.contains(&entry) {
+            self.files.push(entry);
+        }
+    }
+
+    /// Drop `path` from the list, as an input and as an output. This is for
+    /// intermediate files that are deleted once they've been used, which
+    /// build tools would otherwise look for.
+    pub fn forget<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.files.retain(|&(_, ref p)| p != path.as_ref());
+        self
+    }
+
+    /// The files that were opened, in order, each with a flag that is true
+    /// for outputs and false for inputs.
+    pub fn files(&self) -> &[(bool, PathBuf)] {
+        &self.files
+    }
+
+    /// Write out the list in the `.fls` format: a line `INPUT name` or
+    /// `OUTPUT name` for each file, after a `PWD` line giving the directory
+    /// that the names are relative to, if there is one.
+    pub fn write_fls<W: Write>(&self, dest: &mut W, pwd: Option<&Path>) -> Result<()> {
+        if let Some(pwd) = pwd {
+            writeln!(dest, "PWD {}", pwd.display())?;
+        }
+
+        for &(is_output, ref path) in &self.files {
+            writeln!(dest, "{} {}", if is_output { "OUTPUT" } else { "INPUT" }, path.display())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn inner(&self) -> &E {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut E {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+}
+
+impl<E: IoEventBackend> IoEventBackend for FileListRecorder<E> {
+    fn output_opened(&mut self, name: &OsStr) {
+        self.note(true, PathBuf::from(name));
+        self.inner.output_opened(name)
+    }
+
+    fn stdout_opened(&mut self) {
+        self.inner.stdout_opened()
+    }
+
+    fn output_closed(&mut self, name: OsString, digest: DigestData) {
+        self.inner.output_closed(name, digest)
+    }
+
+    fn input_opened(&mut self, name: &OsStr, origin: InputOrigin) {
+        let path = origin.path().map_or_else(|| PathBuf::from(name), |p| p.to_owned());
+        self.note(false, path);
+        self.inner.input_opened(name, origin)
+    }
+
+    fn input_format_opened(&mut self, name: &OsStr, format: FileFormat) {
+        self.inner.input_format_opened(name, format)
+    }
+
+    fn primary_input_opened(&mut self, origin: InputOrigin) {
+        if let Some(name) = self.primary_name.clone() {
+            self.note(false, name);
+        }
+
+        self.inner.primary_input_opened(origin)
+    }
+
+    fn input_not_available(&mut self, name: &OsStr) {
+        self.inner.input_not_available(name)
+    }
+
+    fn input_format_not_available(&mut self, name: &OsStr, format: FileFormat) {
+        self.inner.input_format_not_available(name, format)
+    }
+
+    fn input_closed(&mut self, name: OsString, digest: Option<DigestData>) {
+        self.inner.input_closed(name, digest)
+    }
+
+    fn page_shipped(&mut self, page_number: usize) {
+        self.inner.page_shipped(page_number)
+    }
+}
+
+
 // Now, the private interfaces for executing various engines implemented in C/C++.
 
+/// The stream behind gzipped output handles. Finishing it writes the gzip
+/// trailer and then finishes the underlying stream.
+struct GzOutput(Option<GzEncoder<Box<OutputFeatures>>>);
+
+impl Write for GzOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.0 {
+            Some(ref mut gz) => gz.write(buf),
+            None => Err(io::Error::new(io::ErrorKind::Other, "write to finished output")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.0 {
+            Some(ref mut gz) => gz.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl OutputFeatures for GzOutput {
+    fn finish(&mut self) -> io::Result<()> {
+        match self.0.take() {
+            Some(gz) => gz.finish()?.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+
 /// During the execution of a C/C++ engine, an ExecutionState structure holds
 /// all of the state relevant on the *Rust* side of things: I/O, essentially.
 /// The methods on ExecutionState pretty much all work to implement for the
@@ -103,6 +515,42 @@ struct ExecutionState<'a, I: 'a + IoProvider>  {
     status: &'a mut StatusBackend,
     input_handles: Vec<Box<InputHandle>>,
     output_handles: Vec<Box<OutputHandle>>,
+
+    /// Inputs that we've already failed to find. Documents often probe for
+    /// the same optional files over and over (e.g. with `\IfFileExists`),
+    /// and a miss can be expensive since every I/O layer gets searched. The
+    /// I/O stack can't change during a run, but the engine can create files
+    /// that it then reads back, so the cache is cleared whenever an output
+    /// is opened.
+    missing_inputs: HashSet<(OsString, FileFormat)>,
+
+    shell_escape: ShellEscape,
+
+    /// If set, the engine is asked to stop when this becomes true; see
+    /// `is_cancelled()`.
+    cancel_token: Option<Arc<AtomicBool>>,
+
+    /// Whether we told the engine to stop because `cancel_token` was set.
+    /// Only then does a fatal error mean that the run was cancelled.
+    cancelled: bool,
+
+    /// If set, the TeX engine finishes up the job once it has shipped out
+    /// this many pages.
+    page_limit: Option<usize>,
+
+    /// If set, consulted before each input file is opened; see
+    /// `AccessPolicy`.
+    access_policy: Option<AccessPolicy>,
+
+    /// The message of the first panic caught in one of our callbacks, if
+    /// any; see `ffi_guard()`.
+    panic_message: Option<String>,
+
+    /// The first error, other than reaching the end of the file, that
+    /// happened while reading an input. The engines can't tell such errors
+    /// from the end of the file, so we end the run ourselves; see
+    /// `note_read_error()`.
+    read_error: Option<String>,
 }
 
 
@@ -115,48 +563,130 @@ impl<'a, I: 'a + IoProvider> ExecutionState<'a, I> {
             status: status,
             output_handles: Vec::new(),
             input_handles: Vec::new(),
+            missing_inputs: HashSet::new(),
+            shell_escape: ShellEscape::Disabled,
+            cancel_token: None,
+            cancelled: false,
+            page_limit: None,
+            access_policy: None,
+            panic_message: None,
+            read_error: None,
         }
     }
 
-    // Helpers.
+    /// Whether the engine should stop. The C code checks this each time that
+    /// it opens a file and each time that it ships out a page, so a
+    /// cancellation takes effect within a page or so of typesetting; but
+    /// some single steps, such as loading a large font, can't be
+    /// interrupted.
+    fn is_cancelled(&self) -> bool {
+        self.panic_message.is_some() || self.read_error.is_some() || cancel_requested(&self.cancel_token)
+    }
 
-    fn input_open_name_format(&mut self, name: &OsStr, format: FileFormat) -> OpenResult<InputHandle> {
-        let r = if let FileFormat::Format = format {
-            self.io.input_open_format(name, self.status)
-        } else {
-            self.io.input_open_name(name, self.status)
-        };
+    /// Whether the run was stopped because of the cancellation token, as
+    /// opposed to ending with an error of its own.
+    fn was_cancelled(&self) -> bool {
+        self.cancelled
+    }
 
-        let path = Path::new(name);
+    /// If one of our callbacks panicked during the run, or an input
+    /// couldn't be read, the error that the run should end with, whatever
+    /// the engine itself made of it.
+    fn callback_error(&self) -> Option<Error> {
+        if let Some(ref m) = self.panic_message {
+            return Some(ErrorKind::Msg(format!("internal error in an engine callback: {}", m)).into());
+        }
+
+        self.read_error.as_ref().map(|m| ErrorKind::Msg(format!("error reading an input file: {}", m)).into())
+    }
 
-        match r {
-            OpenResult::NotAvailable if path.extension().is_some() =>
-                // Do not change the extension if provided.
-                return OpenResult::NotAvailable,
-            OpenResult::NotAvailable => {},
-            r => return r,
+    /// Record a failure to read an input, such as a corrupt bundle entry,
+    /// so that the engine stops at its next cancellation check and the run
+    /// ends in an error instead of carrying on as if the file had ended.
+    fn note_read_error(&mut self, e: &Error) {
+        if self.read_error.is_none() {
+            self.read_error = Some(e.to_string());
         }
+    }
+
+    /// Run a `\write18` command if our policy allows it. Returns -1 if it
+    /// wasn't allowed, 0 if it ran successfully, and 1 otherwise.
+    fn shell_escape(&mut self, command: &str) -> libc::c_int {
+        let mut cmd = match self.shell_escape {
+            ShellEscape::Disabled => return -1,
+            ShellEscape::Full => {
+                let mut cmd = Command::new("sh");
+                cmd.arg("-c").arg(command);
+                cmd
+            },
+            ShellEscape::Restricted(ref allowed) => {
+                let mut words = command.split_whitespace();
+
+                let program = match words.next() {
+                    Some(p) if allowed.iter().any(|a| a == p) => p,
+                    _ => {
+                        tt_warning!(self.status, "the shell-escape command \"{}\" is not allowed", command);
+                        return -1;
+                    },
+                };
+
+                let mut cmd = Command::new(program);
+                cmd.args(words);
+                cmd
+            },
+        };
 
-        if path.file_name().is_none() {
-            // No file name, Path::set_extension will not do anything, so why even try?
-            return OpenResult::NotAvailable;
+        match cmd.stdin(Stdio::null()).status() {
+            Ok(s) if s.success() => 0,
+            Ok(s) => {
+                tt_warning!(self.status, "the shell-escape command \"{}\" failed ({})", command, s);
+                1
+            },
+            Err(e) => {
+                tt_warning!(self.status, "couldn't run the shell-escape command \"{}\"", command; e.into());
+                1
+            },
         }
+    }
 
-        let mut ext = path.to_owned();
+    // Helpers.
+
+    fn input_open_name_format(&mut self, name: &OsStr, format: FileFormat) -> OpenResult<InputHandle> {
+        let mut denied = false;
+
+        for n in format_search_names(name, format) {
+            let allowed = match self.access_policy {
+                Some(ref p) => {
+                    // Don't let "./secret.tex" slip past a check for "secret.tex".
+                    let normalized = Path::new(&n).components()
+                        .filter(|c| *c != Component::CurDir)
+                        .collect::<PathBuf>();
+                    p.allows(&normalized, format)
+                },
+                None => true,
+            };
 
-        for e in format_to_extension(format) {
-            ext.set_extension(e);
+            if !allowed {
+                denied = true;
+                continue;
+            }
 
-            if let FileFormat::Format = format {
-                if let r @ OpenResult::Ok(_) = self.io.input_open_format(ext.as_ref(), self.status) {
-                    return r
-                }
+            let r = if let FileFormat::Format = format {
+                self.io.input_open_format(&n, self.status)
             } else {
-                if let r @ OpenResult::Ok(_) = self.io.input_open_name(ext.as_ref(), self.status) {
-                    return r
-                }
+                self.io.input_open_name(&n, self.status)
+            };
+
+            match r {
+                OpenResult::NotAvailable => continue,
+                r => return r,
             }
         }
+
+        if denied {
+            tt_note!(self.status, "access to input \"{}\" was denied", name.to_string_lossy());
+        }
+
         OpenResult::NotAvailable
     }
 
@@ -165,7 +695,18 @@ impl<'a, I: 'a + IoProvider> ExecutionState<'a, I> {
         let base = self.input_open_name_format(name, format);
 
         if !is_gz {
-            return base;
+            // Only bundles store files compressed behind the engine's back;
+            // anything else is passed along just as it was found.
+            return match base {
+                OpenResult::Ok(ih) => match ih.origin() {
+                    InputOrigin::Bundle(_) => match decompress_if_compressed(ih, DEFAULT_MAX_DECOMPRESSED_SIZE) {
+                        Ok(ih) => OpenResult::Ok(ih),
+                        Err(e) => OpenResult::Err(e),
+                    },
+                    _ => OpenResult::Ok(ih),
+                },
+                _ => base
+            };
         }
 
         match base {
@@ -241,6 +782,8 @@ impl<'a, I: 'a + IoProvider> ExecutionState<'a, I> {
     }
 
     fn output_open(&mut self, name: &OsStr, is_gz: bool) -> *const OutputHandle {
+        self.missing_inputs.clear();
+
         let mut oh = match self.io.output_open_name(name) {
             OpenResult::Ok(oh) => oh,
             OpenResult::NotAvailable => return ptr::null(),
@@ -252,7 +795,9 @@ impl<'a, I: 'a + IoProvider> ExecutionState<'a, I> {
 
         if is_gz {
             let name = oh.name().to_os_string();
-            oh = OutputHandle::new(&name, GzBuilder::new().write(oh.into_inner(), Compression::Default));
+            let dest = oh.destination().clone();
+            let gz = GzBuilder::new().write(oh.into_inner(), Compression::Default);
+            oh = OutputHandle::new_with_features(&name, GzOutput(Some(gz)), dest);
         }
 
         self.events.output_opened(oh.name());
@@ -310,7 +855,7 @@ impl<'a, I: 'a + IoProvider> ExecutionState<'a, I> {
 
             if p == handle {
                 let mut oh = self.output_handles.swap_remove(i);
-                if let Err(e) = oh.flush() {
+                if let Err(e) = oh.finish() {
                     tt_warning!(self.status, "error when closing output {}", oh.name().to_string_lossy(); e.into());
                     rv = true;
                 }
@@ -324,10 +869,20 @@ impl<'a, I: 'a + IoProvider> ExecutionState<'a, I> {
     }
 
     fn input_open(&mut self, name: &OsStr, format: FileFormat, is_gz: bool) -> *const InputHandle {
+        let key = (name.to_os_string(), format);
+
+        if self.missing_inputs.contains(&key) {
+            self.events.input_not_available(name);
+            self.events.input_format_not_available(name, format);
+            return ptr::null();
+        }
+
         let ih = match self.input_open_name_format_gz(name, format, is_gz) {
             OpenResult::Ok(ih) => ih,
             OpenResult::NotAvailable => {
                 self.events.input_not_available(name);
+                self.events.input_format_not_available(name, format);
+                self.missing_inputs.insert(key);
                 return ptr::null();
             },
             OpenResult::Err(e) => {
@@ -338,6 +893,7 @@ impl<'a, I: 'a + IoProvider> ExecutionState<'a, I> {
 
         // the file name may have had an extension added, so we use ih.name() here:
         self.events.input_opened(ih.name(), ih.origin());
+        self.events.input_format_opened(ih.name(), format);
         self.input_handles.push(Box::new(ih));
         &*self.input_handles[self.input_handles.len()-1]
     }
@@ -416,6 +972,38 @@ impl<'a, I: 'a + IoProvider> ExecutionState<'a, I> {
 }
 
 
+impl<'a, I: 'a + IoProvider> Drop for ExecutionState<'a, I> {
+    fn drop(&mut self) {
+        // If the engine aborted, its outputs are still open. Dropping them
+        // unfinished marks them as incomplete, so that a file written
+        // atomically keeps its previous contents -- but the log of a failed
+        // run is the one thing needed to see what went wrong, so keep it.
+        for mut oh in self.output_handles.drain(..) {
+            if Path::new(oh.name()).extension() != Some(OsStr::new("log")) {
+                continue;
+            }
+
+            if let Err(e) = oh.finish() {
+                tt_warning!(self.status, "error when closing output {}", oh.name().to_string_lossy(); e.into());
+                continue;
+            }
+
+            let (name, digest) = oh.into_name_digest();
+            self.events.output_closed(name, digest);
+        }
+    }
+}
+
+
+/// Whether a cancellation token is set and has been triggered.
+fn cancel_requested(token: &Option<Arc<AtomicBool>>) -> bool {
+    match *token {
+        Some(ref t) => t.load(Ordering::SeqCst),
+        None => false,
+    }
+}
+
+
 // Now, here' the actual C API. There are two parts to this: the functions in
 // the backing C/C++ code that *we* call, and the API bridge -- a struct of
 // function pointers that we pass to the C/C++ entry points so that they can
@@ -443,240 +1031,330 @@ struct TectonicBridgeApi {
     input_getc: *const libc::c_void,
     input_ungetc: *const libc::c_void,
     input_close: *const libc::c_void,
+    page_shipped: *const libc::c_void,
+    shell_escape: *const libc::c_void,
+    check_cancelled: *const libc::c_void,
 }
 
 extern {
     fn tt_get_error_message() -> *const libc::c_char;
     fn tt_set_int_variable(var_name: *const libc::c_char, value: libc::c_int) -> libc::c_int;
-    //fn tt_set_string_variable(var_name: *const libc::c_char, value: *const libc::c_char) -> libc::c_int;
+    fn tt_set_string_variable(var_name: *const libc::c_char, value: *const libc::c_char) -> libc::c_int;
+    fn tt_set_source_date_epoch(epoch: i64);
     fn tex_simple_main(api: *const TectonicBridgeApi, dump_name: *const libc::c_char, input_file_name: *const libc::c_char) -> libc::c_int;
     fn dvipdfmx_simple_main(api: *const TectonicBridgeApi, dviname: *const libc::c_char, pdfname: *const libc::c_char) -> libc::c_int;
     fn bibtex_simple_main(api: *const TectonicBridgeApi, aux_file_name: *const libc::c_char) -> libc::c_int;
 }
 
 
-// Entry points for the C/C++ API functions.
+// Entry points for the C/C++ API functions. Each one runs its body through
+// `ffi_guard()` so that a panic never unwinds into the C code.
 
-fn kpse_find_file<'a, I: 'a + IoProvider>(es: *mut ExecutionState<'a, I>, name: *const libc::c_char, format: libc::c_int, must_exist: libc::c_int) -> *const libc::c_char {
+/// Run the body of a C API entry point, catching any panic. Unwinding across
+/// the FFI boundary is undefined behavior, so instead we report the panic,
+/// hand `failure` back to the C code, and record the panic so that the
+/// engine stops at its next cancellation check and the run ends in an
+/// error; see `ExecutionState::callback_error()`.
+fn ffi_guard<'a, I, T, F>(es: *mut ExecutionState<'a, I>, failure: T, f: F) -> T
+    where I: 'a + IoProvider, F: FnOnce(&mut ExecutionState<'a, I>) -> T
+{
     let es = unsafe { &mut *es };
-    let rname = unsafe { CStr::from_ptr(name) };
-    let rformat = c_format_to_rust(format);
-    let rmust_exist = must_exist != 0;
+    let result = panic::catch_unwind(AssertUnwindSafe(|| f(&mut *es)));
+
+    match result {
+        Ok(v) => v,
+        Err(payload) => {
+            let msg = if let Some(s) = payload.downcast_ref::<&str>() {
+                (*s).to_owned()
+            } else if let Some(s) = payload.downcast_ref::<String>() {
+                s.clone()
+            } else {
+                "unknown panic".to_owned()
+            };
+
+            tt_error!(es.status, "internal error in an engine callback: {}", msg);
 
-    // This function can never work for Tectonic because files in the bundle
-    // can't be referenced by path names.
-    tt_error!(es.status, "unimplemented feature: kpse_find_file(); please report an issue on GitHub!");
-    tt_error!(es.status, "Diagnostics: {:?}, {:?} ({}), {}", rname, rformat, format, rmust_exist);
+            if es.panic_message.is_none() {
+                es.panic_message = Some(msg);
+            }
+
+            failure
+        }
+    }
+}
 
-    ptr::null()
+fn kpse_find_file<'a, I: 'a + IoProvider>(es: *mut ExecutionState<'a, I>, name: *const libc::c_char, format: libc::c_int, must_exist: libc::c_int) -> *const libc::c_char {
+    ffi_guard(es, ptr::null(), |es| {
+        let rname = unsafe { CStr::from_ptr(name) };
+        let rformat = c_format_to_rust(format);
+        let rmust_exist = must_exist != 0;
+
+        // This function can never work for Tectonic because files in the bundle
+        // can't be referenced by path names.
+        tt_error!(es.status, "unimplemented feature: kpse_find_file(); please report an issue on GitHub!");
+        tt_error!(es.status, "Diagnostics: {:?}, {:?} ({}), {}", rname, rformat, format, rmust_exist);
+
+        ptr::null()
+    })
 }
 
 fn issue_warning<'a, I: 'a + IoProvider>(es: *mut ExecutionState<'a, I>, text: *const libc::c_char) {
-    let es = unsafe { &mut *es };
-    let rtext = unsafe { CStr::from_ptr(text) };
+    ffi_guard(es, (), |es| {
+        let rtext = unsafe { CStr::from_ptr(text) };
 
-    tt_warning!(es.status, "{}", rtext.to_string_lossy());
+        tt_warning!(es.status, "{}", rtext.to_string_lossy());
+    })
 }
 
 fn issue_error<'a, I: 'a + IoProvider>(es: *mut ExecutionState<'a, I>, text: *const libc::c_char) {
-    let es = unsafe { &mut *es };
-    let rtext = unsafe { CStr::from_ptr(text) };
+    ffi_guard(es, (), |es| {
+        let rtext = unsafe { CStr::from_ptr(text) };
 
-    tt_error!(es.status, "{}", rtext.to_string_lossy());
+        tt_error!(es.status, "{}", rtext.to_string_lossy());
+    })
 }
 
 fn get_file_md5<'a, I: 'a + IoProvider>(es: *mut ExecutionState<'a, I>, path: *const libc::c_char, digest: *mut u8) -> libc::c_int {
-    let es = unsafe { &mut *es };
-    let rpath = OsStr::from_bytes(unsafe { CStr::from_ptr(path) }.to_bytes());
-    let rdest = unsafe { slice::from_raw_parts_mut(digest, 16) };
+    ffi_guard(es, 1, |es| {
+        let rpath = OsStr::from_bytes(unsafe { CStr::from_ptr(path) }.to_bytes());
+        let rdest = unsafe { slice::from_raw_parts_mut(digest, 16) };
 
-    if es.get_file_md5(rpath, rdest) {
-        1
-    } else {
-        0
-    }
+        if es.get_file_md5(rpath, rdest) {
+            1
+        } else {
+            0
+        }
+    })
 }
 
-fn get_data_md5<'a, I: 'a + IoProvider>(_es: *mut ExecutionState<'a, I>, data: *const u8, len: libc::size_t, digest: *mut u8) -> libc::c_int {
-    //let es = unsafe { &mut *es };
-    let rdata = unsafe { slice::from_raw_parts(data, len) };
-    let rdest = unsafe { slice::from_raw_parts_mut(digest, 16) };
+fn get_data_md5<'a, I: 'a + IoProvider>(es: *mut ExecutionState<'a, I>, data: *const u8, len: libc::size_t, digest: *mut u8) -> libc::c_int {
+    ffi_guard(es, 1, |_es| {
+        let rdata = unsafe { slice::from_raw_parts(data, len) };
+        let rdest = unsafe { slice::from_raw_parts_mut(digest, 16) };
 
-    let mut hash = Md5::default();
-    hash.input(rdata);
-    let result = hash.result();
-    rdest.copy_from_slice(result.as_slice());
+        let mut hash = Md5::default();
+        hash.input(rdata);
+        let result = hash.result();
+        rdest.copy_from_slice(result.as_slice());
 
-    0
+        0
+    })
 }
 
 fn output_open<'a, I: 'a + IoProvider>(es: *mut ExecutionState<'a, I>, name: *const libc::c_char, is_gz: libc::c_int) -> *const libc::c_void {
-    let es = unsafe { &mut *es };
-    let rname = OsStr::from_bytes(unsafe { CStr::from_ptr(name) }.to_bytes());
-    let ris_gz = is_gz != 0;
+    ffi_guard(es, ptr::null(), |es| {
+        let rname = OsStr::from_bytes(unsafe { CStr::from_ptr(name) }.to_bytes());
+        let ris_gz = is_gz != 0;
 
-    es.output_open(&rname, ris_gz) as *const _
+        es.output_open(&rname, ris_gz) as *const _
+    })
 }
 
 fn output_open_stdout<'a, I: 'a + IoProvider>(es: *mut ExecutionState<'a, I>, ) -> *const libc::c_void {
-    let es = unsafe { &mut *es };
-
-    es.output_open_stdout() as *const _
+    ffi_guard(es, ptr::null(), |es| {
+        es.output_open_stdout() as *const _
+    })
 }
 
 fn output_putc<'a, I: 'a + IoProvider>(es: *mut ExecutionState<'a, I>, handle: *mut libc::c_void, c: libc::c_int) -> libc::c_int {
-    let es = unsafe { &mut *es };
-    let rhandle = handle as *mut OutputHandle;
-    let rc = c as u8;
+    ffi_guard(es, libc::EOF, |es| {
+        let rhandle = handle as *mut OutputHandle;
+        let rc = c as u8;
 
-    if es.output_write(rhandle, &[rc]) {
-        libc::EOF
-    } else {
-        c
-    }
+        if es.output_write(rhandle, &[rc]) {
+            libc::EOF
+        } else {
+            c
+        }
+    })
 }
 
 fn output_write<'a, I: 'a + IoProvider>(es: *mut ExecutionState<'a, I>, handle: *mut libc::c_void, data: *const u8, len: libc::size_t) -> libc::size_t {
-    let es = unsafe { &mut *es };
-    let rhandle = handle as *mut OutputHandle;
-    let rdata = unsafe { slice::from_raw_parts(data, len) };
+    ffi_guard(es, 0, |es| {
+        let rhandle = handle as *mut OutputHandle;
+        let rdata = unsafe { slice::from_raw_parts(data, len) };
 
-    // NOTE: we use f.write_all() so partial writes are not gonna be a thing.
+        // NOTE: we use f.write_all() so partial writes are not gonna be a thing.
 
-    if es.output_write(rhandle, rdata) {
-        0
-    } else {
-        len
-    }
+        if es.output_write(rhandle, rdata) {
+            0
+        } else {
+            len
+        }
+    })
 }
 
 fn output_flush<'a, I: 'a + IoProvider>(es: *mut ExecutionState<'a, I>, handle: *mut libc::c_void) -> libc::c_int {
-    let es = unsafe { &mut *es };
-    let rhandle = handle as *mut OutputHandle;
+    ffi_guard(es, 1, |es| {
+        let rhandle = handle as *mut OutputHandle;
 
-    if es.output_flush(rhandle) {
-        1
-    } else {
-        0
-    }
+        if es.output_flush(rhandle) {
+            1
+        } else {
+            0
+        }
+    })
 }
 
 fn output_close<'a, I: 'a + IoProvider>(es: *mut ExecutionState<'a, I>, handle: *mut libc::c_void) -> libc::c_int {
-    let es = unsafe { &mut *es };
-
-    if handle == 0 as *mut _ {
-        return 0; // This is/was the behavior of close_file() in C.
-    }
+    ffi_guard(es, 1, |es| {
+        if handle == 0 as *mut _ {
+            return 0; // This is/was the behavior of close_file() in C.
+        }
 
-    let rhandle = handle as *mut OutputHandle;
+        let rhandle = handle as *mut OutputHandle;
 
-    if es.output_close(rhandle) {
-        1
-    } else {
-        0
-    }
+        if es.output_close(rhandle) {
+            1
+        } else {
+            0
+        }
+    })
 }
 
 
 fn input_open<'a, I: 'a + IoProvider>(es: *mut ExecutionState<'a, I>, name: *const libc::c_char, format: libc::c_int, is_gz: libc::c_int) -> *const libc::c_void {
-    let es = unsafe { &mut *es };
-    let rname = OsStr::from_bytes(unsafe { CStr::from_ptr(name) }.to_bytes());
-    let rformat = c_format_to_rust(format);
-    let ris_gz = is_gz != 0;
-
-    match rformat {
-        Some(fmt) => {
-            es.input_open(&rname, fmt, ris_gz) as *const _
-        },
-        None => ptr::null()
-    }
+    ffi_guard(es, ptr::null(), |es| {
+        let rname = OsStr::from_bytes(unsafe { CStr::from_ptr(name) }.to_bytes());
+        let rformat = c_format_to_rust(format);
+        let ris_gz = is_gz != 0;
+
+        match rformat {
+            Some(fmt) => {
+                es.input_open(&rname, fmt, ris_gz) as *const _
+            },
+            None => ptr::null()
+        }
+    })
 }
 
 fn input_open_primary<'a, I: 'a + IoProvider>(es: *mut ExecutionState<'a, I>) -> *const libc::c_void {
-    let es = unsafe { &mut *es };
-
-    es.input_open_primary() as *const _
+    ffi_guard(es, ptr::null(), |es| {
+        es.input_open_primary() as *const _
+    })
 }
 
 fn input_get_size<'a, I: 'a + IoProvider>(es: *mut ExecutionState<'a, I>, handle: *mut libc::c_void) -> libc::size_t {
-    let es = unsafe { &mut *es };
-    let rhandle = handle as *mut InputHandle;
+    ffi_guard(es, 0, |es| {
+        let rhandle = handle as *mut InputHandle;
 
-    es.input_get_size(rhandle)
+        es.input_get_size(rhandle)
+    })
 }
 
 fn input_seek<'a, I: 'a + IoProvider>(es: *mut ExecutionState<'a, I>, handle: *mut libc::c_void, offset: libc::ssize_t, whence: libc::c_int) -> libc::size_t {
-    let es = unsafe { &mut *es };
-    let rhandle = handle as *mut InputHandle;
-
-    let rwhence = match whence {
-        libc::SEEK_SET => SeekFrom::Start(offset as u64),
-        libc::SEEK_CUR => SeekFrom::Current(offset as i64),
-        libc::SEEK_END => SeekFrom::End(offset as i64),
-        _ => panic!("Unexpected \"whence\" parameter to fseek() wrapper: {}", whence),
-    };
+    ffi_guard(es, 0, |es| {
+        let rhandle = handle as *mut InputHandle;
+
+        let rwhence = match whence {
+            libc::SEEK_SET => SeekFrom::Start(offset as u64),
+            libc::SEEK_CUR => SeekFrom::Current(offset as i64),
+            libc::SEEK_END => SeekFrom::End(offset as i64),
+            _ => panic!("Unexpected \"whence\" parameter to fseek() wrapper: {}", whence),
+        };
 
-    es.input_seek(rhandle, rwhence) as libc::size_t
+        es.input_seek(rhandle, rwhence) as libc::size_t
+    })
 }
 
 fn input_getc<'a, I: 'a + IoProvider>(es: *mut ExecutionState<'a, I>, handle: *mut libc::c_void) -> libc::c_int {
-    let es = unsafe { &mut *es };
-    let rhandle = handle as *mut InputHandle;
+    ffi_guard(es, -1, |es| {
+        let rhandle = handle as *mut InputHandle;
 
-    // If we couldn't fill the whole (1-byte) buffer, that's boring old EOF.
-    // No need to complain. Fun match statement here.
+        // If we couldn't fill the whole (1-byte) buffer, that's boring old EOF.
+        // No need to complain. Fun match statement here.
 
-    match es.input_getc(rhandle) {
-        Ok(b) => b as libc::c_int,
-        Err(Error(ErrorKind::Io(ref ioe), _)) if ioe.kind() == io::ErrorKind::UnexpectedEof => libc::EOF,
-        Err(e) => {
-            tt_warning!(es.status, "getc failed"; e);
-            -1
+        match es.input_getc(rhandle) {
+            Ok(b) => b as libc::c_int,
+            Err(Error(ErrorKind::Io(ref ioe), _)) if ioe.kind() == io::ErrorKind::UnexpectedEof => libc::EOF,
+            Err(e) => {
+                es.note_read_error(&e);
+                tt_warning!(es.status, "getc failed"; e);
+                -1
+            }
         }
-    }
+    })
 }
 
 fn input_ungetc<'a, I: 'a + IoProvider>(es: *mut ExecutionState<'a, I>, handle: *mut libc::c_void, ch: libc::c_int) -> libc::c_int {
-    let es = unsafe { &mut *es };
-    let rhandle = handle as *mut InputHandle;
+    ffi_guard(es, -1, |es| {
+        let rhandle = handle as *mut InputHandle;
 
-    match es.input_ungetc(rhandle, ch as u8) {
-        Ok(_) => 0,
-        Err(e) => {
-            tt_warning!(es.status, "ungetc() failed"; e);
-            -1
+        match es.input_ungetc(rhandle, ch as u8) {
+            Ok(_) => 0,
+            Err(e) => {
+                tt_warning!(es.status, "ungetc() failed"; e);
+                -1
+            }
         }
-    }
+    })
 }
 
 fn input_read<'a, I: 'a + IoProvider>(es: *mut ExecutionState<'a, I>, handle: *mut libc::c_void, data: *mut u8, len: libc::size_t) -> libc::ssize_t {
-    let es = unsafe { &mut *es };
-    let rhandle = handle as *mut InputHandle;
-    let rdata = unsafe { slice::from_raw_parts_mut(data, len) };
+    ffi_guard(es, -1, |es| {
+        let rhandle = handle as *mut InputHandle;
+        let rdata = unsafe { slice::from_raw_parts_mut(data, len) };
+
+        match es.input_read(rhandle, rdata) {
+            Ok(_) => len as isize,
+            Err(e) => {
+                // Short reads are part of the protocol; anything else means
+                // that the data are bad.
+                match e {
+                    Error(ErrorKind::Io(ref ioe), _) if ioe.kind() == io::ErrorKind::UnexpectedEof => {},
+                    ref e => es.note_read_error(e),
+                }
 
-    match es.input_read(rhandle, rdata) {
-        Ok(_) => len as isize,
-        Err(e) => {
-            tt_warning!(es.status, "{}-byte read failed", len; e);
-            -1
+                tt_warning!(es.status, "{}-byte read failed", len; e);
+                -1
+            }
         }
-    }
+    })
 }
 
 fn input_close<'a, I: 'a + IoProvider>(es: *mut ExecutionState<'a, I>, handle: *mut libc::c_void) -> libc::c_int {
-    let es = unsafe { &mut *es };
+    ffi_guard(es, 1, |es| {
+        if handle == 0 as *mut _ {
+            return 0; // This is/was the behavior of close_file() in C.
+        }
 
-    if handle == 0 as *mut _ {
-        return 0; // This is/was the behavior of close_file() in C.
-    }
+        let rhandle = handle as *mut InputHandle;
+
+        if es.input_close(rhandle) {
+            1
+        } else {
+            0
+        }
+    })
+}
 
-    let rhandle = handle as *mut InputHandle;
+fn page_shipped<'a, I: 'a + IoProvider>(es: *mut ExecutionState<'a, I>, page_number: libc::c_int) -> libc::c_int {
+    ffi_guard(es, 0, |es| {
+        es.events.page_shipped(page_number as usize);
 
-    if es.input_close(rhandle) {
-        1
-    } else {
-        0
-    }
+        match es.page_limit {
+            Some(n) if page_number as usize >= n => 1,
+            _ => 0,
+        }
+    })
+}
+
+fn shell_escape<'a, I: 'a + IoProvider>(es: *mut ExecutionState<'a, I>, command: *const libc::c_char) -> libc::c_int {
+    ffi_guard(es, 1, |es| {
+        let rcommand = unsafe { CStr::from_ptr(command) };
+        es.shell_escape(&rcommand.to_string_lossy())
+    })
+}
+
+fn check_cancelled<'a, I: 'a + IoProvider>(es: *mut ExecutionState<'a, I>) -> libc::c_int {
+    ffi_guard(es, 1, |es| {
+        if es.is_cancelled() {
+            es.cancelled = es.panic_message.is_none() && es.read_error.is_none();
+            1
+        } else {
+            0
+        }
+    })
 }
 
 
@@ -705,6 +1383,9 @@ impl TectonicBridgeApi {
             input_getc: input_getc::<'a, I> as *const libc::c_void,
             input_ungetc: input_ungetc::<'a, I> as *const libc::c_void,
             input_close: input_close::<'a, I> as *const libc::c_void,
+            page_shipped: page_shipped::<'a, I> as *const libc::c_void,
+            shell_escape: shell_escape::<'a, I> as *const libc::c_void,
+            check_cancelled: check_cancelled::<'a, I> as *const libc::c_void,
         }
     }
 }
@@ -714,8 +1395,11 @@ impl TectonicBridgeApi {
 // are "file format" enumerations. This code bridges the two. See the
 // `kpse_file_format_type` enum in <tectonic/core-bridge.h>.
 
-#[derive(Clone,Copy,Debug)]
-enum FileFormat {
+/// The kinds of files that the engines ask for. Each corresponds to one of
+/// the "formats" that kpathsea uses to decide where to look for a file and
+/// which extensions to try.
+#[derive(Clone,Copy,Debug,Eq,Hash,PartialEq)]
+pub enum FileFormat {
     AFM,
     Bib,
     Bst,
@@ -723,6 +1407,7 @@ enum FileFormat {
     Enc,
     Format,
     FontMap,
+    Gf,
     MiscFonts,
     Ofm,
     OpenType,
@@ -739,22 +1424,80 @@ enum FileFormat {
     Vf,
 }
 
+impl FileFormat {
+    /// Whether this format is one of the kinds of font file: metrics,
+    /// glyphs, or virtual fonts.
+    pub fn is_font(self) -> bool {
+        match self {
+            FileFormat::AFM | FileFormat::Gf | FileFormat::MiscFonts | FileFormat::Ofm |
+            FileFormat::OpenType | FileFormat::Ovf | FileFormat::Pk | FileFormat::TFM |
+            FileFormat::TrueType | FileFormat::Type1 | FileFormat::Vf => true,
+            _ => false,
+        }
+    }
+
+    /// The name of this format in kpathsea, as passed to `kpsewhich
+    /// -format`.
+    pub fn kpsewhich_name(self) -> &'static str {
+        match self {
+            FileFormat::AFM => "afm",
+            FileFormat::Bib => "bib",
+            FileFormat::Bst => "bst",
+            FileFormat::Cmap => "cmap files",
+            FileFormat::Enc => "enc files",
+            FileFormat::Format => "fmt",
+            FileFormat::FontMap => "map",
+            FileFormat::Gf => "gf",
+            FileFormat::MiscFonts => "misc fonts",
+            FileFormat::Ofm => "ofm",
+            FileFormat::OpenType => "opentype fonts",
+            FileFormat::Ovf => "ovf",
+            FileFormat::Pict => "graphic/figure",
+            FileFormat::Pk => "pk",
+            FileFormat::ProgramData => "other text files",
+            FileFormat::Sfd => "subfont definition files",
+            FileFormat::Tex => "tex",
+            FileFormat::TexPsHeader => "PostScript header",
+            FileFormat::TFM => "tfm",
+            FileFormat::TrueType => "truetype fonts",
+            FileFormat::Type1 => "type1 fonts",
+            FileFormat::Vf => "vf",
+        }
+    }
+
+    /// Guess the format of the file `name` from its suffix, for code that
+    /// is only given a file name. Where two formats share a suffix, as
+    /// virtual fonts do, the more common one wins.
+    pub fn for_file_name(name: &OsStr) -> Option<FileFormat> {
+        // Ordered so that Vf comes before Ovf.
+        const FORMATS: &'static [FileFormat] = &[
+            FileFormat::Tex, FileFormat::TFM, FileFormat::Vf, FileFormat::Type1, FileFormat::OpenType,
+            FileFormat::TrueType, FileFormat::Enc, FileFormat::FontMap, FileFormat::Bib, FileFormat::Bst,
+            FileFormat::AFM, FileFormat::Ofm, FileFormat::Ovf, FileFormat::Pk, FileFormat::Gf,
+            FileFormat::Sfd, FileFormat::TexPsHeader, FileFormat::Pict, FileFormat::Format,
+        ];
+
+        FORMATS.iter().cloned().find(|&f| format_to_extension(f).iter().any(|e| has_suffix(name, e)))
+    }
+}
+
 fn format_to_extension (format: FileFormat) -> Vec<&'static str> {
     match format {
         FileFormat::AFM => vec!["afm"],
         FileFormat::Bib => vec!["bib"],
         FileFormat::Bst => vec!["bst"],
-        FileFormat::Cmap => vec!["cmap"], /* XXX: kpathsea doesn't define any suffixes for this */
+        FileFormat::Cmap => vec![], /* kpathsea doesn't define any suffixes for this */
         FileFormat::Enc => vec!["enc"],
         FileFormat::Format => vec!["fmt.gz"],
         FileFormat::FontMap => vec!["map"],
-        FileFormat::MiscFonts => vec!["miscfonts"], /* XXX: no kpathsea suffixes */
+        FileFormat::Gf => vec!["gf"],
+        FileFormat::MiscFonts => vec![], /* no kpathsea suffixes */
         FileFormat::Ofm => vec!["ofm"],
         FileFormat::OpenType => vec!["otf", "OTF"],
         FileFormat::Ovf => vec!["ovf", "vf"],
         FileFormat::Pict => vec!["pdf", "jpg", "eps", "epsi"], /* XXX: also .eps, .epsi, ... */
         FileFormat::Pk => vec!["pk"],
-        FileFormat::ProgramData => vec!["programdata"], /* XXX no suffixes */
+        FileFormat::ProgramData => vec![], /* no kpathsea suffixes */
         FileFormat::Sfd => vec!["sfd"],
         FileFormat::Tex => vec!["tex", "sty", "cls", "fd", "aux", "bbl", "def", "clo", "ldf"],
         FileFormat::TexPsHeader => vec!["pro"],
@@ -765,8 +1508,68 @@ fn format_to_extension (format: FileFormat) -> Vec<&'static str> {
     }
 }
 
+/// The names to try, in order, when the engine asks for the file `name` in
+/// the given format. As in kpathsea, a name that already ends in one of the
+/// format's suffixes is used as-is. Otherwise we try the format's main
+/// suffix, then the bare name, then the format's other suffixes, so that
+/// `\input foo` finds `foo.tex` ahead of a file named just `foo`, and that
+/// ahead of `foo.sty`. Suffixes are appended rather than substituted, so
+/// `\input chapter.1` looks for `chapter.1.tex` first.
+fn format_search_names(name: &OsStr, format: FileFormat) -> Vec<OsString> {
+    let extensions = format_to_extension(format);
+
+    if extensions.iter().any(|e| has_suffix(name, e)) || Path::new(name).file_name().is_none() {
+        return vec![name.to_owned()];
+    }
+
+    let with_extension = |e: &str| {
+        let mut n = name.to_owned();
+        n.push(".");
+        n.push(e);
+        n
+    };
+
+    let mut names = Vec::new();
+    let mut extensions = extensions.into_iter();
+
+    if let Some(e) = extensions.next() {
+        names.push(with_extension(e));
+    }
+
+    names.push(name.to_owned());
+
+    for e in extensions {
+        names.push(with_extension(e));
+    }
+
+    names
+}
+
+/// Whether `name` ends with a dot and then `extension`, and has something
+/// before that.
+fn has_suffix(name: &OsStr, extension: &str) -> bool {
+    let bytes = name.as_bytes();
+    let n = bytes.len();
+    n > extension.len() + 1 && bytes.ends_with(extension.as_bytes()) && bytes[n - extension.len() - 1] == b'.'
+}
+
+/// One past the largest kpse format code; `kpse_last_format` in
+/// <tectonic/core-bridge.h>.
+const KPSE_LAST_FORMAT: libc::c_int = 60;
+
+/// List every kpse format code that the engines might pass us which we know
+/// how to handle, along with the FileFormat that we translate it to, in
+/// order of the codes. Codes that aren't listed are treated as unknown, and
+/// requests for files with those formats always fail.
+pub fn all_format_mappings() -> Vec<(libc::c_int, FileFormat)> {
+    (0..KPSE_LAST_FORMAT)
+        .filter_map(|code| c_format_to_rust(code).map(|fmt| (code, fmt)))
+        .collect()
+}
+
 fn c_format_to_rust (format: libc::c_int) -> Option<FileFormat> {
     match format {
+        0 => Some(FileFormat::Gf),
         1 => Some(FileFormat::Pk),
         3 => Some(FileFormat::TFM),
         4 => Some(FileFormat::AFM),
@@ -792,3 +1595,422 @@ fn c_format_to_rust (format: libc::c_int) -> Option<FileFormat> {
         _ => None
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::{IoStack, MemoryIo};
+    use status::NoopStatusBackend;
+
+    #[test]
+    fn packages_are_listed() {
+        let mut rec = InputRecorder::new();
+        rec.input_format_opened(OsStr::new("article.cls"), FileFormat::Tex);
+        rec.input_format_opened(OsStr::new("amsmath.sty"), FileFormat::Tex);
+        rec.input_format_opened(OsStr::new("amstext.sty"), FileFormat::Tex);
+        rec.input_format_opened(OsStr::new("amsmath.sty"), FileFormat::Tex);
+        rec.input_format_opened(OsStr::new("tex/latex/graphicx.sty"), FileFormat::Tex);
+        rec.input_format_opened(OsStr::new("cmr10.tfm"), FileFormat::TFM);
+
+        assert_eq!(rec.packages_used(), vec!["amsmath", "amstext", "graphicx"]);
+    }
+
+    #[test]
+    fn missing_inputs_are_listed() {
+        let mut rec = MissingInputRecorder::new(InputRecorder::new());
+        rec.input_format_opened(OsStr::new("article.cls"), FileFormat::Tex);
+        rec.input_format_not_available(OsStr::new("siunitx.sty"), FileFormat::Tex);
+        rec.input_format_not_available(OsStr::new("cmr10.tfm"), FileFormat::TFM);
+        rec.input_format_not_available(OsStr::new("siunitx.sty"), FileFormat::Tex);
+
+        assert_eq!(rec.missing().len(), 2);
+
+        let mut manifest = Vec::new();
+        rec.write_manifest(&mut manifest).unwrap();
+        assert_eq!(String::from_utf8(manifest).unwrap(), "TFM\tcmr10.tfm\nTex\tsiunitx.sty\n");
+        assert_eq!(rec.into_inner().inputs().len(), 1);
+    }
+
+    #[test]
+    fn file_list_is_written() {
+        let mut rec = FileListRecorder::new(InputRecorder::new());
+        rec.primary_name("paper.tex");
+        rec.primary_input_opened(InputOrigin::Other);
+        rec.input_opened(OsStr::new("article.cls"), InputOrigin::Bundle(OsString::from("article.cls")));
+        rec.input_opened(OsStr::new("fig.tex"), InputOrigin::Filesystem(PathBuf::from("figures/fig.tex")));
+        rec.input_format_opened(OsStr::new("article.cls"), FileFormat::Tex);
+        rec.output_opened(OsStr::new("paper.aux"));
+        rec.stdout_opened();
+        rec.input_not_available(OsStr::new("paper.toc"));
+        rec.input_opened(OsStr::new("paper.aux"), InputOrigin::Other);
+        rec.output_opened(OsStr::new("paper.aux"));
+        rec.input_opened(OsStr::new("article.cls"), InputOrigin::Other);
+
+        let mut fls = Vec::new();
+        rec.write_fls(&mut fls, Some(Path::new("/home/me/paper"))).unwrap();
+        assert_eq!(String::from_utf8(fls).unwrap(), "PWD /home/me/paper\n\
+                                                     INPUT paper.tex\n\
+                                                     INPUT article.cls\n\
+                                                     INPUT figures/fig.tex\n\
+                                                     OUTPUT paper.aux\n\
+                                                     INPUT paper.aux\n");
+        assert_eq!(rec.inner().inputs().len(), 1);
+
+        let mut fls = Vec::new();
+        rec.forget("paper.aux").write_fls(&mut fls, None).unwrap();
+        assert_eq!(String::from_utf8(fls).unwrap(), "INPUT paper.tex\n\
+                                                     INPUT article.cls\n\
+                                                     INPUT figures/fig.tex\n");
+    }
+
+    #[test]
+    fn font_inputs_are_filtered() {
+        let mut rec = InputRecorder::new();
+        rec.input_format_opened(OsStr::new("article.cls"), FileFormat::Tex);
+        rec.input_format_opened(OsStr::new("cmr10.tfm"), FileFormat::TFM);
+        rec.input_format_opened(OsStr::new("lmroman10-regular.otf"), FileFormat::OpenType);
+        rec.input_format_opened(OsStr::new("cmr10.tfm"), FileFormat::TFM);
+        rec.input_format_opened(OsStr::new("pdftex.map"), FileFormat::FontMap);
+
+        assert_eq!(rec.inputs().len(), 5);
+        assert_eq!(rec.font_inputs(), vec![
+            (PathBuf::from("cmr10.tfm"), FileFormat::TFM),
+            (PathBuf::from("lmroman10-regular.otf"), FileFormat::OpenType),
+        ]);
+    }
+
+    /// Some formats have no standard extensions, in which case we should
+    /// only ever try the bare name.
+    #[test]
+    fn format_without_extensions() {
+        let mut mem = MemoryIo::new(false);
+        mem.create_entry(OsStr::new("UniJIS-UTF16-H"), b"cmap data".to_vec());
+        let mut stack = IoStack::new(vec![&mut mem as &mut IoProvider]);
+        let mut events = NoopIoEventBackend::new();
+        let mut status = NoopStatusBackend::new();
+        let mut es = ExecutionState::new(&mut stack, &mut events, &mut status);
+
+        assert!(format_to_extension(FileFormat::Cmap).is_empty());
+        es.input_open_name_format(OsStr::new("UniJIS-UTF16-H"), FileFormat::Cmap).unwrap();
+        assert!(es.input_open_name_format(OsStr::new("Missing-H"), FileFormat::Cmap).is_not_available());
+        assert!(es.input_open_name_format(OsStr::new(".."), FileFormat::Cmap).is_not_available());
+    }
+
+    #[test]
+    fn repeated_misses_are_cached() {
+        use io::memory::CountingIo;
+
+        let mut cio = CountingIo::new();
+        let mut events = NoopIoEventBackend::new();
+        let mut status = NoopStatusBackend::new();
+        let name = OsStr::new("optional.cfg");
+
+        {
+            let mut es = ExecutionState::new(&mut cio, &mut events, &mut status);
+            assert!(es.input_open(name, FileFormat::Tex, false).is_null());
+            let n = es.io.lookups;
+            assert!(es.input_open(name, FileFormat::Tex, false).is_null());
+            assert_eq!(es.io.lookups, n);
+
+            // Creating a file might make the missing one appear.
+            let oh = es.output_open(name, false);
+            es.output_close(oh as *mut _);
+            assert!(!es.input_open(name, FileFormat::Tex, false).is_null());
+            assert!(es.io.lookups > n);
+        }
+    }
+
+    #[test]
+    fn access_policy_is_consulted() {
+        let mut mem = MemoryIo::new(false);
+        mem.create_entry(OsStr::new("public.tex"), b"ok".to_vec());
+        mem.create_entry(OsStr::new("secret.tex"), b"no".to_vec());
+        let mut events = NoopIoEventBackend::new();
+        let mut status = NoopStatusBackend::new();
+        let mut es = ExecutionState::new(&mut mem, &mut events, &mut status);
+
+        es.access_policy = Some(AccessPolicy::new(|name, format| {
+            format == FileFormat::Tex && name != Path::new("secret.tex")
+        }));
+
+        assert!(!es.input_open(OsStr::new("public"), FileFormat::Tex, false).is_null());
+        assert!(es.input_open(OsStr::new("public"), FileFormat::Bib, false).is_null());
+
+        // The policy sees each name that is tried, so the file can't be
+        // reached by spelling it differently.
+        assert!(es.input_open(OsStr::new("secret"), FileFormat::Tex, false).is_null());
+        assert!(es.input_open(OsStr::new("secret.tex"), FileFormat::Tex, false).is_null());
+        assert!(es.input_open(OsStr::new("./secret.tex"), FileFormat::Tex, false).is_null());
+
+        // Nor by asking for its digest.
+        let mut digest = [0u8; 16];
+        es.get_file_md5(OsStr::new("secret.tex"), &mut digest);
+        assert_eq!(digest, [0u8; 16]);
+        es.get_file_md5(OsStr::new("public.tex"), &mut digest);
+        assert!(digest != [0u8; 16]);
+    }
+
+    #[test]
+    fn shell_escape_policy() {
+        let mut mem = MemoryIo::new(false);
+        let mut events = NoopIoEventBackend::new();
+        let mut status = NoopStatusBackend::new();
+        let mut es = ExecutionState::new(&mut mem, &mut events, &mut status);
+
+        assert_eq!(es.shell_escape("true"), -1);
+
+        es.shell_escape = ShellEscape::Restricted(vec!["true".to_owned()]);
+        assert_eq!(es.shell_escape("true --ignored"), 0);
+        assert_eq!(es.shell_escape("false"), -1);
+        assert_eq!(es.shell_escape(""), -1);
+
+        es.shell_escape = ShellEscape::Full;
+        assert_eq!(es.shell_escape("exit 3"), 1);
+    }
+
+    #[test]
+    fn cancellation_is_observed() {
+        let mut mem = MemoryIo::new(false);
+        let mut events = NoopIoEventBackend::new();
+        let mut status = NoopStatusBackend::new();
+        let mut es = ExecutionState::new(&mut mem, &mut events, &mut status);
+        assert!(!es.is_cancelled());
+
+        let token = Arc::new(AtomicBool::new(false));
+        es.cancel_token = Some(token.clone());
+        assert!(!es.is_cancelled());
+
+        assert_eq!(check_cancelled(&mut es), 0);
+        assert!(!es.was_cancelled());
+
+        token.store(true, Ordering::SeqCst);
+        assert!(es.is_cancelled());
+        assert!(!es.was_cancelled());
+        assert_eq!(check_cancelled(&mut es), 1);
+        assert!(es.was_cancelled());
+    }
+
+    #[test]
+    fn page_limit_stops_shipout() {
+        let mut mem = MemoryIo::new(false);
+        let mut events = NoopIoEventBackend::new();
+        let mut status = NoopStatusBackend::new();
+        let mut es = ExecutionState::new(&mut mem, &mut events, &mut status);
+        assert_eq!(page_shipped(&mut es, 100), 0);
+
+        es.page_limit = Some(2);
+        assert_eq!(page_shipped(&mut es, 1), 0);
+        assert_eq!(page_shipped(&mut es, 2), 1);
+    }
+
+    #[test]
+    fn format_mappings_are_listed() {
+        let mappings = all_format_mappings();
+        assert!(mappings.contains(&(26, FileFormat::Tex)));
+        assert!(mappings.contains(&(33, FileFormat::Vf)));
+        assert!(mappings.iter().all(|&(code, fmt)| c_format_to_rust(code) == Some(fmt)));
+        assert!(c_format_to_rust(KPSE_LAST_FORMAT).is_none());
+    }
+
+    #[test]
+    fn vf_format_mapping() {
+        // kpse_vf_format in <tectonic/core-bridge.h>
+        let fmt = c_format_to_rust(33).expect("no mapping for kpse_vf_format");
+        assert_eq!(format_to_extension(fmt), vec!["vf"]);
+    }
+
+    #[test]
+    fn fontmap_format_mapping() {
+        // kpse_fontmap_format in <tectonic/core-bridge.h>
+        let fmt = c_format_to_rust(11).expect("no mapping for kpse_fontmap_format");
+        assert_eq!(fmt, FileFormat::FontMap);
+        assert_eq!(format_to_extension(fmt), vec!["map"]);
+    }
+
+    #[test]
+    fn omega_font_format_mappings() {
+        // kpse_ofm_format and kpse_ovf_format in <tectonic/core-bridge.h>
+        let fmt = c_format_to_rust(20).expect("no mapping for kpse_ofm_format");
+        assert_eq!(fmt, FileFormat::Ofm);
+        assert_eq!(format_to_extension(fmt), vec!["ofm"]);
+        assert!(fmt.is_font());
+
+        let fmt = c_format_to_rust(23).expect("no mapping for kpse_ovf_format");
+        assert_eq!(fmt, FileFormat::Ovf);
+        assert_eq!(format_to_extension(fmt), vec!["ovf", "vf"]);
+        assert!(fmt.is_font());
+    }
+
+    #[test]
+    fn bitmap_font_format_mappings() {
+        // kpse_gf_format and kpse_pk_format in <tectonic/core-bridge.h>
+        let fmt = c_format_to_rust(0).expect("no mapping for kpse_gf_format");
+        assert_eq!(fmt, FileFormat::Gf);
+        assert_eq!(format_to_extension(fmt), vec!["gf"]);
+        assert!(fmt.is_font());
+
+        let fmt = c_format_to_rust(1).expect("no mapping for kpse_pk_format");
+        assert_eq!(fmt, FileFormat::Pk);
+        assert_eq!(format_to_extension(fmt), vec!["pk"]);
+        assert!(fmt.is_font());
+    }
+
+    #[test]
+    fn search_names_follow_kpathsea_order() {
+        let names = |n: &str, f| format_search_names(OsStr::new(n), f);
+
+        assert_eq!(names("article.cls", FileFormat::Tex), vec![OsString::from("article.cls")]);
+        assert_eq!(names("UNUSED.fmt.gz", FileFormat::Format), vec![OsString::from("UNUSED.fmt.gz")]);
+
+        let tex = names("chapter.1", FileFormat::Tex);
+        assert_eq!(&tex[..4], &[OsString::from("chapter.1.tex"), OsString::from("chapter.1"),
+                                OsString::from("chapter.1.sty"), OsString::from("chapter.1.cls")]);
+
+        assert_eq!(names("cmr10", FileFormat::TFM), vec![OsString::from("cmr10.tfm"), OsString::from("cmr10")]);
+        assert_eq!(names("cmap", FileFormat::Cmap), vec![OsString::from("cmap")]);
+    }
+
+    #[test]
+    fn formats_are_guessed_from_names() {
+        let guess = |n: &str| FileFormat::for_file_name(OsStr::new(n));
+
+        assert_eq!(guess("article.cls"), Some(FileFormat::Tex));
+        assert_eq!(guess("cmr10.tfm"), Some(FileFormat::TFM));
+        assert_eq!(guess("cmr10.vf"), Some(FileFormat::Vf));
+        assert_eq!(guess("cmr10.pfb"), Some(FileFormat::Type1));
+        assert_eq!(guess("plain.fmt.gz"), Some(FileFormat::Format));
+        assert_eq!(guess("cmap"), None);
+        assert_eq!(guess(".tex"), None);
+    }
+
+    #[test]
+    fn callback_panics_are_caught() {
+        let mut mem = MemoryIo::new(false);
+        let mut events = NoopIoEventBackend::new();
+        let mut status = NoopStatusBackend::new();
+        let mut es = ExecutionState::new(&mut mem, &mut events, &mut status);
+        assert!(es.callback_error().is_none());
+
+        // A bogus "whence" makes the callback panic before it touches the
+        // handle.
+        assert_eq!(input_seek(&mut es, ptr::null_mut(), 0, 12345), 0);
+        assert!(es.is_cancelled());
+        assert!(es.callback_error().unwrap().to_string().contains("whence"));
+        assert_eq!(check_cancelled(&mut es), 1);
+        assert!(!es.was_cancelled());
+    }
+
+    #[test]
+    fn gzipped_outputs_keep_their_destination() {
+        use io::OutputDestination;
+
+        let mut mem = MemoryIo::new(false);
+        let mut events = NoopIoEventBackend::new();
+        let mut status = NoopStatusBackend::new();
+        let mut es = ExecutionState::new(&mut mem, &mut events, &mut status);
+
+        for &(name, is_gz) in &[("doc.log", false), ("doc.synctex.gz", true)] {
+            let oh = es.output_open(OsStr::new(name), is_gz);
+            assert!(!oh.is_null());
+            assert_eq!(unsafe { &*oh }.destination(), &OutputDestination::Memory);
+        }
+    }
+
+    #[test]
+    fn only_bundle_files_are_decompressed() {
+        use std::io::Cursor;
+        use io::zipbundle::ZipBundle;
+        use zip::{CompressionMethod, ZipWriter};
+
+        let mut gz = GzEncoder::new(Vec::new(), Compression::Default);
+        gz.write_all(b"metrics").unwrap();
+        let gz = gz.finish().unwrap();
+
+        let mut zw = ZipWriter::new(Cursor::new(Vec::new()));
+        zw.start_file("bundled.tfm", CompressionMethod::Stored).unwrap();
+        zw.write_all(&gz).unwrap();
+        let mut bundle = ZipBundle::new(Cursor::new(zw.finish().unwrap().into_inner())).unwrap();
+
+        let mut mem = MemoryIo::new(false);
+        mem.create_entry(OsStr::new("local.tfm"), gz.clone());
+
+        let mut stack = IoStack::new(vec![&mut mem as &mut IoProvider, &mut bundle as &mut IoProvider]);
+        let mut events = NoopIoEventBackend::new();
+        let mut status = NoopStatusBackend::new();
+        let mut es = ExecutionState::new(&mut stack, &mut events, &mut status);
+
+        for &(name, expected) in &[("bundled.tfm", &b"metrics"[..]), ("local.tfm", &gz[..])] {
+            let mut data = Vec::new();
+            es.input_open_name_format_gz(OsStr::new(name), FileFormat::TFM, false)
+                .unwrap().read_to_end(&mut data).unwrap();
+            assert_eq!(&data[..], expected);
+        }
+    }
+
+    #[test]
+    fn bad_reads_end_the_run() {
+        use std::io::Cursor;
+
+        struct Failing;
+
+        impl Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::Other, "the data are corrupt"))
+            }
+        }
+
+        impl InputFeatures for Failing {
+            fn get_size(&mut self) -> Result<usize> { Ok(4) }
+            fn try_seek(&mut self, _: SeekFrom) -> Result<u64> { Ok(0) }
+        }
+
+        let mut mem = MemoryIo::new(false);
+        let mut events = NoopIoEventBackend::new();
+        let mut status = NoopStatusBackend::new();
+        let mut es = ExecutionState::new(&mut mem, &mut events, &mut status);
+        let mut buf = [0u8; 4];
+
+        // Running off the end of a file is nothing special.
+        let mut short = InputHandle::new(OsStr::new("short.tex"), Cursor::new(vec![1, 2]), InputOrigin::Other);
+        assert_eq!(input_read(&mut es, &mut short as *mut _ as *mut libc::c_void, buf.as_mut_ptr(), 4), -1);
+        assert!(es.callback_error().is_none());
+
+        let mut bad = InputHandle::new(OsStr::new("bad.tex"), Failing, InputOrigin::Other);
+        assert_eq!(input_read(&mut es, &mut bad as *mut _ as *mut libc::c_void, buf.as_mut_ptr(), 4), -1);
+        assert!(es.is_cancelled());
+        assert_eq!(check_cancelled(&mut es), 1);
+        assert!(!es.was_cancelled());
+        assert!(es.callback_error().unwrap().to_string().contains("corrupt"));
+    }
+
+    #[cfg(not(feature = "no-filesystem"))]
+    #[test]
+    fn logs_survive_an_abort() {
+        use io::FilesystemIo;
+        use std::fs::File;
+        use tempdir::TempDir;
+
+        let tempdir = TempDir::new("tectonic_engines_test").unwrap();
+        let mut fsio = FilesystemIo::new(tempdir.path(), true, false, HashSet::new());
+
+        {
+            let mut events = NoopIoEventBackend::new();
+            let mut status = NoopStatusBackend::new();
+            let mut es = ExecutionState::new(&mut fsio, &mut events, &mut status);
+
+            for name in &["texput.log", "texput.aux"] {
+                let oh = es.output_open(OsStr::new(name), false);
+                assert!(!oh.is_null());
+                assert!(!es.output_write(oh as *mut _, b"partial"));
+            }
+
+            // The engine gives up here, without closing anything.
+        }
+
+        let mut s = String::new();
+        File::open(tempdir.path().join("texput.log")).unwrap().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "partial");
+        assert!(!tempdir.path().join("texput.aux").exists());
+    }
+}
@@ -3,11 +3,13 @@
 // Licensed under the MIT License.
 
 use std::ffi::{CStr, CString};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
 
 use errors::{DefinitelySame, ErrorKind, Result};
 use io::IoStack;
 use status::StatusBackend;
-use super::{IoEventBackend, ExecutionState, TectonicBridgeApi};
+use super::{AccessPolicy, IoEventBackend, ExecutionState, ShellEscape, TectonicBridgeApi};
 
 
 #[derive(Clone,Copy,Debug,Eq,PartialEq)]
@@ -21,6 +23,47 @@ pub enum TexResult {
     Errors,
 }
 
+/// The overall outcome of an engine run, following TeX's "history" levels.
+/// Unlike TexResult, this also describes runs that ended in a fatal error,
+/// so it can summarize any run after the fact.
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub enum TexOutcome {
+    Spotless,
+    Warnings,
+    Errors,
+    Fatal,
+}
+
+impl From<TexResult> for TexOutcome {
+    fn from(r: TexResult) -> TexOutcome {
+        match r {
+            TexResult::Spotless => TexOutcome::Spotless,
+            TexResult::Warnings => TexOutcome::Warnings,
+            TexResult::Errors => TexOutcome::Errors,
+        }
+    }
+}
+
+/// Whether a TeX run creates a format file or processes a document using
+/// one. The engine does both in the same way, with the choice made by a
+/// global setting and by which format file, if any, it is told to load;
+/// this packages the two together so that they can't get out of sync.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub enum EngineMode {
+    /// Run in "initex" mode, starting from a blank slate and dumping the
+    /// engine state into a format file. The engine names the file after the
+    /// TeX job, which is named `dump_name` unless a job name is set
+    /// explicitly; so, by default, a `dump_name` of "plain" gives a file
+    /// called "plain.fmt.gz". This is also the name under which the format
+    /// file should be saved for later use, as passed to
+    /// `IoProvider::write_format`.
+    InitFormat { dump_name: String },
+
+    /// Process a document, starting from the state in the format file
+    /// `format`.
+    Normal { format: String },
+}
+
 // Sigh, have to do this manually because of the Result/PartialEq conflict in errors.rs
 impl DefinitelySame for TexResult {
     fn definitely_same(&self, other: &Self) -> bool {
@@ -36,6 +79,13 @@ pub struct TexEngine {
     halt_on_error: bool,
     initex_mode: bool,
     synctex_enabled: bool,
+    source_date_epoch: Option<i64>,
+    shell_escape: ShellEscape,
+    cancel_token: Option<Arc<AtomicBool>>,
+    page_limit: Option<usize>,
+    access_policy: Option<AccessPolicy>,
+    job_name: Option<String>,
+    last_outcome: Option<TexOutcome>,
 }
 
 impl Default for TexEngine {
@@ -44,6 +94,13 @@ impl Default for TexEngine {
             halt_on_error: true,
             initex_mode: false,
             synctex_enabled: false,
+            source_date_epoch: None,
+            shell_escape: ShellEscape::Disabled,
+            cancel_token: None,
+            page_limit: None,
+            access_policy: None,
+            job_name: None,
+            last_outcome: None,
         }
     }
 }
@@ -72,6 +129,99 @@ impl TexEngine {
         self
     }
 
+    /// Fix the date and time that the engine reports to documents (e.g.,
+    /// through `\time` and `\year`), in seconds since the Unix epoch.
+    /// Combined with the same setting for the xdvipdfmx engine, this makes
+    /// for reproducible builds. If None, which is the default, the engine
+    /// uses the current time.
+    pub fn source_date_epoch (&mut self, epoch: Option<i64>) -> &mut Self {
+        self.source_date_epoch = epoch;
+        self
+    }
+
+    /// Set whether and which external commands the document may run with
+    /// `\write18`. The default is `ShellEscape::Disabled`. Note that the
+    /// commands run in the current directory and their outputs aren't
+    /// visible to the I/O stack.
+    pub fn shell_escape (&mut self, policy: ShellEscape) -> &mut Self {
+        self.shell_escape = policy;
+        self
+    }
+
+    /// Stop the engine with an `ErrorKind::Cancelled` error once `token`
+    /// becomes true. The engine checks the token whenever it opens a file
+    /// or ships out a page.
+    pub fn cancel_token (&mut self, token: Option<Arc<AtomicBool>>) -> &mut Self {
+        self.cancel_token = token;
+        self
+    }
+
+    /// Stop once this many pages have been shipped out, finishing up the
+    /// job as if the document had ended there: the output files are closed
+    /// properly, so the XDV file holds the pages so far. This is handy for
+    /// previewing the start of a long document. If None, which is the
+    /// default, the whole document is processed.
+    pub fn stop_after_page (&mut self, page: Option<usize>) -> &mut Self {
+        self.page_limit = page;
+        self
+    }
+
+    /// Check every input that the engine asks for against `policy` before
+    /// opening it; see `AccessPolicy`. If None, which is the default, the
+    /// engine may read anything that the I/O stack provides.
+    pub fn access_policy (&mut self, policy: Option<AccessPolicy>) -> &mut Self {
+        self.access_policy = policy;
+        self
+    }
+
+    /// Set the TeX job name, which is what `\jobname` expands to and what
+    /// the output files are named after: a job name of "paper" gives
+    /// "paper.log", "paper.xdv", and so on. If None, which is the default,
+    /// the job is named after the primary input file, or "texput" if it
+    /// has no name.
+    pub fn job_name (&mut self, name: Option<String>) -> &mut Self {
+        self.job_name = name;
+        self
+    }
+
+    /// Returns the outcome of the most recent call to `process()`, or None
+    /// if the engine hasn't been run yet or the most recent call failed
+    /// before the engine could start.
+    pub fn last_outcome (&self) -> Option<TexOutcome> {
+        self.last_outcome
+    }
+
+    /// Run the engine in the given mode, overriding any `initex_mode()`
+    /// setting for this run only: the engine's settings are left as they
+    /// were afterwards.
+    pub fn process_in_mode (&mut self, io: &mut IoStack,
+                            events: &mut IoEventBackend,
+                            status: &mut StatusBackend,
+                            mode: &EngineMode, input_file_name: &str) -> Result<TexResult> {
+        let prev_initex_mode = self.initex_mode;
+        let prev_job_name = self.job_name.clone();
+
+        let result = match *mode {
+            EngineMode::InitFormat { ref dump_name } => {
+                self.initex_mode = true;
+
+                if self.job_name.is_none() {
+                    self.job_name = Some(dump_name.clone());
+                }
+
+                self.process(io, events, status, "UNUSED.fmt.gz", input_file_name)
+            },
+            EngineMode::Normal { ref format } => {
+                self.initex_mode = false;
+                self.process(io, events, status, format, input_file_name)
+            },
+        };
+
+        self.initex_mode = prev_initex_mode;
+        self.job_name = prev_job_name;
+        result
+    }
+
     // This function can't be generic across the IoProvider trait, for now,
     // since the global pointer that stashes the ExecutionState must have a
     // complete type.
@@ -80,10 +230,19 @@ impl TexEngine {
                     events: &mut IoEventBackend,
                     status: &mut StatusBackend,
                     format_file_name: &str, input_file_name: &str) -> Result<TexResult> {
+        // Don't let a call that fails before the engine starts leave the
+        // previous run's outcome in place.
+        self.last_outcome = None;
+
         let cformat = CString::new(format_file_name)?;
         let cinput = CString::new(input_file_name)?;
+        let cjobname = CString::new(self.job_name.clone().unwrap_or_default())?;
 
-        let /*mut*/ state = ExecutionState::new(io, events, status);
+        let mut state = ExecutionState::new(io, events, status);
+        state.shell_escape = self.shell_escape.clone();
+        state.cancel_token = self.cancel_token.clone();
+        state.page_limit = self.page_limit;
+        state.access_policy = self.access_policy.clone();
         let bridge = TectonicBridgeApi::new(&state);
 
         // initialize globals
@@ -93,12 +252,17 @@ impl TexEngine {
         unsafe { super::tt_set_int_variable(b"in_initex_mode\0".as_ptr() as _, v); }
         let v = if self.synctex_enabled { 1 } else { 0 };
         unsafe { super::tt_set_int_variable(b"synctex_enabled\0".as_ptr() as _, v); }
+        let v = self.shell_escape.engine_mode();
+        unsafe { super::tt_set_int_variable(b"shell_escape_mode\0".as_ptr() as _, v); }
+        unsafe { super::tt_set_string_variable(b"job_name\0".as_ptr() as _, cjobname.as_ptr()); }
+        unsafe { super::tt_set_source_date_epoch(self.source_date_epoch.unwrap_or(-1)); }
 
-        unsafe {
+        let result = unsafe {
             match super::tex_simple_main(&bridge, cformat.as_ptr(), cinput.as_ptr()) {
                 0 => Ok(TexResult::Spotless),
                 1 => Ok(TexResult::Warnings),
                 2 => Ok(TexResult::Errors),
+                3 if state.was_cancelled() => Err(ErrorKind::Cancelled.into()),
                 3 => {
                     let ptr = super::tt_get_error_message();
                     let msg = CStr::from_ptr(ptr).to_string_lossy().into_owned();
@@ -106,6 +270,57 @@ impl TexEngine {
                 },
                 x => Err(ErrorKind::Msg(format!("internal error: unexpected 'history' value {}", x)).into())
             }
-        }
+        };
+
+        let result = match state.callback_error() {
+            Some(e) => Err(e),
+            None => result,
+        };
+
+        self.last_outcome = Some(match result {
+            Ok(ref r) => TexOutcome::from(*r),
+            Err(_) => TexOutcome::Fatal,
+        });
+
+        result
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use engines::NoopIoEventBackend;
+    use status::NoopStatusBackend;
+
+    #[test]
+    fn early_failure_clears_last_outcome() {
+        let mut engine = TexEngine::new();
+        engine.last_outcome = Some(TexOutcome::Spotless);
+
+        let mut io = IoStack::new(Vec::new());
+        let mut events = NoopIoEventBackend::new();
+        let mut status = NoopStatusBackend::new();
+        assert!(engine.process(&mut io, &mut events, &mut status, "plain.fmt", "bad\0name.tex").is_err());
+        assert_eq!(engine.last_outcome(), None);
+    }
+
+    #[test]
+    fn modes_apply_to_one_run() {
+        let mut engine = TexEngine::new();
+        let mut io = IoStack::new(Vec::new());
+        let mut events = NoopIoEventBackend::new();
+        let mut status = NoopStatusBackend::new();
+
+        let mode = EngineMode::InitFormat { dump_name: "custom".to_owned() };
+        assert!(engine.process_in_mode(&mut io, &mut events, &mut status, &mode, "bad\0name.tex").is_err());
+        assert!(!engine.initex_mode);
+        assert_eq!(engine.job_name, None);
+
+        engine.initex_mode(true);
+        let mode = EngineMode::Normal { format: "plain".to_owned() };
+        assert!(engine.process_in_mode(&mut io, &mut events, &mut status, &mode, "bad\0name.tex").is_err());
+        assert!(engine.initex_mode);
     }
 }
@@ -3,20 +3,52 @@
 // Licensed under the MIT License.
 
 use std::ffi::{CStr, CString};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
 
 use errors::{ErrorKind, Result};
 use io::IoStack;
 use status::StatusBackend;
-use super::{IoEventBackend, ExecutionState, TectonicBridgeApi};
+use super::{AccessPolicy, IoEventBackend, ExecutionState, TectonicBridgeApi};
 
 
 pub struct XdvipdfmxEngine {
+    source_date_epoch: Option<i64>,
+    cancel_token: Option<Arc<AtomicBool>>,
+    access_policy: Option<AccessPolicy>,
 }
 
 
 impl XdvipdfmxEngine {
     pub fn new () -> XdvipdfmxEngine {
-        XdvipdfmxEngine {}
+        XdvipdfmxEngine {
+            source_date_epoch: None,
+            cancel_token: None,
+            access_policy: None,
+        }
+    }
+
+    /// Fix the creation date recorded in the PDF, in seconds since the Unix
+    /// epoch, and use it to seed the generation of font subset tags, so
+    /// that the same input always gives byte-identical output. If None, the
+    /// SOURCE_DATE_EPOCH environment variable is consulted as usual.
+    pub fn source_date_epoch (&mut self, epoch: Option<i64>) -> &mut Self {
+        self.source_date_epoch = epoch;
+        self
+    }
+
+    /// Stop with an `ErrorKind::Cancelled` error once `token` becomes true.
+    /// The check is made whenever a file is opened.
+    pub fn cancel_token (&mut self, token: Option<Arc<AtomicBool>>) -> &mut Self {
+        self.cancel_token = token;
+        self
+    }
+
+    /// Check every input that the engine asks for against `policy` before
+    /// opening it; see `AccessPolicy`.
+    pub fn access_policy (&mut self, policy: Option<AccessPolicy>) -> &mut Self {
+        self.access_policy = policy;
+        self
     }
 
     pub fn process (&mut self, io: &mut IoStack,
@@ -25,11 +57,16 @@ impl XdvipdfmxEngine {
         let cdvi = CString::new(dvi)?;
         let cpdf = CString::new(pdf)?;
 
-        let /*mut*/ state = ExecutionState::new(io, events, status);
+        let mut state = ExecutionState::new(io, events, status);
+        state.cancel_token = self.cancel_token.clone();
+        state.access_policy = self.access_policy.clone();
         let bridge = TectonicBridgeApi::new(&state);
 
-        unsafe {
+        unsafe { super::tt_set_source_date_epoch(self.source_date_epoch.unwrap_or(-1)); }
+
+        let result = unsafe {
             match super::dvipdfmx_simple_main(&bridge, cdvi.as_ptr(), cpdf.as_ptr()) {
+                99 if state.was_cancelled() => Err(ErrorKind::Cancelled.into()),
                 99 => {
                     let ptr = super::tt_get_error_message();
                     let msg = CStr::from_ptr(ptr).to_string_lossy().into_owned();
@@ -37,6 +74,11 @@ impl XdvipdfmxEngine {
                 },
                 x => Ok(x as i32)
             }
+        };
+
+        match state.callback_error() {
+            Some(e) => Err(e),
+            None => result,
         }
     }
 }
@@ -29,6 +29,16 @@ error_chain! {
     }
 
     errors {
+        BadHttpStatus(status: u16) {
+            description("the server returned an unexpected HTTP status")
+            display("unexpected HTTP response status {}", status)
+        }
+
+        Cancelled {
+            description("the operation was cancelled")
+            display("the compile was cancelled")
+        }
+
         BadLength(expected: usize, observed: usize) {
             description("the item is not the expected length")
             display("expected length {}; found {}", expected, observed)
@@ -0,0 +1,112 @@
+// src/io/auxseed.rs -- starting a compile with a previously generated .aux
+// Copyright 2017 the Tectonic Project
+// Licensed under the MIT License.
+
+use std::ffi::{OsStr, OsString};
+use std::io::Cursor;
+
+use errors::Result;
+use status::StatusBackend;
+use super::{InputHandle, InputOrigin, IoProvider, OpenResult, OutputHandle};
+
+
+/// AuxSeedIo wraps another I/O provider and serves a saved copy of a `.aux`
+/// file until the engine writes a new one. A LaTeX document only comes out
+/// right once its `.aux` has settled down, which usually takes two passes
+/// from scratch; seeding the first pass with the `.aux` from a previous
+/// compile of the same document often gets it there in one.
+///
+/// As soon as the engine opens the file for writing, the seed is discarded
+/// and all further reads go to the inner provider, so that the engine sees
+/// the `.aux` that it generated itself.
+
+pub struct AuxSeedIo<P: IoProvider> {
+    inner: P,
+    name: OsString,
+    seed: Option<Vec<u8>>,
+}
+
+
+impl<P: IoProvider> AuxSeedIo<P> {
+    /// Serve `seed` as the file `name` until it is written.
+    pub fn new<S: AsRef<OsStr>>(inner: P, name: S, seed: Vec<u8>) -> AuxSeedIo<P> {
+        AuxSeedIo {
+            inner: inner,
+            name: name.as_ref().to_os_string(),
+            seed: Some(seed),
+        }
+    }
+
+    /// Whether the seed is still being served.
+    pub fn is_seeding(&self) -> bool {
+        self.seed.is_some()
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+}
+
+
+impl<P: IoProvider> IoProvider for AuxSeedIo<P> {
+    fn output_open_name(&mut self, name: &OsStr) -> OpenResult<OutputHandle> {
+        if name == self.name.as_os_str() {
+            self.seed = None;
+        }
+
+        self.inner.output_open_name(name)
+    }
+
+    fn output_open_stdout(&mut self) -> OpenResult<OutputHandle> {
+        self.inner.output_open_stdout()
+    }
+
+    fn input_open_name(&mut self, name: &OsStr, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        if name == self.name.as_os_str() {
+            if let Some(ref seed) = self.seed {
+                return OpenResult::Ok(InputHandle::new(name, Cursor::new(seed.clone()), InputOrigin::Other));
+            }
+        }
+
+        self.inner.input_open_name(name, status)
+    }
+
+    fn input_open_primary(&mut self, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        self.inner.input_open_primary(status)
+    }
+
+    fn input_open_format(&mut self, name: &OsStr, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        self.inner.input_open_format(name, status)
+    }
+
+    fn write_format(&mut self, name: &str, data: &[u8], status: &mut StatusBackend) -> Result<()> {
+        self.inner.write_format(name, data, status)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use io::MemoryIo;
+    use status::NoopStatusBackend;
+
+    fn read_aux(io: &mut IoProvider) -> String {
+        let mut s = String::new();
+        io.input_open_name(OsStr::new("doc.aux"), &mut NoopStatusBackend::new())
+            .unwrap().read_to_string(&mut s).unwrap();
+        s
+    }
+
+    #[test]
+    fn seed_is_served_until_written() {
+        let mut aio = AuxSeedIo::new(MemoryIo::new(false), "doc.aux", b"seed".to_vec());
+        assert_eq!(read_aux(&mut aio), "seed");
+        assert!(aio.is_seeding());
+
+        aio.output_open_name(OsStr::new("doc.aux")).unwrap().write_all(b"fresh").unwrap();
+        assert!(!aio.is_seeding());
+        assert_eq!(read_aux(&mut aio), "fresh");
+    }
+}
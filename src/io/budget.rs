@@ -0,0 +1,144 @@
+// src/io/budget.rs -- limiting the total size of outputs
+// Copyright 2017 the Tectonic Project
+// Licensed under the MIT License.
+
+use std::cell::Cell;
+use std::ffi::OsStr;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use errors::Result;
+use status::StatusBackend;
+use super::{InputHandle, IoProvider, OpenResult, OutputFeatures, OutputHandle};
+
+
+/// BudgetedOutputIo wraps another I/O provider and caps the total number of
+/// bytes that may be written to all of its outputs combined, so that a
+/// runaway document can't fill up the disk. Once the budget is exhausted,
+/// every further write fails. The check is made before any data are passed
+/// along, so the inner provider never receives more than the budget.
+/// Inputs are passed straight through.
+
+pub struct BudgetedOutputIo<P: IoProvider> {
+    inner: P,
+    limit: u64,
+    used: Rc<Cell<u64>>,
+}
+
+
+impl<P: IoProvider> BudgetedOutputIo<P> {
+    pub fn new(inner: P, limit: u64) -> BudgetedOutputIo<P> {
+        BudgetedOutputIo {
+            inner: inner,
+            limit: limit,
+            used: Rc::new(Cell::new(0)),
+        }
+    }
+
+    /// The number of bytes that have been written so far.
+    pub fn bytes_written(&self) -> u64 {
+        self.used.get()
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+
+    fn wrap(&self, oh: OutputHandle) -> OutputHandle {
+        let name = oh.name().to_os_string();
+        let dest = oh.destination().clone();
+
+        OutputHandle::new_with_features(&name, BudgetedWriter {
+            inner: oh,
+            limit: self.limit,
+            used: self.used.clone(),
+        }, dest)
+    }
+}
+
+
+/// The stream behind the OutputHandles created by BudgetedOutputIo.
+struct BudgetedWriter {
+    inner: OutputHandle,
+    limit: u64,
+    used: Rc<Cell<u64>>,
+}
+
+impl Write for BudgetedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let used = self.used.get();
+
+        if used + buf.len() as u64 > self.limit {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      format!("output size limit of {} bytes exceeded", self.limit)));
+        }
+
+        let n = self.inner.write(buf)?;
+        self.used.set(used + n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl OutputFeatures for BudgetedWriter {
+    fn finish(&mut self) -> io::Result<()> {
+        self.inner.finish()
+    }
+}
+
+
+impl<P: IoProvider> IoProvider for BudgetedOutputIo<P> {
+    fn output_open_name(&mut self, name: &OsStr) -> OpenResult<OutputHandle> {
+        match self.inner.output_open_name(name) {
+            OpenResult::Ok(oh) => OpenResult::Ok(self.wrap(oh)),
+            r => r,
+        }
+    }
+
+    fn output_open_stdout(&mut self) -> OpenResult<OutputHandle> {
+        match self.inner.output_open_stdout() {
+            OpenResult::Ok(oh) => OpenResult::Ok(self.wrap(oh)),
+            r => r,
+        }
+    }
+
+    fn input_open_name(&mut self, name: &OsStr, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        self.inner.input_open_name(name, status)
+    }
+
+    fn input_open_primary(&mut self, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        self.inner.input_open_primary(status)
+    }
+
+    fn input_open_format(&mut self, name: &OsStr, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        self.inner.input_open_format(name, status)
+    }
+
+    fn write_format(&mut self, name: &str, data: &[u8], status: &mut StatusBackend) -> Result<()> {
+        self.inner.write_format(name, data, status)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::MemoryIo;
+
+    #[test]
+    fn budget_is_enforced_across_outputs() {
+        let mut bio = BudgetedOutputIo::new(MemoryIo::new(false), 10);
+
+        let mut oh = bio.output_open_name(OsStr::new("a.aux")).unwrap();
+        oh.write_all(b"123456").unwrap();
+
+        let mut oh2 = bio.output_open_name(OsStr::new("b.aux")).unwrap();
+        assert!(oh2.write_all(b"12345").is_err());
+        oh2.write_all(b"1234").unwrap();
+        assert!(oh.write_all(b"x").is_err());
+        assert_eq!(bio.bytes_written(), 10);
+    }
+}
@@ -0,0 +1,205 @@
+// src/io/dedup.rs -- suppress rewrites of unchanged outputs
+// Copyright 2017 the Tectonic Project
+// Licensed under the MIT License.
+
+use std::cell::RefCell;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+use digest::{self, Digest, DigestData};
+use errors::Result;
+use status::{NoopStatusBackend, StatusBackend};
+use super::{InputHandle, IoProvider, OpenResult, OutputDestination, OutputFeatures, OutputHandle};
+
+
+/// DedupOutputIo wraps another I/O provider and avoids rewriting output files
+/// whose contents haven't changed. TeX rewrites its auxiliary files on every
+/// pass, usually with the same contents; this keeps their modification times
+/// intact so that tools like `make` don't think that they need to rebuild
+/// things that depend on them.
+///
+/// Data written to an output are buffered in memory. When the output is
+/// finished, their digest is compared to that of the file as the inner
+/// provider currently has it, and the data are only passed along to the
+/// inner provider if they differ. An output that is dropped without being
+/// finished is incomplete, so it is discarded and the stored file is left
+/// alone. Because this decision is deferred, a
+/// DedupOutputIo accepts every output: it should wrap a provider that can
+/// actually store them, such as a FilesystemIo. Inputs and the standard
+/// output are passed straight through.
+
+pub struct DedupOutputIo<P: IoProvider> {
+    inner: Rc<RefCell<P>>,
+}
+
+
+impl<P: 'static + IoProvider> DedupOutputIo<P> {
+    pub fn new(inner: P) -> DedupOutputIo<P> {
+        DedupOutputIo {
+            inner: Rc::new(RefCell::new(inner)),
+        }
+    }
+
+    /// Take back the wrapped provider. Returns None if any outputs opened
+    /// through this one are still alive.
+    pub fn into_inner(self) -> Option<P> {
+        Rc::try_unwrap(self.inner).ok().map(|c| c.into_inner())
+    }
+}
+
+
+fn digest_of(data: &[u8]) -> DigestData {
+    let mut dc = digest::create();
+    dc.input(data);
+    DigestData::from(dc)
+}
+
+
+/// The stream behind the OutputHandles created by DedupOutputIo.
+struct DedupWriter<P: IoProvider> {
+    inner: Rc<RefCell<P>>,
+    name: OsString,
+    buf: Vec<u8>,
+
+    /// The digest of what the inner provider has stored under `name`, if
+    /// anything.
+    stored: Option<DigestData>,
+}
+
+impl<P: IoProvider> DedupWriter<P> {
+    fn commit(&mut self) -> io::Result<()> {
+        let new_digest = digest_of(&self.buf);
+
+        if self.stored == Some(new_digest) {
+            return Ok(());
+        }
+
+        let mut oh = match self.inner.borrow_mut().output_open_name(&self.name) {
+            OpenResult::Ok(oh) => oh,
+            OpenResult::NotAvailable => {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                          format!("cannot write output \"{}\"", self.name.to_string_lossy())));
+            },
+            OpenResult::Err(e) => return Err(e.into()),
+        };
+
+        oh.write_all(&self.buf)?;
+        oh.finish()?;
+        self.stored = Some(new_digest);
+        Ok(())
+    }
+}
+
+impl<P: IoProvider> Write for DedupWriter<P> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Nothing reaches the inner provider until we know the output is
+        // complete.
+        Ok(())
+    }
+}
+
+impl<P: IoProvider> OutputFeatures for DedupWriter<P> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.commit()
+    }
+}
+
+
+impl<P: 'static + IoProvider> IoProvider for DedupOutputIo<P> {
+    fn output_open_name(&mut self, name: &OsStr) -> OpenResult<OutputHandle> {
+        // We don't want progress messages about a file that the engine
+        // doesn't know we're reading.
+        let mut status = NoopStatusBackend::new();
+
+        let stored = match self.inner.borrow_mut().input_open_name(name, &mut status) {
+            OpenResult::Ok(mut ih) => {
+                let mut data = Vec::new();
+
+                if let Err(e) = ih.read_to_end(&mut data) {
+                    return OpenResult::Err(e.into());
+                }
+
+                Some(digest_of(&data))
+            },
+            OpenResult::NotAvailable => None,
+            OpenResult::Err(e) => return OpenResult::Err(e),
+        };
+
+        OpenResult::Ok(OutputHandle::new_with_features(name, DedupWriter {
+            inner: self.inner.clone(),
+            name: name.to_os_string(),
+            buf: Vec::new(),
+            stored: stored,
+        }, OutputDestination::Named(name.to_os_string())))
+    }
+
+    fn output_open_stdout(&mut self) -> OpenResult<OutputHandle> {
+        self.inner.borrow_mut().output_open_stdout()
+    }
+
+    fn input_open_name(&mut self, name: &OsStr, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        self.inner.borrow_mut().input_open_name(name, status)
+    }
+
+    fn input_open_primary(&mut self, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        self.inner.borrow_mut().input_open_primary(status)
+    }
+
+    fn input_open_format(&mut self, name: &OsStr, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        self.inner.borrow_mut().input_open_format(name, status)
+    }
+
+    fn write_format(&mut self, name: &str, data: &[u8], status: &mut StatusBackend) -> Result<()> {
+        self.inner.borrow_mut().write_format(name, data, status)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::memory::CountingIo;
+
+    fn write_file<P: IoProvider>(io: &mut P, name: &str, text: &str) {
+        let mut oh = io.output_open_name(OsStr::new(name)).unwrap();
+        oh.write_all(text.as_bytes()).unwrap();
+        oh.finish().unwrap();
+    }
+
+    #[test]
+    fn unchanged_outputs_are_not_rewritten() {
+        let mut dio = DedupOutputIo::new(CountingIo::new());
+
+        write_file(&mut dio, "doc.aux", "\\relax");
+        write_file(&mut dio, "doc.aux", "\\relax");
+        assert_eq!(dio.inner.borrow().opens, 1);
+
+        write_file(&mut dio, "doc.aux", "\\relax\\relax");
+        assert_eq!(dio.inner.borrow().opens, 2);
+
+        let inner = dio.into_inner().unwrap();
+        assert_eq!(inner.mem.files.borrow()[OsStr::new("doc.aux")], b"\\relax\\relax");
+    }
+
+    #[test]
+    fn unfinished_outputs_are_discarded() {
+        let mut dio = DedupOutputIo::new(CountingIo::new());
+        write_file(&mut dio, "doc.aux", "\\relax");
+
+        {
+            let mut oh = dio.output_open_name(OsStr::new("doc.aux")).unwrap();
+            oh.write_all(b"\\rel").unwrap();
+            oh.flush().unwrap();
+        }
+
+        assert_eq!(dio.inner.borrow().opens, 1);
+        let inner = dio.into_inner().unwrap();
+        assert_eq!(inner.mem.files.borrow()[OsStr::new("doc.aux")], b"\\relax");
+    }
+}
@@ -2,16 +2,22 @@
 // Copyright 2016-2017 the Tectonic Project
 // Licensed under the MIT License.
 
+use fs2;
 use libc;
-use std::collections::HashSet;
-use std::ffi::OsStr;
-use std::fs::File;
-use std::io::{self, BufReader, Seek, SeekFrom};
-use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
 
 use errors::{ErrorKind, Result};
 use status::StatusBackend;
-use super::{try_open_file, InputFeatures, InputHandle, InputOrigin, IoProvider, OpenResult, OutputHandle};
+use super::{try_open_file, InputFeatures, InputHandle, InputOrigin, IoProvider, OpenResult,
+            OutputDestination, OutputFeatures, OutputHandle};
+
+#[cfg(feature = "mmap")] use memmap::{Mmap, Protection};
+#[cfg(feature = "mmap")] use std::io::Cursor;
+#[cfg(feature = "mmap")] use std::rc::Rc;
 
 
 /// FilesystemPrimaryInputIo is an I/O provider that provides the TeX "primary input"
@@ -45,7 +51,178 @@ impl IoProvider for FilesystemPrimaryInputIo {
             OpenResult::Err(e) => return OpenResult::Err(e),
         };
 
-        OpenResult::Ok(InputHandle::new(OsStr::new(""), BufReader::new(f), InputOrigin::Filesystem))
+        OpenResult::Ok(InputHandle::new(OsStr::new(""), BufReader::new(f),
+                                        InputOrigin::Filesystem(self.path.clone())))
+    }
+}
+
+
+/// MmapPrimaryIo provides the primary input file by memory-mapping it, so
+/// that a large document needn't be read into memory all at once. The same
+/// mapping is reused every time that the file is opened, so it supports
+/// multiple passes just as well as BufferedPrimaryIo. The file must not be
+/// modified while it is mapped.
+
+#[cfg(feature = "mmap")]
+pub struct MmapPrimaryIo {
+    path: PathBuf,
+    map: SharedMmap,
+}
+
+#[cfg(feature = "mmap")]
+impl MmapPrimaryIo {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<MmapPrimaryIo> {
+        let path = path.as_ref();
+
+        // Empty files can't be mapped, but they're valid inputs.
+        let map = if fs::metadata(path)?.len() == 0 {
+            None
+        } else {
+            Some(Rc::new(Mmap::open_path(path, Protection::Read)?))
+        };
+
+        Ok(MmapPrimaryIo { path: path.to_owned(), map: SharedMmap(map) })
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl IoProvider for MmapPrimaryIo {
+    fn input_open_primary(&mut self, _status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        OpenResult::Ok(InputHandle::new(OsStr::new(""), Cursor::new(self.map.clone()),
+                                        InputOrigin::Filesystem(self.path.clone())))
+    }
+}
+
+/// A memory mapping shared by all of the handles opened by a MmapPrimaryIo.
+#[cfg(feature = "mmap")]
+#[derive(Clone)]
+struct SharedMmap(Option<Rc<Mmap>>);
+
+#[cfg(feature = "mmap")]
+impl AsRef<[u8]> for SharedMmap {
+    fn as_ref(&self) -> &[u8] {
+        match self.0 {
+            // This is safe as long as nobody modifies the file; see above.
+            Some(ref m) => unsafe { m.as_slice() },
+            None => &[],
+        }
+    }
+}
+
+/// AtomicFileWriter writes a file "atomically": the data go into a temporary
+/// file in the same directory as the destination, which is only renamed into
+/// place when writing is finished. If the program crashes mid-write, any
+/// previous version of the file is left untouched.
+///
+/// The rename only happens when `finish()` is called, after the data have
+/// been synced to disk. If a write has failed, or the writer is dropped
+/// without being finished, the temporary file is deleted instead and any
+/// previous version of the file is left alone.
+
+pub struct AtomicFileWriter {
+    temp: Option<File>,
+    temp_path: PathBuf,
+    final_path: PathBuf,
+    failed: bool,
+}
+
+impl AtomicFileWriter {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<AtomicFileWriter> {
+        let final_path = path.as_ref().to_owned();
+
+        let base = match final_path.file_name() {
+            Some(n) => n.to_owned(),
+            None => return Err(ErrorKind::Msg(format!("cannot write to \"{}\": not a file name",
+                                                      final_path.display())).into()),
+        };
+
+        // We don't use mkstemp here, since we want the final file to have
+        // the usual permissions of a newly-created file.
+
+        let pid = unsafe { libc::getpid() };
+        let mut serial = 0;
+
+        loop {
+            let mut temp_name = OsString::from(".");
+            temp_name.push(&base);
+            temp_name.push(format!(".{}-{}.tmp", pid, serial));
+            let temp_path = final_path.with_file_name(temp_name);
+
+            match OpenOptions::new().write(true).create_new(true).open(&temp_path) {
+                Ok(f) => {
+                    return Ok(AtomicFileWriter {
+                        temp: Some(f),
+                        temp_path: temp_path,
+                        final_path: final_path,
+                        failed: false,
+                    });
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    serial += 1;
+                },
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Sync the data to disk and move the finished file into place.
+    pub fn finish(&mut self) -> io::Result<()> {
+        let temp = match self.temp.take() {
+            Some(f) => f,
+            None => return Ok(()), // already finished
+        };
+
+        let result = if self.failed {
+            Err(io::Error::new(io::ErrorKind::Other, "not replacing file after failed write"))
+        } else {
+            temp.sync_all()
+        };
+
+        drop(temp);
+
+        if let Err(e) = result {
+            let _ = fs::remove_file(&self.temp_path);
+            return Err(e);
+        }
+
+        fs::rename(&self.temp_path, &self.final_path)
+    }
+}
+
+impl Write for AtomicFileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let r = match self.temp {
+            Some(ref mut f) => f.write(buf),
+            None => Err(io::Error::new(io::ErrorKind::Other, "write to finished file")),
+        };
+
+        if r.is_err() {
+            self.failed = true;
+        }
+
+        r
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.temp {
+            Some(ref mut f) => f.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl OutputFeatures for AtomicFileWriter {
+    fn finish(&mut self) -> io::Result<()> {
+        AtomicFileWriter::finish(self)
+    }
+}
+
+impl Drop for AtomicFileWriter {
+    fn drop(&mut self) {
+        // If we weren't finished, the output is incomplete.
+        if self.temp.take().is_some() {
+            let _ = fs::remove_file(&self.temp_path);
+        }
     }
 }
 
@@ -54,12 +231,17 @@ impl IoProvider for FilesystemPrimaryInputIo {
 /// from a given root directory. NOTE: no effort is made to contain I/O within
 /// the specified root!! We have an option to disallow absolute paths, but we
 /// don't do anything about "../../../...." paths.
+///
+/// Outputs are written relative to the same root, unless a separate one is
+/// set with `output_root()`.
 
 pub struct FilesystemIo {
     root: PathBuf,
+    output_root: Option<PathBuf>,
     writes_allowed: bool,
     absolute_allowed: bool,
     hidden_input_paths: HashSet<PathBuf>,
+    serves_bundle: bool,
 }
 
 impl FilesystemIo {
@@ -67,13 +249,31 @@ impl FilesystemIo {
                   hidden_input_paths: HashSet<PathBuf>) -> FilesystemIo {
         FilesystemIo {
             root: PathBuf::from(root),
+            output_root: None,
             writes_allowed: writes_allowed,
             absolute_allowed: absolute_allowed,
             hidden_input_paths: hidden_input_paths,
+            serves_bundle: false,
         }
     }
 
-    fn construct_path(&mut self, name: &OsStr) -> Result<PathBuf> {
+    /// Treat the files under the root as the contents of an unpacked bundle:
+    /// inputs are reported as coming from a bundle rather than from the
+    /// filesystem, so that they aren't listed as dependencies of the
+    /// document.
+    pub fn serves_bundle(&mut self, serves_bundle: bool) -> &mut Self {
+        self.serves_bundle = serves_bundle;
+        self
+    }
+
+    /// Resolve the names of output files relative to `dir` rather than the
+    /// root directory used for inputs.
+    pub fn output_root<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.output_root = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    fn construct_path(&self, root: &Path, name: &OsStr) -> Result<PathBuf> {
         let path = Path::new(name);
 
         if path.is_absolute() && !self.absolute_allowed {
@@ -81,7 +281,7 @@ impl FilesystemIo {
             return Err(ErrorKind::PathForbidden(as_str).into());
         }
 
-        let mut combined = PathBuf::from(&self.root);
+        let mut combined = PathBuf::from(root);
         combined.push(path);
         Ok(combined)
     }
@@ -94,17 +294,21 @@ impl IoProvider for FilesystemIo {
             return OpenResult::NotAvailable;
         }
 
-        let path = match self.construct_path(name) {
-            Ok(p) => p,
-            Err(e) => return OpenResult::Err(e.into())
+        let path = {
+            let root = self.output_root.as_ref().unwrap_or(&self.root);
+
+            match self.construct_path(root, name) {
+                Ok(p) => p,
+                Err(e) => return OpenResult::Err(e.into())
+            }
         };
 
-        let f = match File::create (path) {
+        let f = match AtomicFileWriter::create(path) {
             Ok(f) => f,
-            Err(e) => return OpenResult::Err(e.into())
+            Err(e) => return OpenResult::Err(e)
         };
 
-        OpenResult::Ok(OutputHandle::new(name, f))
+        OpenResult::Ok(OutputHandle::new_with_features(name, f, OutputDestination::Named(name.to_os_string())))
     }
 
     fn output_open_stdout(&mut self) -> OpenResult<OutputHandle> {
@@ -113,7 +317,7 @@ impl IoProvider for FilesystemIo {
     }
 
     fn input_open_name(&mut self, name: &OsStr, _status: &mut StatusBackend) -> OpenResult<InputHandle> {
-        let path = match self.construct_path(name) {
+        let path = match self.construct_path(&self.root, name) {
             Ok(p) => p,
             Err(e) => return OpenResult::Err(e.into())
         };
@@ -122,7 +326,12 @@ impl IoProvider for FilesystemIo {
             return OpenResult::NotAvailable;
         }
 
-        let f = match File::open (path) {
+        // Only the absence of the file means that we should let some other
+        // provider have a go. Other failures, such as a lack of permission
+        // to read the file, mean that the user's intent is being thwarted,
+        // so they should be reported.
+
+        let f = match File::open (&path) {
             Ok(f) => f,
             Err(e) => return if e.kind() == io::ErrorKind::NotFound {
                 OpenResult::NotAvailable
@@ -137,7 +346,177 @@ impl IoProvider for FilesystemIo {
             }
         };
 
-        OpenResult::Ok(InputHandle::new(name, BufReader::new(f), InputOrigin::Filesystem))
+        let origin = if self.serves_bundle {
+            InputOrigin::Bundle(name.to_os_string())
+        } else {
+            InputOrigin::Filesystem(path)
+        };
+
+        OpenResult::Ok(InputHandle::new(name, BufReader::new(f), origin))
+    }
+}
+
+
+/// How many levels of subdirectories RecursiveFilesystemIo searches by
+/// default.
+pub const DEFAULT_SEARCH_DEPTH: usize = 4;
+
+
+/// RecursiveFilesystemIo reads input files from a directory tree, like a
+/// `TEXINPUTS` entry ending in `//`. A file is looked for directly under the
+/// root first; if it isn't there, the subdirectories are searched for it,
+/// shallowest first, down to a limited depth. This lets projects that keep
+/// their figures and chapters in nested folders refer to them by bare name.
+///
+/// If a name turns up in more than one place, the first match is used and
+/// a warning is issued. Hidden directories (such as `.git`) are skipped, and
+/// symbolic links to directories aren't followed, so the search can't loop.
+/// The subdirectories are scanned once, when the first file has to be
+/// searched for, so files created in them after that are only found if
+/// they are named by their full path from the root. Absolute names and
+/// names that climb out of the tree with `..` are never looked for.
+
+pub struct RecursiveFilesystemIo {
+    root: PathBuf,
+    max_depth: usize,
+    warned: HashSet<OsString>,
+
+    /// The files in the subdirectories, by file name, each list in order of
+    /// preference; see `build_index()`.
+    index: Option<HashMap<OsString, Vec<PathBuf>>>,
+}
+
+impl RecursiveFilesystemIo {
+    pub fn new<P: AsRef<Path>>(root: P) -> RecursiveFilesystemIo {
+        RecursiveFilesystemIo {
+            root: root.as_ref().to_owned(),
+            max_depth: DEFAULT_SEARCH_DEPTH,
+            warned: HashSet::new(),
+            index: None,
+        }
+    }
+
+    /// Set how many levels of subdirectories to search; zero means that only
+    /// the root itself is consulted.
+    pub fn max_depth(&mut self, depth: usize) -> &mut Self {
+        self.max_depth = depth;
+        self.index = None;
+        self
+    }
+
+    /// List the files in the subdirectories of the root, level by level,
+    /// and in sorted order within each level so that the results don't
+    /// depend on the whims of the filesystem.
+    fn build_index(&self) -> HashMap<OsString, Vec<PathBuf>> {
+        let mut index = HashMap::new();
+        let mut level = vec![self.root.clone()];
+
+        for _ in 0..self.max_depth {
+            let mut next = Vec::new();
+
+            for dir in &level {
+                let mut subdirs = match fs::read_dir(dir) {
+                    Ok(entries) => entries
+                        .filter_map(|e| e.ok())
+                        .filter(|e| e.file_type().map(|t| t.is_dir()).unwrap_or(false))
+                        .filter(|e| !e.file_name().to_string_lossy().starts_with('.'))
+                        .map(|e| e.path())
+                        .collect::<Vec<_>>(),
+                    Err(_) => continue,
+                };
+
+                subdirs.sort();
+
+                for sub in subdirs {
+                    let mut files = match fs::read_dir(&sub) {
+                        Ok(entries) => entries
+                            .filter_map(|e| e.ok())
+                            .map(|e| e.path())
+                            .filter(|p| p.is_file())
+                            .collect::<Vec<_>>(),
+                        Err(_) => Vec::new(),
+                    };
+
+                    files.sort();
+
+                    for f in files {
+                        if let Some(n) = f.file_name().map(|n| n.to_os_string()) {
+                            index.entry(n).or_insert_with(Vec::new).push(f);
+                        }
+                    }
+
+                    next.push(sub);
+                }
+            }
+
+            level = next;
+        }
+
+        index
+    }
+
+    /// Find `name` in the subdirectories of the root. It's looked up by its
+    /// last part, so a name with directories in it matches wherever those
+    /// directories are.
+    fn search(&mut self, name: &Path) -> Vec<PathBuf> {
+        if self.index.is_none() {
+            self.index = Some(self.build_index());
+        }
+
+        let base = match name.file_name() {
+            Some(b) => b,
+            None => return Vec::new(),
+        };
+
+        match self.index.as_ref().unwrap().get(base) {
+            Some(paths) => paths.iter().filter(|p| p.ends_with(name)).cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+
+impl IoProvider for RecursiveFilesystemIo {
+    fn input_open_name(&mut self, name: &OsStr, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        // Joining an absolute path to the root would replace it, and `..`
+        // would leave the tree.
+        let searchable = Path::new(name).components().all(|c| match c {
+            Component::Normal(_) | Component::CurDir => true,
+            _ => false,
+        });
+
+        let path = Path::new(name).components().filter(|c| *c != Component::CurDir).collect::<PathBuf>();
+
+        if !searchable || path.as_os_str().is_empty() {
+            return OpenResult::NotAvailable;
+        }
+
+        let direct = self.root.join(&path);
+
+        let found = if direct.is_file() {
+            direct
+        } else {
+            let mut matches = self.search(&path).into_iter();
+
+            let first = match matches.next() {
+                Some(p) => p,
+                None => return OpenResult::NotAvailable,
+            };
+
+            let others = matches.map(|p| p.display().to_string()).collect::<Vec<_>>();
+
+            if !others.is_empty() && self.warned.insert(name.to_os_string()) {
+                tt_warning!(status, "found \"{}\" in more than one place; using {} and not {}",
+                            name.to_string_lossy(), first.display(), others.join(", "));
+            }
+
+            first
+        };
+
+        match File::open(&found) {
+            Ok(f) => OpenResult::Ok(InputHandle::new(name, BufReader::new(f), InputOrigin::Filesystem(found))),
+            Err(e) => OpenResult::Err(e.into()),
+        }
     }
 }
 
@@ -153,6 +532,25 @@ impl InputFeatures for File {
 }
 
 
+/// Make sure that the filesystem holding `dir` has room for `needed` more
+/// bytes, so that writing a big file such as a format fails up front with a
+/// clear message rather than partway through. If the free space can't be
+/// determined, we just go ahead.
+pub fn ensure_free_space(dir: &Path, needed: u64) -> Result<()> {
+    let available = match fs2::available_space(dir) {
+        Ok(n) => n,
+        Err(_) => return Ok(()),
+    };
+
+    if needed > available {
+        return Err(ErrorKind::Msg(format!("not enough free space in \"{}\": {} bytes are needed, but only {} \
+                                           are available", dir.display(), needed, available)).into());
+    }
+
+    Ok(())
+}
+
+
 impl InputFeatures for BufReader<File> {
     fn get_size(&mut self) -> Result<usize> {
         Ok(self.get_mut().metadata()?.len() as usize)
@@ -162,3 +560,198 @@ impl InputFeatures for BufReader<File> {
         Ok(self.seek(pos)?)
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, Permissions};
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use tempdir::TempDir;
+    use status::NoopStatusBackend;
+
+    #[test]
+    fn missing_file_is_not_available() {
+        let tempdir = TempDir::new("tectonic_fs_test").unwrap();
+        let mut fsio = FilesystemIo::new(tempdir.path(), false, false, HashSet::new());
+        let mut sb = NoopStatusBackend::new();
+        assert!(fsio.input_open_name(OsStr::new("nonexistent.tex"), &mut sb).is_not_available());
+    }
+
+    #[test]
+    fn outputs_go_to_output_root() {
+        let tempdir = TempDir::new("tectonic_fs_test").unwrap();
+        let outdir = tempdir.path().join("out");
+        fs::create_dir(&outdir).unwrap();
+
+        let mut fsio = FilesystemIo::new(tempdir.path(), true, false, HashSet::new());
+        fsio.output_root(&outdir);
+        let mut oh = fsio.output_open_name(OsStr::new("doc.log")).unwrap();
+        oh.write_all(b"hello").unwrap();
+        oh.finish().unwrap();
+
+        assert!(outdir.join("doc.log").exists());
+        assert!(!tempdir.path().join("doc.log").exists());
+    }
+
+    #[test]
+    fn subdirectories_are_searched() {
+        use std::io::Read;
+
+        let tempdir = TempDir::new("tectonic_fs_test").unwrap();
+        let root = tempdir.path();
+
+        for &(dir, name, contents) in &[("a", "fig.tex", "shallow"), ("b/c", "fig.tex", "deep"),
+                                        ("z", "fig.tex", "ambiguous"), (".git", "hidden.tex", "hidden"),
+                                        ("b/c", "only.tex", "deep")] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+            File::create(root.join(dir).join(name)).unwrap().write_all(contents.as_bytes()).unwrap();
+        }
+
+        let mut rio = RecursiveFilesystemIo::new(root);
+        let mut sb = NoopStatusBackend::new();
+
+        for &(name, expected) in &[("fig.tex", "shallow"), ("only.tex", "deep"), ("c/only.tex", "deep")] {
+            let mut s = String::new();
+            rio.input_open_name(OsStr::new(name), &mut sb).unwrap().read_to_string(&mut s).unwrap();
+            assert_eq!(s, expected);
+        }
+
+        for name in &["hidden.tex", "../fig.tex", "missing.tex"] {
+            assert!(rio.input_open_name(OsStr::new(name), &mut sb).is_not_available());
+        }
+
+        // Names that lead out of the tree aren't used, even if they exist.
+        let mut inner = RecursiveFilesystemIo::new(root.join("b"));
+        let outside = root.join("a").join("fig.tex");
+        assert!(inner.input_open_name(outside.as_os_str(), &mut sb).is_not_available());
+        assert!(inner.input_open_name(OsStr::new("../a/fig.tex"), &mut sb).is_not_available());
+
+        // The subdirectories are only scanned once.
+        File::create(root.join("a").join("new.tex")).unwrap();
+        assert!(rio.input_open_name(OsStr::new("new.tex"), &mut sb).is_not_available());
+        assert!(!rio.input_open_name(OsStr::new("a/new.tex"), &mut sb).is_not_available());
+
+        rio.max_depth(1);
+        assert!(!rio.input_open_name(OsStr::new("./new.tex"), &mut sb).is_not_available());
+        assert!(rio.input_open_name(OsStr::new("only.tex"), &mut sb).is_not_available());
+    }
+
+    #[test]
+    fn free_space_is_checked() {
+        let tempdir = TempDir::new("tectonic_fs_test").unwrap();
+        ensure_free_space(tempdir.path(), 0).unwrap();
+
+        let e = ensure_free_space(tempdir.path(), u64::max_value()).unwrap_err();
+        assert!(e.to_string().contains("not enough free space"));
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mmapped_primary_is_rereadable() {
+        use std::io::Read;
+
+        let tempdir = TempDir::new("tectonic_fs_test").unwrap();
+        let path = tempdir.path().join("doc.tex");
+        File::create(&path).unwrap().write_all(b"\\bye").unwrap();
+
+        let mut mio = MmapPrimaryIo::open(&path).unwrap();
+        let mut sb = NoopStatusBackend::new();
+
+        for _ in 0..2 {
+            let mut s = String::new();
+            mio.input_open_primary(&mut sb).unwrap().read_to_string(&mut s).unwrap();
+            assert_eq!(s, "\\bye");
+        }
+    }
+
+    #[test]
+    fn unreadable_file_is_an_error() {
+        let tempdir = TempDir::new("tectonic_fs_test").unwrap();
+        let path = tempdir.path().join("secret.tex");
+
+        {
+            let mut f = File::create(&path).unwrap();
+            writeln!(f, "\\relax").unwrap();
+        }
+
+        fs::set_permissions(&path, Permissions::from_mode(0o000)).unwrap();
+
+        if File::open(&path).is_ok() {
+            // We're running with privileges that ignore file permissions, so
+            // there's nothing to test.
+            return;
+        }
+
+        let mut fsio = FilesystemIo::new(tempdir.path(), false, false, HashSet::new());
+        let mut sb = NoopStatusBackend::new();
+
+        match fsio.input_open_name(OsStr::new("secret.tex"), &mut sb) {
+            OpenResult::Err(_) => {},
+            _ => panic!("permission problem was not reported"),
+        }
+    }
+
+    #[test]
+    fn unpacked_bundle_files_have_bundle_origin() {
+        let tempdir = TempDir::new("tectonic_fs_test").unwrap();
+        File::create(tempdir.path().join("plain.tex")).unwrap().write_all(b"\\relax").unwrap();
+        let mut sb = NoopStatusBackend::new();
+
+        let mut fsio = FilesystemIo::new(tempdir.path(), false, false, HashSet::new());
+        let ih = fsio.input_open_name(OsStr::new("plain.tex"), &mut sb).unwrap();
+        assert_eq!(ih.origin(), InputOrigin::Filesystem(tempdir.path().join("plain.tex")));
+
+        fsio.serves_bundle(true);
+        let ih = fsio.input_open_name(OsStr::new("plain.tex"), &mut sb).unwrap();
+        assert_eq!(ih.origin(), InputOrigin::Bundle(OsString::from("plain.tex")));
+    }
+
+    #[test]
+    fn atomic_writes_need_finishing() {
+        use std::io::Read;
+
+        let tempdir = TempDir::new("tectonic_fs_test").unwrap();
+        let path = tempdir.path().join("doc.pdf");
+        File::create(&path).unwrap().write_all(b"old").unwrap();
+
+        let read = || {
+            let mut s = String::new();
+            File::open(&path).unwrap().read_to_string(&mut s).unwrap();
+            s
+        };
+
+        // Abandoned writes leave the old file, and no temporary file, behind.
+        {
+            let mut f = AtomicFileWriter::create(&path).unwrap();
+            f.write_all(b"partial").unwrap();
+        }
+
+        assert_eq!(read(), "old");
+        assert_eq!(fs::read_dir(tempdir.path()).unwrap().count(), 1);
+
+        let mut f = AtomicFileWriter::create(&path).unwrap();
+        f.write_all(b"new").unwrap();
+        assert_eq!(read(), "old");
+        f.finish().unwrap();
+        assert_eq!(read(), "new");
+        drop(f);
+        assert_eq!(read(), "new");
+        assert_eq!(fs::read_dir(tempdir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn filesystem_outputs_are_committed_on_finish() {
+        let tempdir = TempDir::new("tectonic_fs_test").unwrap();
+        let mut fsio = FilesystemIo::new(tempdir.path(), true, false, HashSet::new());
+
+        fsio.output_open_name(OsStr::new("abandoned.log")).unwrap().write_all(b"hello").unwrap();
+        assert!(!tempdir.path().join("abandoned.log").exists());
+
+        let mut oh = fsio.output_open_name(OsStr::new("doc.log")).unwrap();
+        oh.write_all(b"hello").unwrap();
+        oh.finish().unwrap();
+        assert!(tempdir.path().join("doc.log").exists());
+    }
+}
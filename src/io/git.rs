@@ -0,0 +1,126 @@
+// src/io/git.rs -- I/O on the files in a Git tree
+// Copyright 2017 the Tectonic Project
+// Licensed under the MIT License.
+
+use git2::{self, ErrorCode, ObjectType, Oid, Repository};
+use std::ffi::OsStr;
+use std::io::Cursor;
+use std::path::Path;
+
+use errors::{Error, ErrorKind, Result};
+use status::StatusBackend;
+use super::{normalize_tex_path, InputHandle, InputOrigin, IoProvider, OpenResult};
+
+
+/// GitTreeIo serves input files out of a tree in a Git repository, reading
+/// the blobs directly from the object database. This makes it possible to
+/// process a document exactly as it stood at a given commit, without
+/// checking anything out.
+
+pub struct GitTreeIo {
+    repo: Repository,
+    tree_id: Oid,
+}
+
+
+fn git_error(e: git2::Error) -> Error {
+    ErrorKind::Msg(format!("Git error: {}", e)).into()
+}
+
+
+impl GitTreeIo {
+    /// Serve the files of `revision` in the repository at `repo_path`. The
+    /// revision can be anything that `git rev-parse` understands and that
+    /// leads to a tree: a branch or tag name, a commit hash, and so on.
+    pub fn open<P: AsRef<Path>>(repo_path: P, revision: &str) -> Result<GitTreeIo> {
+        let repo = Repository::open(repo_path).map_err(git_error)?;
+        let tree_id = repo.revparse_single(revision)
+            .and_then(|obj| obj.peel(ObjectType::Tree))
+            .map_err(git_error)?
+            .id();
+
+        Ok(GitTreeIo {
+            repo: repo,
+            tree_id: tree_id,
+        })
+    }
+
+    fn read_blob(&self, path: &Path) -> OpenResult<Vec<u8>> {
+        let tree = match self.repo.find_tree(self.tree_id) {
+            Ok(t) => t,
+            Err(e) => return OpenResult::Err(git_error(e)),
+        };
+
+        let entry = match tree.get_path(path) {
+            Ok(e) => e,
+            Err(ref e) if e.code() == ErrorCode::NotFound => return OpenResult::NotAvailable,
+            Err(e) => return OpenResult::Err(git_error(e)),
+        };
+
+        let object = match entry.to_object(&self.repo) {
+            Ok(o) => o,
+            Err(e) => return OpenResult::Err(git_error(e)),
+        };
+
+        // Subtrees and submodules aren't files that we can read.
+        match object.as_blob() {
+            Some(blob) => OpenResult::Ok(blob.content().to_owned()),
+            None => OpenResult::NotAvailable,
+        }
+    }
+}
+
+
+impl IoProvider for GitTreeIo {
+    fn input_open_name(&mut self, name: &OsStr, _status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        let name = normalize_tex_path(name);
+
+        match self.read_blob(Path::new(&name)) {
+            OpenResult::Ok(data) => OpenResult::Ok(InputHandle::new(&name, Cursor::new(data), InputOrigin::Other)),
+            OpenResult::NotAvailable => OpenResult::NotAvailable,
+            OpenResult::Err(e) => OpenResult::Err(e),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use status::NoopStatusBackend;
+    use tempdir::TempDir;
+
+    #[test]
+    fn blobs_are_served() {
+        let tempdir = TempDir::new("tectonic_git_test").unwrap();
+        let tree_id = {
+            let repo = Repository::init(tempdir.path()).unwrap();
+            let main = repo.blob(b"\\input sub").unwrap();
+            let sub = repo.blob(b"\\bye").unwrap();
+
+            let mut subdir = repo.treebuilder(None).unwrap();
+            subdir.insert("sub.tex", sub, 0o100644).unwrap();
+            let subdir = subdir.write().unwrap();
+
+            let mut top = repo.treebuilder(None).unwrap();
+            top.insert("main.tex", main, 0o100644).unwrap();
+            top.insert("sub", subdir, 0o040000).unwrap();
+            top.write().unwrap()
+        };
+
+        let mut gio = GitTreeIo::open(tempdir.path(), &tree_id.to_string()).unwrap();
+        let mut sb = NoopStatusBackend::new();
+
+        let mut s = String::new();
+        gio.input_open_name(OsStr::new("main.tex"), &mut sb).unwrap().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "\\input sub");
+
+        s.clear();
+        gio.input_open_name(OsStr::new("./sub/sub.tex"), &mut sb).unwrap().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "\\bye");
+
+        assert!(gio.input_open_name(OsStr::new("sub"), &mut sb).is_not_available());
+        assert!(gio.input_open_name(OsStr::new("nope.tex"), &mut sb).is_not_available());
+    }
+}
@@ -2,18 +2,16 @@
 // Copyright 2017 the Tectonic Project
 // Licensed under the MIT License.
 
-// TODO: this should probably be its own crate, but I'm writing this on an
-// airplane and I can't get that set up without a web connection.
-
 use hyper::Client;
 use hyper::header::{Headers, ContentLength, Range};
+use hyper::net::HttpsConnector;
 use hyper::status::StatusCode;
+use hyper_native_tls::NativeTlsClient;
 use std::cmp;
 use std::collections::HashMap;
 use std::io::{self, Read, Seek, SeekFrom};
-use std::str;
 
-use errors::{ErrorKind, Result};
+use errors::{ErrorKind, Result, ResultExt};
 
 // Of course, we are not actually caching because nothing ever gets expired
 // from `cache`. TBD.
@@ -22,6 +20,10 @@ const CHUNK_SIZE: usize = 8192;
 
 type Chunk = [u8; CHUNK_SIZE];
 
+/// A file on a web server that can be read and seeked like a local one,
+/// using HTTP range requests. Data are fetched in chunks, each of which is
+/// fetched only once and kept in memory, so that (for instance) the central
+/// directory of a Zip archive can be read without downloading the rest.
 pub struct SeekableHttpFile {
     url: String,
     client: Client,
@@ -33,7 +35,7 @@ pub struct SeekableHttpFile {
 
 impl SeekableHttpFile {
     pub fn new(url: &str) -> Result<SeekableHttpFile> {
-        let ssl = NativeTlsClient::new().unwrap();
+        let ssl = ctry!(NativeTlsClient::new(); "couldn't initialize the TLS library");
         let connector = HttpsConnector::new(ssl);
         let client = Client::with_connector(connector);
 
@@ -91,7 +93,6 @@ impl Read for SeekableHttpFile {
         let end_inclusive = cmp::min(start + CHUNK_SIZE as u64 - 1,
                                      self.len - 1);
         let chunk_size = (end_inclusive + 1 - start) as usize;
-        println!("FETCHING {} -> {} (inclusive)", start, end_inclusive);
 
         let mut headers = Headers::new();
         headers.set(Range::bytes(start, end_inclusive));
@@ -145,7 +146,6 @@ impl Seek for SeekableHttpFile {
             }
         };
 
-        println!("OK SEEK: {}", self.pos);
         Ok(self.pos)
     }
 }
@@ -169,19 +169,15 @@ mod tests {
         retval
     }
 
-    fn with_connected_shf<H, F, T>(h: H, f: F) -> T where H: Handler + 'static,
-                                                          F: FnOnce (&mut SeekableHttpFile) -> T {
-        with_server_thread(h, |sock| {
-            let url = format!("http://{}:{}/", sock.ip(), sock.port());
-            let mut shf = SeekableHttpFile::new(&url).unwrap();
-            f(&mut shf)
-        })
-    }
+    /// Serve `buf` at the URL passed to `f`, which has the path `/file`.
+    fn with_fixed_buf_url<B, F, T>(buf: B, f: F) -> T
+        where B: AsRef<[u8]> + Send + Sync + 'static, F: FnOnce (&str) -> T
+    {
+        let sz = buf.as_ref().len() as u64;
 
-    fn with_fixed_buf_server<F, T>(buf: &'static [u8], f: F) -> T where F: FnOnce (&mut SeekableHttpFile) -> T {
-        let sz = buf.len() as u64;
+        with_server_thread(move |req: Request, mut res: Response| {
+            let buf = buf.as_ref();
 
-        with_connected_shf(move |req: Request, mut res: Response| {
             match req.method {
                 Method::Head => {
                     res.headers_mut().set(ContentLength(buf.len() as u64));
@@ -219,7 +215,16 @@ mod tests {
                 },
                 _ => *res.status_mut() = StatusCode::MethodNotAllowed
             }
-        }, f)
+        }, |sock| {
+            f(&format!("http://{}:{}/file", sock.ip(), sock.port()))
+        })
+    }
+
+    fn with_fixed_buf_server<F, T>(buf: &'static [u8], f: F) -> T where F: FnOnce (&mut SeekableHttpFile) -> T {
+        with_fixed_buf_url(buf, |url| {
+            let mut shf = SeekableHttpFile::new(url).unwrap();
+            f(&mut shf)
+        })
     }
 
 
@@ -309,4 +314,32 @@ mod tests {
             assert_eq!(&full[..], TWO_CHUNKS);
         });
     }
+
+    #[test]
+    fn zip_bundle_over_http() {
+        use std::ffi::OsStr;
+        use std::io::Cursor;
+        use zip::{CompressionMethod, ZipWriter};
+        use io::IoProvider;
+        use io::zipbundle::ZipBundle;
+        use status::NoopStatusBackend;
+
+        // Put the entry that we read well away from the central directory,
+        // so that it has to be fetched separately.
+        let mut zw = ZipWriter::new(Cursor::new(Vec::new()));
+        zw.start_file("remote.tex", CompressionMethod::Deflated).unwrap();
+        zw.write_all(b"fetched over the network").unwrap();
+        zw.start_file("padding.bin", CompressionMethod::Stored).unwrap();
+        zw.write_all(&[0u8; 3 * CHUNK_SIZE]).unwrap();
+        let zipdata = zw.finish().unwrap().into_inner();
+
+        with_fixed_buf_url(zipdata, |url| {
+            let mut bundle = ZipBundle::open_url(url).unwrap();
+            let mut sb = NoopStatusBackend::new();
+            let mut s = String::new();
+            bundle.input_open_name(OsStr::new("remote.tex"), &mut sb).unwrap().read_to_string(&mut s).unwrap();
+            assert_eq!(s, "fetched over the network");
+            assert!(bundle.input_open_name(OsStr::new("absent.tex"), &mut sb).is_not_available());
+        })
+    }
 }
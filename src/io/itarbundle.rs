@@ -12,13 +12,16 @@ use hyper_native_tls::NativeTlsClient;
 use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::io::{BufRead, BufReader, Cursor, Read};
+use std::thread;
+use std::time::Duration;
 
 use errors::{Error, ErrorKind, Result, ResultExt};
-use super::{InputHandle, InputOrigin, IoProvider, OpenResult};
+use super::{split_index_line, InputHandle, InputOrigin, IoProvider, OpenResult};
 use status::StatusBackend;
 
 
 const MAX_HTTP_ATTEMPTS: usize = 4;
+const INITIAL_RETRY_DELAY_MS: u64 = 500;
 
 
 // A simple way to read chunks out of a big seekable byte stream. You could
@@ -65,8 +68,7 @@ impl RangeRead for HttpRangeReader {
         let res = req.send()?;
 
         if res.status != StatusCode::PartialContent {
-            // FIXME: this loses the actual status code! Should report it.
-            return Err(hyper::Error::Status.into());
+            return Err(ErrorKind::BadHttpStatus(res.status.to_u16()).into());
         }
 
         Ok(res)
@@ -95,6 +97,20 @@ pub struct ITarBundle<F: ITarIoFactory> {
     factory: F,
     data: Option<F::DataReader>,
     index: HashMap<OsString,FileInfo>,
+    max_attempts: usize,
+    retry_delay: Duration,
+}
+
+
+/// Decide whether a failed fetch is worth retrying. Errors at the network
+/// level (dropped connections, timeouts) usually are, as are server-side
+/// failures; but if the server says that it doesn't have the file, asking
+/// again won't help.
+fn is_transient(err: &Error) -> bool {
+    match *err.kind() {
+        ErrorKind::BadHttpStatus(status) => status == 408 || status == 429 || status >= 500,
+        _ => true,
+    }
 }
 
 
@@ -104,9 +120,24 @@ impl<F: ITarIoFactory> ITarBundle<F> {
             factory: factory,
             data: None,
             index: HashMap::new(),
+            max_attempts: MAX_HTTP_ATTEMPTS,
+            retry_delay: Duration::from_millis(INITIAL_RETRY_DELAY_MS),
         }
     }
 
+    /// Set how many times we will try to fetch each file before giving up.
+    pub fn max_attempts (&mut self, attempts: usize) -> &mut Self {
+        self.max_attempts = attempts;
+        self
+    }
+
+    /// Set how long we wait before the first retry of a failed fetch. The
+    /// delay doubles with each subsequent retry.
+    pub fn retry_delay (&mut self, delay: Duration) -> &mut Self {
+        self.retry_delay = delay;
+        self
+    }
+
     fn ensure_loaded(&mut self, status: &mut StatusBackend) -> Result<()> {
         if self.data.is_some() {
             return Ok(());
@@ -119,15 +150,15 @@ impl<F: ITarIoFactory> ITarBundle<F> {
 
         for res in br.lines() {
             let line = res?;
-            let bits = line.split_whitespace().collect::<Vec<_>>();
 
-            if bits.len() < 3 {
-                continue; // TODO: preserve the warning info or something!
-            }
+            let (name, bits) = match split_index_line(&line, 2) {
+                Some(x) => x,
+                None => continue, // TODO: preserve the warning info or something!
+            };
 
-            let name = OsString::from(bits[0]);
-            let offset = bits[1].parse::<u64>()?;
-            let length = bits[2].parse::<u64>()?;
+            let name = OsString::from(name);
+            let offset = bits[0].parse::<u64>()?;
+            let length = bits[1].parse::<u64>()?;
             self.index.insert(name, FileInfo { offset: offset, length: length });
         }
 
@@ -160,20 +191,34 @@ impl<F: ITarIoFactory> IoProvider for ITarBundle<F> {
         // When fetching a bunch of resource files (i.e., on the first
         // invocation), bintray will sometimes drop connections. The error
         // manifests itself in a way that has a not-so-nice user experience.
-        // Our solution: retry the HTTP a few times in case it was a transient
-        // problem.
+        // Our solution: retry the HTTP a few times, backing off
+        // exponentially, in case it was a transient problem.
 
         let mut buf = Vec::with_capacity(info.length as usize);
         let mut overall_failed = true;
         let mut any_failed = false;
+        let mut delay = self.retry_delay;
+
+        for attempt in 0..self.max_attempts {
+            if attempt > 0 {
+                thread::sleep(delay);
+                delay = delay * 2;
+            }
+
+            buf.clear();
 
-        for _ in 0..MAX_HTTP_ATTEMPTS {
             let mut stream = match self.data.as_mut().unwrap().read_range(info.offset, info.length as usize) {
                 Ok(r) => r,
                 Err(e) => {
+                    let retry = is_transient(&e);
                     tt_warning!(status, "failure requesting \"{}\" from network", name.to_string_lossy(); e.into());
                     any_failed = true;
-                    continue;
+
+                    if retry {
+                        continue;
+                    }
+
+                    break;
                 },
             };
 
@@ -197,7 +242,7 @@ impl<F: ITarIoFactory> IoProvider for ITarBundle<F> {
             tt_note!(status, "download succeeded after retry");
         }
 
-        OpenResult::Ok(InputHandle::new(name, Cursor::new(buf), InputOrigin::Other))
+        OpenResult::Ok(InputHandle::new(name, Cursor::new(buf), InputOrigin::Bundle(name.to_owned())))
     }
 }
 
@@ -268,3 +313,104 @@ impl ITarBundle<HttpITarIoFactory> {
         Self::construct(HttpITarIoFactory { url: url.to_owned() })
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use status::NoopStatusBackend;
+
+    /// A range reader that fails with a given HTTP status a certain number
+    /// of times before succeeding.
+    struct FlakyReader {
+        failure_status: u16,
+        failures_left: usize,
+        attempts: Rc<Cell<usize>>,
+    }
+
+    impl RangeRead for FlakyReader {
+        type InnerRead = Cursor<Vec<u8>>;
+
+        fn read_range(&mut self, _offset: u64, length: usize) -> Result<Cursor<Vec<u8>>> {
+            self.attempts.set(self.attempts.get() + 1);
+
+            if self.failures_left > 0 {
+                self.failures_left -= 1;
+                return Err(ErrorKind::BadHttpStatus(self.failure_status).into());
+            }
+
+            Ok(Cursor::new(vec![b'x'; length]))
+        }
+    }
+
+    struct FlakyFactory {
+        failure_status: u16,
+        failures: usize,
+        attempts: Rc<Cell<usize>>,
+    }
+
+    impl ITarIoFactory for FlakyFactory {
+        type IndexReader = Cursor<Vec<u8>>;
+        type DataReader = FlakyReader;
+
+        fn get_index(&mut self, _status: &mut StatusBackend) -> Result<Cursor<Vec<u8>>> {
+            Ok(Cursor::new(b"file.tex 0 5\nmy file 100%.tex 5 3\n".to_vec()))
+        }
+
+        fn get_data(&self) -> Result<FlakyReader> {
+            Ok(FlakyReader {
+                failure_status: self.failure_status,
+                failures_left: self.failures,
+                attempts: self.attempts.clone(),
+            })
+        }
+
+        fn report_fetch(&self, _name: &OsStr, _status: &mut StatusBackend) {}
+    }
+
+    fn flaky_bundle(failure_status: u16, failures: usize) -> (ITarBundle<FlakyFactory>, Rc<Cell<usize>>) {
+        let attempts = Rc::new(Cell::new(0));
+        let mut bundle = ITarBundle::construct(FlakyFactory {
+            failure_status: failure_status,
+            failures: failures,
+            attempts: attempts.clone(),
+        });
+        bundle.retry_delay(Duration::from_millis(0));
+        (bundle, attempts)
+    }
+
+    #[test]
+    fn transient_failures_are_retried() {
+        let (mut bundle, attempts) = flaky_bundle(503, 2);
+        let mut sb = NoopStatusBackend::new();
+        let mut buf = Vec::new();
+        bundle.input_open_name(OsStr::new("file.tex"), &mut sb).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"xxxxx");
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[test]
+    fn names_with_spaces_are_indexed() {
+        let (mut bundle, _) = flaky_bundle(503, 0);
+        let mut sb = NoopStatusBackend::new();
+        let mut buf = Vec::new();
+        bundle.input_open_name(OsStr::new("my file 100%.tex"), &mut sb).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"xxx");
+        assert!(bundle.input_open_name(OsStr::new("my"), &mut sb).is_not_available());
+    }
+
+    #[test]
+    fn missing_files_are_not_retried() {
+        let (mut bundle, attempts) = flaky_bundle(404, 1);
+        let mut sb = NoopStatusBackend::new();
+
+        match bundle.input_open_name(OsStr::new("file.tex"), &mut sb) {
+            OpenResult::Err(_) => {},
+            _ => panic!("expected the fetch to fail"),
+        }
+
+        assert_eq!(attempts.get(), 1);
+    }
+}
@@ -0,0 +1,155 @@
+// src/io/kpathsea.rs -- finding files in a system TeX installation
+// Copyright 2017 the Tectonic Project
+// Licensed under the MIT License.
+
+use std::ffi::{OsStr, OsString};
+use std::io::{self, BufReader};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use engines::FileFormat;
+use status::StatusBackend;
+use super::{try_open_file, InputHandle, InputOrigin, IoProvider, OpenResult};
+
+
+/// KpathseaIo finds input files using the `kpsewhich` program of a
+/// traditional TeX installation, if there is one. This lets documents use
+/// packages that are installed on the system but missing from the bundle.
+/// The system files can differ from the ones in the bundle in arbitrary
+/// ways, so this provider belongs at the very end of the I/O stack (see
+/// `IoStackBuilder::fallback`), where it is only consulted when nothing
+/// else has the file.
+///
+/// Files found this way are reported as coming from a bundle, since the
+/// installation plays the same role as one: they aren't dependencies of the
+/// document in the way that the user's own files are.
+///
+/// If the program can't be run, every file is reported as not available.
+
+pub struct KpathseaIo {
+    program: OsString,
+    usable: bool,
+}
+
+
+impl Default for KpathseaIo {
+    fn default() -> Self {
+        KpathseaIo::new()
+    }
+}
+
+
+impl KpathseaIo {
+    /// Search with the `kpsewhich` found on the `$PATH`.
+    pub fn new() -> KpathseaIo {
+        KpathseaIo::with_program("kpsewhich")
+    }
+
+    /// Search with the given `kpsewhich` program.
+    pub fn with_program<S: AsRef<OsStr>>(program: S) -> KpathseaIo {
+        KpathseaIo {
+            program: program.as_ref().to_os_string(),
+            usable: true,
+        }
+    }
+
+    /// Ask `kpsewhich` where the file `name` of the given format is. The
+    /// format decides which directories are searched and which suffixes are
+    /// tried; if it's None, `kpsewhich` guesses it from the name. Its exit
+    /// status is nonzero if it can't find the file.
+    pub fn lookup(&mut self, name: &OsStr, format: Option<FileFormat>) -> OpenResult<PathBuf> {
+        // Names that look like options would be taken as such.
+        if !self.usable || name.is_empty() || name.as_bytes()[0] == b'-' {
+            return OpenResult::NotAvailable;
+        }
+
+        let mut cmd = Command::new(&self.program);
+
+        if let Some(f) = format {
+            cmd.arg(format!("-format={}", f.kpsewhich_name()));
+        }
+
+        let output = match cmd.arg(name).stdin(Stdio::null()).stderr(Stdio::null()).output() {
+            Ok(o) => o,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                // No TeX installation; don't bother trying again.
+                self.usable = false;
+                return OpenResult::NotAvailable;
+            },
+            Err(e) => return OpenResult::Err(e.into()),
+        };
+
+        if !output.status.success() {
+            return OpenResult::NotAvailable;
+        }
+
+        let path = match output.stdout.split(|&b| b == b'\n').next() {
+            Some(p) if !p.is_empty() => p,
+            _ => return OpenResult::NotAvailable,
+        };
+
+        OpenResult::Ok(PathBuf::from(OsStr::from_bytes(path)))
+    }
+}
+
+
+impl IoProvider for KpathseaIo {
+    fn input_open_name(&mut self, name: &OsStr, _status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        // The engines try each candidate name with its suffix, so we can
+        // tell the format from the name.
+        let path = match self.lookup(name, FileFormat::for_file_name(name)) {
+            OpenResult::Ok(p) => p,
+            OpenResult::NotAvailable => return OpenResult::NotAvailable,
+            OpenResult::Err(e) => return OpenResult::Err(e),
+        };
+
+        let f = match try_open_file(&path) {
+            OpenResult::Ok(f) => f,
+            OpenResult::NotAvailable => return OpenResult::NotAvailable,
+            OpenResult::Err(e) => return OpenResult::Err(e),
+        };
+
+        OpenResult::Ok(InputHandle::new(name, BufReader::new(f), InputOrigin::Bundle(name.to_os_string())))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use status::NoopStatusBackend;
+
+    #[test]
+    fn missing_program_is_not_available() {
+        let mut kio = KpathseaIo::with_program("tectonic-test-no-such-kpsewhich");
+        let mut sb = NoopStatusBackend::new();
+
+        assert!(kio.input_open_name(OsStr::new("article.cls"), &mut sb).is_not_available());
+        assert!(!kio.usable);
+    }
+
+    #[test]
+    fn files_are_found_by_format() {
+        use std::io::Read;
+
+        let mut kio = KpathseaIo::new();
+
+        // Only run this where there is a TeX installation.
+        if kio.lookup(OsStr::new("plain.tex"), None).is_not_available() {
+            return;
+        }
+
+        // Without the format, "cmr10" would be looked for as a TeX file.
+        let path = kio.lookup(OsStr::new("cmr10"), Some(FileFormat::TFM)).unwrap();
+        assert!(path.ends_with("cmr10.tfm"));
+
+        let mut sb = NoopStatusBackend::new();
+        let mut ih = kio.input_open_name(OsStr::new("plain.tex"), &mut sb).unwrap();
+        assert_eq!(ih.origin(), InputOrigin::Bundle(OsString::from("plain.tex")));
+
+        let mut text = String::new();
+        ih.read_to_string(&mut text).unwrap();
+        assert!(text.contains("\\def"));
+    }
+}
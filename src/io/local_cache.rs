@@ -14,7 +14,8 @@ use std::str::FromStr;
 
 use digest::{self, Digest, DigestData};
 use errors::{ErrorKind, Result, ResultExt};
-use super::{try_open_file, InputHandle, InputOrigin, IoProvider, OpenResult};
+use super::{split_index_line, try_open_file, InputFeatures, InputHandle, InputOrigin, IoProvider, OpenResult};
+use super::filesystem::ensure_free_space;
 use status::StatusBackend;
 
 
@@ -23,6 +24,21 @@ struct LocalCacheItem {
     digest: Option<DigestData>, // None => negative cache: this file is not in the bundle
 }
 
+/// Counts of how lookups in a LocalCache were satisfied, to help judge how
+/// much good the cache is doing.
+#[derive(Clone,Copy,Debug,Default,Eq,PartialEq)]
+pub struct CacheStats {
+    /// Lookups answered from the cache, including those for files that
+    /// are known not to exist.
+    pub hits: u64,
+
+    /// Lookups that had to consult the backend.
+    pub misses: u64,
+
+    /// Files that were fetched from the backend and saved in the cache.
+    pub extractions: u64,
+}
+
 pub struct LocalCache<B: IoProvider> {
     backend: B,
     digest_path: PathBuf,
@@ -32,6 +48,7 @@ pub struct LocalCache<B: IoProvider> {
     formats_base: PathBuf,
     data_path: PathBuf,
     contents: HashMap<OsString,LocalCacheItem>,
+    stats: CacheStats,
 }
 
 
@@ -113,13 +130,10 @@ impl<B: IoProvider> LocalCache<B> {
 
                 for res in f.lines() {
                     let line = res?;
-                    let mut bits = line.rsplitn(3, ' ');
 
-
-                    let (original_name, length, digest) = match (bits.next(), bits.next(),
-                                                                 bits.next(), bits.next()) {
-                        (Some(s), Some(t), Some(r), None) => (r, t, s),
-                        _ => continue,
+                    let (original_name, length, digest) = match split_index_line(&line, 2) {
+                        Some((name, bits)) => (name, bits[0], bits[1]),
+                        None => continue,
                     };
 
                     let name = OsString::from(original_name);
@@ -157,11 +171,18 @@ impl<B: IoProvider> LocalCache<B> {
             manifest_path: manifest_path,
             formats_base: formats_base.to_owned(),
             data_path: data.to_owned(),
-            contents: contents
+            contents: contents,
+            stats: CacheStats::default(),
         })
     }
 
 
+    /// Get the counts of cache hits and misses since this cache was opened.
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+
     fn record_cache_result(&mut self, name: &OsStr, length: u64, digest: Option<DigestData>) -> Result<()> {
         let digest_text = match digest {
             Some(ref d) => d.to_string(),
@@ -231,6 +252,7 @@ impl<B: IoProvider> LocalCache<B> {
 
     fn path_for_name(&mut self, name: &OsStr, status: &mut StatusBackend) -> OpenResult<PathBuf> {
         if let Some(info) = self.contents.get(name) {
+            self.stats.hits += 1;
             return match info.digest {
                 None => OpenResult::NotAvailable,
                 Some(ref d) => match d.create_two_part_path(&self.data_path) {
@@ -246,6 +268,8 @@ impl<B: IoProvider> LocalCache<B> {
         // Fun times. Because we're touching the backend, we need to verify that
         // its digest is what we think.
 
+        self.stats.misses += 1;
+
         if let Err(e) = self.check_digest(status) {
             return OpenResult::Err(e);
         }
@@ -267,7 +291,14 @@ impl<B: IoProvider> LocalCache<B> {
         };
 
         // OK, we can stream the file to a temporary location on disk,
-        // computing its SHA256 as we go.
+        // computing its SHA256 as we go. If we know how big it is, make sure
+        // that it'll fit first.
+
+        if let Ok(size) = stream.get_size() {
+            if let Err(e) = ensure_free_space(&self.data_path, size as u64) {
+                return OpenResult::Err(e);
+            }
+        }
 
         let mut digest_builder = digest::create();
         let mut length = 0;
@@ -333,6 +364,7 @@ impl<B: IoProvider> LocalCache<B> {
             return OpenResult::Err(e.into());
         }
 
+        self.stats.extractions += 1;
         OpenResult::Ok(final_path)
     }
 
@@ -375,7 +407,7 @@ impl<B: IoProvider> IoProvider for LocalCache<B> {
             Err(e) => return OpenResult::Err(e.into())
         };
 
-        OpenResult::Ok(InputHandle::new(name, BufReader::new(f), InputOrigin::Other))
+        OpenResult::Ok(InputHandle::new(name, BufReader::new(f), InputOrigin::Bundle(name.to_owned())))
     }
 
 
@@ -397,6 +429,7 @@ impl<B: IoProvider> IoProvider for LocalCache<B> {
 
     fn write_format(&mut self, name: &str, data: &[u8], _status: &mut StatusBackend) -> Result<()> {
         let final_path = self.path_for_format(OsStr::new(name))?;
+        ensure_free_space(&self.formats_base, data.len() as u64)?;
 
         let mut templ = self.formats_base.clone();
         templ.push("format_XXXXXX");
@@ -410,3 +443,54 @@ impl<B: IoProvider> IoProvider for LocalCache<B> {
         fs::rename(&temp_path, &final_path).map_err(|e| e.into())
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::MemoryIo;
+    use status::NoopStatusBackend;
+    use tempdir::TempDir;
+
+    #[test]
+    fn stats_count_hits_and_misses() {
+        let tempdir = TempDir::new("tectonic_cache_test").unwrap();
+        let digest_text = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        let digest_path = tempdir.path().join("digest");
+        File::create(&digest_path).unwrap().write_all(digest_text.as_bytes()).unwrap();
+
+        let mut backend = MemoryIo::new(false);
+        backend.create_entry(OsStr::new(digest::DIGEST_NAME), digest_text.as_bytes().to_owned());
+        backend.create_entry(OsStr::new("a.tex"), b"hello".to_vec());
+
+        let mut sb = NoopStatusBackend::new();
+        let mut cache = LocalCache::new(backend, &digest_path, tempdir.path(), tempdir.path(),
+                                        tempdir.path(), &mut sb).unwrap();
+
+        for _ in 0..3 {
+            cache.input_open_name(OsStr::new("a.tex"), &mut sb).unwrap();
+            assert!(cache.input_open_name(OsStr::new("missing.tex"), &mut sb).is_not_available());
+        }
+
+        assert_eq!(cache.stats(), CacheStats { hits: 4, misses: 2, extractions: 1 });
+    }
+
+    #[test]
+    fn crlf_manifests_are_read() {
+        let tempdir = TempDir::new("tectonic_cache_test").unwrap();
+        let digest_text = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        let digest_path = tempdir.path().join("digest");
+        File::create(&digest_path).unwrap().write_all(digest_text.as_bytes()).unwrap();
+
+        let manifest_path = tempdir.path().join(format!("{}.txt", digest_text));
+        File::create(&manifest_path).unwrap()
+            .write_all(format!("my file.tex 5 {}\r\ngone.sty 0 -\r\n", digest_text).as_bytes()).unwrap();
+
+        let mut sb = NoopStatusBackend::new();
+        let cache = LocalCache::new(MemoryIo::new(false), &digest_path, tempdir.path(), tempdir.path(),
+                                    tempdir.path(), &mut sb).unwrap();
+
+        assert_eq!(cache.contents[OsStr::new("my file.tex")].digest.unwrap().to_string(), digest_text);
+        assert!(cache.contents[OsStr::new("gone.sty")].digest.is_none());
+    }
+}
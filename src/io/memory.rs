@@ -2,16 +2,17 @@
 // Copyright 2016-2017 the Tectonic Project
 // Licensed under the MIT License.
 
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cell::{Ref, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map;
 use std::ffi::{OsStr, OsString};
 use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
 use std::rc::Rc;
 
-use errors::Result;
+use errors::{ErrorKind, Result};
 use status::StatusBackend;
-use super::{InputFeatures, InputHandle, InputOrigin, IoProvider, OpenResult, OutputHandle,
-            normalize_tex_path};
+use super::{InputFeatures, InputHandle, InputOrigin, IoProvider, OpenResult, OutputDestination,
+            OutputHandle, normalize_tex_path};
 
 
 // MemoryIo is an IoProvider that stores "files" in in-memory buffers.
@@ -46,6 +47,20 @@ impl MemoryIoItem {
             state: Cursor::new(cur)
         }
     }
+
+    /// Open `name` for writing, emptying it. If it doesn't exist yet, its
+    /// storage comes from `spare` if possible.
+    fn for_output(files: &Rc<RefCell<HashMap<OsString, Vec<u8>>>>, name: &OsStr,
+                  spare: &mut Vec<Vec<u8>>) -> MemoryIoItem {
+        let mut cur = files.borrow_mut().remove(name).or_else(|| spare.pop()).unwrap_or_else(Vec::new);
+        cur.clear();
+
+        MemoryIoItem {
+            files: files.clone(),
+            name: name.to_os_string(),
+            state: Cursor::new(cur)
+        }
+    }
 }
 
 impl Read for MemoryIoItem {
@@ -94,6 +109,8 @@ impl Drop for MemoryIoItem {
 pub struct MemoryIo {
     pub files: Rc<RefCell<HashMap<OsString, Vec<u8>>>>,
     stdout_allowed: bool,
+    written: HashSet<OsString>,
+    spare: Vec<Vec<u8>>,
 }
 
 impl MemoryIo {
@@ -101,6 +118,8 @@ impl MemoryIo {
         MemoryIo {
             files: Rc::new(RefCell::new(HashMap::new())),
             stdout_allowed: stdout_allowed,
+            written: HashSet::new(),
+            spare: Vec::new(),
         }
     }
 
@@ -112,6 +131,155 @@ impl MemoryIo {
     pub fn stdout_key(& self) -> &OsStr {
         OsStr::new("")
     }
+
+    /// Get access to all of the files held in memory, without copying their
+    /// contents. Iterate over the return value to get `(name, data)` pairs.
+    /// Besides the engine's outputs, this includes any files added with
+    /// `create_entry()` and the captured standard output, which is named
+    /// `stdout_key()`.
+    ///
+    /// The files stay borrowed until the return value is dropped, so it
+    /// must not be held while the engine is running.
+    pub fn iter_outputs(&self) -> MemoryOutputs {
+        MemoryOutputs { files: self.files.borrow() }
+    }
+
+    /// Forget every file that has been opened for writing, including the
+    /// captured standard output, so that the MemoryIo can be reused for
+    /// another compile. Files added with `create_entry()` stay unless they
+    /// were overwritten. The memory of the forgotten files is kept and
+    /// handed out again as new files are written, which saves a server
+    /// running many compiles from reallocating it each time.
+    pub fn clear_outputs(&mut self) {
+        let mut mfiles = self.files.borrow_mut();
+
+        for name in self.written.drain() {
+            if let Some(data) = mfiles.remove(&name) {
+                self.spare.push(data);
+            }
+        }
+    }
+
+    /// Like `clear_outputs()`, but forget the files added with
+    /// `create_entry()` too.
+    pub fn clear_all(&mut self) {
+        self.clear_outputs();
+        self.spare.extend(self.files.borrow_mut().drain().map(|(_, data)| data));
+    }
+
+    /// Copy the files of `other` into this one, as when combining the
+    /// outputs of separately compiled parts of a document. If a file exists
+    /// in both with different contents, nothing is copied and an error
+    /// naming all such files is returned; identical copies are fine. The
+    /// captured standard output belongs to each compile alone, so it is
+    /// not merged.
+    pub fn merge(&mut self, other: &MemoryIo) -> Result<()> {
+        if Rc::ptr_eq(&self.files, &other.files) {
+            return Ok(());
+        }
+
+        let mut mfiles = self.files.borrow_mut();
+        let ofiles = other.files.borrow();
+        let stdout_key = other.stdout_key();
+
+        let mut conflicts = ofiles.iter()
+            .filter(|&(name, data)| name.as_os_str() != stdout_key && mfiles.get(name).map_or(false, |d| d != data))
+            .map(|(name, _)| name.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+
+        if !conflicts.is_empty() {
+            conflicts.sort();
+            return Err(ErrorKind::Msg(format!("conflicting versions of output file(s): {}",
+                                              conflicts.join(", "))).into());
+        }
+
+        for (name, data) in ofiles.iter() {
+            if name.as_os_str() != stdout_key {
+                mfiles.insert(name.clone(), data.clone());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Merge the files of several MemoryIos into a new one, failing if any two
+/// of them disagree about the contents of a file; see `MemoryIo::merge()`.
+pub fn merge_outputs<'a, I: IntoIterator<Item = &'a MemoryIo>>(parts: I) -> Result<MemoryIo> {
+    let mut merged = MemoryIo::new(false);
+
+    for part in parts {
+        merged.merge(part)?;
+    }
+
+    Ok(merged)
+}
+
+
+/// MemoryPrimaryIo serves one of the files of a MemoryIo as the primary
+/// input. This is handy when documents and their inputs all live together
+/// in memory, e.g. in a server; see `primary_from_named()`.
+pub struct MemoryPrimaryIo {
+    files: Rc<RefCell<HashMap<OsString, Vec<u8>>>>,
+    name: OsString,
+}
+
+/// Create a provider whose primary input is the file called `name` in
+/// `mem`. The file stays in `mem`, which shares its storage with the new
+/// provider, so the file may also be opened under its name as usual. If the
+/// file doesn't exist when the engine asks for the primary input, the
+/// provider reports it as not available.
+pub fn primary_from_named(mem: &MemoryIo, name: &OsStr) -> MemoryPrimaryIo {
+    MemoryPrimaryIo {
+        files: mem.files.clone(),
+        name: normalize_tex_path(name).into_owned(),
+    }
+}
+
+impl IoProvider for MemoryPrimaryIo {
+    fn input_open_primary(&mut self, _status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        if !self.files.borrow().contains_key(&self.name) {
+            return OpenResult::NotAvailable;
+        }
+
+        OpenResult::Ok(InputHandle::new(OsStr::new(""),
+                                        MemoryIoItem::new(&self.files, &self.name, false),
+                                        InputOrigin::Other))
+    }
+}
+
+
+/// A borrowed view of the files in a MemoryIo; see `MemoryIo::iter_outputs`.
+pub struct MemoryOutputs<'a> {
+    files: Ref<'a, HashMap<OsString, Vec<u8>>>,
+}
+
+impl<'a> MemoryOutputs<'a> {
+    pub fn iter(&self) -> MemoryOutputsIter {
+        MemoryOutputsIter { inner: self.files.iter() }
+    }
+}
+
+impl<'a, 'b> IntoIterator for &'b MemoryOutputs<'a> {
+    type Item = (&'b OsStr, &'b [u8]);
+    type IntoIter = MemoryOutputsIter<'b>;
+
+    fn into_iter(self) -> MemoryOutputsIter<'b> {
+        self.iter()
+    }
+}
+
+pub struct MemoryOutputsIter<'b> {
+    inner: hash_map::Iter<'b, OsString, Vec<u8>>,
+}
+
+impl<'b> Iterator for MemoryOutputsIter<'b> {
+    type Item = (&'b OsStr, &'b [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(k, v)| (k.as_os_str(), &v[..]))
+    }
 }
 
 impl IoProvider for MemoryIo {
@@ -119,8 +287,12 @@ impl IoProvider for MemoryIo {
         assert!(name.len() > 0, "name must be non-empty");
 
         let name = normalize_tex_path(name);
+        self.written.insert(name.clone().into_owned());
 
-        OpenResult::Ok(OutputHandle::new(&name, MemoryIoItem::new(&self.files, &name, true)))
+        OpenResult::Ok(OutputHandle::new_with_destination(&name,
+                                                          MemoryIoItem::for_output(&self.files, &name,
+                                                                                   &mut self.spare),
+                                                          OutputDestination::Memory))
     }
 
     fn output_open_stdout(&mut self) -> OpenResult<OutputHandle> {
@@ -128,7 +300,13 @@ impl IoProvider for MemoryIo {
             return OpenResult::NotAvailable;
         }
 
-        OpenResult::Ok(OutputHandle::new(self.stdout_key(), MemoryIoItem::new(&self.files, self.stdout_key(), true)))
+        let key = self.stdout_key().to_os_string();
+        self.written.insert(key.clone());
+
+        OpenResult::Ok(OutputHandle::new_with_destination(&key,
+                                                          MemoryIoItem::for_output(&self.files, &key,
+                                                                                   &mut self.spare),
+                                                          OutputDestination::Memory))
     }
 
     fn input_open_name(&mut self, name: &OsStr, _status: &mut StatusBackend) -> OpenResult<InputHandle> {
@@ -147,6 +325,40 @@ impl IoProvider for MemoryIo {
 }
 
 
+/// A MemoryIo that counts the input lookups and output opens that reach it,
+/// for tests of layers that are supposed to save work.
+#[cfg(test)]
+pub struct CountingIo {
+    pub mem: MemoryIo,
+    pub lookups: usize,
+    pub opens: usize,
+}
+
+#[cfg(test)]
+impl CountingIo {
+    pub fn new() -> CountingIo {
+        CountingIo {
+            mem: MemoryIo::new(false),
+            lookups: 0,
+            opens: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+impl IoProvider for CountingIo {
+    fn output_open_name(&mut self, name: &OsStr) -> OpenResult<OutputHandle> {
+        self.opens += 1;
+        self.mem.output_open_name(name)
+    }
+
+    fn input_open_name(&mut self, name: &OsStr, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        self.lookups += 1;
+        self.mem.input_open_name(name, status)
+    }
+}
+
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -200,4 +412,108 @@ mod tests {
             assert_eq!(s.len(), 0);
         }
     }
+
+    #[test]
+    fn primary_from_named_file() {
+        let mut mem = MemoryIo::new(false);
+        mem.create_entry(OsStr::new("docs/a.tex"), b"document A".to_vec());
+        mem.create_entry(OsStr::new("docs/b.tex"), b"document B".to_vec());
+        let mut sb = NoopStatusBackend::new();
+
+        for _ in 0..2 {
+            let mut pio = primary_from_named(&mem, OsStr::new("./docs/b.tex"));
+            let mut s = String::new();
+            pio.input_open_primary(&mut sb).unwrap().read_to_string(&mut s).unwrap();
+            assert_eq!(s, "document B");
+        }
+
+        // The file is still available under its own name.
+        assert_eq!(mem.input_open_name(OsStr::new("docs/b.tex"), &mut sb).unwrap().get_size().unwrap(), 10);
+        assert!(primary_from_named(&mem, OsStr::new("c.tex")).input_open_primary(&mut sb).is_not_available());
+    }
+
+    #[test]
+    fn iterate_outputs() {
+        let mut mem = MemoryIo::new(true);
+
+        {
+            let mut h = mem.output_open_name(OsStr::new("doc.aux")).unwrap();
+            write!(h, "aux").unwrap();
+        }
+        {
+            let mut h = mem.output_open_name(OsStr::new("doc.log")).unwrap();
+            write!(h, "log").unwrap();
+        }
+        {
+            let mut h = mem.output_open_stdout().unwrap();
+            write!(h, "chatter").unwrap();
+        }
+
+        let mut seen = mem.iter_outputs().iter()
+            .map(|(name, data)| (name.to_os_string(), data.to_vec()))
+            .collect::<Vec<_>>();
+        seen.sort();
+
+        assert_eq!(seen, vec![
+            (OsString::from(""), b"chatter".to_vec()),
+            (OsString::from("doc.aux"), b"aux".to_vec()),
+            (OsString::from("doc.log"), b"log".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn merge_detects_conflicts() {
+        let mut a = MemoryIo::new(true);
+        a.create_entry(OsStr::new("ch1.aux"), b"one".to_vec());
+        a.create_entry(OsStr::new("shared.tex"), b"same".to_vec());
+        a.create_entry(OsStr::new(""), b"chatter".to_vec());
+        let mut b = MemoryIo::new(true);
+        b.create_entry(OsStr::new("ch2.aux"), b"two".to_vec());
+        b.create_entry(OsStr::new("shared.tex"), b"same".to_vec());
+        b.create_entry(OsStr::new(""), b"other chatter".to_vec());
+
+        let mut merged = merge_outputs(&[a, b]).unwrap();
+        let mut names = merged.files.borrow().keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec![OsString::from("ch1.aux"), OsString::from("ch2.aux"),
+                               OsString::from("shared.tex")]);
+
+        let mut c = MemoryIo::new(false);
+        c.create_entry(OsStr::new("ch1.aux"), b"other".to_vec());
+        c.create_entry(OsStr::new("ch3.aux"), b"three".to_vec());
+        let e = merged.merge(&c).unwrap_err();
+        assert!(e.to_string().contains("ch1.aux"));
+        assert!(!merged.files.borrow().contains_key(OsStr::new("ch3.aux")));
+    }
+
+    #[test]
+    fn outputs_are_cleared_between_compiles() {
+        let mut mem = MemoryIo::new(true);
+        mem.create_entry(OsStr::new("doc.tex"), b"\\input chapter".to_vec());
+        let mut sb = NoopStatusBackend::new();
+
+        let outputs = |mem: &MemoryIo| {
+            let mut names = mem.iter_outputs().iter().map(|(n, _)| n.to_os_string()).collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        for &(run, out) in &[("first", "first.log"), ("second", "second.log")] {
+            mem.clear_outputs();
+            assert_eq!(outputs(&mem), vec![OsString::from("doc.tex")]);
+            assert!(mem.input_open_name(OsStr::new("first.log"), &mut sb).is_not_available());
+
+            mem.output_open_name(OsStr::new(out)).unwrap().write_all(run.as_bytes()).unwrap();
+            mem.output_open_stdout().unwrap().write_all(run.as_bytes()).unwrap();
+            assert_eq!(outputs(&mem), vec![OsString::from(""), OsString::from("doc.tex"), OsString::from(out)]);
+            assert_eq!(&mem.files.borrow()[OsStr::new(out)][..], run.as_bytes());
+        }
+
+        // The buffers of the first run's outputs went into the second's.
+        assert!(mem.spare.is_empty());
+
+        mem.clear_all();
+        assert!(mem.files.borrow().is_empty());
+        assert_eq!(mem.spare.len(), 3);
+    }
 }
@@ -5,40 +5,83 @@
 use flate2::read::GzDecoder;
 use std::borrow::Cow;
 use std::ffi::{OsStr, OsString};
-use std::fs::File;
+#[cfg(not(feature = "no-filesystem"))] use std::fs::File;
 use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use digest::{self, Digest, DigestData};
 use errors::{Error, ErrorKind, Result};
 use status::StatusBackend;
 
-pub mod filesystem;
-//pub mod hyper_seekable; -- Not currently used, but nice code to keep around.
+#[cfg(feature = "xz")] use xz2::read::XzDecoder;
+
+// Everything that touches the filesystem can be compiled out using the
+// `no-filesystem` feature.
+
+pub mod auxseed;
+pub mod budget;
+pub mod dedup;
+#[cfg(not(feature = "no-filesystem"))] pub mod filesystem;
+#[cfg(feature = "git")] pub mod git;
+pub mod hyper_seekable;
 pub mod itarbundle;
-pub mod local_cache;
+#[cfg(all(feature = "kpathsea", not(feature = "no-filesystem")))] pub mod kpathsea;
+#[cfg(not(feature = "no-filesystem"))] pub mod local_cache;
 pub mod memory;
+pub mod overlay;
+#[cfg(feature = "sqlite")] pub mod sqlite;
 pub mod stack;
 pub mod stdstreams;
+#[cfg(all(feature = "system-fonts", not(feature = "no-filesystem")))] pub mod systemfonts;
+pub mod tarball;
+pub mod texmfconfig;
 pub mod zipbundle;
+pub mod zipindex;
 
 
 
 pub trait InputFeatures: Read {
     fn get_size(&mut self) -> Result<usize>;
     fn try_seek(&mut self, pos: SeekFrom) -> Result<u64>;
+
+    /// Check that seeking and sizing are consistent with the data that can
+    /// actually be read: seeking to the end should land at `get_size()`,
+    /// and reading from the start should yield exactly that many bytes.
+    /// This is meant for tests and for diagnosing broken readers. On
+    /// success the stream is left positioned at its start. Streams that
+    /// can't seek at all yield an error.
+    fn seek_read_roundtrip(&mut self) -> Result<bool> {
+        let end = self.try_seek(SeekFrom::End(0))?;
+        let size = self.get_size()? as u64;
+
+        if end != size {
+            return Ok(false);
+        }
+
+        if self.try_seek(SeekFrom::Start(0))? != 0 {
+            return Ok(false);
+        }
+
+        let n_read = io::copy(self, &mut io::sink())?;
+        self.try_seek(SeekFrom::Start(0))?;
+        Ok(n_read == size)
+    }
 }
 
 
-/// What kind of source an input file ultimately came from. We keep track of
-/// this in order to be able to emit Makefile-style dependencies for input
-/// files. Right now, we only provide enough options to achieve this goal; we
-/// could add more.
-#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+/// What kind of source an input file ultimately came from, and where
+/// exactly it was found. We keep track of this in order to be able to emit
+/// Makefile-style dependencies and `.fls` file lists for input files, which
+/// should name the files that were actually read rather than the names
+/// that the engine asked for.
+#[derive(Clone,Debug,Eq,PartialEq)]
 pub enum InputOrigin {
-    /// This file lives on the filesystem and might change under us. (That is
-    /// it is not a cached bundle file.)
-    Filesystem,
+    /// This file lives on the filesystem, at the given path, and might
+    /// change under us. (That is it is not a cached bundle file.)
+    Filesystem(PathBuf),
+
+    /// This file came out of a bundle, where it has the given name.
+    Bundle(OsString),
 
     /// This file was never used as an input.
     NotInput,
@@ -47,6 +90,16 @@ pub enum InputOrigin {
     Other,
 }
 
+impl InputOrigin {
+    /// The path of the file, if it came from the filesystem.
+    pub fn path(&self) -> Option<&Path> {
+        match *self {
+            InputOrigin::Filesystem(ref p) => Some(p),
+            _ => None,
+        }
+    }
+}
+
 
 /// Input handles are basically Read objects with a few extras. We don't
 /// require the standard io::Seek because we need to provide a dummy
@@ -101,7 +154,7 @@ impl InputHandle {
     }
 
     pub fn origin(&self) -> InputOrigin {
-        self.origin
+        self.origin.clone()
     }
 
     /// Consumes the object and returns the underlying readable handle that
@@ -201,19 +254,85 @@ impl InputFeatures for InputHandle {
 }
 
 
+/// Where the data written to an output handle actually end up. The
+/// `output_open_stdout` and `output_open_name` entry points of IoProvider
+/// already distinguish the first two cases, but once a handle has been
+/// created that information is otherwise lost. Callers can use it to decide
+/// how to surface results to the user.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub enum OutputDestination {
+    /// The data are going to the process's genuine standard output stream.
+    Stdout,
+
+    /// The data are going to a named file, presumably on the filesystem.
+    Named(OsString),
+
+    /// The data are being captured into an in-memory buffer.
+    Memory,
+
+    /// The data are being streamed to a writer supplied by the caller.
+    Stream,
+}
+
+
+/// Output streams that have work to do once all of their data have been
+/// written, such as moving a finished file into place. A stream that is
+/// dropped without being finished should assume that the output is
+/// incomplete.
+pub trait OutputFeatures: Write {
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+
+/// Adapts a plain writer to OutputFeatures: finishing it just flushes it.
+struct FlushOnFinish<T: Write>(T);
+
+impl<T: Write> Write for FlushOnFinish<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<T: Write> OutputFeatures for FlushOnFinish<T> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+
 pub struct OutputHandle {
     name: OsString,
-    inner: Box<Write>,
+    inner: Box<OutputFeatures>,
     digest: digest::DigestComputer,
+    destination: OutputDestination,
 }
 
 
 impl OutputHandle {
+    /// Create a new handle. Its destination is recorded as a file with the
+    /// specified name; use `new_with_destination()` if that's not right.
     pub fn new<T: 'static + Write>(name: &OsStr, inner: T) -> OutputHandle {
+        Self::new_with_destination(name, inner, OutputDestination::Named(name.to_os_string()))
+    }
+
+    pub fn new_with_destination<T: 'static + Write>(name: &OsStr, inner: T,
+                                                    destination: OutputDestination) -> OutputHandle {
+        Self::new_with_features(name, FlushOnFinish(inner), destination)
+    }
+
+    /// Create a handle for a stream that needs to know when the output is
+    /// complete; see `finish()`.
+    pub fn new_with_features<T: 'static + OutputFeatures>(name: &OsStr, inner: T,
+                                                          destination: OutputDestination) -> OutputHandle {
         OutputHandle {
             name: name.to_os_string(),
             inner: Box::new(inner),
             digest: digest::create(),
+            destination: destination,
         }
     }
 
@@ -221,12 +340,24 @@ impl OutputHandle {
         self.name.as_os_str()
     }
 
+    /// Returns where the data written to this handle are going.
+    pub fn destination(&self) -> &OutputDestination {
+        &self.destination
+    }
+
     /// Consumes the object and returns the underlying writable handle that
     /// it references.
-    pub fn into_inner(self) -> Box<Write> {
+    pub fn into_inner(self) -> Box<OutputFeatures> {
         self.inner
     }
 
+    /// Declare the output complete. For files written atomically, this is
+    /// what moves the new file into place; a handle that is dropped without
+    /// being finished may leave nothing behind.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.inner.finish()
+    }
+
     /// Consumes the object and returns the SHA256 sum of the content that was
     /// written.
     pub fn into_name_digest(self) -> (OsString, DigestData) {
@@ -246,6 +377,12 @@ impl Write for OutputHandle {
     }
 }
 
+impl OutputFeatures for OutputHandle {
+    fn finish(&mut self) -> io::Result<()> {
+        OutputHandle::finish(self)
+    }
+}
+
 
 // An Io provider is a source of handles. One wrinkle is that it's good to be
 // able to distinguish between unavailability of a given name and error
@@ -277,6 +414,17 @@ impl<T> OpenResult<T> {
             false
         }
     }
+
+    /// Convert this result into a standard I/O result, so that it can be
+    /// used with code that expects one. NotAvailable becomes an error of kind
+    /// `NotFound`.
+    pub fn into_io_result(self) -> io::Result<T> {
+        match self {
+            OpenResult::Ok(t) => Ok(t),
+            OpenResult::NotAvailable => Err(io::Error::new(io::ErrorKind::NotFound, "not available")),
+            OpenResult::Err(e) => Err(e.into()),
+        }
+    }
 }
 
 
@@ -333,9 +481,11 @@ impl<R: Read> InputFeatures for GzDecoder<R> {
 }
 
 
-impl InputFeatures for Cursor<Vec<u8>> {
+/// In-memory data can be read through a Cursor over anything that can be
+/// viewed as bytes: a `Vec<u8>`, a `&[u8]`, a shared buffer, and so on.
+impl<T: AsRef<[u8]>> InputFeatures for Cursor<T> {
     fn get_size(&mut self) -> Result<usize> {
-        Ok(self.get_ref().len())
+        Ok(self.get_ref().as_ref().len())
     }
 
     fn try_seek(&mut self, pos: SeekFrom) -> Result<u64> {
@@ -344,16 +494,92 @@ impl InputFeatures for Cursor<Vec<u8>> {
 }
 
 
+// Transparent decompression
+
+const GZIP_MAGIC: &'static [u8] = b"\x1f\x8b";
+const XZ_MAGIC: &'static [u8] = b"\xfd7zXZ\x00";
+
+/// The default limit on the size of the data that `decompress_if_compressed`
+/// will produce. This matches the limit on the size of bundle entries.
+pub const DEFAULT_MAX_DECOMPRESSED_SIZE: u64 = 1 << 30;
+
+/// Read all of `src`, failing if it yields more than `limit` bytes. This is
+/// for data whose size we can't trust, such as the output of a
+/// decompressor, so the bytes are counted as they arrive. The error message
+/// calls the data `what`.
+fn read_limited<R: Read>(src: R, limit: u64, what: &str) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+
+    // Reading one byte past the limit tells us whether it was exceeded.
+    src.take(limit + 1).read_to_end(&mut data)?;
+
+    if data.len() as u64 > limit {
+        return Err(ErrorKind::Msg(format!("{} is larger than the limit of {} bytes", what, limit)).into());
+    }
+
+    Ok(data)
+}
+
+/// Some bundles store support files such as font metrics compressed, under
+/// their usual names. If the data behind `ih` start with the magic number of
+/// a gzip or xz stream, return a handle to the decompressed data, keeping
+/// the name and origin of `ih`; otherwise, return `ih` rewound to its start.
+/// We go by the contents rather than the file name, since the name is the
+/// one that the engine asked for. Streams that can't seek are returned
+/// untouched. Decompression happens up front, so that the new handle can
+/// seek and report its size; if it would produce more than `limit` bytes,
+/// an error is returned instead.
+pub fn decompress_if_compressed(mut ih: InputHandle, limit: u64) -> Result<InputHandle> {
+    if ih.try_seek(SeekFrom::Current(0)).is_err() {
+        return Ok(ih);
+    }
+
+    let name = ih.name().to_os_string();
+    let origin = ih.origin();
+
+    let mut magic = Vec::new();
+    (&mut ih).take(XZ_MAGIC.len() as u64).read_to_end(&mut magic)?;
+    ih.try_seek(SeekFrom::Start(0))?;
+
+    let what = format!("the decompressed form of \"{}\"", name.to_string_lossy());
+
+    let data = if magic.starts_with(GZIP_MAGIC) {
+        read_limited(GzDecoder::new(&mut ih)?, limit, &what)?
+    } else if magic.starts_with(XZ_MAGIC) {
+        decode_xz(&mut ih, limit, &what)?
+    } else {
+        return Ok(ih);
+    };
+
+    Ok(InputHandle::new(&name, Cursor::new(data), origin))
+}
+
+#[cfg(feature = "xz")]
+fn decode_xz<R: Read>(src: R, limit: u64, what: &str) -> Result<Vec<u8>> {
+    read_limited(XzDecoder::new(src), limit, what)
+}
+
+#[cfg(not(feature = "xz"))]
+fn decode_xz<R: Read>(_src: R, _limit: u64, _what: &str) -> Result<Vec<u8>> {
+    Err(ErrorKind::Msg("the data are xz-compressed, but this build of Tectonic \
+                        does not have the \"xz\" feature".to_owned()).into())
+}
+
+
 // Reexports
 
-pub use self::filesystem::{FilesystemIo, FilesystemPrimaryInputIo};
+#[cfg(not(feature = "no-filesystem"))]
+pub use self::filesystem::{AtomicFileWriter, FilesystemIo, FilesystemPrimaryInputIo, RecursiveFilesystemIo};
+#[cfg(all(feature = "mmap", not(feature = "no-filesystem")))]
+pub use self::filesystem::MmapPrimaryIo;
 pub use self::stdstreams::GenuineStdoutIo;
 pub use self::memory::MemoryIo;
-pub use self::stack::IoStack;
+pub use self::stack::{IoStack, IoStackBuilder};
 
 
 // Helpful.
 
+#[cfg(not(feature = "no-filesystem"))]
 pub fn try_open_file<P: AsRef<Path>>(path: P) -> OpenResult<File> {
     use std::io::ErrorKind::NotFound;
 
@@ -369,6 +595,34 @@ pub fn try_open_file<P: AsRef<Path>>(path: P) -> OpenResult<File> {
     }
 }
 
+/// Split a line of a bundle index or cache manifest, which gives a file name
+/// followed by `n_fields` fields that can't contain whitespace, such as
+/// sizes and digests. File names may contain spaces, so we take the fields
+/// from the right. Any kind or amount of whitespace separates them, and the
+/// line is trimmed first, so that files written on Windows, with CRLF line
+/// endings, read correctly. Returns None if the line has too few fields.
+fn split_index_line(line: &str, n_fields: usize) -> Option<(&str, Vec<&str>)> {
+    let mut rest = line.trim();
+    let mut fields = Vec::with_capacity(n_fields);
+
+    for _ in 0..n_fields {
+        let start = match rest.char_indices().rev().find(|&(_, c)| c.is_whitespace()) {
+            Some((i, c)) => i + c.len_utf8(),
+            None => return None,
+        };
+
+        fields.push(&rest[start..]);
+        rest = rest[..start].trim_right();
+    }
+
+    if rest.is_empty() {
+        return None;
+    }
+
+    fields.reverse();
+    Some((rest, fields))
+}
+
 /// Normalize a TeX path in a system independent™ way by stripping any `.`, `..`,
 /// or extra separators '/' so that it is of the form
 ///
@@ -447,6 +701,7 @@ fn normalize_tex_path(path: &OsStr) -> Cow<OsStr> {
 // Helper for testing. FIXME: I want this to be conditionally compiled with
 // #[cfg(test)] but things break if I do that.
 
+#[cfg(not(feature = "no-filesystem"))]
 pub mod testing {
     use std::ffi::{OsStr, OsString};
     use std::fs::File;
@@ -480,7 +735,8 @@ pub mod testing {
 
         fn input_open_name(&mut self, name: &OsStr, _status: &mut StatusBackend) -> OpenResult<InputHandle> {
             if name == self.name {
-                OpenResult::Ok(InputHandle::new(name, File::open(&self.full_path).unwrap(), InputOrigin::Filesystem))
+                OpenResult::Ok(InputHandle::new(name, File::open(&self.full_path).unwrap(),
+                                                InputOrigin::Filesystem(self.full_path.clone())))
             } else {
                 OpenResult::NotAvailable
             }
@@ -492,6 +748,91 @@ pub mod testing {
 mod tests {
     use super::*;
 
+    #[test]
+    fn seek_read_roundtrip() {
+        let mut cur = Cursor::new(b"0123456789".to_vec());
+        cur.seek(SeekFrom::Start(3)).unwrap();
+        assert!(cur.seek_read_roundtrip().unwrap());
+        assert_eq!(cur.position(), 0);
+
+        let gz = GzDecoder::new(Cursor::new(b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x00\x03\x03\x00\x00\x00\x00\x00\x00\x00\x00\x00".to_vec()));
+        assert!(gz.unwrap().seek_read_roundtrip().is_err());
+    }
+
+    #[test]
+    fn borrowed_cursor_is_an_input() {
+        let data: &'static [u8] = b"borrowed";
+        let mut ih = InputHandle::new(OsStr::new("b.tex"), Cursor::new(data), InputOrigin::Other);
+        assert_eq!(ih.get_size().unwrap(), 8);
+        assert!(ih.seek_read_roundtrip().unwrap());
+    }
+
+    #[test]
+    fn compressed_entries_are_detected() {
+        use flate2::Compression;
+        use flate2::write::GzEncoder;
+        use status::NoopStatusBackend;
+        use zip::{CompressionMethod, ZipWriter};
+        use io::zipbundle::ZipBundle;
+
+        let mut gz = GzEncoder::new(Vec::new(), Compression::Default);
+        gz.write_all(b"metrics").unwrap();
+
+        let mut zw = ZipWriter::new(Cursor::new(Vec::new()));
+        zw.start_file("cmr10.tfm", CompressionMethod::Stored).unwrap();
+        zw.write_all(&gz.finish().unwrap()).unwrap();
+        zw.start_file("plain.tex", CompressionMethod::Stored).unwrap();
+        zw.write_all(b"\\bye").unwrap();
+        let mut bundle = ZipBundle::new(Cursor::new(zw.finish().unwrap().into_inner())).unwrap();
+        let mut sb = NoopStatusBackend::new();
+
+        for &(name, expected) in &[("cmr10.tfm", "metrics"), ("plain.tex", "\\bye")] {
+            let ih = bundle.input_open_name(OsStr::new(name), &mut sb).unwrap();
+            let mut ih = decompress_if_compressed(ih, DEFAULT_MAX_DECOMPRESSED_SIZE).unwrap();
+            assert_eq!(ih.name(), OsStr::new(name));
+
+            let mut s = String::new();
+            ih.read_to_string(&mut s).unwrap();
+            assert_eq!(s, expected);
+        }
+    }
+
+    #[test]
+    fn decompression_is_limited() {
+        use flate2::Compression;
+        use flate2::write::GzEncoder;
+
+        let mut gz = GzEncoder::new(Vec::new(), Compression::Default);
+        gz.write_all(&vec![0; 1000]).unwrap();
+        let gz = gz.finish().unwrap();
+
+        let ih = InputHandle::new(OsStr::new("zeros.tfm"), Cursor::new(gz.clone()), InputOrigin::Other);
+        assert_eq!(decompress_if_compressed(ih, 1000).unwrap().get_size().unwrap(), 1000);
+
+        let ih = InputHandle::new(OsStr::new("zeros.tfm"), Cursor::new(gz), InputOrigin::Other);
+        let e = decompress_if_compressed(ih, 999).unwrap_err();
+        assert!(e.to_string().contains("larger than the limit"));
+    }
+
+    #[test]
+    fn index_lines_are_split_from_the_right() {
+        assert_eq!(split_index_line("a b.tex 10 20", 2), Some(("a b.tex", vec!["10", "20"])));
+        assert_eq!(split_index_line("plain.tex\t10  20\r", 2), Some(("plain.tex", vec!["10", "20"])));
+        assert_eq!(split_index_line("  spaced name.sty 5 -\r\n", 2), Some(("spaced name.sty", vec!["5", "-"])));
+        assert_eq!(split_index_line("10 20", 2), None);
+        assert_eq!(split_index_line("", 1), None);
+    }
+
+    #[test]
+    fn open_result_to_io_result() {
+        assert_eq!(OpenResult::Ok(1).into_io_result().unwrap(), 1);
+        assert_eq!(OpenResult::NotAvailable::<()>.into_io_result().unwrap_err().kind(),
+                   io::ErrorKind::NotFound);
+
+        let r: OpenResult<()> = OpenResult::Err(ErrorKind::Msg("oops".to_owned()).into());
+        assert_eq!(r.into_io_result().unwrap_err().kind(), io::ErrorKind::Other);
+    }
+
     #[test]
     fn test_try_normalize_tex_path() {
         // edge cases
@@ -0,0 +1,95 @@
+// src/io/overlay.rs -- shadowing files with in-memory edits
+// Copyright 2017 the Tectonic Project
+// Licensed under the MIT License.
+
+use std::ffi::OsStr;
+
+use errors::Result;
+use status::StatusBackend;
+use super::{InputHandle, IoProvider, MemoryIo, OpenResult, OutputHandle};
+
+
+/// OverlayIo layers a set of in-memory files over another I/O provider. When
+/// an input is opened, it comes from `edits` if a file of that name is
+/// there, and from `base` otherwise. This models the state of an editor in
+/// which some files have unsaved changes: update `edits` with the contents
+/// of the modified buffers, and the engine will see them in place of the
+/// saved versions. Everything else is handled by `base`.
+
+pub struct OverlayIo {
+    pub edits: MemoryIo,
+    pub base: Box<IoProvider>,
+}
+
+
+impl OverlayIo {
+    pub fn new(base: Box<IoProvider>) -> OverlayIo {
+        OverlayIo {
+            edits: MemoryIo::new(false),
+            base: base,
+        }
+    }
+}
+
+
+impl IoProvider for OverlayIo {
+    fn output_open_name(&mut self, name: &OsStr) -> OpenResult<OutputHandle> {
+        self.base.output_open_name(name)
+    }
+
+    fn output_open_stdout(&mut self) -> OpenResult<OutputHandle> {
+        self.base.output_open_stdout()
+    }
+
+    fn input_open_name(&mut self, name: &OsStr, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        match self.edits.input_open_name(name, status) {
+            OpenResult::NotAvailable => self.base.input_open_name(name, status),
+            r => r,
+        }
+    }
+
+    fn input_open_primary(&mut self, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        self.base.input_open_primary(status)
+    }
+
+    fn input_open_format(&mut self, name: &OsStr, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        self.base.input_open_format(name, status)
+    }
+
+    fn write_format(&mut self, name: &str, data: &[u8], status: &mut StatusBackend) -> Result<()> {
+        self.base.write_format(name, data, status)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read, Write};
+    use zip::{CompressionMethod, ZipWriter};
+    use io::zipbundle::ZipBundle;
+    use status::NoopStatusBackend;
+
+    fn read_input(io: &mut IoProvider, name: &str) -> String {
+        let mut s = String::new();
+        io.input_open_name(OsStr::new(name), &mut NoopStatusBackend::new()).unwrap().read_to_string(&mut s).unwrap();
+        s
+    }
+
+    #[test]
+    fn edits_shadow_base() {
+        let mut zw = ZipWriter::new(Cursor::new(Vec::new()));
+        zw.start_file("chapter.tex", CompressionMethod::Stored).unwrap();
+        zw.write_all(b"saved").unwrap();
+        zw.start_file("other.tex", CompressionMethod::Stored).unwrap();
+        zw.write_all(b"untouched").unwrap();
+        let data = zw.finish().unwrap().into_inner();
+
+        let mut oio = OverlayIo::new(Box::new(ZipBundle::new(Cursor::new(data)).unwrap()));
+        assert_eq!(read_input(&mut oio, "chapter.tex"), "saved");
+
+        oio.edits.create_entry(OsStr::new("chapter.tex"), b"unsaved".to_vec());
+        assert_eq!(read_input(&mut oio, "chapter.tex"), "unsaved");
+        assert_eq!(read_input(&mut oio, "other.tex"), "untouched");
+    }
+}
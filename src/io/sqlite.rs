@@ -0,0 +1,97 @@
+// src/io/sqlite.rs -- I/O on files stored in an SQLite database
+// Copyright 2017 the Tectonic Project
+// Licensed under the MIT License.
+
+use rusqlite::{self, Connection};
+use std::ffi::OsStr;
+use std::io::Cursor;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use errors::{Error, ErrorKind, Result};
+use status::StatusBackend;
+use super::{normalize_tex_path, InputHandle, InputOrigin, IoProvider, OpenResult};
+
+
+/// SqliteIo serves input files out of an SQLite database, which must have a
+/// table created with
+///
+/// ```sql
+/// CREATE TABLE files (name BLOB PRIMARY KEY, data BLOB);
+/// ```
+///
+/// The names are the paths of the files as bytes, normalized as for
+/// `normalize_tex_path()`. Keeping a TeX tree in a database like this means
+/// that it can be updated atomically, in a transaction, while compiles are
+/// reading from it.
+
+pub struct SqliteIo {
+    conn: Connection,
+}
+
+
+fn sqlite_error(e: rusqlite::Error) -> Error {
+    ErrorKind::Msg(format!("SQLite error: {}", e)).into()
+}
+
+
+impl SqliteIo {
+    /// Serve the files in the database at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SqliteIo> {
+        let conn = Connection::open(path).map_err(sqlite_error)?;
+        Ok(SqliteIo::new(conn))
+    }
+
+    /// Serve the files in the database of an existing connection.
+    pub fn new(conn: Connection) -> SqliteIo {
+        SqliteIo {
+            conn: conn,
+        }
+    }
+
+    pub fn into_inner(self) -> Connection {
+        self.conn
+    }
+}
+
+
+impl IoProvider for SqliteIo {
+    fn input_open_name(&mut self, name: &OsStr, _status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        let name = normalize_tex_path(name);
+        let key = name.as_bytes().to_vec();
+
+        let r: rusqlite::Result<Vec<u8>> = self.conn.query_row("SELECT data FROM files WHERE name = ?",
+                                                               &[&key], |row| row.get(0));
+
+        match r {
+            Ok(data) => OpenResult::Ok(InputHandle::new(&name, Cursor::new(data), InputOrigin::Other)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => OpenResult::NotAvailable,
+            Err(e) => OpenResult::Err(sqlite_error(e)),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use status::NoopStatusBackend;
+
+    #[test]
+    fn rows_are_served() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute("CREATE TABLE files (name BLOB PRIMARY KEY, data BLOB)", &[]).unwrap();
+        conn.execute("INSERT INTO files (name, data) VALUES (?, ?)",
+                     &[&b"sub/plain.tex".to_vec(), &b"\\bye".to_vec()]).unwrap();
+
+        let mut sio = SqliteIo::new(conn);
+        let mut sb = NoopStatusBackend::new();
+
+        let mut s = String::new();
+        sio.input_open_name(OsStr::new("./sub/plain.tex"), &mut sb).unwrap().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "\\bye");
+
+        assert!(sio.input_open_name(OsStr::new("nope.tex"), &mut sb).is_not_available());
+    }
+}
@@ -2,7 +2,8 @@
 // Copyright 2016-2017 the Tectonic Project
 // Licensed under the MIT License.
 
-use std::ffi::OsStr;
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
 
 use status::StatusBackend;
 use super::{InputHandle, IoProvider, OpenResult, OutputHandle};
@@ -12,9 +13,14 @@ use super::{InputHandle, IoProvider, OpenResult, OutputHandle};
 /// subordinate IoProviders. It also checks the order in which files are read
 /// and written to detect "circular" access patterns that indicate whether we
 /// need to run multiple passes of the TeX engine.
+///
+/// The stack can optionally rewrite the names of requested input files
+/// before consulting its items, which allows documents that reference
+/// site-specific paths to be processed without modification.
 
 pub struct IoStack<'a> {
     items: Vec<&'a mut IoProvider>,
+    name_rewriter: Option<Box<Fn(&OsStr) -> OsString + 'a>>,
 }
 
 
@@ -22,11 +28,153 @@ impl<'a> IoStack<'a> {
     pub fn new(items: Vec<&'a mut IoProvider>) -> IoStack<'a> {
         IoStack {
             items: items,
+            name_rewriter: None,
+        }
+    }
+
+    /// Apply `rewriter` to the name of every input file (including format
+    /// files) that is requested of this stack, before searching for it.
+    pub fn set_name_rewriter<F: 'a + Fn(&OsStr) -> OsString>(&mut self, rewriter: F) -> &mut Self {
+        self.name_rewriter = Some(Box::new(rewriter));
+        self
+    }
+
+    fn rewrite_name<'b>(&self, name: &'b OsStr) -> Cow<'b, OsStr> {
+        match self.name_rewriter {
+            Some(ref f) => Cow::Owned(f(name)),
+            None => Cow::Borrowed(name),
         }
     }
 }
 
 
+/// IoStackBuilder assembles an IoStack out of the standard kinds of I/O
+/// layers so that callers don't have to get their relative order right by
+/// hand. Regardless of the order in which the builder methods are called, the
+/// resulting stack consults its layers in the following order:
+///
+/// 1. the "genuine stdout" layer, so that it captures the engine's chatter;
+/// 2. the primary input layer;
+/// 3. the format layer, for pinning the engine to a specific format file;
+/// 4. the in-memory layer, so that intermediate files written during
+///    processing shadow anything else;
+/// 5. the filesystem layer;
+/// 6. the bundle layer, which serves as the fallback for support files;
+/// 7. the defaults layer, for configuration files that the bundle may or
+///    may not provide;
+/// 8. the fallback layer, for support files that nothing else could
+///    provide, such as those of a system TeX installation.
+///
+/// Any layer may be omitted. Specifying the same layer twice replaces the
+/// earlier setting. If `prefer_bundle` is set, layers 5 and 6 trade places.
+
+pub struct IoStackBuilder<'a> {
+    stdout: Option<&'a mut IoProvider>,
+    primary: Option<&'a mut IoProvider>,
+    format: Option<&'a mut IoProvider>,
+    memory: Option<&'a mut IoProvider>,
+    filesystem: Option<&'a mut IoProvider>,
+    bundle: Option<&'a mut IoProvider>,
+    defaults: Option<&'a mut IoProvider>,
+    fallback: Option<&'a mut IoProvider>,
+    prefer_bundle: bool,
+}
+
+impl<'a> Default for IoStackBuilder<'a> {
+    fn default() -> Self {
+        IoStackBuilder {
+            stdout: None,
+            primary: None,
+            format: None,
+            memory: None,
+            filesystem: None,
+            bundle: None,
+            defaults: None,
+            fallback: None,
+            prefer_bundle: false,
+        }
+    }
+}
+
+impl<'a> IoStackBuilder<'a> {
+    pub fn new() -> IoStackBuilder<'a> {
+        IoStackBuilder::default()
+    }
+
+    pub fn stdout(&mut self, stdout: &'a mut IoProvider) -> &mut Self {
+        self.stdout = Some(stdout);
+        self
+    }
+
+    pub fn primary(&mut self, primary: &'a mut IoProvider) -> &mut Self {
+        self.primary = Some(primary);
+        self
+    }
+
+    pub fn format(&mut self, format: &'a mut IoProvider) -> &mut Self {
+        self.format = Some(format);
+        self
+    }
+
+    pub fn memory(&mut self, memory: &'a mut IoProvider) -> &mut Self {
+        self.memory = Some(memory);
+        self
+    }
+
+    pub fn filesystem(&mut self, filesystem: &'a mut IoProvider) -> &mut Self {
+        self.filesystem = Some(filesystem);
+        self
+    }
+
+    pub fn bundle(&mut self, bundle: &'a mut IoProvider) -> &mut Self {
+        self.bundle = Some(bundle);
+        self
+    }
+
+    pub fn defaults(&mut self, defaults: &'a mut IoProvider) -> &mut Self {
+        self.defaults = Some(defaults);
+        self
+    }
+
+    pub fn fallback(&mut self, fallback: &'a mut IoProvider) -> &mut Self {
+        self.fallback = Some(fallback);
+        self
+    }
+
+    /// Consult the bundle before the filesystem, so that stray local copies
+    /// of support files can't shadow the versions in the bundle. Files that
+    /// the bundle doesn't provide, such as the document's own sources, are
+    /// still found on the filesystem.
+    pub fn prefer_bundle(&mut self, prefer: bool) -> &mut Self {
+        self.prefer_bundle = prefer;
+        self
+    }
+
+    /// Assemble the stack. This takes the layers out of the builder, so that
+    /// it can be used at the end of a chain of setter calls; the builder is
+    /// left empty afterwards.
+    pub fn create(&mut self) -> IoStack<'a> {
+        let mut items = Vec::new();
+
+        let (first, second) = if self.prefer_bundle {
+            (self.bundle.take(), self.filesystem.take())
+        } else {
+            (self.filesystem.take(), self.bundle.take())
+        };
+
+        for item in vec![self.stdout.take(), self.primary.take(), self.format.take(),
+                         self.memory.take(), first, second, self.defaults.take(),
+                         self.fallback.take()] {
+            if let Some(p) = item {
+                items.push(p);
+            }
+        }
+
+        IoStack::new(items)
+    }
+}
+
+
 impl<'a> IoProvider for IoStack<'a> {
     fn output_open_name(&mut self, name: &OsStr) -> OpenResult<OutputHandle> {
         for item in self.items.iter_mut() {
@@ -55,8 +203,10 @@ impl<'a> IoProvider for IoStack<'a> {
     }
 
     fn input_open_name(&mut self, name: &OsStr, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        let name = self.rewrite_name(name);
+
         for item in self.items.iter_mut() {
-            let r = item.input_open_name(name, status);
+            let r = item.input_open_name(&name, status);
 
             match r {
                 OpenResult::NotAvailable => continue,
@@ -81,8 +231,10 @@ impl<'a> IoProvider for IoStack<'a> {
     }
 
     fn input_open_format(&mut self, name: &OsStr, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        let name = self.rewrite_name(name);
+
         for item in self.items.iter_mut() {
-            let r = item.input_open_format(name, status);
+            let r = item.input_open_format(&name, status);
 
             match r {
                 OpenResult::NotAvailable => continue,
@@ -93,3 +245,72 @@ impl<'a> IoProvider for IoStack<'a> {
         OpenResult::NotAvailable
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use io::MemoryIo;
+    use status::NoopStatusBackend;
+
+    fn read_sty(io: &mut IoProvider) -> String {
+        let mut s = String::new();
+        io.input_open_name(OsStr::new("pkg.sty"), &mut NoopStatusBackend::new())
+            .unwrap().read_to_string(&mut s).unwrap();
+        s
+    }
+
+    #[test]
+    fn prefer_bundle_reorders_layers() {
+        let mut local = MemoryIo::new(false);
+        local.create_entry(OsStr::new("pkg.sty"), b"local".to_vec());
+        let mut bundle = MemoryIo::new(false);
+        bundle.create_entry(OsStr::new("pkg.sty"), b"bundle".to_vec());
+
+        {
+            let mut builder = IoStackBuilder::new();
+            builder.filesystem(&mut local);
+            builder.bundle(&mut bundle);
+            assert_eq!(read_sty(&mut builder.create()), "local");
+        }
+
+        let mut builder = IoStackBuilder::new();
+        builder.filesystem(&mut local);
+        builder.bundle(&mut bundle);
+        builder.prefer_bundle(true);
+        assert_eq!(read_sty(&mut builder.create()), "bundle");
+    }
+
+    #[test]
+    fn builder_calls_can_be_chained() {
+        let mut local = MemoryIo::new(false);
+        local.create_entry(OsStr::new("pkg.sty"), b"local".to_vec());
+        let mut bundle = MemoryIo::new(false);
+        bundle.create_entry(OsStr::new("pkg.sty"), b"bundle".to_vec());
+
+        let mut stack = IoStackBuilder::new()
+            .filesystem(&mut local)
+            .bundle(&mut bundle)
+            .prefer_bundle(true)
+            .create();
+        assert_eq!(read_sty(&mut stack), "bundle");
+    }
+
+    #[test]
+    fn input_names_are_rewritten() {
+        let mut mem = MemoryIo::new(false);
+        mem.create_entry(OsStr::new("pkg.sty"), b"found".to_vec());
+
+        let mut stack = IoStack::new(vec![&mut mem as &mut IoProvider]);
+        stack.set_name_rewriter(|name: &OsStr| {
+            OsString::from(name.to_string_lossy().trim_left_matches("/site/texmf/"))
+        });
+
+        let mut sb = NoopStatusBackend::new();
+        let mut s = String::new();
+        stack.input_open_name(OsStr::new("/site/texmf/pkg.sty"), &mut sb).unwrap().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "found");
+        assert!(!stack.input_open_format(OsStr::new("/site/texmf/pkg.sty"), &mut sb).is_not_available());
+    }
+}
@@ -2,24 +2,44 @@
 // Copyright 2016-2017 the Tectonic Project
 // Licensed under the MIT License.
 
-use std::ffi::OsStr;
-use std::io::{stdin, stdout, Cursor, Read, Seek, SeekFrom};
+#[cfg(feature = "brotli")] use brotli::Decompressor;
+use flate2::read::GzDecoder;
+use std::cell::RefCell;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io::{self, stdin, stdout, Cursor, Read, Write};
 use std::rc::Rc;
 
-use errors::Result;
+#[cfg(not(feature = "no-filesystem"))] use std::fs::File;
+#[cfg(not(feature = "no-filesystem"))] use std::path::Path;
+
+use errors::{ErrorKind, Result};
 use status::StatusBackend;
-use super::{InputFeatures, InputHandle, InputOrigin, IoProvider, OpenResult, OutputHandle};
+use super::{InputHandle, InputOrigin, IoProvider, OpenResult, OutputDestination,
+            OutputHandle, DEFAULT_MAX_DECOMPRESSED_SIZE, read_limited};
 
 
 /// GenuineStdoutIo provides a mechanism for the "stdout" output to actually
-/// go to the process's stdout.
-#[derive(Clone,Copy,Debug,Eq,PartialEq)]
-pub struct GenuineStdoutIo {}
+/// go to the process's stdout -- or, if created with `to_writer()`, to some
+/// other stream of the caller's choosing.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct GenuineStdoutIo {
+    writer: Option<SharedWriter>,
+}
 
 
 impl GenuineStdoutIo {
     pub fn new() -> GenuineStdoutIo {
-        GenuineStdoutIo {}
+        GenuineStdoutIo { writer: None }
+    }
+
+    /// Create a provider that sends the engine's "stdout" to `w` rather
+    /// than the process's standard output. The engine may open its stdout
+    /// several times over the course of a processing session; everything
+    /// ends up in `w`, in order.
+    pub fn to_writer<W: Write + 'static>(w: W) -> GenuineStdoutIo {
+        let w: Box<Write> = Box::new(w);
+        GenuineStdoutIo { writer: Some(SharedWriter(Rc::new(RefCell::new(w)))) }
     }
 }
 
@@ -27,7 +47,85 @@ impl GenuineStdoutIo {
 impl IoProvider for GenuineStdoutIo {
     fn output_open_stdout(&mut self) -> OpenResult<OutputHandle> {
         // NOTE: keep in sync with io::memory::MemoryIo::stdout_key()
-        OpenResult::Ok(OutputHandle::new(OsStr::new(""), stdout()))
+        let name = OsStr::new("");
+
+        match self.writer {
+            Some(ref w) => OpenResult::Ok(OutputHandle::new_with_destination(name, w.clone(),
+                                                                             OutputDestination::Stdout)),
+            None => OpenResult::Ok(OutputHandle::new_with_destination(name, stdout(),
+                                                                      OutputDestination::Stdout)),
+        }
+    }
+}
+
+
+/// StreamingOutputIo sends one named output straight to a writer of the
+/// caller's choosing as the engine produces it, rather than buffering it.
+/// This avoids holding a large PDF in memory when it's just going to be sent
+/// over a network connection. Any other output is NotAvailable, so this
+/// layer should sit in the I/O stack above one that can store the rest of
+/// them. If the output is opened more than once, everything ends up in the
+/// writer, in order, so this is only suitable for final outputs that are
+/// written once, such as the PDF created by xdvipdfmx.
+#[derive(Clone)]
+pub struct StreamingOutputIo {
+    name: OsString,
+    writer: SharedWriter,
+}
+
+
+impl StreamingOutputIo {
+    pub fn new<W: Write + 'static>(name: &OsStr, w: W) -> StreamingOutputIo {
+        let w: Box<Write> = Box::new(w);
+
+        StreamingOutputIo {
+            name: name.to_os_string(),
+            writer: SharedWriter(Rc::new(RefCell::new(w))),
+        }
+    }
+}
+
+
+impl IoProvider for StreamingOutputIo {
+    fn output_open_name(&mut self, name: &OsStr) -> OpenResult<OutputHandle> {
+        if name != self.name.as_os_str() {
+            return OpenResult::NotAvailable;
+        }
+
+        OpenResult::Ok(OutputHandle::new_with_destination(name, self.writer.clone(), OutputDestination::Stream))
+    }
+}
+
+
+/// A handle to a writer that is shared by all of the output handles opened
+/// by a GenuineStdoutIo or StreamingOutputIo.
+#[derive(Clone)]
+struct SharedWriter(Rc<RefCell<Box<Write>>>);
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+// Writers can't be compared or printed, so the best we can do is to tell
+// whether two handles share the same one.
+
+impl PartialEq for SharedWriter {
+    fn eq(&self, other: &SharedWriter) -> bool {
+        &*self.0 as *const RefCell<Box<Write>> == &*other.0 as *const RefCell<Box<Write>>
+    }
+}
+
+impl Eq for SharedWriter {}
+
+impl fmt::Debug for SharedWriter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedWriter({:p})", &*self.0)
     }
 }
 
@@ -35,33 +133,33 @@ impl IoProvider for GenuineStdoutIo {
 /// This helper type is needed to get full InputFeatures functionality on a
 /// shared, ref-counted Vec<u8>: we're not allowed to implement AsRef<[u8]> on
 /// Rc<Vec<u8>> since none of the types or traits come from the Tectonic
-/// crate.
+/// crate. The buffer may also skip some leading bytes of the shared data,
+/// so that they can be trimmed without copying everything else.
 #[derive(Clone,Debug,Eq,PartialEq)]
-struct SharedByteBuffer(Rc<Vec<u8>>);
+struct SharedByteBuffer {
+    data: Rc<Vec<u8>>,
+    start: usize,
+}
 
 impl SharedByteBuffer {
     fn new(data: Vec<u8>) -> SharedByteBuffer {
-        SharedByteBuffer(Rc::new(data))
+        Self::from_shared(Rc::new(data), 0)
     }
-}
 
-impl AsRef<[u8]> for SharedByteBuffer {
-    fn as_ref(&self) -> &[u8] {
-        &*self.0
+    fn from_shared(data: Rc<Vec<u8>>, start: usize) -> SharedByteBuffer {
+        SharedByteBuffer {
+            data: data,
+            start: start,
+        }
     }
 }
 
-impl InputFeatures for Cursor<SharedByteBuffer> {
-    fn get_size(&mut self) -> Result<usize> {
-        Ok(self.get_ref().0.len())
-    }
-
-    fn try_seek(&mut self, pos: SeekFrom) -> Result<u64> {
-        Ok(self.seek(pos)?)
+impl AsRef<[u8]> for SharedByteBuffer {
+    fn as_ref(&self) -> &[u8] {
+        &self.data[self.start..]
     }
 }
 
-
 /// BufferedPrimaryIo provides a mechanism for the TeX "primary input"
 /// to come from stdin. Because Tectonic makes multiple passes through the
 /// input by default, we have to buffer it in memory so that the input can be
@@ -82,24 +180,149 @@ pub struct BufferedPrimaryIo {
     buffer: SharedByteBuffer,
 }
 
-impl BufferedPrimaryIo {
-    pub fn from_stream<T: Read>(stream: &mut T) -> Result<Self> {
-        let mut buf = [0u8; 8192];
-        let mut alldata = Vec::<u8>::new();
+/// The UTF-8 encoding of U+FEFF, the byte order mark. Some editors insist on
+/// prefixing documents with it, and TeX doesn't know to ignore it.
+const UTF8_BOM: &'static [u8] = b"\xef\xbb\xbf";
+
+/// If `data` start with a UTF-16 byte order mark, decode them and return
+/// their UTF-8 encoding; otherwise return None.
+fn transcode_utf16(data: &[u8]) -> Result<Option<Vec<u8>>> {
+    let big_endian = if data.starts_with(b"\xff\xfe") {
+        false
+    } else if data.starts_with(b"\xfe\xff") {
+        true
+    } else {
+        return Ok(None);
+    };
+
+    let body = &data[2..];
 
-        loop {
-            let nbytes = stream.read(&mut buf)?;
+    if body.len() % 2 != 0 {
+        return Err(ErrorKind::Msg("UTF-16 input has an odd number of bytes".to_owned()).into());
+    }
+
+    let units = body.chunks(2).map(|c| {
+        if big_endian {
+            (c[0] as u16) << 8 | c[1] as u16
+        } else {
+            (c[1] as u16) << 8 | c[0] as u16
+        }
+    });
 
-            if nbytes == 0 {
-                break;
+    let mut text = String::with_capacity(body.len());
+
+    for r in ::std::char::decode_utf16(units) {
+        match r {
+            Ok(c) => text.push(c),
+            Err(e) => {
+                return Err(ErrorKind::Msg(format!("invalid UTF-16 input: unpaired surrogate {:04x}",
+                                                  e.unpaired_surrogate())).into());
             }
+        }
+    }
+
+    Ok(Some(text.into_bytes()))
+}
+
+/// How the data handed to `BufferedPrimaryIo::from_encoded_stream()` are
+/// compressed. The variants correspond to the values of the HTTP
+/// `Content-Encoding` header, which is where services that receive documents
+/// over the web will learn them from. Brotli streams have no magic number,
+/// so the encoding has to be given rather than guessed.
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub enum ContentEncoding {
+    Identity,
+    Gzip,
+    /// Only supported if Tectonic was built with the "brotli" feature.
+    Brotli,
+}
+
+/// Undo `encoding` on `stream`, failing if that would produce more than
+/// `limit` bytes.
+fn decode_stream<T: Read>(stream: &mut T, encoding: ContentEncoding, limit: u64) -> Result<Vec<u8>> {
+    match encoding {
+        ContentEncoding::Identity => read_all(stream),
+        ContentEncoding::Gzip => read_limited(GzDecoder::new(stream)?, limit, DECODED_WHAT),
+        ContentEncoding::Brotli => decode_brotli(stream, limit),
+    }
+}
+
+/// What to call the primary input in errors about its decompressed size.
+const DECODED_WHAT: &'static str = "the decompressed primary input";
+
+/// Read `stream` to its end, retrying reads that get interrupted.
+fn read_all<T: Read>(stream: &mut T) -> Result<Vec<u8>> {
+    let mut buf = [0u8; 8192];
+    let mut alldata = Vec::<u8>::new();
+
+    loop {
+        // If we're reading from a pipe, a signal may interrupt the read
+        // before any data arrive. That's not a real error, so just try
+        // again.
+        let nbytes = match stream.read(&mut buf) {
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
 
-            alldata.extend_from_slice(&buf[..nbytes]);
+        if nbytes == 0 {
+            break;
         }
 
-        Ok(BufferedPrimaryIo {
-            buffer: SharedByteBuffer::new(alldata),
-        })
+        alldata.extend_from_slice(&buf[..nbytes]);
+    }
+
+    Ok(alldata)
+}
+
+#[cfg(feature = "brotli")]
+fn decode_brotli<T: Read>(stream: &mut T, limit: u64) -> Result<Vec<u8>> {
+    read_limited(Decompressor::new(stream, 8192), limit, DECODED_WHAT)
+}
+
+#[cfg(not(feature = "brotli"))]
+fn decode_brotli<T: Read>(_stream: &mut T, _limit: u64) -> Result<Vec<u8>> {
+    Err(ErrorKind::Msg("the input is brotli-compressed, but this build of Tectonic \
+                        does not have the \"brotli\" feature".to_owned()).into())
+}
+
+impl BufferedPrimaryIo {
+    /// Adopt data that the caller already holds in memory, without copying
+    /// them. All of the other constructors funnel through here, so that the
+    /// data get the same cleanups no matter where they came from. The
+    /// exception is that UTF-16 input, which has to be copied to be turned
+    /// into the UTF-8 that the engine expects, is only handled by the
+    /// constructors that read the data themselves, like `from_stream()`.
+    pub fn from_shared(data: Rc<Vec<u8>>) -> Self {
+        let start = if data.starts_with(UTF8_BOM) {
+            UTF8_BOM.len()
+        } else {
+            0
+        };
+
+        BufferedPrimaryIo {
+            buffer: SharedByteBuffer::from_shared(data, start),
+        }
+    }
+
+    fn from_data(data: Vec<u8>) -> Result<Self> {
+        let data = match transcode_utf16(&data)? {
+            Some(utf8) => utf8,
+            None => data,
+        };
+
+        Ok(Self::from_shared(Rc::new(data)))
+    }
+
+    pub fn from_stream<T: Read>(stream: &mut T) -> Result<Self> {
+        Self::from_data(read_all(stream)?)
+    }
+
+    /// Like `from_stream()`, but first undo the compression given by
+    /// `encoding`. Compressed data that would expand to more than
+    /// `DEFAULT_MAX_DECOMPRESSED_SIZE` bytes are rejected with an error.
+    pub fn from_encoded_stream<T: Read>(stream: &mut T, encoding: ContentEncoding) -> Result<Self> {
+        Self::from_data(decode_stream(stream, encoding, DEFAULT_MAX_DECOMPRESSED_SIZE)?)
     }
 
     pub fn from_stdin() -> Result<Self> {
@@ -107,9 +330,45 @@ impl BufferedPrimaryIo {
     }
 
     pub fn from_text<T: AsRef<str>>(text: T) -> Self {
-        BufferedPrimaryIo {
-            buffer: SharedByteBuffer::new(text.as_ref().as_bytes().to_owned())
+        Self::from_shared(Rc::new(text.as_ref().as_bytes().to_owned()))
+    }
+
+    /// Convert DOS-style (CR LF) and old-Mac-style (lone CR) line endings in
+    /// the buffered input to plain LF. This copies the data if there is
+    /// anything to change. It only makes sense for textual inputs, which the
+    /// primary input always should be.
+    pub fn normalize_line_endings(&mut self) -> &mut Self {
+        let normalized = {
+            let data = self.buffer.as_ref();
+
+            if data.contains(&b'\r') {
+                let mut out = Vec::with_capacity(data.len());
+                let mut iter = data.iter().peekable();
+
+                while let Some(&b) = iter.next() {
+                    if b != b'\r' {
+                        out.push(b);
+                        continue;
+                    }
+
+                    out.push(b'\n');
+
+                    if iter.peek() == Some(&&b'\n') {
+                        iter.next();
+                    }
+                }
+
+                Some(out)
+            } else {
+                None
+            }
+        };
+
+        if let Some(out) = normalized {
+            self.buffer = SharedByteBuffer::new(out);
         }
+
+        self
     }
 }
 
@@ -119,3 +378,348 @@ impl IoProvider for BufferedPrimaryIo {
         OpenResult::Ok(InputHandle::new(OsStr::new(""), Cursor::new(self.buffer.clone()), InputOrigin::Other))
     }
 }
+
+
+/// LazyPrimaryIo is like BufferedPrimaryIo, except that the data aren't
+/// gathered until the engine first asks for the primary input. This way,
+/// callers can set up their I/O early without paying for reads that might
+/// never be needed, e.g. if the processing gets cancelled. Once the data
+/// have been read, they're buffered for later passes just as with
+/// BufferedPrimaryIo. If the read fails, the next attempt to open the
+/// primary input will try again.
+pub struct LazyPrimaryIo {
+    source: Box<FnMut() -> Result<Vec<u8>>>,
+    buffered: Option<BufferedPrimaryIo>,
+}
+
+impl LazyPrimaryIo {
+    /// Create a provider that will call `source` to obtain the primary
+    /// input data, once, when they're first needed.
+    pub fn new<F: 'static + FnMut() -> Result<Vec<u8>>>(source: F) -> Self {
+        LazyPrimaryIo {
+            source: Box::new(source),
+            buffered: None,
+        }
+    }
+
+    pub fn from_stdin() -> Self {
+        Self::new(|| {
+            let mut data = Vec::new();
+            stdin().read_to_end(&mut data)?;
+            Ok(data)
+        })
+    }
+
+    #[cfg(not(feature = "no-filesystem"))]
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref().to_owned();
+
+        Self::new(move || {
+            let mut data = Vec::new();
+            File::open(&path)?.read_to_end(&mut data)?;
+            Ok(data)
+        })
+    }
+}
+
+
+impl IoProvider for LazyPrimaryIo {
+    fn input_open_primary(&mut self, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        if self.buffered.is_none() {
+            match (self.source)().and_then(BufferedPrimaryIo::from_data) {
+                Ok(b) => { self.buffered = Some(b); },
+                Err(e) => return OpenResult::Err(e),
+            }
+        }
+
+        self.buffered.as_mut().unwrap().input_open_primary(status)
+    }
+}
+
+
+/// BufferedFormatIo serves a specific, caller-supplied format file in
+/// response to every format-file request, regardless of the name that the
+/// engine asks for. This allows builds to be pinned to an exact format
+/// rather than one found in (or generated from) the bundle. The data should
+/// be the gzipped format file, as it would appear on disk.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct BufferedFormatIo {
+    buffer: SharedByteBuffer,
+}
+
+impl BufferedFormatIo {
+    pub fn new(data: Vec<u8>) -> Self {
+        BufferedFormatIo {
+            buffer: SharedByteBuffer::new(data),
+        }
+    }
+}
+
+
+impl IoProvider for BufferedFormatIo {
+    fn input_open_format(&mut self, name: &OsStr, _status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        OpenResult::Ok(InputHandle::new(name, Cursor::new(self.buffer.clone()), InputOrigin::Other))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use status::NoopStatusBackend;
+
+    /// A writer whose contents we can still inspect after handing it off.
+    #[derive(Clone)]
+    struct Capture(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stdout_to_writer() {
+        let capture = Capture(Rc::new(RefCell::new(Vec::new())));
+        let mut io = GenuineStdoutIo::to_writer(capture.clone());
+
+        for text in &["first pass\n", "second pass\n"] {
+            let mut h = io.output_open_stdout().unwrap();
+            h.write_all(text.as_bytes()).unwrap();
+        }
+
+        assert_eq!(&capture.0.borrow()[..], b"first pass\nsecond pass\n");
+    }
+
+    /// A reader that fails with `Interrupted` a few times before yielding
+    /// its data, as a signal-interrupted pipe might.
+    struct InterruptingReader {
+        interruptions: usize,
+        data: Cursor<Vec<u8>>,
+    }
+
+    impl Read for InterruptingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.interruptions > 0 {
+                self.interruptions -= 1;
+                return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"));
+            }
+
+            self.data.read(buf)
+        }
+    }
+
+    #[test]
+    fn from_stream_retries_interrupted() {
+        let mut stream = InterruptingReader {
+            interruptions: 2,
+            data: Cursor::new(b"hello".to_vec()),
+        };
+
+        let mut pio = BufferedPrimaryIo::from_stream(&mut stream).unwrap();
+        let mut sb = NoopStatusBackend::new();
+        let mut s = String::new();
+        pio.input_open_primary(&mut sb).unwrap().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "hello");
+    }
+
+    fn primary_contents(pio: &mut BufferedPrimaryIo) -> Vec<u8> {
+        let mut sb = NoopStatusBackend::new();
+        let mut data = Vec::new();
+        pio.input_open_primary(&mut sb).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn leading_bom_is_stripped() {
+        let mut stream = Cursor::new(b"\xef\xbb\xbf\\relax\xef\xbb\xbf".to_vec());
+        let mut pio = BufferedPrimaryIo::from_stream(&mut stream).unwrap();
+        // Only the leading BOM goes away.
+        assert_eq!(primary_contents(&mut pio), b"\\relax\xef\xbb\xbf");
+
+        let mut pio = BufferedPrimaryIo::from_text("\u{feff}\u{feff}\\relax");
+        // ... and only once.
+        assert_eq!(primary_contents(&mut pio), "\u{feff}\\relax".as_bytes());
+    }
+
+    #[test]
+    fn streamed_output() {
+        let capture = Capture(Rc::new(RefCell::new(Vec::new())));
+        let mut io = StreamingOutputIo::new(OsStr::new("doc.pdf"), capture.clone());
+
+        assert!(io.output_open_name(OsStr::new("doc.log")).is_not_available());
+
+        let mut oh = io.output_open_name(OsStr::new("doc.pdf")).unwrap();
+        assert_eq!(oh.destination(), &OutputDestination::Stream);
+        oh.write_all(b"%PDF-1.5").unwrap();
+        assert_eq!(&capture.0.borrow()[..], b"%PDF-1.5");
+    }
+
+    #[test]
+    fn utf16_is_transcoded() {
+        let text = "\\relax caf\u{e9} \u{1d11e}";
+        let units = text.encode_utf16().collect::<Vec<_>>();
+
+        let mut le = b"\xff\xfe".to_vec();
+        let mut be = b"\xfe\xff".to_vec();
+
+        for u in &units {
+            le.push(*u as u8);
+            le.push((*u >> 8) as u8);
+            be.push((*u >> 8) as u8);
+            be.push(*u as u8);
+        }
+
+        for data in vec![le, be] {
+            let mut pio = BufferedPrimaryIo::from_stream(&mut Cursor::new(data)).unwrap();
+            let mut s = String::new();
+            pio.input_open_primary(&mut NoopStatusBackend::new()).unwrap().read_to_string(&mut s).unwrap();
+            assert_eq!(s, text);
+        }
+
+        // An unpaired high surrogate.
+        assert!(BufferedPrimaryIo::from_stream(&mut Cursor::new(b"\xff\xfe\x00\xd8a\x00".to_vec())).is_err());
+        assert!(BufferedPrimaryIo::from_stream(&mut Cursor::new(b"\xff\xfea".to_vec())).is_err());
+    }
+
+    #[test]
+    fn encoded_streams_are_decoded() {
+        use flate2::Compression;
+        use flate2::write::GzEncoder;
+
+        let text = b"\\documentclass{article}\\begin{document}Hi\\end{document}";
+
+        let mut gz = GzEncoder::new(Vec::new(), Compression::Default);
+        gz.write_all(text).unwrap();
+        let gz = gz.finish().unwrap();
+
+        let mut pio = BufferedPrimaryIo::from_encoded_stream(&mut Cursor::new(gz), ContentEncoding::Gzip).unwrap();
+        assert_eq!(primary_contents(&mut pio), &text[..]);
+
+        let mut pio = BufferedPrimaryIo::from_encoded_stream(&mut Cursor::new(text.to_vec()),
+                                                             ContentEncoding::Identity).unwrap();
+        assert_eq!(primary_contents(&mut pio), &text[..]);
+    }
+
+    #[test]
+    fn encoded_streams_are_limited() {
+        use flate2::Compression;
+        use flate2::write::GzEncoder;
+
+        let mut gz = GzEncoder::new(Vec::new(), Compression::Default);
+        gz.write_all(&[b'%'; 1000]).unwrap();
+        let gz = gz.finish().unwrap();
+
+        assert_eq!(decode_stream(&mut Cursor::new(gz.clone()), ContentEncoding::Gzip, 1000).unwrap().len(), 1000);
+        assert!(decode_stream(&mut Cursor::new(gz), ContentEncoding::Gzip, 999).is_err());
+    }
+
+    #[cfg(feature = "brotli")]
+    #[test]
+    fn brotli_round_trip() {
+        use brotli::CompressorReader;
+
+        let text = b"\\documentclass{article}\\begin{document}Hi\\end{document}";
+
+        let mut compressed = Vec::new();
+        CompressorReader::new(&text[..], 4096, 9, 22).read_to_end(&mut compressed).unwrap();
+        assert!(compressed != &text[..]);
+
+        let mut pio = BufferedPrimaryIo::from_encoded_stream(&mut Cursor::new(compressed),
+                                                             ContentEncoding::Brotli).unwrap();
+        assert_eq!(primary_contents(&mut pio), &text[..]);
+    }
+
+    #[test]
+    fn line_endings_are_normalized() {
+        let mut pio = BufferedPrimaryIo::from_text("dos\r\nmac\runix\n\r\r\nend\r");
+        pio.normalize_line_endings();
+        let mut s = String::new();
+        pio.input_open_primary(&mut NoopStatusBackend::new()).unwrap().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "dos\nmac\nunix\n\n\nend\n");
+    }
+
+    #[test]
+    fn lazy_primary_reads_once_on_demand() {
+        let calls = Rc::new(RefCell::new(0));
+        let calls2 = calls.clone();
+
+        let mut pio = LazyPrimaryIo::new(move || {
+            *calls2.borrow_mut() += 1;
+            Ok(b"\\relax".to_vec())
+        });
+        assert_eq!(*calls.borrow(), 0);
+
+        let mut sb = NoopStatusBackend::new();
+
+        for _ in 0..2 {
+            let mut data = Vec::new();
+            pio.input_open_primary(&mut sb).unwrap().read_to_end(&mut data).unwrap();
+            assert_eq!(data, b"\\relax");
+        }
+
+        assert_eq!(*calls.borrow(), 1);
+    }
+
+    #[test]
+    fn from_shared_does_not_copy() {
+        let data = Rc::new(b"\xef\xbb\xbf\\relax".to_vec());
+        let mut pio = BufferedPrimaryIo::from_shared(data.clone());
+        assert_eq!(Rc::strong_count(&data), 2);
+        assert_eq!(primary_contents(&mut pio), b"\\relax");
+    }
+
+    #[test]
+    fn from_stream_propagates_real_errors() {
+        struct BrokenReader;
+
+        impl Read for BrokenReader {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken"))
+            }
+        }
+
+        assert!(BufferedPrimaryIo::from_stream(&mut BrokenReader).is_err());
+    }
+
+    #[test]
+    fn pinned_format_shadows_bundle() {
+        use io::{IoStackBuilder, MemoryIo};
+
+        let mut pinned = BufferedFormatIo::new(b"pinned".to_vec());
+        let mut bundle = MemoryIo::new(false);
+        bundle.create_entry(OsStr::new("latex.fmt"), b"bundled".to_vec());
+        bundle.create_entry(OsStr::new("doc.tex"), b"\\relax".to_vec());
+
+        let mut stack = IoStackBuilder::new().format(&mut pinned).bundle(&mut bundle).create();
+        let mut sb = NoopStatusBackend::new();
+
+        // Whatever format is asked for, we get the pinned one ...
+        for name in &["latex.fmt", "plain.fmt"] {
+            let mut data = Vec::new();
+            stack.input_open_format(OsStr::new(name), &mut sb).unwrap().read_to_end(&mut data).unwrap();
+            assert_eq!(data, b"pinned");
+        }
+
+        // ... but ordinary inputs are untouched.
+        let mut data = Vec::new();
+        stack.input_open_name(OsStr::new("doc.tex"), &mut sb).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"\\relax");
+    }
+
+    #[test]
+    fn stdout_providers_compare_by_writer() {
+        let capture = Capture(Rc::new(RefCell::new(Vec::new())));
+        let redirected = GenuineStdoutIo::to_writer(capture.clone());
+
+        assert_eq!(GenuineStdoutIo::new(), GenuineStdoutIo::new());
+        assert_eq!(redirected.clone(), redirected);
+        assert!(redirected != GenuineStdoutIo::to_writer(capture));
+        assert!(redirected != GenuineStdoutIo::new());
+        assert!(format!("{:?}", redirected).starts_with("GenuineStdoutIo"));
+    }
+}
@@ -0,0 +1,207 @@
+// src/io/systemfonts.rs -- fonts installed on the operating system
+// Copyright 2017 the Tectonic Project
+// Licensed under the MIT License.
+
+use std::collections::HashMap;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use status::StatusBackend;
+use super::{try_open_file, InputHandle, InputOrigin, IoProvider, OpenResult};
+
+
+/// The suffixes of the font files that SystemFontIo serves, compared without
+/// regard to case.
+const FONT_EXTENSIONS: &'static [&'static str] = &["otf", "ttc", "ttf"];
+
+
+/// SystemFontIo serves OpenType and TrueType fonts out of the directories
+/// where the operating system keeps its installed fonts, so that documents
+/// using `fontspec` can get at fonts that the bundle lacks. Requests for
+/// anything other than `.otf`, `.ttf`, and `.ttc` files are reported as not
+/// available. The system fonts vary from one computer to the next, so this
+/// provider belongs below the bundle in the I/O stack (see
+/// `IoStackBuilder::fallback`), where the bundle's fonts take priority.
+///
+/// Fonts are found by file name alone, wherever they lie below the font
+/// directories. The directories are scanned when the first font is asked
+/// for; fonts installed after that aren't noticed. If the same file name
+/// turns up more than once, the first directory wins.
+
+pub struct SystemFontIo {
+    dirs: Vec<PathBuf>,
+    fonts: Option<HashMap<OsString, PathBuf>>,
+}
+
+
+impl Default for SystemFontIo {
+    fn default() -> Self {
+        SystemFontIo::new()
+    }
+}
+
+
+/// The places where fonts are installed on this platform, for the system
+/// as a whole and for the current user.
+#[cfg(target_os = "macos")]
+fn platform_font_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+
+    if let Some(home) = env::home_dir() {
+        dirs.push(home.join("Library/Fonts"));
+    }
+
+    dirs.push(PathBuf::from("/Library/Fonts"));
+    dirs.push(PathBuf::from("/System/Library/Fonts"));
+    dirs
+}
+
+#[cfg(not(target_os = "macos"))]
+fn platform_font_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+
+    if let Some(home) = env::home_dir() {
+        dirs.push(home.join(".local/share/fonts"));
+        dirs.push(home.join(".fonts"));
+    }
+
+    dirs.push(PathBuf::from("/usr/local/share/fonts"));
+    dirs.push(PathBuf::from("/usr/share/fonts"));
+    dirs
+}
+
+
+fn is_font_name(name: &Path) -> bool {
+    match name.extension().and_then(|e| e.to_str()) {
+        Some(e) => FONT_EXTENSIONS.contains(&&e.to_lowercase()[..]),
+        None => false,
+    }
+}
+
+
+impl SystemFontIo {
+    /// Serve the fonts in the usual font directories of this platform.
+    pub fn new() -> SystemFontIo {
+        SystemFontIo::with_dirs(platform_font_dirs())
+    }
+
+    /// Serve the fonts in `dirs`, which are searched in order.
+    pub fn with_dirs(dirs: Vec<PathBuf>) -> SystemFontIo {
+        SystemFontIo {
+            dirs: dirs,
+            fonts: None,
+        }
+    }
+
+    /// Record every font file below `dir` that isn't already known. Hidden
+    /// directories are skipped, and symbolic links to directories aren't
+    /// followed, so that the scan always terminates.
+    fn scan(fonts: &mut HashMap<OsString, PathBuf>, dir: &Path) {
+        let mut entries = match fs::read_dir(dir) {
+            Ok(e) => e.filter_map(|e| e.ok()).collect::<Vec<_>>(),
+            Err(_) => return,
+        };
+
+        entries.sort_by_key(|e| e.file_name());
+
+        for entry in entries {
+            let name = entry.file_name();
+
+            if name.to_string_lossy().starts_with('.') {
+                continue;
+            }
+
+            let is_dir = match entry.file_type() {
+                Ok(t) => t.is_dir(),
+                Err(_) => continue,
+            };
+
+            if is_dir {
+                SystemFontIo::scan(fonts, &entry.path());
+            } else if is_font_name(Path::new(&name)) && !fonts.contains_key(&name) {
+                fonts.insert(name, entry.path());
+            }
+        }
+    }
+
+    fn find(&mut self, name: &OsStr) -> Option<PathBuf> {
+        if self.fonts.is_none() {
+            let mut fonts = HashMap::new();
+
+            for dir in &self.dirs {
+                SystemFontIo::scan(&mut fonts, dir);
+            }
+
+            self.fonts = Some(fonts);
+        }
+
+        self.fonts.as_ref().and_then(|f| f.get(name).cloned())
+    }
+}
+
+
+impl IoProvider for SystemFontIo {
+    fn input_open_name(&mut self, name: &OsStr, _status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        let path = Path::new(name);
+
+        // Only bare file names make sense, since the fonts may be anywhere
+        // below the font directories.
+        if !is_font_name(path) || path.file_name() != Some(name) {
+            return OpenResult::NotAvailable;
+        }
+
+        let found = match self.find(name) {
+            Some(p) => p,
+            None => return OpenResult::NotAvailable,
+        };
+
+        match try_open_file(&found) {
+            OpenResult::Ok(f) => {
+                OpenResult::Ok(InputHandle::new(name, BufReader::new(f), InputOrigin::Filesystem(found)))
+            },
+            OpenResult::NotAvailable => OpenResult::NotAvailable,
+            OpenResult::Err(e) => OpenResult::Err(e),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use status::NoopStatusBackend;
+    use tempdir::TempDir;
+
+    #[test]
+    fn only_fonts_are_served() {
+        let tempdir = TempDir::new("tectonic_fonts_test").unwrap();
+        let user = tempdir.path().join("user");
+        let system = tempdir.path().join("system");
+
+        for &(path, contents) in &[("user/Mine.otf", "user"), ("system/truetype/Mine.otf", "system"),
+                                   ("system/truetype/dejavu/DejaVuSans.TTF", "dejavu"),
+                                   ("system/notes.tex", "not a font")] {
+            let path = tempdir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(path).unwrap().write_all(contents.as_bytes()).unwrap();
+        }
+
+        let mut sfio = SystemFontIo::with_dirs(vec![user, system]);
+        let mut sb = NoopStatusBackend::new();
+
+        for &(name, expected) in &[("Mine.otf", "user"), ("DejaVuSans.TTF", "dejavu")] {
+            let mut s = String::new();
+            sfio.input_open_name(OsStr::new(name), &mut sb).unwrap().read_to_string(&mut s).unwrap();
+            assert_eq!(s, expected);
+        }
+
+        for name in &["notes.tex", "truetype/Mine.otf", "Missing.ttf"] {
+            assert!(sfio.input_open_name(OsStr::new(name), &mut sb).is_not_available());
+        }
+    }
+}
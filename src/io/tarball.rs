@@ -0,0 +1,419 @@
+// src/io/tarball.rs -- I/O on the members of plain tar archives
+// Copyright 2017 the Tectonic Project
+// Licensed under the MIT License.
+
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::rc::Rc;
+use std::str;
+
+use errors::{Error, ErrorKind, Result};
+use status::StatusBackend;
+use super::{normalize_tex_path, InputHandle, InputOrigin, IoProvider, OpenResult, OutputDestination,
+            OutputHandle};
+
+
+const BLOCK_SIZE: u64 = 512;
+
+/// The longest GNU long file name that we'll read. Real names are nowhere
+/// near this; the limit keeps a bogus header from making us allocate
+/// whatever size it claims.
+const MAX_LONG_NAME_SIZE: u64 = 4096;
+
+
+/// Where a member's data live inside the archive.
+struct TarMemberInfo {
+    offset: u64,
+    length: u64,
+}
+
+
+/// TarInputIo serves input files out of a (seekable) tar archive, which is
+/// convenient for pipelines that produce a tarball of a document's inputs.
+/// When created it scans the archive's headers to build an index of its
+/// regular files; members are then read into memory when they are opened.
+/// Unlike the indexed-tar bundle backend, no separate index file is needed.
+///
+/// We understand the ustar format and GNU long file names, which together
+/// cover the archives that common tools produce.
+
+pub struct TarInputIo<R: Read + Seek> {
+    reader: R,
+    index: HashMap<OsString, TarMemberInfo>,
+}
+
+
+impl<R: Read + Seek> TarInputIo<R> {
+    pub fn new(mut reader: R) -> Result<TarInputIo<R>> {
+        let mut index = HashMap::new();
+        let mut header = [0u8; BLOCK_SIZE as usize];
+        let mut pos = 0;
+        let mut long_name = None;
+
+        loop {
+            reader.seek(SeekFrom::Start(pos))?;
+
+            match reader.read_exact(&mut header) {
+                Ok(_) => {},
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+
+            // The archive ends with (at least) one block of zeros.
+            if header.iter().all(|b| *b == 0) {
+                break;
+            }
+
+            let length = parse_octal(&header[124..136])?;
+            let offset = match pos.checked_add(BLOCK_SIZE) {
+                Some(o) => o,
+                None => return Err(bad_length_error(pos)),
+            };
+
+            match header[156] {
+                b'0' | 0 => {
+                    let name = match long_name.take() {
+                        Some(n) => n,
+                        None => header_name(&header),
+                    };
+
+                    let name = normalize_tex_path(OsStr::from_bytes(&name)).into_owned();
+                    index.insert(name, TarMemberInfo { offset: offset, length: length });
+                },
+                b'L' => {
+                    // GNU extension: the data of this member are the name of
+                    // the next one.
+                    if length > MAX_LONG_NAME_SIZE {
+                        return Err(ErrorKind::Msg(format!("the tar member at offset {} has a {}-byte long \
+                                                           name; at most {} bytes are supported",
+                                                          pos, length, MAX_LONG_NAME_SIZE)).into());
+                    }
+
+                    let mut name = Vec::new();
+                    (&mut reader).take(length).read_to_end(&mut name)?;
+
+                    if name.len() as u64 != length {
+                        return Err(ErrorKind::BadLength(length as usize, name.len()).into());
+                    }
+
+                    long_name = Some(trim_nuls(&name).to_owned());
+                },
+                _ => {
+                    // Directories, links, etc.; we have no use for them.
+                    long_name = None;
+                },
+            }
+
+            pos = match length.checked_add(BLOCK_SIZE - 1)
+                .and_then(|l| offset.checked_add(l / BLOCK_SIZE * BLOCK_SIZE)) {
+                    Some(p) => p,
+                    None => return Err(bad_length_error(pos)),
+                };
+        }
+
+        Ok(TarInputIo {
+            reader: reader,
+            index: index,
+        })
+    }
+
+    fn read_member(&mut self, name: &OsStr) -> OpenResult<Vec<u8>> {
+        let (offset, length) = match self.index.get(name) {
+            Some(info) => (info.offset, info.length),
+            None => return OpenResult::NotAvailable,
+        };
+
+        if let Err(e) = self.reader.seek(SeekFrom::Start(offset)) {
+            return OpenResult::Err(e.into());
+        }
+
+        // Don't trust the header's length for the allocation; the read
+        // below finds out whether the data are really there.
+        let mut buf = Vec::with_capacity(cmp::min(length, 1 << 20) as usize);
+
+        match (&mut self.reader).take(length).read_to_end(&mut buf) {
+            Ok(n) if n as u64 == length => OpenResult::Ok(buf),
+            Ok(n) => OpenResult::Err(ErrorKind::BadLength(length as usize, n).into()),
+            Err(e) => OpenResult::Err(e.into()),
+        }
+    }
+}
+
+
+impl<R: Read + Seek> IoProvider for TarInputIo<R> {
+    fn input_open_name(&mut self, name: &OsStr, _status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        let name = normalize_tex_path(name);
+
+        match self.read_member(&name) {
+            OpenResult::Ok(buf) => OpenResult::Ok(InputHandle::new(&name, Cursor::new(buf),
+                                                                   InputOrigin::Bundle(name.to_os_string()))),
+            OpenResult::NotAvailable => OpenResult::NotAvailable,
+            OpenResult::Err(e) => OpenResult::Err(e),
+        }
+    }
+}
+
+
+/// TarOutputIo collects the files written by the engines into a tar
+/// archive, which is a convenient way to hand over everything that a
+/// compile produced in one piece. Each output is buffered in memory until
+/// its handle is closed; if a file is written more than once, as the `.aux`
+/// typically is, the archive gets the last version. Nothing is written to
+/// the underlying writer until `finish()` is called, so all of the output
+/// handles should be closed before then.
+
+pub struct TarOutputIo<W: Write> {
+    dest: W,
+    members: Rc<RefCell<Vec<(OsString, Vec<u8>)>>>,
+}
+
+
+impl<W: Write> TarOutputIo<W> {
+    pub fn new(dest: W) -> TarOutputIo<W> {
+        TarOutputIo {
+            dest: dest,
+            members: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    /// Write out the archive and return the underlying writer.
+    pub fn finish(mut self) -> Result<W> {
+        for &(ref name, ref data) in &*self.members.borrow() {
+            let name = name.as_bytes();
+
+            if name.len() > 100 {
+                // GNU extension, as understood by TarInputIo.
+                let mut long_name = name.to_owned();
+                long_name.push(0);
+                write_member(&mut self.dest, b"././@LongLink", b'L', &long_name)?;
+            }
+
+            write_member(&mut self.dest, name, b'0', data)?;
+        }
+
+        // The end of the archive is marked with two blocks of zeros.
+        self.dest.write_all(&[0u8; 2 * BLOCK_SIZE as usize])?;
+        Ok(self.dest)
+    }
+}
+
+
+/// The stream behind the OutputHandles created by TarOutputIo.
+struct TarMemberWriter {
+    name: OsString,
+    data: Vec<u8>,
+    members: Rc<RefCell<Vec<(OsString, Vec<u8>)>>>,
+}
+
+impl Write for TarMemberWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for TarMemberWriter {
+    fn drop(&mut self) {
+        let mut members = self.members.borrow_mut();
+        members.retain(|&(ref n, _)| *n != self.name);
+        members.push((self.name.clone(), self.data.split_off(0)));
+    }
+}
+
+
+impl<W: Write> IoProvider for TarOutputIo<W> {
+    fn output_open_name(&mut self, name: &OsStr) -> OpenResult<OutputHandle> {
+        let writer = TarMemberWriter {
+            name: name.to_os_string(),
+            data: Vec::new(),
+            members: self.members.clone(),
+        };
+
+        OpenResult::Ok(OutputHandle::new_with_destination(name, writer, OutputDestination::Stream))
+    }
+}
+
+
+// Header writing helpers.
+
+/// Write a member of type `kind` with the given name and contents. Names
+/// longer than the 100 bytes that fit in the header are truncated; callers
+/// should precede such members with a long-name member.
+fn write_member<W: Write>(dest: &mut W, name: &[u8], kind: u8, data: &[u8]) -> Result<()> {
+    let mut header = [0u8; BLOCK_SIZE as usize];
+    let n = cmp::min(name.len(), 100);
+    header[..n].copy_from_slice(&name[..n]);
+    header[100..107].copy_from_slice(b"0000644");
+    header[108..115].copy_from_slice(b"0000000");
+    header[116..123].copy_from_slice(b"0000000");
+    header[124..135].copy_from_slice(format!("{:011o}", data.len()).as_bytes());
+    header[136..147].copy_from_slice(b"00000000000");
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is computed with its own field filled with spaces.
+    for b in &mut header[148..156] {
+        *b = b' ';
+    }
+
+    let sum: u32 = header.iter().map(|b| *b as u32).sum();
+    header[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+
+    dest.write_all(&header)?;
+    dest.write_all(data)?;
+
+    let padding = (BLOCK_SIZE - data.len() as u64 % BLOCK_SIZE) % BLOCK_SIZE;
+    dest.write_all(&[0u8; BLOCK_SIZE as usize][..padding as usize])?;
+    Ok(())
+}
+
+
+// Header parsing helpers.
+
+fn trim_nuls(field: &[u8]) -> &[u8] {
+    match field.iter().position(|b| *b == 0) {
+        Some(n) => &field[..n],
+        None => field,
+    }
+}
+
+/// Get the name of a member from its header, taking into account the ustar
+/// "prefix" field if it's there.
+fn header_name(header: &[u8]) -> Vec<u8> {
+    let name = trim_nuls(&header[0..100]);
+
+    if &header[257..262] == b"ustar" {
+        let prefix = trim_nuls(&header[345..500]);
+
+        if prefix.len() > 0 {
+            let mut full = prefix.to_owned();
+            full.push(b'/');
+            full.extend_from_slice(name);
+            return full;
+        }
+    }
+
+    name.to_owned()
+}
+
+/// Numeric fields are usually ASCII octal, but large values may be stored
+/// as big-endian binary flagged by the high bit of the first byte.
+fn bad_length_error(pos: u64) -> Error {
+    ErrorKind::Msg(format!("the tar member at offset {} has an impossible length", pos)).into()
+}
+
+
+fn parse_octal(field: &[u8]) -> Result<u64> {
+    if field[0] & 0x80 != 0 {
+        let mut value = (field[0] & 0x7f) as u64;
+
+        for b in &field[1..] {
+            value = (value << 8) | *b as u64;
+        }
+
+        return Ok(value);
+    }
+
+    let text = str::from_utf8(trim_nuls(field))?.trim();
+
+    if text.len() == 0 {
+        return Ok(0);
+    }
+
+    Ok(u64::from_str_radix(text, 8)?)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use status::NoopStatusBackend;
+
+    fn append_member(archive: &mut Vec<u8>, name: &str, data: &[u8]) {
+        let mut header = [0u8; BLOCK_SIZE as usize];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        let size = format!("{:011o}", data.len());
+        header[124..135].copy_from_slice(size.as_bytes());
+        header[156] = b'0';
+        header[257..262].copy_from_slice(b"ustar");
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(data);
+
+        while archive.len() % BLOCK_SIZE as usize != 0 {
+            archive.push(0);
+        }
+    }
+
+    #[test]
+    fn members_are_served() {
+        let mut archive = Vec::new();
+        append_member(&mut archive, "./main.tex", b"\\input sub");
+        append_member(&mut archive, "sub/sub.tex", b"\\bye");
+        archive.extend_from_slice(&[0u8; 2 * BLOCK_SIZE as usize]);
+
+        let mut tio = TarInputIo::new(Cursor::new(archive)).unwrap();
+        let mut sb = NoopStatusBackend::new();
+
+        let mut s = String::new();
+        tio.input_open_name(OsStr::new("main.tex"), &mut sb).unwrap().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "\\input sub");
+
+        s.clear();
+        tio.input_open_name(OsStr::new("./sub//sub.tex"), &mut sb).unwrap().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "\\bye");
+
+        assert!(tio.input_open_name(OsStr::new("nope.tex"), &mut sb).is_not_available());
+    }
+
+    #[test]
+    fn outputs_are_archived() {
+        let long_name = format!("{}.log", "x".repeat(120));
+        let mut tio = TarOutputIo::new(Vec::new());
+
+        tio.output_open_name(OsStr::new("doc.aux")).unwrap().write_all(b"first").unwrap();
+        tio.output_open_name(OsStr::new("doc.aux")).unwrap().write_all(b"second").unwrap();
+        tio.output_open_name(OsStr::new(&long_name)).unwrap().write_all(b"log").unwrap();
+        let archive = tio.finish().unwrap();
+        assert_eq!(archive.len() % BLOCK_SIZE as usize, 0);
+
+        let mut tio = TarInputIo::new(Cursor::new(archive)).unwrap();
+        let mut sb = NoopStatusBackend::new();
+
+        let mut s = String::new();
+        tio.input_open_name(OsStr::new("doc.aux"), &mut sb).unwrap().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "second");
+
+        s.clear();
+        tio.input_open_name(OsStr::new(&long_name), &mut sb).unwrap().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "log");
+    }
+
+    #[test]
+    fn bogus_lengths_are_rejected() {
+        // A long-name member claiming to be enormous, in base-256.
+        let mut archive = Vec::new();
+        append_member(&mut archive, "././@LongLink", b"");
+        archive[156] = b'L';
+        archive[124] = 0x80;
+        archive[128..136].copy_from_slice(&[0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        archive.extend_from_slice(&[0u8; 2 * BLOCK_SIZE as usize]);
+        assert!(TarInputIo::new(Cursor::new(archive)).is_err());
+
+        // A regular member whose length would take the next header's
+        // position past the end of a u64.
+        let mut archive = Vec::new();
+        append_member(&mut archive, "big.tex", b"");
+        archive[124] = 0x80;
+        archive[128..136].copy_from_slice(&[0xff; 8]);
+        archive.extend_from_slice(&[0u8; 2 * BLOCK_SIZE as usize]);
+        assert!(TarInputIo::new(Cursor::new(archive)).is_err());
+    }
+}
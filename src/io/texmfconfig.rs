@@ -0,0 +1,134 @@
+// src/io/texmfconfig.rs -- default engine configuration files
+// Copyright 2017 the Tectonic Project
+// Licensed under the MIT License.
+
+use std::ffi::{OsStr, OsString};
+use std::io::Cursor;
+
+use status::StatusBackend;
+use super::{InputHandle, InputOrigin, IoProvider, OpenResult};
+
+
+/// The `texmf.cnf` that we provide if the bundle doesn't have one. In a
+/// Kpathsea-based TeX installation this file sets up search paths and
+/// various engine parameters. We do our own searching, so only the settings
+/// that macro packages sometimes inspect matter, and we give them the values
+/// that TeX Live uses by default.
+pub const DEFAULT_TEXMF_CNF: &'static str = "\
+% texmf.cnf -- default configuration provided by Tectonic
+TEXMFROOT = .
+TEXMF = .
+TEXINPUTS = .
+error_line = 79
+half_error_line = 50
+max_print_line = 79
+openin_any = a
+openout_any = p
+shell_escape = f
+";
+
+
+/// TexmfConfigIo serves configuration files such as `texmf.cnf` out of
+/// memory, so that the engines see consistent settings no matter which
+/// bundle is in use. It should go at the very bottom of an I/O stack (see
+/// `IoStackBuilder::defaults`), so that a bundle that provides its own
+/// versions of the files overrides these ones.
+
+pub struct TexmfConfigIo {
+    files: Vec<(OsString, Vec<u8>)>,
+}
+
+
+impl Default for TexmfConfigIo {
+    fn default() -> Self {
+        TexmfConfigIo::new()
+    }
+}
+
+
+impl TexmfConfigIo {
+    /// Create a provider that serves `DEFAULT_TEXMF_CNF`.
+    pub fn new() -> TexmfConfigIo {
+        let mut tci = TexmfConfigIo { files: Vec::new() };
+        tci.set_file("texmf.cnf", DEFAULT_TEXMF_CNF.as_bytes().to_owned());
+        tci
+    }
+
+    /// Serve a `texmf.cnf` that is the default one, except that its
+    /// `shell_escape` setting is `setting` ("t", "p", or "f", as in TeX
+    /// Live), so that packages that look at it see the policy that the
+    /// engine actually follows. This replaces any `texmf.cnf` set before.
+    pub fn shell_escape(&mut self, setting: &str) -> &mut Self {
+        let cnf = DEFAULT_TEXMF_CNF.replace("shell_escape = f\n", &format!("shell_escape = {}\n", setting));
+        self.set_file("texmf.cnf", cnf.into_bytes())
+    }
+
+    /// Serve `data` as the file `name`, replacing any previous contents.
+    pub fn set_file<S: AsRef<OsStr>>(&mut self, name: S, data: Vec<u8>) -> &mut Self {
+        let name = name.as_ref();
+        self.files.retain(|&(ref n, _)| n != name);
+        self.files.push((name.to_os_string(), data));
+        self
+    }
+}
+
+
+impl IoProvider for TexmfConfigIo {
+    fn input_open_name(&mut self, name: &OsStr, _status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        for &(ref n, ref data) in &self.files {
+            if n == name {
+                return OpenResult::Ok(InputHandle::new(name, Cursor::new(data.clone()), InputOrigin::Other));
+            }
+        }
+
+        OpenResult::NotAvailable
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use io::{IoStackBuilder, MemoryIo};
+    use status::NoopStatusBackend;
+
+    fn read_cnf(io: &mut IoProvider) -> String {
+        let mut s = String::new();
+        io.input_open_name(OsStr::new("texmf.cnf"), &mut NoopStatusBackend::new())
+            .unwrap().read_to_string(&mut s).unwrap();
+        s
+    }
+
+    #[test]
+    fn bundle_overrides_defaults() {
+        let mut config = TexmfConfigIo::new();
+        let mut bundle = MemoryIo::new(false);
+
+        {
+            let mut builder = IoStackBuilder::new();
+            builder.bundle(&mut bundle);
+            builder.defaults(&mut config);
+            assert_eq!(read_cnf(&mut builder.create()), DEFAULT_TEXMF_CNF);
+        }
+
+        bundle.create_entry(OsStr::new("texmf.cnf"), b"TEXMF = bundle".to_vec());
+
+        let mut builder = IoStackBuilder::new();
+        builder.bundle(&mut bundle);
+        builder.defaults(&mut config);
+        assert_eq!(read_cnf(&mut builder.create()), "TEXMF = bundle");
+    }
+
+    #[test]
+    fn shell_escape_setting_is_served() {
+        let mut config = TexmfConfigIo::new();
+        assert!(read_cnf(&mut config).contains("shell_escape = f\n"));
+
+        config.shell_escape("p");
+        let cnf = read_cnf(&mut config);
+        assert!(cnf.contains("shell_escape = p\n"));
+        assert!(!cnf.contains("shell_escape = f"));
+        assert!(cnf.contains("openout_any = p\n"));
+    }
+}
@@ -2,32 +2,559 @@
 // Copyright 2016 the Tectonic Project
 // Licensed under the MIT License.
 
-use std::ffi::OsStr;
-use std::fs::File;
-use std::io::{Cursor, Read, Seek};
-use std::path::Path;
+use flate2::CrcReader;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::cmp;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+use zip::read::ZipFile;
 use zip::result::ZipError;
-use zip::ZipArchive;
+use zip::{CompressionMethod, ZipArchive};
 
-use errors::Result;
-use super::{InputHandle, InputOrigin, IoProvider, OpenResult};
+use errors::{ErrorKind, Result};
+use super::hyper_seekable::SeekableHttpFile;
+use super::{InputFeatures, InputHandle, InputOrigin, IoProvider, OpenResult, read_limited,
+            try_normalize_tex_path};
 use status::StatusBackend;
 
+#[cfg(not(feature = "no-filesystem"))] use libc;
+#[cfg(not(feature = "no-filesystem"))] use std::ffi::CString;
+#[cfg(not(feature = "no-filesystem"))] use std::fs::{self, File};
+#[cfg(not(feature = "no-filesystem"))] use std::os::unix::ffi::OsStrExt;
+#[cfg(not(feature = "no-filesystem"))] use std::path::{Path, PathBuf};
+#[cfg(not(feature = "no-filesystem"))] use errors::ResultExt;
+#[cfg(not(feature = "no-filesystem"))] use super::filesystem::ensure_free_space;
+#[cfg(all(unix, not(feature = "no-filesystem")))] use std::os::unix::io::{FromRawFd, RawFd};
+
+
+/// The default limit on the uncompressed size of a single bundle entry. The
+/// largest files in the standard bundle are fonts of a few tens of
+/// megabytes, so this is very generous.
+pub const DEFAULT_MAX_ENTRY_SIZE: u64 = 1 << 30;
+
 
 pub struct ZipBundle<R: Read + Seek> {
-    zip: ZipArchive<R>
+    /// This is shared with the handles of entries that haven't been read
+    /// yet; see `LazyEntry`.
+    zip: Arc<Mutex<ZipArchive<R>>>,
+    max_entry_size: u64,
+
+    /// Bundles created on Windows may name their entries with backslashes
+    /// as path separators. This maps the forward-slash versions of such
+    /// names to the names actually used in the archive.
+    aliases: HashMap<String, String>,
+
+    /// The archive comment, which bundle builders use to record the
+    /// bundle's version.
+    comment: Vec<u8>,
+
+    #[cfg(not(feature = "no-filesystem"))]
+    keep_dir: Option<PathBuf>,
 }
 
 
 impl<R: Read + Seek> ZipBundle<R> {
     pub fn new (reader: R) -> Result<ZipBundle<R>> {
+        Self::new_with_aliases(reader, true)
+    }
+
+    /// Finding the entries whose names use backslashes means looking at the
+    /// header of every entry, which is only cheap if `reader` is.
+    fn new_with_aliases (mut reader: R, find_aliases: bool) -> Result<ZipBundle<R>> {
+        let comment = read_archive_comment(&mut reader)?;
+        let mut zip = ZipArchive::new(reader)?;
+        let mut aliases = HashMap::new();
+
+        if find_aliases {
+            for i in 0..zip.len() {
+                // A bad entry shouldn't make the rest of the bundle
+                // unusable; `validate()` reports it.
+                let name = match zip.by_index(i) {
+                    Ok(zipitem) => zipitem.name().to_owned(),
+                    Err(_) => continue,
+                };
+
+                if name.contains('\\') {
+                    aliases.insert(name.replace('\\', "/"), name);
+                }
+            }
+        }
+
         Ok(ZipBundle {
-            zip: ZipArchive::new(reader)?
+            zip: Arc::new(Mutex::new(zip)),
+            max_entry_size: DEFAULT_MAX_ENTRY_SIZE,
+            aliases: aliases,
+            comment: comment,
+
+            #[cfg(not(feature = "no-filesystem"))]
+            keep_dir: None,
         })
     }
+
+    /// Set the largest uncompressed size, in bytes, that we will accept for
+    /// a single entry. A tiny compressed entry can expand to gigabytes of
+    /// data, so services that accept bundles from untrusted users may want
+    /// to lower this. Entries that exceed the limit are treated as errors.
+    pub fn max_entry_size (&mut self, size: u64) -> &mut Self {
+        self.max_entry_size = size;
+        self
+    }
+
+    /// The archive comment, if there is one. Bundle builders put a version
+    /// string here, so that tools can tell which bundle they're using.
+    /// Anything that isn't valid UTF-8 is replaced.
+    pub fn archive_comment (&self) -> Option<String> {
+        if self.comment.is_empty() {
+            None
+        } else {
+            Some(String::from_utf8_lossy(&self.comment).into_owned())
+        }
+    }
+
+    /// Open an entry of this bundle that is itself a Zip bundle. Its
+    /// contents are read into memory, so the outer bundle may be used
+    /// independently afterwards. The inner bundle inherits our limit on
+    /// entry sizes.
+    pub fn open_nested (&mut self, name: &str) -> Result<ZipBundle<Cursor<Vec<u8>>>> {
+        let name = self.entry_name(name);
+
+        let data = {
+            let mut zip = self.archive();
+            let mut zipitem = zip.by_name(&name)?;
+            read_entry(&mut zipitem, self.max_entry_size)?
+        };
+
+        let mut inner = ZipBundle::new(Cursor::new(data))?;
+        inner.max_entry_size(self.max_entry_size);
+        Ok(inner)
+    }
+}
+
+
+/// A NestedZipBundle serves files out of a Zip bundle and a second bundle
+/// stored inside of it, as found in some layered distribution formats.
+/// Files in the outer bundle take precedence over those in the inner one.
+pub struct NestedZipBundle<R: Read + Seek> {
+    outer: ZipBundle<R>,
+    inner: ZipBundle<Cursor<Vec<u8>>>,
+}
+
+impl<R: Read + Seek> NestedZipBundle<R> {
+    /// Set up a bundle chaining `outer` and its entry `inner_name`.
+    pub fn new (mut outer: ZipBundle<R>, inner_name: &str) -> Result<NestedZipBundle<R>> {
+        let inner = outer.open_nested(inner_name)?;
+
+        Ok(NestedZipBundle {
+            outer: outer,
+            inner: inner,
+        })
+    }
+}
+
+impl<R: 'static + Read + Seek> IoProvider for NestedZipBundle<R> {
+    fn input_open_name(&mut self, name: &OsStr, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        match self.outer.input_open_name(name, status) {
+            OpenResult::NotAvailable => self.inner.input_open_name(name, status),
+            r => r,
+        }
+    }
 }
 
 
+/// Read the whole of a Zip entry into memory, checking its size against
+/// `limit` and its contents against the CRC32 recorded in the archive.
+fn read_entry(zipitem: &mut ZipFile, limit: u64) -> Result<Vec<u8>> {
+    let name = zipitem.name().to_owned();
+
+    if zipitem.size() > limit {
+        return Err(ErrorKind::Msg(format!("bundle entry \"{}\" is larger than the limit of {} bytes",
+                                          name, limit)).into());
+    }
+
+    let expected_crc = zipitem.crc32();
+    read_checked(&name, zipitem, expected_crc, limit)
+}
+
+
+/// The guts of `read_entry()`: read all of `src`, failing if it yields
+/// more than `limit` bytes or if its CRC32 isn't `expected_crc`. We don't
+/// trust the size declared in the archive, so the bytes are counted as they
+/// come out of the decompressor.
+fn read_checked<R: Read>(name: &str, src: R, expected_crc: u32, limit: u64) -> Result<Vec<u8>> {
+    // The zip crate checks CRCs as it reads, but it's cheap to make sure
+    // ourselves, and a corrupt bundle feeding bad data to TeX leads to
+    // very confusing failures.
+
+    let mut crc_reader = CrcReader::new(src);
+    let buf = read_limited(&mut crc_reader, limit, &format!("bundle entry \"{}\"", name))?;
+    let observed_crc = crc_reader.crc().sum();
+
+    if observed_crc != expected_crc {
+        return Err(ErrorKind::Msg(format!("bundle entry \"{}\" is corrupt: expected CRC32 {:08x}, got {:08x}",
+                                          name, expected_crc, observed_crc)).into());
+    }
+
+    Ok(buf)
+}
+
+
+/// The size of the "end of central directory" record that ends a Zip file,
+/// not counting the archive comment that follows it.
+const EOCD_SIZE: usize = 22;
+
+
+/// Read the archive comment out of the end of central directory record. The
+/// record sits at the very end of the file, but the comment can contain
+/// anything, including the record's signature, so we search backwards for
+/// the record whose comment length takes us exactly to the end. A file too
+/// short to have such a record gets no comment; ZipArchive will then refuse
+/// it with a proper error.
+fn read_archive_comment<R: Read + Seek>(reader: &mut R) -> Result<Vec<u8>> {
+    let file_size = reader.seek(io::SeekFrom::End(0))?;
+    let tail_size = cmp::min(file_size, (EOCD_SIZE + 0xFFFF) as u64);
+    reader.seek(io::SeekFrom::Start(file_size - tail_size))?;
+
+    let mut tail = Vec::with_capacity(tail_size as usize);
+    reader.by_ref().take(tail_size).read_to_end(&mut tail)?;
+    reader.seek(io::SeekFrom::Start(0))?;
+
+    if tail.len() < EOCD_SIZE {
+        return Ok(Vec::new());
+    }
+
+    for pos in (0..tail.len() - EOCD_SIZE + 1).rev() {
+        if tail[pos..pos + 4] != b"PK\x05\x06"[..] {
+            continue;
+        }
+
+        let comment_len = tail[pos + 20] as usize | (tail[pos + 21] as usize) << 8;
+
+        if pos + EOCD_SIZE + comment_len == tail.len() {
+            return Ok(tail[pos + EOCD_SIZE..].to_vec());
+        }
+    }
+
+    Ok(Vec::new())
+}
+
+
+/// Metadata about one entry of a Zip bundle, as recorded in the archive.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct EntryInfo {
+    /// The uncompressed size of the entry, in bytes.
+    pub size: u64,
+
+    /// The size of the entry as stored in the archive, in bytes.
+    pub compressed_size: u64,
+
+    /// How the entry's data are compressed.
+    pub method: CompressionMethod,
+}
+
+
+impl<R: Read + Seek> ZipBundle<R> {
+    /// Get the archive's metadata about the entry `name`, without reading
+    /// its data. Returns None if there is no such entry.
+    pub fn entry_info (&mut self, name: &str) -> Result<Option<EntryInfo>> {
+        let name = self.entry_name(name);
+
+        let mut zip = self.archive();
+
+        let zipitem = match zip.by_name(&name) {
+            Ok(f) => f,
+            Err(ZipError::FileNotFound) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Some(EntryInfo {
+            size: zipitem.size(),
+            compressed_size: zipitem.compressed_size(),
+            method: zipitem.compression(),
+        }))
+    }
+}
+
+
+impl<R: Read + Seek> ZipBundle<R> {
+    /// List the names of the files in the bundle that match the glob
+    /// `pattern`, in sorted order. In the pattern, `?` matches any single
+    /// character and `*` matches any sequence of characters, including path
+    /// separators, so `*.sty` finds every LaTeX package in the bundle. Only
+    /// the archive's directory is consulted; no data are extracted.
+    pub fn glob (&mut self, pattern: &str) -> Result<Vec<String>> {
+        let pattern = pattern.chars().collect::<Vec<_>>();
+        let mut names = Vec::new();
+        let mut zip = self.archive();
+
+        for i in 0..zip.len() {
+            let name = match zip.by_index(i) {
+                Ok(zipitem) => zipitem.name().replace('\\', "/"),
+                Err(_) => continue, // see `new_with_aliases()`
+            };
+
+            if !name.ends_with('/') && glob_matches(&pattern, &name) {
+                names.push(name);
+            }
+        }
+
+        names.sort();
+        Ok(names)
+    }
+}
+
+
+/// Match `name` against a glob pattern, as described for `ZipBundle::glob()`.
+/// When a `*` is followed by a mismatch, we only ever need to retry from the
+/// most recent `*`, so this takes linear time in the common cases.
+fn glob_matches(pattern: &[char], name: &str) -> bool {
+    let name = name.chars().collect::<Vec<_>>();
+    let (mut p, mut n) = (0, 0);
+    let mut last_star = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || (pattern[p] != '*' && pattern[p] == name[n])) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            last_star = Some((p, n));
+            p += 1;
+        } else if let Some((sp, sn)) = last_star {
+            // Let the star swallow one more character and try again.
+            p = sp + 1;
+            n = sn + 1;
+            last_star = Some((sp, sn + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+
+/// The results of checking over a bundle with `ZipBundle::validate()`.
+#[derive(Clone,Debug,Default)]
+pub struct BundleReport {
+    /// The number of files in the bundle.
+    pub n_files: usize,
+
+    /// Descriptions of everything that we found to be wrong with the bundle.
+    pub problems: Vec<String>,
+}
+
+impl BundleReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+
+impl<R: Read + Seek> ZipBundle<R> {
+    /// Check that the bundle is well-formed without running any engines.
+    /// Every entry is read and checked just as it would be during
+    /// processing, and we make sure that the bundle has the files needed to
+    /// generate a format and typeset something with it. An error is only
+    /// returned if the archive can't be read at all; problems with the
+    /// contents are listed in the report.
+    pub fn validate(&mut self) -> Result<BundleReport> {
+        let mut report = BundleReport::default();
+        let mut have_format_source = false;
+        let mut have_fonts = false;
+        let mut zip = self.archive();
+
+        for i in 0..zip.len() {
+            let mut zipitem = match zip.by_index(i) {
+                Ok(z) => z,
+                Err(e) => {
+                    report.problems.push(format!("entry #{} is unreadable: {}", i, e));
+                    continue;
+                }
+            };
+
+            let name = zipitem.name().to_owned();
+
+            if name.ends_with('/') {
+                continue; // a directory entry
+            }
+
+            report.n_files += 1;
+
+            match try_normalize_tex_path(&name) {
+                Some(ref p) if !p.starts_with("/") && !p.starts_with("..") => {},
+                _ => report.problems.push(format!("entry \"{}\" has a forbidden path", name)),
+            }
+
+            if let Err(e) = read_entry(&mut zipitem, self.max_entry_size) {
+                report.problems.push(format!("entry \"{}\" is unreadable: {}", name, e));
+            }
+
+            if name.starts_with("tectonic-format-") && name.ends_with(".tex") {
+                have_format_source = true;
+            }
+
+            if name.ends_with(".tfm") || name.ends_with(".otf") {
+                have_fonts = true;
+            }
+        }
+
+        if !have_format_source {
+            report.problems.push("no format source files (tectonic-format-*.tex) were found".to_owned());
+        }
+
+        if !have_fonts {
+            report.problems.push("no font files (*.tfm, *.otf) were found".to_owned());
+        }
+
+        Ok(report)
+    }
+}
+
+
+#[cfg(not(feature = "no-filesystem"))]
+impl<R: Read + Seek> ZipBundle<R> {
+    /// Write every file in the bundle into the directory `dest`, creating
+    /// subdirectories as needed. Each file's modification time is set from
+    /// the timestamp stored in the Zip archive, so that tools like `make`
+    /// don't think that everything has just changed.
+    pub fn extract_all(&mut self, dest: &Path) -> Result<()> {
+        let mut zip = self.archive();
+
+        for i in 0..zip.len() {
+            let mut zipitem = zip.by_index(i)?;
+
+            if zipitem.name().ends_with('/') {
+                continue; // a directory entry
+            }
+
+            // Don't let a malicious bundle write outside of `dest`.
+            let relpath = match try_normalize_tex_path(zipitem.name()) {
+                Some(ref p) if !p.starts_with("/") && !p.starts_with("..") => p.clone(),
+                _ => {
+                    return Err(ErrorKind::PathForbidden(zipitem.name().to_owned()).into());
+                }
+            };
+
+            let name = zipitem.name().to_owned();
+            let path = dest.join(&relpath);
+
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+                ensure_free_space(parent, zipitem.size())?;
+            }
+
+            {
+                let data = read_limited(&mut zipitem, self.max_entry_size,
+                                        &format!("bundle entry \"{}\"", name))?;
+                File::create(&path)?.write_all(&data)?;
+            }
+
+            let mtime = zipitem.last_modified().to_timespec().sec;
+            ctry!(set_mtime(&path, mtime); "couldn't set modification time of {}", path.display());
+        }
+
+        Ok(())
+    }
+
+    /// Whenever a file is read from the bundle, save a copy of it in `dir`
+    /// under its name in the bundle. This is for debugging problems with
+    /// fonts, formats, and so on: afterwards, `dir` holds exactly the bundle
+    /// files that the engines used. Failures to save copies are reported as
+    /// warnings, but don't stop processing.
+    pub fn keep_extracted_files<P: AsRef<Path>> (&mut self, dir: P) -> &mut Self {
+        self.keep_dir = Some(dir.as_ref().to_owned());
+        self
+    }
+}
+
+
+impl<R: Read + Seek> ZipBundle<R> {
+    fn archive(&self) -> MutexGuard<ZipArchive<R>> {
+        lock_archive(&self.zip)
+    }
+
+    /// Get the name in the archive of the entry that should be used for
+    /// `name`. Backslashes in `name` are treated as forward slashes, and
+    /// we find entries no matter which separator they were stored with.
+    fn entry_name(&self, name: &str) -> String {
+        let name = name.replace('\\', "/");
+
+        match self.aliases.get(&name) {
+            Some(actual) => actual.clone(),
+            None => name,
+        }
+    }
+
+    #[cfg(not(feature = "no-filesystem"))]
+    fn maybe_keep_copy(&self, name: &str, data: &[u8], status: &mut StatusBackend) {
+        if let Some(ref dir) = self.keep_dir {
+            if let Err(e) = keep_copy(dir, name, data) {
+                tt_warning!(status, "couldn't save a copy of bundle file \"{}\"", name; e);
+            }
+        }
+    }
+
+    #[cfg(feature = "no-filesystem")]
+    fn maybe_keep_copy(&self, _name: &str, _data: &[u8], _status: &mut StatusBackend) {}
+
+    #[cfg(not(feature = "no-filesystem"))]
+    fn keeping_copies(&self) -> bool {
+        self.keep_dir.is_some()
+    }
+
+    #[cfg(feature = "no-filesystem")]
+    fn keeping_copies(&self) -> bool {
+        false
+    }
+}
+
+
+/// A panic while the archive was locked doesn't leave it in a state that
+/// we care about, so we don't let the lock's poisoning stop us.
+fn lock_archive<R: Read + Seek>(zip: &Mutex<ZipArchive<R>>) -> MutexGuard<ZipArchive<R>> {
+    zip.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+
+/// Save a copy of the bundle entry `name` in `dir`.
+#[cfg(not(feature = "no-filesystem"))]
+fn keep_copy(dir: &Path, name: &str, data: &[u8]) -> Result<()> {
+    let relpath = match try_normalize_tex_path(name) {
+        Some(ref p) if !p.starts_with("/") && !p.starts_with("..") => p.clone(),
+        _ => return Err(ErrorKind::PathForbidden(name.to_owned()).into()),
+    };
+
+    let path = dir.join(&relpath);
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    File::create(&path)?.write_all(data)?;
+    Ok(())
+}
+
+
+/// Set the access and modification times of the file at `path` to `mtime`,
+/// measured in seconds since the Unix epoch.
+#[cfg(not(feature = "no-filesystem"))]
+fn set_mtime(path: &Path, mtime: i64) -> Result<()> {
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    let tv = libc::timeval {
+        tv_sec: mtime as libc::time_t,
+        tv_usec: 0,
+    };
+    let times = [tv, tv];
+
+    if unsafe { libc::utimes(cpath.as_ptr(), times.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+
+#[cfg(not(feature = "no-filesystem"))]
 impl ZipBundle<File> {
     pub fn open (path: &Path) -> Result<ZipBundle<File>> {
         Self::new(File::open(path)?)
@@ -35,37 +562,529 @@ impl ZipBundle<File> {
 }
 
 
-impl<R: Read + Seek> IoProvider for ZipBundle<R> {
-    fn input_open_name(&mut self, name: &OsStr, _status: &mut StatusBackend) -> OpenResult<InputHandle> {
+impl ZipBundle<SeekableHttpFile> {
+    /// Open a Zip bundle on a web server without downloading the whole
+    /// thing: the parts of the archive that we need, starting with its
+    /// central directory, are fetched with HTTP range requests and kept in
+    /// memory. The server must support range requests. Entries whose names
+    /// use backslashes as separators can only be found under those names,
+    /// since spotting them would mean fetching the header of every entry.
+    pub fn open_url (url: &str) -> Result<ZipBundle<SeekableHttpFile>> {
+        Self::new_with_aliases(SeekableHttpFile::new(url)?, false)
+    }
+}
+
+
+/// Create a bundle from a file descriptor that the caller already has open,
+/// such as a sealed memfd, so that the bundle needn't have a path at all.
+///
+/// This is unsafe because the bundle takes ownership of `fd` and will close
+/// it when dropped: the caller must not use or close it afterwards.
+#[cfg(all(unix, not(feature = "no-filesystem")))]
+pub unsafe fn open_bundle_from_fd(fd: RawFd) -> Result<ZipBundle<File>> {
+    ZipBundle::new(File::from_raw_fd(fd))
+}
+
+
+/// Open the Zip bundle at `path` and check it over; see
+/// `ZipBundle::validate()`.
+#[cfg(not(feature = "no-filesystem"))]
+pub fn validate_bundle(path: &Path) -> Result<BundleReport> {
+    ZipBundle::open(path)?.validate()
+}
+
+
+/// How much of an entry to decompress when only its first few bytes are
+/// read. See `LazyEntry`.
+const PREFIX_SIZE: u64 = 1024;
+
+
+/// LazyEntry holds the data of a bundle entry that has been opened, but not
+/// necessarily read. LaTeX opens files just to see whether they exist, as in
+/// `\IfFileExists`, and XeTeX reads the first couple of bytes of every file
+/// that it opens in order to sniff its encoding; so we only decompress a
+/// short prefix of an entry until something reads past it, and nothing at
+/// all if the entry is never read. The size comes from the archive's
+/// directory, so it can be reported without touching the data. Entries no
+/// bigger than the prefix gain nothing from this, so they are read in full
+/// when they are opened.
+///
+/// The full contents are checked against the archive's CRC32 when they are
+/// decompressed, so a large corrupt entry shows up as an error when it is
+/// read rather than when it is opened. The engines treat such errors as
+/// fatal.
+struct LazyEntry<R: Read + Seek> {
+    zip: Arc<Mutex<ZipArchive<R>>>,
+    name: String,
+    size: u64,
+    limit: u64,
+
+    /// The entry's data if `complete`, or a prefix of them otherwise.
+    data: Vec<u8>,
+    complete: bool,
+    pos: u64,
+}
+
+impl<R: Read + Seek> LazyEntry<R> {
+    /// Make sure that `data` extends at least as far as `end`, or as far as
+    /// it can go.
+    fn load(&mut self, end: u64) -> Result<()> {
+        if self.complete || end <= self.data.len() as u64 {
+            return Ok(());
+        }
+
+        let mut zip = lock_archive(&self.zip);
+        let mut zipitem = zip.by_name(&self.name)?;
+
+        if self.data.is_empty() && end <= PREFIX_SIZE && self.size > PREFIX_SIZE {
+            zipitem.take(PREFIX_SIZE).read_to_end(&mut self.data)?;
+        } else {
+            self.data = read_entry(&mut zipitem, self.limit)?;
+            self.complete = true;
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for LazyEntry<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let end = self.pos + buf.len() as u64;
+
+        if let Err(e) = self.load(end) {
+            return Err(io::Error::new(io::ErrorKind::Other, e.to_string()));
+        }
+
+        let start = cmp::min(self.pos, self.data.len() as u64) as usize;
+        let n = cmp::min(buf.len(), self.data.len() - start);
+        buf[..n].copy_from_slice(&self.data[start..start + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> InputFeatures for LazyEntry<R> {
+    fn get_size(&mut self) -> Result<usize> {
+        Ok(self.size as usize)
+    }
+
+    fn try_seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => p as i64,
+            SeekFrom::Current(d) => self.pos as i64 + d,
+            SeekFrom::End(d) => self.size as i64 + d,
+        };
+
+        if new_pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position").into());
+        }
+
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+
+/// The handles of large entries refer back to the archive (see `LazyEntry`),
+/// and handles can't borrow anything, so this needs a reader that doesn't
+/// either. Files and in-memory buffers are fine.
+impl<R: 'static + Read + Seek> IoProvider for ZipBundle<R> {
+    fn input_open_name(&mut self, name: &OsStr, status: &mut StatusBackend) -> OpenResult<InputHandle> {
         // We need to be able to look at other items in the Zip file while
-        // reading this one, so the only path forward is to read the entire
-        // contents into a buffer right now. RAM is cheap these days.
+        // reading this one, so we can't stream an entry's data straight out
+        // of the archive: they are decompressed into memory as needed, using
+        // a reference to the archive that the handle shares with us. A nice
+        // side effect is that no temporary files are involved, and the
+        // handle stays valid even if it outlives the bundle.
 
         // If `name` cannot be converted to Unicode, we return NotAvailable. I
         // *think* that's what we should do.
 
         let namestr = match name.to_str() {
-            Some(s) => s,
+            Some(s) => self.entry_name(s),
             None => return OpenResult::NotAvailable
         };
 
-        let mut zipitem = match self.zip.by_name (namestr) {
-            Ok(f) => f,
-            Err(e) => {
-                return match e {
-                    ZipError::Io(sube) => OpenResult::Err(sube.into()),
-                    ZipError::FileNotFound => OpenResult::NotAvailable,
-                    _ => OpenResult::Err(e.into()),
+        let keeping_copies = self.keeping_copies();
+
+        let (size, buf) = {
+            let mut zip = self.archive();
+
+            let mut zipitem = match zip.by_name (&namestr) {
+                Ok(f) => f,
+                Err(e) => {
+                    return match e {
+                        ZipError::Io(sube) => OpenResult::Err(sube.into()),
+                        ZipError::FileNotFound => OpenResult::NotAvailable,
+                        _ => OpenResult::Err(e.into()),
+                    }
+                }
+            };
+
+            if zipitem.size() > self.max_entry_size {
+                return OpenResult::Err(ErrorKind::Msg(format!("bundle entry \"{}\" is larger than the limit of \
+                                                               {} bytes", namestr, self.max_entry_size)).into());
+            }
+
+            // When saving copies of what we read, we need the data right
+            // away so that any problems can be reported. Small entries are
+            // read (and checked) right away too; see `LazyEntry`.
+            if !keeping_copies && zipitem.size() > PREFIX_SIZE {
+                (zipitem.size(), None)
+            } else {
+                match read_entry(&mut zipitem, self.max_entry_size) {
+                    Ok(b) => (zipitem.size(), Some(b)),
+                    Err(e) => return OpenResult::Err(e),
                 }
             }
         };
 
-        let mut buf = Vec::with_capacity(zipitem.size() as usize);
+        let origin = InputOrigin::Bundle(OsString::from(namestr.clone()));
+
+        if let Some(buf) = buf {
+            self.maybe_keep_copy(&namestr.replace('\\', "/"), &buf, status);
+            return OpenResult::Ok(InputHandle::new(name, Cursor::new(buf), origin));
+        }
+
+        let entry = LazyEntry {
+            zip: self.zip.clone(),
+            name: namestr,
+            size: size,
+            limit: self.max_entry_size,
+            data: Vec::new(),
+            complete: false,
+            pos: 0,
+        };
+
+        OpenResult::Ok(InputHandle::new(name, entry, origin))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zip::{CompressionMethod, ZipWriter};
+    use io::InputFeatures;
+    use status::NoopStatusBackend;
+
+    fn make_zip_with_method(name: &str, data: &[u8], method: CompressionMethod) -> Vec<u8> {
+        let mut zw = ZipWriter::new(Cursor::new(Vec::new()));
+        zw.start_file(name, method).unwrap();
+        zw.write_all(data).unwrap();
+        zw.finish().unwrap().into_inner()
+    }
+
+    fn make_zip(name: &str, data: &[u8]) -> Vec<u8> {
+        make_zip_with_method(name, data, CompressionMethod::Stored)
+    }
+
+    #[test]
+    fn intact_entry_is_readable() {
+        let zipdata = make_zip("hello.tex", b"hello world");
+        let mut bundle = ZipBundle::new(Cursor::new(zipdata)).unwrap();
+        let mut sb = NoopStatusBackend::new();
+        let mut s = String::new();
+        bundle.input_open_name(OsStr::new("hello.tex"), &mut sb).unwrap().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "hello world");
+    }
+
+    /// TeX queries the sizes of some inputs, so we need to report the
+    /// uncompressed size of the entry, not its size inside the archive.
+    #[test]
+    fn size_is_uncompressed_size() {
+        let data = vec![b'a'; 10000];
+        let zipdata = make_zip_with_method("big.tfm", &data, CompressionMethod::Deflated);
+        assert!(zipdata.len() < data.len());
+
+        let mut bundle = ZipBundle::new(Cursor::new(zipdata)).unwrap();
+        let mut sb = NoopStatusBackend::new();
+        let mut ih = bundle.input_open_name(OsStr::new("big.tfm"), &mut sb).unwrap();
+        assert_eq!(ih.get_size().unwrap(), data.len());
+    }
+
+    #[test]
+    fn oversized_entry_is_rejected() {
+        let zipdata = make_zip_with_method("bomb.tex", &vec![0u8; 100000], CompressionMethod::Deflated);
+        let mut bundle = ZipBundle::new(Cursor::new(zipdata)).unwrap();
+        bundle.max_entry_size(1000);
+        let mut sb = NoopStatusBackend::new();
+
+        match bundle.input_open_name(OsStr::new("bomb.tex"), &mut sb) {
+            OpenResult::Err(_) => {},
+            _ => panic!("oversized bundle entry was not rejected"),
+        }
+    }
+
+    #[test]
+    fn validation_reports_problems() {
+        let mut zipdata = make_zip("hello.tex", b"hello world");
+        let pos = zipdata.windows(11).position(|w| w == b"hello world").unwrap();
+        zipdata[pos] = b'j';
+
+        let report = ZipBundle::new(Cursor::new(zipdata)).unwrap().validate().unwrap();
+        assert_eq!(report.n_files, 1);
+        assert!(!report.is_ok());
+        assert!(report.problems.iter().any(|p| p.contains("hello.tex")));
+    }
+
+    #[test]
+    fn handle_outlives_bundle() {
+        let mut ih = {
+            let mut bundle = ZipBundle::new(Cursor::new(make_zip("hello.tex", b"hello world"))).unwrap();
+            let mut sb = NoopStatusBackend::new();
+            bundle.input_open_name(OsStr::new("hello.tex"), &mut sb).unwrap()
+        };
+
+        let mut s = String::new();
+        ih.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "hello world");
+    }
+
+    #[test]
+    fn nested_bundles_cascade() {
+        let inner = make_zip("inner.tex", b"from the inner bundle");
+
+        let mut zw = ZipWriter::new(Cursor::new(Vec::new()));
+        zw.start_file("outer.tex", CompressionMethod::Stored).unwrap();
+        zw.write_all(b"from the outer bundle").unwrap();
+        zw.start_file("texmf.zip", CompressionMethod::Stored).unwrap();
+        zw.write_all(&inner).unwrap();
+        let outer = zw.finish().unwrap().into_inner();
+
+        let outer = ZipBundle::new(Cursor::new(outer)).unwrap();
+        let mut bundle = NestedZipBundle::new(outer, "texmf.zip").unwrap();
+        let mut sb = NoopStatusBackend::new();
+
+        for &(name, expected) in &[("outer.tex", "from the outer bundle"), ("inner.tex", "from the inner bundle")] {
+            let mut s = String::new();
+            bundle.input_open_name(OsStr::new(name), &mut sb).unwrap().read_to_string(&mut s).unwrap();
+            assert_eq!(s, expected);
+        }
+
+        assert!(bundle.input_open_name(OsStr::new("neither.tex"), &mut sb).is_not_available());
+    }
+
+    #[cfg(not(feature = "no-filesystem"))]
+    #[test]
+    fn bundle_from_fd() {
+        use std::os::unix::io::IntoRawFd;
+        use tempdir::TempDir;
+
+        let tempdir = TempDir::new("tectonic_zip_test").unwrap();
+        let path = tempdir.path().join("bundle.zip");
+        File::create(&path).unwrap().write_all(&make_zip("hello.tex", b"hello world")).unwrap();
+
+        let fd = File::open(&path).unwrap().into_raw_fd();
+        let mut bundle = unsafe { open_bundle_from_fd(fd) }.unwrap();
+        let mut sb = NoopStatusBackend::new();
+        let mut s = String::new();
+        bundle.input_open_name(OsStr::new("hello.tex"), &mut sb).unwrap().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "hello world");
+    }
+
+    #[cfg(not(feature = "no-filesystem"))]
+    #[test]
+    fn extracted_files_are_kept() {
+        use tempdir::TempDir;
+
+        let tempdir = TempDir::new("tectonic_zip_test").unwrap();
+        let mut bundle = ZipBundle::new(Cursor::new(make_zip("fonts/a.tfm", b"metrics"))).unwrap();
+        bundle.keep_extracted_files(tempdir.path());
+
+        let mut sb = NoopStatusBackend::new();
+        bundle.input_open_name(OsStr::new("fonts/a.tfm"), &mut sb).unwrap();
+
+        let mut s = String::new();
+        File::open(tempdir.path().join("fonts/a.tfm")).unwrap().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "metrics");
+    }
+
+    #[test]
+    fn entry_info_reports_compression() {
+        let mut bundle = ZipBundle::new(Cursor::new(make_zip_with_method("big.tex", &[b'x'; 4096],
+                                                                         CompressionMethod::Deflated))).unwrap();
+
+        let info = bundle.entry_info("big.tex").unwrap().unwrap();
+        assert_eq!(info.method, CompressionMethod::Deflated);
+        assert_eq!(info.size, 4096);
+        assert!(info.compressed_size < info.size);
+        assert!(bundle.entry_info("missing.tex").unwrap().is_none());
+    }
+
+    #[test]
+    fn backslash_separators_are_normalized() {
+        let mut bundle = ZipBundle::new(Cursor::new(make_zip("fonts\\tfm\\a.tfm", b"metrics"))).unwrap();
+        let mut sb = NoopStatusBackend::new();
+
+        for name in &["fonts/tfm/a.tfm", "fonts\\tfm\\a.tfm"] {
+            let mut s = String::new();
+            bundle.input_open_name(OsStr::new(name), &mut sb).unwrap().read_to_string(&mut s).unwrap();
+            assert_eq!(s, "metrics");
+        }
+
+        assert!(bundle.entry_info("fonts/tfm/a.tfm").unwrap().is_some());
+        assert!(bundle.input_open_name(OsStr::new("fonts/a.tfm"), &mut sb).is_not_available());
+    }
+
+    #[test]
+    fn glob_lists_matching_entries() {
+        let mut zw = ZipWriter::new(Cursor::new(Vec::new()));
+
+        for name in &["graphicx.sty", "latex/color.sty", "article.cls", "sty/"] {
+            zw.start_file(*name, CompressionMethod::Stored).unwrap();
+        }
+
+        let mut bundle = ZipBundle::new(Cursor::new(zw.finish().unwrap().into_inner())).unwrap();
+        assert_eq!(bundle.glob("*.sty").unwrap(), vec!["graphicx.sty", "latex/color.sty"]);
+        assert_eq!(bundle.glob("article.c?s").unwrap(), vec!["article.cls"]);
+        assert_eq!(bundle.glob("*").unwrap().len(), 3);
+        assert!(bundle.glob("*.tfm").unwrap().is_empty());
+    }
+
+    #[test]
+    fn archive_comment_is_read() {
+        let mut zipdata = make_zip("hello.tex", b"hello world");
+        assert_eq!(ZipBundle::new(Cursor::new(zipdata.clone())).unwrap().archive_comment(), None);
+
+        // Our version of the zip crate can't write comments, so patch one
+        // onto the end of central directory record by hand. Throw in a
+        // decoy signature for good measure.
+        let comment = b"bundle PK\x05\x06 2017.1";
+        let n = zipdata.len();
+        zipdata[n - 2] = comment.len() as u8;
+        zipdata.extend_from_slice(comment);
+
+        let bundle = ZipBundle::new(Cursor::new(zipdata)).unwrap();
+        assert_eq!(bundle.archive_comment().unwrap(), "bundle PK\u{5}\u{6} 2017.1");
+    }
 
-        if let Err(e) = zipitem.read_to_end(&mut buf) {
-            return OpenResult::Err(e.into());
+    #[test]
+    fn unusual_names_are_found() {
+        for name in &["my file.tex", "100%.tex", "caf\u{e9}.tex", "dir/with space/a b.sty"] {
+            let mut bundle = ZipBundle::new(Cursor::new(make_zip(name, b"found"))).unwrap();
+            let mut sb = NoopStatusBackend::new();
+            let mut s = String::new();
+            bundle.input_open_name(OsStr::new(name), &mut sb).unwrap().read_to_string(&mut s).unwrap();
+            assert_eq!(s, "found");
         }
+    }
+
+    #[test]
+    fn corrupted_entry_is_rejected() {
+        let mut zipdata = make_zip("hello.tex", b"hello world");
+
+        // The entry is stored uncompressed, so we can find its contents
+        // verbatim and clobber them.
+        let pos = zipdata.windows(11).position(|w| w == b"hello world").unwrap();
+        zipdata[pos] = b'j';
+
+        let mut bundle = ZipBundle::new(Cursor::new(zipdata)).unwrap();
+        let mut sb = NoopStatusBackend::new();
+
+        match bundle.input_open_name(OsStr::new("hello.tex"), &mut sb) {
+            OpenResult::Err(_) => {},
+            _ => panic!("corrupted bundle entry was not detected"),
+        }
+    }
+
+    #[test]
+    fn probes_do_not_extract() {
+        let mut data = vec![b'x'; 5000];
+        data[..2].copy_from_slice(b"\\r");
+        let mut zipdata = make_zip("big.sty", &data);
+
+        // Clobber the end of the entry, so that we can tell whether it has
+        // been decompressed in full.
+        let pos = zipdata.windows(4).position(|w| w == b"xxxx").unwrap() + 4990;
+        zipdata[pos] = b'y';
+
+        let mut bundle = ZipBundle::new(Cursor::new(zipdata)).unwrap();
+        let mut sb = NoopStatusBackend::new();
+        let mut ih = bundle.input_open_name(OsStr::new("big.sty"), &mut sb).unwrap();
+
+        // This is what XeTeX does when it opens a file.
+        let mut sniff = [0u8; 2];
+        ih.read_exact(&mut sniff).unwrap();
+        assert_eq!(&sniff, b"\\r");
+        ih.try_seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(ih.get_size().unwrap(), 5000);
+
+        let mut buf = Vec::new();
+        assert!(ih.read_to_end(&mut buf).is_err());
+    }
+
+    /// The zip crate checks CRCs too, so a clobbered archive never gets as
+    /// far as our own checks; exercise them directly.
+    #[test]
+    fn checked_reads_catch_bad_data() {
+        let data = b"hello world";
+        let crc = {
+            let mut r = CrcReader::new(&data[..]);
+            r.read_to_end(&mut Vec::new()).unwrap();
+            r.crc().sum()
+        };
+
+        assert_eq!(read_checked("hello.tex", &data[..], crc, 100).unwrap(), &data[..]);
+
+        let e = read_checked("hello.tex", &data[..], crc ^ 1, 100).unwrap_err();
+        assert!(e.to_string().contains("is corrupt"), "unexpected error: {}", e);
+
+        let e = read_checked("hello.tex", &data[..], crc, 5).unwrap_err();
+        assert!(e.to_string().contains("larger than the limit"), "unexpected error: {}", e);
+    }
+
+    #[cfg(not(feature = "no-filesystem"))]
+    #[test]
+    fn extraction_preserves_contents_and_times() {
+        use std::time::UNIX_EPOCH;
+        use tempdir::TempDir;
+
+        let zipdata = make_zip("tex/plain.tex", b"\\relax");
+        let expected_mtime = {
+            let mut zip = ZipArchive::new(Cursor::new(zipdata.clone())).unwrap();
+            let t = zip.by_name("tex/plain.tex").unwrap().last_modified().to_timespec().sec;
+            t
+        };
+
+        let tempdir = TempDir::new("tectonic_zip_test").unwrap();
+        ZipBundle::new(Cursor::new(zipdata)).unwrap().extract_all(tempdir.path()).unwrap();
+
+        let path = tempdir.path().join("tex/plain.tex");
+        let mut s = String::new();
+        File::open(&path).unwrap().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "\\relax");
+
+        let mtime = fs::metadata(&path).unwrap().modified().unwrap().duration_since(UNIX_EPOCH).unwrap();
+        assert_eq!(mtime.as_secs() as i64, expected_mtime);
+
+        let evil = ZipBundle::new(Cursor::new(make_zip("../evil.tex", b"boo"))).unwrap().extract_all(tempdir.path());
+        assert!(evil.is_err());
+        assert!(!tempdir.path().parent().unwrap().join("evil.tex").exists());
+    }
+
+    #[test]
+    fn bad_entries_do_not_spoil_the_bundle() {
+        let mut zw = ZipWriter::new(Cursor::new(Vec::new()));
+        zw.start_file("good.tex", CompressionMethod::Stored).unwrap();
+        zw.write_all(b"good").unwrap();
+        zw.start_file("dir\\bad.tex", CompressionMethod::Stored).unwrap();
+        zw.write_all(b"bad").unwrap();
+        let mut zipdata = zw.finish().unwrap().into_inner();
+
+        // Give the second entry's central directory record a compression
+        // method that nobody supports.
+        let pos = zipdata.windows(4).rposition(|w| w == b"PK\x01\x02").unwrap();
+        zipdata[pos + 10] = 99;
+        zipdata[pos + 11] = 0;
+
+        let mut bundle = ZipBundle::new(Cursor::new(zipdata)).unwrap();
+        let mut sb = NoopStatusBackend::new();
+        assert!(!bundle.input_open_name(OsStr::new("good.tex"), &mut sb).is_not_available());
+        assert_eq!(bundle.glob("*.tex").unwrap(), vec!["good.tex".to_owned()]);
 
-        OpenResult::Ok(InputHandle::new(name, Cursor::new(buf), InputOrigin::Other))
+        let report = bundle.validate().unwrap();
+        assert_eq!(report.n_files, 1);
+        assert!(report.problems.iter().any(|p| p.starts_with("entry #1 is unreadable")));
     }
 }
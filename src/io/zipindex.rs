@@ -0,0 +1,372 @@
+// src/io/zipindex.rs -- Zip bundles accessed through a precomputed index
+// Copyright 2017 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Opening a `ZipBundle` parses the archive's entire central directory, which
+//! is slow for huge bundles. This module lets tools compute an index of a
+//! Zip bundle once, save it alongside the bundle, and then open the bundle
+//! with that index so that the central directory never needs to be read.
+//!
+//! We only need to understand enough of the Zip format to handle the bundles
+//! that we produce: Zip64 archives and compression methods other than
+//! "stored" and "deflated" are not supported.
+
+use flate2::CrcReader;
+use flate2::read::DeflateDecoder;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Write};
+
+use errors::{ErrorKind, Result};
+use status::StatusBackend;
+use super::{InputHandle, InputOrigin, IoProvider, OpenResult, read_limited};
+use super::zipbundle::DEFAULT_MAX_ENTRY_SIZE;
+
+#[cfg(not(feature = "no-filesystem"))] use std::fs::File;
+#[cfg(not(feature = "no-filesystem"))] use std::path::Path;
+
+
+const EOCD_SIGNATURE: u32 = 0x06054b50;
+const CENTRAL_SIGNATURE: u32 = 0x02014b50;
+const LOCAL_SIGNATURE: u32 = 0x04034b50;
+const EOCD_SIZE: usize = 22;
+const MAX_COMMENT_SIZE: usize = 0xFFFF;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+
+fn le16(b: &[u8]) -> u16 {
+    (b[0] as u16) | (b[1] as u16) << 8
+}
+
+fn le32(b: &[u8]) -> u32 {
+    (le16(b) as u32) | (le16(&b[2..]) as u32) << 16
+}
+
+fn malformed(what: &str) -> ::errors::Error {
+    ErrorKind::Msg(format!("malformed or unsupported Zip archive: {}", what)).into()
+}
+
+
+/// What we need to know in order to read one entry of a Zip archive.
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub struct IndexEntry {
+    /// The offset of the entry's local header from the start of the archive.
+    pub header_offset: u64,
+    pub compressed_size: u64,
+    pub size: u64,
+    pub method: u16,
+    pub crc32: u32,
+}
+
+
+/// A BundleIndex records the location of every entry in a Zip bundle.
+///
+/// Its serialized form is textual, with one line per entry: the header
+/// offset, compressed size, uncompressed size, compression method, CRC32 (in
+/// hex), and finally the entry name, all separated by single spaces. The
+/// name comes last so that it may itself contain spaces. Whitespace around
+/// each line is ignored, so that indices with CRLF line endings read
+/// correctly.
+#[derive(Clone,Debug,Default,Eq,PartialEq)]
+pub struct BundleIndex {
+    entries: HashMap<String, IndexEntry>,
+}
+
+impl BundleIndex {
+    pub fn get(&self, name: &str) -> Option<&IndexEntry> {
+        self.entries.get(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn write<W: Write>(&self, dest: &mut W) -> Result<()> {
+        let mut names = self.entries.keys().collect::<Vec<_>>();
+        names.sort();
+
+        for name in names {
+            let e = &self.entries[name];
+            writeln!(dest, "{} {} {} {} {:08x} {}", e.header_offset, e.compressed_size, e.size,
+                     e.method, e.crc32, name)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read<R: Read>(src: R) -> Result<BundleIndex> {
+        let mut entries = HashMap::new();
+
+        for res in BufReader::new(src).lines() {
+            let line = res?;
+            let line = line.trim();
+            let bits = line.splitn(6, ' ').collect::<Vec<_>>();
+
+            if bits.len() != 6 {
+                return Err(ErrorKind::Msg(format!("bad bundle index line \"{}\"", line)).into());
+            }
+
+            entries.insert(bits[5].to_owned(), IndexEntry {
+                header_offset: bits[0].parse()?,
+                compressed_size: bits[1].parse()?,
+                size: bits[2].parse()?,
+                method: bits[3].parse()?,
+                crc32: u32::from_str_radix(bits[4], 16)?,
+            });
+        }
+
+        Ok(BundleIndex { entries: entries })
+    }
+}
+
+
+/// Compute the index of a Zip bundle by parsing its central directory.
+pub fn build_index<R: Read + Seek>(reader: &mut R) -> Result<BundleIndex> {
+    // First, find the "end of central directory" record. It lives at the
+    // very end of the file, unless the archive has a comment.
+
+    let file_len = reader.seek(SeekFrom::End(0))?;
+
+    if file_len < EOCD_SIZE as u64 {
+        return Err(malformed("too short"));
+    }
+
+    let tail_len = ::std::cmp::min(file_len, (EOCD_SIZE + MAX_COMMENT_SIZE) as u64);
+    reader.seek(SeekFrom::Start(file_len - tail_len))?;
+    let mut tail = vec![0u8; tail_len as usize];
+    reader.read_exact(&mut tail)?;
+
+    let eocd = match (0..tail.len() - EOCD_SIZE + 1).rev().find(|&i| le32(&tail[i..]) == EOCD_SIGNATURE) {
+        Some(i) => &tail[i..],
+        None => return Err(malformed("no end-of-central-directory record")),
+    };
+
+    let n_entries = le16(&eocd[10..]) as usize;
+    let cd_size = le32(&eocd[12..]) as usize;
+    let cd_offset = le32(&eocd[16..]) as u64;
+
+    if n_entries == 0xFFFF || cd_offset == 0xFFFF_FFFF {
+        return Err(malformed("Zip64 archives are not supported"));
+    }
+
+    // Now we can read the central directory. Its size comes from the
+    // archive, so make sure that it fits in the file before allocating.
+
+    if cd_offset.checked_add(cd_size as u64).map_or(true, |end| end > file_len) {
+        return Err(malformed("central directory extends past the end of the file"));
+    }
+
+    reader.seek(SeekFrom::Start(cd_offset))?;
+    let mut cd = vec![0u8; cd_size];
+    reader.read_exact(&mut cd)?;
+
+    let mut entries = HashMap::new();
+    let mut pos = 0;
+
+    for _ in 0..n_entries {
+        if pos + 46 > cd.len() || le32(&cd[pos..]) != CENTRAL_SIGNATURE {
+            return Err(malformed("bad central directory entry"));
+        }
+
+        let rec = &cd[pos..];
+        let name_len = le16(&rec[28..]) as usize;
+        let extra_len = le16(&rec[30..]) as usize;
+        let comment_len = le16(&rec[32..]) as usize;
+
+        if 46 + name_len > rec.len() {
+            return Err(malformed("truncated central directory"));
+        }
+
+        let name = String::from_utf8_lossy(&rec[46..46 + name_len]).into_owned();
+
+        entries.insert(name, IndexEntry {
+            header_offset: le32(&rec[42..]) as u64,
+            compressed_size: le32(&rec[20..]) as u64,
+            size: le32(&rec[24..]) as u64,
+            method: le16(&rec[10..]),
+            crc32: le32(&rec[16..]),
+        });
+
+        pos += 46 + name_len + extra_len + comment_len;
+    }
+
+    Ok(BundleIndex { entries: entries })
+}
+
+
+/// An IndexedZipBundle is like a ZipBundle, except that it locates entries
+/// using a precomputed BundleIndex rather than the archive's central
+/// directory. Entries are subject to the same limit on their size as in a
+/// ZipBundle.
+pub struct IndexedZipBundle<R: Read + Seek> {
+    reader: R,
+    index: BundleIndex,
+    max_entry_size: u64,
+}
+
+impl<R: Read + Seek> IndexedZipBundle<R> {
+    pub fn new(reader: R, index: BundleIndex) -> IndexedZipBundle<R> {
+        IndexedZipBundle {
+            reader: reader,
+            index: index,
+            max_entry_size: DEFAULT_MAX_ENTRY_SIZE,
+        }
+    }
+
+    /// Set the largest uncompressed size allowed for an entry, which
+    /// defaults to `DEFAULT_MAX_ENTRY_SIZE`. Entries that exceed it, going
+    /// by either the index or the data themselves, are treated as errors.
+    pub fn max_entry_size(&mut self, size: u64) -> &mut Self {
+        self.max_entry_size = size;
+        self
+    }
+
+    fn read_entry(&mut self, name: &str, entry: &IndexEntry) -> Result<Vec<u8>> {
+        let what = format!("bundle entry \"{}\"", name);
+
+        if entry.size > self.max_entry_size {
+            return Err(ErrorKind::Msg(format!("{} is larger than the limit of {} bytes",
+                                              what, self.max_entry_size)).into());
+        }
+
+        let mut header = [0u8; 30];
+        self.reader.seek(SeekFrom::Start(entry.header_offset))?;
+        self.reader.read_exact(&mut header)?;
+
+        if le32(&header) != LOCAL_SIGNATURE {
+            return Err(malformed("bad local header; is the index out of date?"));
+        }
+
+        let skip = le16(&header[26..]) as i64 + le16(&header[28..]) as i64;
+        self.reader.seek(SeekFrom::Current(skip))?;
+
+        let raw = (&mut self.reader).take(entry.compressed_size);
+        let limit = self.max_entry_size;
+
+        let (buf, crc) = match entry.method {
+            METHOD_STORED => {
+                let mut cr = CrcReader::new(raw);
+                (read_limited(&mut cr, limit, &what)?, cr.crc().sum())
+            },
+            METHOD_DEFLATED => {
+                let mut cr = CrcReader::new(DeflateDecoder::new(raw));
+                (read_limited(&mut cr, limit, &what)?, cr.crc().sum())
+            },
+            m => return Err(malformed(&format!("unsupported compression method {}", m))),
+        };
+
+        if buf.len() as u64 != entry.size {
+            return Err(ErrorKind::BadLength(entry.size as usize, buf.len()).into());
+        }
+
+        if crc != entry.crc32 {
+            return Err(ErrorKind::Msg(format!("bundle entry is corrupt: expected CRC32 {:08x}, got {:08x}",
+                                              entry.crc32, crc)).into());
+        }
+
+        Ok(buf)
+    }
+}
+
+#[cfg(not(feature = "no-filesystem"))]
+impl IndexedZipBundle<File> {
+    pub fn open(path: &Path, index: BundleIndex) -> Result<IndexedZipBundle<File>> {
+        Ok(Self::new(File::open(path)?, index))
+    }
+}
+
+
+impl<R: Read + Seek> IoProvider for IndexedZipBundle<R> {
+    fn input_open_name(&mut self, name: &OsStr, _status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        let (namestr, entry) = match name.to_str().and_then(|s| self.index.get(s).map(|e| (s, *e))) {
+            Some(x) => x,
+            None => return OpenResult::NotAvailable,
+        };
+
+        match self.read_entry(namestr, &entry) {
+            Ok(buf) => OpenResult::Ok(InputHandle::new(name, Cursor::new(buf), InputOrigin::Bundle(name.to_owned()))),
+            Err(e) => OpenResult::Err(e),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zip::{CompressionMethod, ZipWriter};
+    use status::NoopStatusBackend;
+
+    fn make_zip() -> Vec<u8> {
+        let mut zw = ZipWriter::new(Cursor::new(Vec::new()));
+        zw.start_file("stored.tex", CompressionMethod::Stored).unwrap();
+        zw.write_all(b"stored data").unwrap();
+        zw.start_file("dir/deflated file.tex", CompressionMethod::Deflated).unwrap();
+        zw.write_all(&vec![b'x'; 5000]).unwrap();
+        zw.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn index_roundtrip() {
+        let index = build_index(&mut Cursor::new(make_zip())).unwrap();
+        assert_eq!(index.len(), 2);
+
+        let mut text = Vec::new();
+        index.write(&mut text).unwrap();
+        assert_eq!(BundleIndex::read(&text[..]).unwrap(), index);
+    }
+
+    #[test]
+    fn indexed_reads() {
+        let data = make_zip();
+        let index = build_index(&mut Cursor::new(data.clone())).unwrap();
+        let mut bundle = IndexedZipBundle::new(Cursor::new(data), index);
+        let mut sb = NoopStatusBackend::new();
+
+        let mut buf = Vec::new();
+        bundle.input_open_name(OsStr::new("stored.tex"), &mut sb).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"stored data");
+
+        buf.clear();
+        bundle.input_open_name(OsStr::new("dir/deflated file.tex"), &mut sb).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, vec![b'x'; 5000]);
+
+        assert!(bundle.input_open_name(OsStr::new("missing.tex"), &mut sb).is_not_available());
+    }
+
+    #[test]
+    fn crlf_indices_are_read() {
+        let index = build_index(&mut Cursor::new(make_zip())).unwrap();
+
+        let mut text = Vec::new();
+        index.write(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap().replace("\n", "\r\n");
+        assert_eq!(BundleIndex::read(text.as_bytes()).unwrap(), index);
+    }
+
+    #[test]
+    fn entry_sizes_are_limited() {
+        let data = make_zip();
+        let mut index = build_index(&mut Cursor::new(data.clone())).unwrap();
+        let mut bundle = IndexedZipBundle::new(Cursor::new(data.clone()), index.clone());
+        bundle.max_entry_size(4999);
+        let mut sb = NoopStatusBackend::new();
+
+        assert!(!bundle.input_open_name(OsStr::new("stored.tex"), &mut sb).is_not_available());
+        match bundle.input_open_name(OsStr::new("dir/deflated file.tex"), &mut sb) {
+            OpenResult::Err(e) => assert!(e.to_string().contains("larger than the limit")),
+            _ => panic!("oversized entry was opened"),
+        }
+
+        // An index that understates the size doesn't get around the limit.
+        index.entries.get_mut("dir/deflated file.tex").unwrap().size = 10;
+        let mut bundle = IndexedZipBundle::new(Cursor::new(data), index);
+        bundle.max_entry_size(4999);
+        match bundle.input_open_name(OsStr::new("dir/deflated file.tex"), &mut sb) {
+            OpenResult::Err(e) => assert!(e.to_string().contains("larger than the limit")),
+            _ => panic!("oversized entry was opened"),
+        }
+    }
+}
@@ -33,33 +33,46 @@
 //! for a demonstration of how to run the engine.
 
 extern crate app_dirs;
+#[cfg(feature = "brotli")] extern crate brotli;
 #[macro_use] extern crate error_chain;
 extern crate flate2;
 extern crate fs2;
+#[cfg(feature = "async")] extern crate futures;
+#[cfg(feature = "git")] extern crate git2;
 extern crate hyper;
 extern crate hyper_native_tls;
+#[macro_use] extern crate lazy_static;
 extern crate libc;
 extern crate md_5 as md5;
+#[cfg(feature = "mmap")] extern crate memmap;
 extern crate mkstemp;
+#[cfg(feature = "sqlite")] extern crate rusqlite;
 #[macro_use] extern crate serde_derive;
 extern crate serde;
 extern crate sha2;
+#[cfg(test)] extern crate tempdir;
 extern crate termcolor;
 extern crate toml;
 extern crate zip;
+#[cfg(feature = "xz")] extern crate xz2;
 
 #[macro_use] pub mod status;
 #[macro_use] pub mod errors;
-pub mod config;
+#[cfg(not(feature = "no-filesystem"))] pub mod config;
 pub mod digest;
+pub mod driver;
 pub mod engines;
 pub mod io;
+pub mod texlog;
 
-pub use engines::tex::{TexEngine, TexResult};
+pub use engines::tex::{EngineMode, TexEngine, TexOutcome, TexResult};
 pub use engines::xdvipdfmx::XdvipdfmxEngine;
 pub use engines::bibtex::BibtexEngine;
 pub use errors::{Error, ErrorKind, Result};
+pub use driver::{compile_to_memory, compile_to_writer};
+#[cfg(not(feature = "no-filesystem"))] pub use driver::latex_to_pdf;
 
+#[cfg(not(feature = "no-filesystem"))]
 const APP_INFO: app_dirs::AppInfo = app_dirs::AppInfo {name: "Tectonic", author: "TectonicProject"};
 
 const FORMAT_SERIAL: u32 = 25; // keep synchronized with tectonic/constants.h!!
@@ -3,6 +3,7 @@
 // Licensed under the MIT License.
 
 #[macro_use] pub mod termcolor;
+pub mod sse;
 
 use std::cmp;
 use std::fmt::Arguments;
@@ -0,0 +1,95 @@
+// src/status/sse.rs -- status updates as Server-Sent Events
+// Copyright 2017 the Tectonic Project
+// Licensed under the MIT License.
+
+use std::fmt::Arguments;
+use std::io::Write;
+
+use errors::Error;
+use super::{MessageKind, StatusBackend};
+
+
+/// SseStatusBackend writes each status message to a stream in the
+/// Server-Sent Events format, so that the stream can be passed along as the
+/// body of an HTTP response with content type `text/event-stream`. A browser
+/// can then display a compile's messages as they happen using the standard
+/// `EventSource` API.
+///
+/// Each message becomes one event, named after its kind (`note`, `warning`
+/// or `error`). The message text is carried in `data:` lines, followed by a
+/// `caused by:` line for each link in the attached error's chain, if any.
+///
+/// Write errors are ignored: if the client has gone away, there is nobody
+/// left to tell about them, and the compile itself shouldn't fail.
+
+pub struct SseStatusBackend<W: Write> {
+    inner: W,
+}
+
+
+impl<W: Write> SseStatusBackend<W> {
+    pub fn new(inner: W) -> SseStatusBackend<W> {
+        SseStatusBackend {
+            inner: inner,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    fn write_event(&mut self, kind: MessageKind, text: &str) -> ::std::io::Result<()> {
+        let event = match kind {
+            MessageKind::Note => "note",
+            MessageKind::Warning => "warning",
+            MessageKind::Error => "error",
+        };
+
+        writeln!(self.inner, "event: {}", event)?;
+
+        // A newline would end the data field early, so each line of the
+        // message gets its own field; the client joins them back up.
+        for line in text.lines() {
+            writeln!(self.inner, "data: {}", line)?;
+        }
+
+        self.inner.write_all(b"\n")?;
+        self.inner.flush()
+    }
+}
+
+
+impl<W: Write> StatusBackend for SseStatusBackend<W> {
+    fn report(&mut self, kind: MessageKind, args: Arguments, err: Option<&Error>) {
+        let mut text = format!("{}", args);
+
+        if let Some(e) = err {
+            for item in e.iter() {
+                text.push_str(&format!("\ncaused by: {}", item));
+            }
+        }
+
+        let _ = self.write_event(kind, &text);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::ErrorKind;
+
+    #[test]
+    fn messages_become_events() {
+        let mut sb = SseStatusBackend::new(Vec::new());
+        tt_note!(sb, "running {} pass", "first");
+        tt_warning!(sb, "two\nlines");
+        let e: Error = ErrorKind::Msg("disk full".to_owned()).into();
+        tt_error!(sb, "write failed"; e);
+
+        assert_eq!(String::from_utf8(sb.into_inner()).unwrap(),
+                   "event: note\ndata: running first pass\n\n\
+                    event: warning\ndata: two\ndata: lines\n\n\
+                    event: error\ndata: write failed\ndata: caused by: disk full\n\n");
+    }
+}
@@ -0,0 +1,103 @@
+// src/texlog.rs -- scanning TeX log files
+// Copyright 2017 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Helpers for picking information out of the log files that the TeX engine
+//! writes. Many problems with a document, such as undefined references, are
+//! only ever reported in the log, so tools that want to be strict about them
+//! have to look there.
+
+use std::str::FromStr;
+
+use errors::{Error, ErrorKind};
+
+
+/// The kinds of warnings that we know how to find in a log.
+#[derive(Clone,Copy,Debug,Eq,Hash,PartialEq)]
+pub enum WarningCategory {
+    /// Warnings issued by the LaTeX kernel, such as undefined references or
+    /// citations ("LaTeX Warning: ...").
+    Latex,
+
+    /// Warnings issued by packages and classes ("Package foo Warning: ...").
+    Package,
+
+    /// Overfull boxes.
+    Overfull,
+
+    /// Underfull boxes.
+    Underfull,
+}
+
+impl WarningCategory {
+    /// All of the categories.
+    pub fn all() -> Vec<WarningCategory> {
+        vec![WarningCategory::Latex, WarningCategory::Package,
+             WarningCategory::Overfull, WarningCategory::Underfull]
+    }
+
+    fn matches(self, line: &str) -> bool {
+        match self {
+            WarningCategory::Latex => line.starts_with("LaTeX Warning:"),
+            WarningCategory::Package => {
+                (line.starts_with("Package ") || line.starts_with("Class ")) && line.contains(" Warning:")
+            },
+            WarningCategory::Overfull => line.starts_with("Overfull \\"),
+            WarningCategory::Underfull => line.starts_with("Underfull \\"),
+        }
+    }
+}
+
+impl FromStr for WarningCategory {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<WarningCategory, Error> {
+        match s {
+            "latex" => Ok(WarningCategory::Latex),
+            "package" => Ok(WarningCategory::Package),
+            "overfull" => Ok(WarningCategory::Overfull),
+            "underfull" => Ok(WarningCategory::Underfull),
+            _ => Err(ErrorKind::Msg(format!("unknown warning category \"{}\"", s)).into()),
+        }
+    }
+}
+
+
+/// Find the warnings of the specified categories in the text of a TeX log
+/// file. The first line of each warning is returned.
+pub fn find_warnings(log: &str, categories: &[WarningCategory]) -> Vec<String> {
+    log.lines()
+        .filter(|line| categories.iter().any(|c| c.matches(line)))
+        .map(|line| line.to_owned())
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOG: &'static str = "\
+This is XeTeX, Version 3.14159265-2.6-0.99996 (Tectonic)
+LaTeX Warning: Reference `fig:1' on page 1 undefined on input line 5.
+Package hyperref Warning: Token not allowed in a PDF string (Unicode):
+Overfull \\hbox (12.0pt too wide) in paragraph at lines 7--8
+Underfull \\vbox (badness 10000) has occurred while \\output is active
+Output written on doc.xdv (1 page, 1234 bytes).
+";
+
+    #[test]
+    fn warnings_by_category() {
+        assert_eq!(find_warnings(LOG, &WarningCategory::all()).len(), 4);
+        assert_eq!(find_warnings(LOG, &[WarningCategory::Latex]),
+                   vec!["LaTeX Warning: Reference `fig:1' on page 1 undefined on input line 5.".to_owned()]);
+        assert_eq!(find_warnings(LOG, &[WarningCategory::Overfull, WarningCategory::Package]).len(), 2);
+        assert!(find_warnings(LOG, &[]).is_empty());
+    }
+
+    #[test]
+    fn parse_categories() {
+        assert_eq!("underfull".parse::<WarningCategory>().unwrap(), WarningCategory::Underfull);
+        assert!("bogus".parse::<WarningCategory>().is_err());
+    }
+}
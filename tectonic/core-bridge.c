@@ -23,6 +23,10 @@ tt_bridge_api_t *tectonic_global_bridge = NULL;
 
 #define BUF_SIZE 1024
 
+/* Values passed to longjmp(). */
+#define JUMP_ABORT 1
+#define JUMP_STOPPED_EARLY 2
+
 static jmp_buf jump_buffer;
 static char error_buf[BUF_SIZE] = "";
 
@@ -34,7 +38,7 @@ _tt_abort(const char *format, ...)
     va_start(ap, format);
     vsnprintf(error_buf, BUF_SIZE, format, ap);
     va_end(ap);
-    longjmp(jump_buffer, 1);
+    longjmp(jump_buffer, JUMP_ABORT);
 }
 
 const char *
@@ -51,12 +55,19 @@ int
 tex_simple_main(tt_bridge_api_t *api, char *dump_name, char *input_file_name)
 {
     extern tt_history_t tt_run_engine(char *dump_name, char *input_file_name);
+    extern tt_history_t history;
 
     int rv;
 
     tectonic_global_bridge = api;
 
-    if (setjmp(jump_buffer)) {
+    switch (setjmp(jump_buffer)) {
+    case 0:
+        break;
+    case JUMP_STOPPED_EARLY:
+        tectonic_global_bridge = NULL;
+        return history;
+    default:
         tectonic_global_bridge = NULL;
         return HISTORY_FATAL_ERROR;
     }
@@ -109,6 +120,17 @@ bibtex_simple_main(tt_bridge_api_t *api, char *aux_file_name)
 
 #define TGB tectonic_global_bridge
 
+/* The Rust side can ask for the engine to stop early. It gets the chance to
+ * do so whenever a file is opened and whenever a page is shipped out; if it
+ * wants to, we abort just as we would for a fatal error. */
+
+static void
+check_cancelled(void)
+{
+    if (TGB->check_cancelled(TGB->context))
+        _tt_abort("the compile was cancelled");
+}
+
 char *
 kpse_find_file(char const *name, kpse_file_format_type format, int must_exist)
 {
@@ -174,6 +196,7 @@ ttstub_get_data_md5(char const *data, size_t len, char *digest)
 rust_output_handle_t
 ttstub_output_open(char const *path, int is_gz)
 {
+    check_cancelled();
     return TGB->output_open(TGB->context, path, is_gz);
 }
 
@@ -210,6 +233,7 @@ ttstub_output_close(rust_output_handle_t handle)
 rust_input_handle_t
 ttstub_input_open(char const *path, kpse_file_format_type format, int is_gz)
 {
+    check_cancelled();
     return TGB->input_open(TGB->context, path, format, is_gz);
 }
 
@@ -254,3 +278,41 @@ ttstub_input_close(rust_input_handle_t handle)
 {
     return TGB->input_close(TGB->context, handle);
 }
+
+/* The Rust side can also ask the TeX engine to stop once it has shipped out
+ * enough pages. In that case we finish up the output files just as TeX would
+ * at the end of the job, so that the pages so far are usable, and return
+ * from tex_simple_main() as if the job had ended normally. */
+
+static void
+stop_early(void)
+{
+    extern void close_files_and_terminate(void);
+    extern void pdf_files_close(void);
+    extern char *output_comment;
+
+    close_files_and_terminate();
+    pdf_files_close();
+
+    /* This is freed at the end of a normal run, too. */
+    free(output_comment);
+    output_comment = NULL;
+
+    longjmp(jump_buffer, JUMP_STOPPED_EARLY);
+}
+
+void
+ttstub_page_shipped(int page_number)
+{
+    int stop = TGB->page_shipped(TGB->context, page_number);
+    check_cancelled();
+
+    if (stop)
+        stop_early();
+}
+
+int
+ttstub_shell_escape(const char *command)
+{
+    return TGB->shell_escape(TGB->context, command);
+}
@@ -113,6 +113,10 @@ typedef struct tt_bridge_api_t {
     int (*input_getc)(void *context, rust_input_handle_t handle);
     int (*input_ungetc)(void *context, rust_input_handle_t handle, int ch);
     int (*input_close)(void *context, rust_input_handle_t handle);
+
+    int (*page_shipped)(void *context, int page_number);
+    int (*shell_escape)(void *context, const char *command);
+    int (*check_cancelled)(void *context);
 } tt_bridge_api_t;
 
 
@@ -161,6 +165,9 @@ int ttstub_input_getc (rust_input_handle_t handle);
 int ttstub_input_ungetc (rust_input_handle_t handle, int ch);
 int ttstub_input_close (rust_input_handle_t handle);
 
+void ttstub_page_shipped (int page_number);
+int ttstub_shell_escape (const char *command);
+
 END_EXTERN_C
 
 #endif /* not TECTONIC_CORE_BRIDGE_H */
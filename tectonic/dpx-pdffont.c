@@ -80,7 +80,8 @@ pdf_font_set_dpi (int font_dpi)
 }
 
 
-/* If an environment variable SOURCE_DATE_EPOCH is correctly defined like
+/* If the driving program has set tt_source_date_epoch, or if an environment
+ * variable SOURCE_DATE_EPOCH is correctly defined like
  * SOURCE_DATE_EPOCH=1456304492, then returns this value, to be used as the
  * 'current time', otherwise returns INVALID_EPOCH_VALUE (= (time_t)-1).
  * In the case of Microsoft Visual Studio 2010, the value should be less
@@ -95,6 +96,9 @@ get_unique_time_if_given(void)
   char *endptr;
   time_t ret = INVALID_EPOCH_VALUE;
 
+  if (tt_source_date_epoch >= 0)
+    return (time_t) tt_source_date_epoch;
+
   source_date_epoch = getenv("SOURCE_DATE_EPOCH");
   if (source_date_epoch) {
     errno = 0;
@@ -10,6 +10,9 @@
 
 #include <string.h>
 
+
+int64_t tt_source_date_epoch = -1;
+
 int
 tt_set_int_variable (char *var_name, int value)
 {
@@ -19,6 +22,8 @@ tt_set_int_variable (char *var_name, int value)
         in_initex_mode = (value != 0);
     else if (streq_ptr(var_name, "synctex_enabled"))
         synctex_enabled = (value != 0);
+    else if (streq_ptr(var_name, "shell_escape_mode"))
+        shell_escape_mode = value;
     else
         return 1; /* Uh oh: unrecognized variable */
 
@@ -39,8 +44,20 @@ tt_set_string_variable (char *var_name, char *value)
             strncpy (output_comment, value, 255);
             output_comment[255] = '\0';
         }
+    } else if (streq_ptr(var_name, "job_name")) {
+        /* An empty value means to use the name of the primary input, as
+         * usual. */
+        free (job_name_override);
+        job_name_override = (*value) ? xstrdup (value) : NULL;
     } else
         return 1; /* Uh oh: unrecognized variable */
 
     return 0; /* success */
 }
+
+
+void
+tt_set_source_date_epoch (int64_t epoch)
+{
+    tt_source_date_epoch = epoch;
+}
@@ -59,6 +59,12 @@ BEGIN_EXTERN_C
 
 int tt_set_int_variable (char *var_name, int value);
 int tt_set_string_variable (char *var_name, char *value);
+void tt_set_source_date_epoch (int64_t epoch);
+
+/* If non-negative, the time (in seconds since the Unix epoch) that the
+ * engines should use as "now", overriding the SOURCE_DATE_EPOCH environment
+ * variable. */
+extern int64_t tt_source_date_epoch;
 
 END_EXTERN_C
 
@@ -22,9 +22,17 @@ get_date_and_time (integer *minutes,  integer *day,
                    integer *month,  integer *year)
 {
   struct tm *tmptr;
+  time_t myclock;
+
+  if (tt_source_date_epoch >= 0) {
+    /* Reproducible mode: a fixed time, independent of the time zone. */
+    myclock = (time_t) tt_source_date_epoch;
+    tmptr = gmtime (&myclock);
+  } else {
+    myclock = time ((time_t *) 0);
+    tmptr = localtime (&myclock);
+  }
 
-  time_t myclock = time ((time_t *) 0);
-  tmptr = localtime (&myclock);
   *minutes = tmptr->tm_hour * 60 + tmptr->tm_min;
   *day = tmptr->tm_mday;
   *month = tmptr->tm_mon + 1;
@@ -7588,7 +7588,7 @@ scan_something_internal(small_number level, bool negative)
                     break;
 
                 case PDF_SHELL_ESCAPE_CODE:
-                    cur_val = 0; /* shellenabledp */
+                    cur_val = shell_escape_mode;
                     break;
 
                 case ETEX_VERSION_CODE:
@@ -10190,7 +10190,7 @@ conditional(void)
     case IF_EOF_CODE:
         scan_four_bit_int_or_18();
         if (cur_val == 18)
-            b = 1; /* !shellenabledp */
+            b = (shell_escape_mode == 0);
         else
             b = (read_open[cur_val] == CLOSED);
         break;
@@ -10791,7 +10791,10 @@ start_input(const char *primary_input_name)
     /* Finally we start really doing stuff with the newly-opened file. */
 
     if (job_name == 0) {
-        job_name = cur_name;
+        if (job_name_override != NULL)
+            job_name = maketexstring(job_name_override);
+        else
+            job_name = cur_name;
         open_log_file();
     }
 
@@ -12384,6 +12387,10 @@ write_out(int32_t p)
     flush_list(def_ref);
 
     if (j == 18) {
+        str_number cmd_str;
+        char *cmd;
+        int escape_status;
+
         if (INTPAR(tracing_online) <= 0)
             selector = SELECTOR_LOG_ONLY;
         else
@@ -12392,15 +12399,29 @@ write_out(int32_t p)
         if (!log_opened)
             selector = SELECTOR_TERM_ONLY;
 
+        /* Whether the command is actually run is up to the Rust side's
+         * shell-escape policy. */
+        cmd_str = make_string();
+        cmd = gettexstring(cmd_str);
+        escape_status = ttstub_shell_escape(cmd);
+        free(cmd);
+
         print_nl(S(runsystem_));
-        for (d = 0; d <= (pool_ptr - str_start[(str_ptr) - 65536L]) - 1; d++)
-            print(str_pool[str_start[(str_ptr) - 65536L] + d]);
+        for (d = 0; d <= (pool_ptr - str_start[(str_ptr - 1) - 65536L]) - 1; d++)
+            print(str_pool[str_start[(str_ptr - 1) - 65536L] + d]);
 
         print(S(_____Z2/*")..."*/));
-        print(S(disabled));
+        if (escape_status < 0)
+            print(S(disabled));
+        else if (escape_status == 0)
+            print_c_string("executed");
+        else
+            print_c_string("failed");
         print_char(46 /*"." */ );
         print_nl(S());
         print_ln();
+
+        str_ptr--;
         pool_ptr = str_start[(str_ptr) - 65536L];
     }
 
@@ -14189,6 +14210,7 @@ void ship_out(int32_t p)
         }
         total_pages++;
         cur_s = -1; /*:662 */
+        ttstub_page_shipped(total_pages);
 
     done:
 /*1518: */
@@ -302,6 +302,7 @@ integer dvi_buf_size;
 integer expand_depth;
 int file_line_error_style_p;
 int halt_on_error_p;
+int shell_escape_mode; /* 0: disabled; 1: unrestricted; 2: restricted */
 bool quoted_filename;
 bool src_specials_p;
 bool insert_src_special_auto;
@@ -675,6 +676,7 @@ bool stop_at_space;
 str_number save_str_ptr;
 pool_pointer save_pool_ptr;
 char *output_comment;
+char *job_name_override;
 unsigned char k, l;
 integer native_font_type_flag;
 bool xtx_ligature_present;
@@ -143,6 +143,28 @@ fn help_flag() {
     success_or_panic(output);
 }
 
+#[test]
+fn outfmt_xdv() {
+    // With --outfmt=xdv we stop after the TeX pass: we get the XDV file, but
+    // xdvipdfmx never runs.
+    let tempdir = setup_and_copy_files(&["test space.tex"]);
+
+    let output = run_tectonic(tempdir.path(), &["--format=plain.fmt.gz", "--outfmt=xdv", "test space.tex"]);
+    success_or_panic(output);
+    check_file(&tempdir, "test space.xdv");
+    assert!(!tempdir.path().join("test space.pdf").exists());
+
+    let output = run_tectonic(tempdir.path(), &["--format=plain.fmt.gz", "test space.tex"]);
+    success_or_panic(output);
+
+    let mut xdv = Vec::new();
+    File::open(tempdir.path().join("test space.xdv")).unwrap().read_to_end(&mut xdv).unwrap();
+    let mut pdf = Vec::new();
+    File::open(tempdir.path().join("test space.pdf")).unwrap().read_to_end(&mut pdf).unwrap();
+    assert!(pdf.starts_with(b"%PDF"));
+    assert!(xdv != pdf);
+}
+
 #[test] // GitHub #31
 fn relative_include() {
     let tempdir = setup_and_copy_files(&["subdirectory/relative_include.tex",
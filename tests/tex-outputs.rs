@@ -17,9 +17,10 @@ use tectonic::errors::{DefinitelySame, ErrorKind, Result};
 use tectonic::engines::NoopIoEventBackend;
 use tectonic::engines::tex::TexResult;
 use tectonic::io::{FilesystemIo, FilesystemPrimaryInputIo, IoStack, MemoryIo, try_open_file};
+use tectonic::io::stdstreams::BufferedPrimaryIo;
 use tectonic::io::testing::SingleInputFileIo;
 use tectonic::status::NoopStatusBackend;
-use tectonic::TexEngine;
+use tectonic::{TexEngine, XdvipdfmxEngine};
 
 const TOP: &'static str = env!("CARGO_MANIFEST_DIR");
 
@@ -240,3 +241,75 @@ fn tectoniccodatokens_ok() { TestCase::new("tectoniccodatokens_ok").go() }
 
 #[test]
 fn the_letter_a() { TestCase::new("the_letter_a").go() }
+
+#[test]
+fn job_name_sets_output_names() {
+    let _guard = LOCK.lock().unwrap(); // until we're thread-safe ...
+
+    let mut p = PathBuf::from(TOP);
+    p.push("tests");
+    let mut fmt = set_up_format_file(&p).expect("couldn't write format file");
+
+    p.push("tex-outputs");
+    p.push("the_letter_a.tex");
+    let mut tex = FilesystemPrimaryInputIo::new(&p);
+    let mut mem = MemoryIo::new(true);
+
+    {
+        let mut io = IoStack::new(vec![
+            &mut mem,
+            &mut tex,
+            &mut fmt,
+        ]);
+        TexEngine::new()
+            .job_name(Some("renamed".to_owned()))
+            .process(&mut io, &mut NoopIoEventBackend::new(),
+                     &mut NoopStatusBackend::new(), "plain.fmt.gz", "the_letter_a.tex")
+            .unwrap();
+    }
+
+    let files = mem.files.borrow();
+    assert!(files.contains_key(OsStr::new("renamed.log")));
+    assert!(files.contains_key(OsStr::new("renamed.xdv")));
+    assert!(!files.contains_key(OsStr::new("the_letter_a.xdv")));
+}
+
+#[test]
+fn fixed_source_date_epoch_is_reproducible() {
+    let _guard = LOCK.lock().unwrap(); // until we're thread-safe ...
+
+    let mut p = PathBuf::from(TOP);
+    p.push("tests");
+    let mut fmt = set_up_format_file(&p).expect("couldn't write format file");
+
+    // No text, so that xdvipdfmx doesn't need any fonts.
+    let mut make_pdf = |epoch: i64| {
+        let mut tex = BufferedPrimaryIo::from_text("\\nopagenumbers \\hrule height 1in width 1in \\end");
+        let mut mem = MemoryIo::new(true);
+
+        {
+            let mut io = IoStack::new(vec![
+                &mut mem,
+                &mut tex,
+                &mut fmt,
+            ]);
+            TexEngine::new()
+                .source_date_epoch(Some(epoch))
+                .process(&mut io, &mut NoopIoEventBackend::new(),
+                         &mut NoopStatusBackend::new(), "plain.fmt.gz", "texput.tex")
+                .unwrap();
+            XdvipdfmxEngine::new()
+                .source_date_epoch(Some(epoch))
+                .process(&mut io, &mut NoopIoEventBackend::new(),
+                         &mut NoopStatusBackend::new(), "texput.xdv", "texput.pdf")
+                .unwrap();
+        }
+
+        let pdf = mem.files.borrow().get(OsStr::new("texput.pdf")).unwrap().clone();
+        pdf
+    };
+
+    let first = make_pdf(1500000000);
+    assert_eq!(first, make_pdf(1500000000));
+    assert!(first != make_pdf(1600000000));
+}